@@ -42,9 +42,9 @@ impl<'a> Item<'a> {
     }
 }
 
-impl<'a> Into<String> for Item<'a> {
-    fn into(self) -> String {
-        format!("{}", self)
+impl<'a> From<Item<'a>> for String {
+    fn from(value: Item<'a>) -> Self {
+        format!("{}", value)
     }
 }
 
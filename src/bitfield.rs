@@ -0,0 +1,116 @@
+use anyhow::bail;
+
+/// Pieces a peer has, one bit per piece, the high bit of the first byte standing for the first
+/// piece. Bits past the last piece, filling the last byte, are spare and must be cleared.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+}
+
+impl Bitfield {
+    /// Bitfield of a torrent of `pieces_count` pieces, none of them set
+    pub fn new(pieces_count: usize) -> Self {
+        Self {
+            bytes: vec![0; pieces_count.div_ceil(8)],
+        }
+    }
+
+    /// Bitfield of a torrent of `pieces_count` pieces with `pieces` set, leaving out those past
+    /// the last piece
+    pub fn from_pieces(pieces_count: usize, pieces: impl IntoIterator<Item = u32>) -> Self {
+        let mut bitfield = Self::new(pieces_count);
+        for index in pieces.into_iter().filter(|i| (*i as usize) < pieces_count) {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    /// Bitfield as sent on the wire, which is not checked against a number of pieces until
+    /// [`Bitfield::validate`] is called
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self { bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        self.bytes
+            .get(index as usize / 8)
+            .is_some_and(|i| i & (0x80 >> (index % 8)) != 0)
+    }
+
+    /// Sets piece `index`, which is ignored when past the end of the bitfield
+    pub fn set(&mut self, index: u32) {
+        if let Some(byte) = self.bytes.get_mut(index as usize / 8) {
+            *byte |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// How many pieces are set
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|i| i.count_ones() as usize).sum()
+    }
+
+    /// Indexes of the pieces set, in order
+    pub fn pieces(&self) -> impl Iterator<Item = u32> + '_ {
+        self.bytes
+            .iter()
+            .enumerate()
+            .flat_map(|(byte_index, byte)| {
+                (0..8u32)
+                    .filter(move |bit| byte & (0x80 >> bit) != 0)
+                    .map(move |bit| byte_index as u32 * 8 + bit)
+            })
+    }
+
+    /// Checks that the bitfield is the one of a torrent of `pieces_count` pieces: as many bytes
+    /// as needed for them, and no spare bit set
+    pub fn validate(&self, pieces_count: usize) -> anyhow::Result<()> {
+        let expected = pieces_count.div_ceil(8);
+        if self.bytes.len() != expected {
+            bail!(
+                "bitfield of {} bytes instead of {expected} for {pieces_count} pieces",
+                self.bytes.len()
+            );
+        }
+        if let Some(index) = self.pieces().find(|i| *i as usize >= pieces_count) {
+            bail!("bitfield has spare bit {index} set, past the last of {pieces_count} pieces");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Bitfield;
+
+    #[test]
+    fn bits_of_pieces() {
+        let mut bitfield = Bitfield::from_pieces(10, [0, 7, 12]);
+        assert_eq!(&[0b1000_0001, 0b0000_0000], bitfield.as_bytes());
+        bitfield.set(9);
+        bitfield.set(16);
+
+        assert_eq!(&[0b1000_0001, 0b0100_0000], bitfield.as_bytes());
+        assert!(bitfield.has(7) && bitfield.has(9));
+        assert!(!bitfield.has(8) && !bitfield.has(16));
+        assert_eq!(3, bitfield.count());
+        assert_eq!(vec![0, 7, 9], bitfield.pieces().collect::<Vec<_>>());
+        assert_eq!(Bitfield::from_bytes(Vec::new()), Bitfield::new(0));
+    }
+
+    #[test]
+    fn validate_length_and_spare_bits() {
+        assert!(Bitfield::from_pieces(10, [0, 9]).validate(10).is_ok());
+        assert!(Bitfield::new(0).validate(0).is_ok());
+        assert!(Bitfield::new(10).validate(17).is_err());
+        assert!(Bitfield::from_bytes(vec![0, 0b0010_0000])
+            .validate(10)
+            .is_err());
+        assert!(Bitfield::from_bytes(vec![0, 0b0010_0000])
+            .validate(11)
+            .is_ok());
+    }
+}
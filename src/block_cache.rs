@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+
+/// Size of the runs of contiguous blocks written at once as they arrive
+pub const FLUSHED_RUN: usize = 256 * 1024;
+
+/// Blocks of pieces that are not complete yet, held back so that they reach the disk as large
+/// contiguous runs instead of one small write each
+#[derive(Debug)]
+pub struct BlockCache {
+    /// Data of the blocks by piece and offset in the piece
+    pieces: BTreeMap<u32, BTreeMap<u32, Vec<u8>>>,
+    flushed_run: usize,
+}
+
+/// Contiguous blocks of a piece, to be written at once
+#[derive(Debug, PartialEq)]
+pub struct Run {
+    pub piece: u32,
+    pub begin: u32,
+    pub data: Vec<u8>,
+    /// The blocks of the run, as `(begin, length)`
+    pub blocks: Vec<(u32, u32)>,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self::new(FLUSHED_RUN)
+    }
+}
+
+impl BlockCache {
+    /// A cache handing out runs once they are `flushed_run` bytes long
+    pub fn new(flushed_run: usize) -> Self {
+        Self {
+            pieces: BTreeMap::new(),
+            flushed_run,
+        }
+    }
+
+    /// Adds the block of piece `index` at `begin`, replacing the blocks it overlaps, such as those
+    /// of an earlier download of the piece. Returns the run the block is part of, taken out of
+    /// the cache, once it is long enough to be written.
+    pub fn insert(&mut self, index: u32, begin: u32, data: Vec<u8>) -> Option<Run> {
+        let end = begin as usize + data.len();
+        let blocks = self.pieces.entry(index).or_default();
+        blocks.retain(|i, block| *i as usize >= end || *i as usize + block.len() <= begin as usize);
+        blocks.insert(begin, data);
+
+        let runs = runs(index, blocks);
+        let run = runs
+            .into_iter()
+            .find(|i| i.begin <= begin && begin < i.begin + i.data.len() as u32)?;
+        if run.data.len() < self.flushed_run {
+            return None;
+        }
+        for (begin, _) in &run.blocks {
+            blocks.remove(begin);
+        }
+        if blocks.is_empty() {
+            self.pieces.remove(&index);
+        }
+        Some(run)
+    }
+
+    /// Drops the blocks of piece `index`, which are written along with the piece
+    pub fn remove(&mut self, index: u32) {
+        self.pieces.remove(&index);
+    }
+
+    /// The runs of every block left, emptying the cache
+    pub fn drain(&mut self) -> Vec<Run> {
+        std::mem::take(&mut self.pieces)
+            .into_iter()
+            .flat_map(|(index, blocks)| runs(index, &blocks))
+            .collect()
+    }
+}
+
+/// Runs of contiguous `blocks` of piece `index`, in order
+fn runs(index: u32, blocks: &BTreeMap<u32, Vec<u8>>) -> Vec<Run> {
+    let mut runs: Vec<Run> = Vec::new();
+    for (begin, data) in blocks {
+        let block = (*begin, data.len() as u32);
+        match runs.last_mut() {
+            Some(run) if run.begin as usize + run.data.len() == *begin as usize => {
+                run.data.extend_from_slice(data);
+                run.blocks.push(block);
+            }
+            _ => runs.push(Run {
+                piece: index,
+                begin: *begin,
+                data: data.clone(),
+                blocks: vec![block],
+            }),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BlockCache, Run};
+
+    #[test]
+    fn contiguous_blocks_are_written_as_runs() {
+        let mut cache = BlockCache::new(48);
+
+        assert_eq!(None, cache.insert(0, 0, vec![0; 16]));
+        assert_eq!(None, cache.insert(0, 32, vec![2; 16]));
+        assert_eq!(None, cache.insert(1, 16, vec![1; 16]));
+        let run = cache.insert(0, 16, vec![1; 16]).expect("run of 48 bytes");
+
+        assert_eq!(0, run.piece);
+        assert_eq!(0, run.begin);
+        assert_eq!([[0; 16], [1; 16], [2; 16]].concat(), run.data);
+        assert_eq!(vec![(0, 16), (16, 16), (32, 16)], run.blocks);
+        assert_eq!(
+            vec![Run {
+                piece: 1,
+                begin: 16,
+                data: vec![1; 16],
+                blocks: vec![(16, 16)]
+            }],
+            cache.drain()
+        );
+        assert!(cache.drain().is_empty());
+    }
+
+    #[test]
+    fn blocks_replace_those_they_overlap() {
+        let mut cache = BlockCache::new(64);
+
+        cache.insert(0, 0, vec![0; 16]);
+        cache.insert(0, 16, vec![0; 16]);
+        cache.insert(0, 48, vec![0; 16]);
+        // downloaded again, with larger blocks
+        cache.insert(0, 0, vec![1; 32]);
+        cache.remove(1);
+
+        let runs = cache.drain();
+        assert_eq!(2, runs.len());
+        assert_eq!(vec![1; 32], runs[0].data);
+        assert_eq!(vec![(0, 32)], runs[0].blocks);
+        assert_eq!(48, runs[1].begin);
+
+        cache.insert(0, 0, vec![0; 16]);
+        cache.remove(0);
+        assert!(cache.drain().is_empty());
+    }
+}
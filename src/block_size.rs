@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+};
+
+/// Block size every peer is expected to serve
+pub const DEFAULT_BLOCK_SIZE: u32 = 16 * 1024;
+/// Largest block size requested, peers serving none larger
+pub const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+
+/// Decides the size of the blocks requested from peers. The last block of a piece is cut to
+/// what remains of it whatever the size.
+pub trait BlockSizePolicy: Send + Sync {
+    /// Size of the blocks to request from `peer`, or from any peer for blocks handed out to
+    /// several of them
+    fn block_size(&self, peer: Option<SocketAddr>) -> u32;
+
+    /// Records that `peer` served a piece with blocks of `size`
+    fn served(&self, _peer: SocketAddr, _size: u32) {}
+
+    /// Records that downloading a piece from `peer` with blocks of `size` failed
+    fn failed(&self, _peer: SocketAddr, _size: u32) {}
+}
+
+/// The same block size for every peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FixedBlockSize(pub u32);
+
+impl Default for FixedBlockSize {
+    fn default() -> Self {
+        Self(DEFAULT_BLOCK_SIZE)
+    }
+}
+
+impl BlockSizePolicy for FixedBlockSize {
+    fn block_size(&self, _peer: Option<SocketAddr>) -> u32 {
+        self.0.clamp(1, MAX_BLOCK_SIZE)
+    }
+}
+
+/// Starts at `base` for every peer, then doubles the block size of peers on the local network
+/// after each piece they serve, up to `max`. Once a larger size fails, a peer keeps the last
+/// size it served.
+#[derive(Debug)]
+pub struct LanProbing {
+    base: u32,
+    max: u32,
+    probes: Mutex<HashMap<SocketAddr, Probe>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Probe {
+    size: u32,
+    settled: bool,
+}
+
+impl Default for LanProbing {
+    fn default() -> Self {
+        Self::new(DEFAULT_BLOCK_SIZE, MAX_BLOCK_SIZE)
+    }
+}
+
+impl LanProbing {
+    pub fn new(base: u32, max: u32) -> Self {
+        let base = base.clamp(1, MAX_BLOCK_SIZE);
+        Self {
+            base,
+            max: max.clamp(base, MAX_BLOCK_SIZE),
+            probes: Mutex::default(),
+        }
+    }
+
+    fn probes(&self) -> std::sync::MutexGuard<'_, HashMap<SocketAddr, Probe>> {
+        self.probes.lock().expect("probes lock poisoned")
+    }
+}
+
+impl BlockSizePolicy for LanProbing {
+    fn block_size(&self, peer: Option<SocketAddr>) -> u32 {
+        peer.and_then(|i| self.probes().get(&i).map(|i| i.size))
+            .unwrap_or(self.base)
+    }
+
+    fn served(&self, peer: SocketAddr, size: u32) {
+        if !is_local(peer.ip()) {
+            return;
+        }
+        let mut probes = self.probes();
+        let probe = probes.entry(peer).or_insert(Probe {
+            size: self.base,
+            settled: false,
+        });
+        if !probe.settled && probe.size == size {
+            probe.size = size.saturating_mul(2).min(self.max);
+        }
+    }
+
+    fn failed(&self, peer: SocketAddr, size: u32) {
+        if size <= self.base {
+            return;
+        }
+        if let Some(probe) = self.probes().get_mut(&peer) {
+            probe.size = (size / 2).max(self.base);
+            probe.settled = true;
+        }
+    }
+}
+
+/// Whether `ip` is on the local network: loopback, private or link-local
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        // unique local fc00::/7 and link-local fe80::/10
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.segments()[0] & 0xfe00 == 0xfc00
+                || ip.segments()[0] & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, str::FromStr};
+
+    use super::{BlockSizePolicy, FixedBlockSize, LanProbing, MAX_BLOCK_SIZE};
+
+    #[test]
+    fn fixed_block_size_stays_in_bounds() {
+        assert_eq!(16 * 1024, FixedBlockSize::default().block_size(None));
+        assert_eq!(1, FixedBlockSize(0).block_size(None));
+        assert_eq!(MAX_BLOCK_SIZE, FixedBlockSize(u32::MAX).block_size(None));
+    }
+
+    #[test]
+    fn lan_peers_are_probed_with_larger_blocks() -> anyhow::Result<()> {
+        let lan = SocketAddr::from_str("192.168.1.2:6881")?;
+        let remote = SocketAddr::from_str("1.2.3.4:6881")?;
+        let policy = LanProbing::new(16, 64);
+
+        for peer in [lan, remote] {
+            assert_eq!(16, policy.block_size(Some(peer)));
+            policy.served(peer, 16);
+        }
+        assert_eq!(32, policy.block_size(Some(lan)));
+        assert_eq!(16, policy.block_size(Some(remote)));
+        // blocks shared by peers stay at the base size
+        assert_eq!(16, policy.block_size(None));
+
+        policy.served(lan, 32);
+        assert_eq!(64, policy.block_size(Some(lan)));
+        policy.served(lan, 64);
+        assert_eq!(64, policy.block_size(Some(lan)));
+
+        policy.failed(lan, 64);
+        assert_eq!(32, policy.block_size(Some(lan)));
+        // not probed again
+        policy.served(lan, 32);
+        assert_eq!(32, policy.block_size(Some(lan)));
+        Ok(())
+    }
+}
@@ -1,27 +1,102 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     fmt::Debug,
-    io::{Read, Write},
-    net::{SocketAddrV4, TcpStream},
+    fs,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
 use reqwest::Url;
 
 use crate::{
+    bitfield::Bitfield,
+    block_cache::{BlockCache, Run},
+    block_size::{BlockSizePolicy, FixedBlockSize},
+    choker::{Choker, ChokerConfig},
+    dht::Dht,
+    extensions::{ExtensionHandler, ExtensionRegistry, ExtensionSender, UT_METADATA_ID},
+    hooks::{Hooks, MessageHook, Verdict},
+    http_stream::WrittenPieces,
+    logging,
+    magnet_links::MagnetLink,
+    mse::{self, EncryptionPolicy, PeerStream},
     peer_messages::{
-        Extension, ExtensionMessage, ExtensionsData, ExtensionsInfo, Handshake, Message,
+        supports_extension_protocol, Extension, ExtensionMessage, ExtensionsData, ExtensionsInfo,
+        Handshake, Message, Metadata,
     },
-    torrent::Info,
+    peer_scores::{BanPolicy, PeerScores},
+    peer_selection::{DefaultPeerSelection, PeerCandidate, PeerSelection},
+    pex::{PexMessage, PexState, UT_PEX_ID},
+    resolver::Resolver,
+    resume::Resume,
+    retry::RetryPolicies,
+    scheduler::{Block, BlockScheduler},
+    sha1::{IncrementalHash, PieceHasher, RustCryptoSha1},
+    stats::DownloadStats,
+    timeouts::Timeouts,
+    torrent::{raw_info_hash, BlockInfo, Info, Keys, PieceInfo, Torrent},
     torrent_info::TorrentInfo,
-    tracker,
+    tracker_client::{self, AnnounceEvent, AnnounceRequest, AnnounceResponse},
     tracker_info::TrackerInfo,
+    tracker_manager::TrackerManager,
+    verify::{self, DiskContent},
+    web_seed::{self, Fetched, WebSeed},
+    wire_trace::WireTrace,
 };
 
 pub const PEER_ID: &str = "alice_is_1_feet_tall";
 
+/// Requests outstanding at once when downloading a piece from a single peer
+const DEFAULT_PIPELINE_DEPTH: usize = 8;
+/// Blocks in flight across all the peers of a multi-peer download
+const DOWNLOAD_WINDOW: usize = 32;
+/// How long a peer may go without delivering any of the blocks requested from it before it is
+/// considered to snub us
+const SNUB_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long a peer that choked us in the middle of a download may keep us choked before another
+/// peer is tried
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often a peer with nothing to request checks whether blocks were given back
+const IDLE_PEER_POLL: Duration = Duration::from_millis(50);
+/// How long a peer connecting to us may stay silent before being dropped
+const INBOUND_READ_TIMEOUT: Duration = Duration::from_secs(120);
+/// Largest block a peer may request from us
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+/// Peers a DHT lookup looks for before stopping
+const DHT_WANTED_PEERS: usize = 32;
+/// Peers learnt through PEX that a multi-peer download connects to, on top of those it started
+/// with
+const MAX_PEX_PEERS: usize = 16;
+/// Peers whose handshake latency is measured at once
+const MAX_LATENCY_PROBES: usize = 16;
+
 pub trait HttpClient {
     fn get(&self, url: Url) -> anyhow::Result<Vec<u8>>;
+
+    /// The bytes of `range` of the resource at `url`. Clients that cannot make range requests
+    /// get the whole resource.
+    fn get_range(&self, url: Url, range: Range<u64>) -> anyhow::Result<Vec<u8>> {
+        take_range(HttpClient::get(self, url)?, range)
+    }
+}
+
+/// `range` of the whole resource `bytes`
+fn take_range(mut bytes: Vec<u8>, range: Range<u64>) -> anyhow::Result<Vec<u8>> {
+    if (bytes.len() as u64) < range.end {
+        bail!("resource shorter than the requested range");
+    }
+    bytes.truncate(range.end as usize);
+    Ok(bytes.split_off(range.start as usize))
 }
 
 impl HttpClient for reqwest::blocking::Client {
@@ -32,14 +107,133 @@ impl HttpClient for reqwest::blocking::Client {
                 response.copy_to(&mut buf)?;
                 Ok(buf)
             }
-            Err(err) => return Err(err.into()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn get_range(&self, url: Url, range: Range<u64>) -> anyhow::Result<Vec<u8>> {
+        let mut response = self
+            .get(url)
+            .header(
+                reqwest::header::RANGE,
+                format!("bytes={}-{}", range.start, range.end.saturating_sub(1)),
+            )
+            .send()?
+            .error_for_status()?;
+        let mut bytes = Vec::new();
+        response.copy_to(&mut bytes)?;
+        // servers ignoring the range send everything
+        match response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => Ok(bytes),
+            _ => take_range(bytes, range),
+        }
+    }
+}
+
+/// A piece as received from a peer, along with where it came from, how long it took and whether
+/// it matches the hash advertised in the torrent
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadedPiece {
+    pub index: u32,
+    pub data: Vec<u8>,
+    pub hash_ok: bool,
+    pub from_peer: SocketAddr,
+    pub duration: Duration,
+}
+
+/// Builds a [`BtClient`] with the settings of how blocks are requested from peers, which are set
+/// once for all the downloads of the client. Other settings are given to the client itself.
+pub struct BtClientBuilder<T: HttpClient> {
+    client: T,
+    block_size: Box<dyn BlockSizePolicy>,
+    pipeline_depth: usize,
+    window: usize,
+}
+
+impl BtClientBuilder<reqwest::blocking::Client> {
+    pub fn new() -> Self {
+        Self::with_client(reqwest::blocking::Client::new())
+    }
+}
+
+impl Default for BtClientBuilder<reqwest::blocking::Client> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: HttpClient> BtClientBuilder<T> {
+    pub fn with_client(client: T) -> Self {
+        Self {
+            client,
+            block_size: Box::new(FixedBlockSize::default()),
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            window: DOWNLOAD_WINDOW,
+        }
+    }
+
+    /// Size of the blocks requested from every peer, 16 KiB by default
+    pub fn with_block_size(self, block_size: u32) -> Self {
+        self.with_block_size_policy(FixedBlockSize(block_size))
+    }
+
+    /// How large the blocks requested from peers are
+    pub fn with_block_size_policy<P: BlockSizePolicy + 'static>(mut self, policy: P) -> Self {
+        self.block_size = Box::new(policy);
+        self
+    }
+
+    /// How many block requests may be outstanding at once when downloading pieces from a single
+    /// peer, at least one
+    pub fn with_pipeline_depth(mut self, depth: usize) -> Self {
+        self.pipeline_depth = depth.max(1);
+        self
+    }
+
+    /// How many blocks may be in flight at once across the peers of a multi-peer download, at
+    /// least one
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    pub fn build(self) -> BtClient<T> {
+        BtClient {
+            block_size: self.block_size,
+            pipeline_depth: self.pipeline_depth,
+            window: self.window,
+            ..BtClient::with_client(self.client)
         }
     }
 }
 
 pub struct BtClient<T: HttpClient> {
     client: T,
-    block_size: u32,
+    block_size: Box<dyn BlockSizePolicy>,
+    pipeline_depth: usize,
+    window: usize,
+    choker: ChokerConfig,
+    wire_trace: WireTrace,
+    show_progress: bool,
+    stats: Mutex<DownloadStats>,
+    hasher: Box<dyn PieceHasher>,
+    extra_trackers: Vec<Url>,
+    corrupt_piece_dir: Option<PathBuf>,
+    retry: RetryPolicies,
+    timeouts: Timeouts,
+    peer_selection: Box<dyn PeerSelection>,
+    resolver: Resolver,
+    extensions: ExtensionRegistry,
+    hooks: Hooks,
+    encryption: EncryptionPolicy,
+    written: Option<Arc<WrittenPieces>>,
+    ban_policy: BanPolicy,
+    snub_timeout: Duration,
+    unchoke_timeout: Duration,
+    stats_interval: Option<Duration>,
+    dht_port: Option<u16>,
+    /// DHT nodes learnt from the Port messages of peers
+    dht_nodes: Mutex<Vec<SocketAddr>>,
 }
 
 impl BtClient<reqwest::blocking::Client> {
@@ -47,12 +241,14 @@ impl BtClient<reqwest::blocking::Client> {
         BtClient::<reqwest::blocking::Client>::with_client(reqwest::blocking::Client::new())
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn with_block_size(block_size: u32) -> Self {
-        BtClient::<reqwest::blocking::Client>::with_client_and_block_size(
-            reqwest::blocking::Client::new(),
-            block_size,
-        )
+    pub fn builder() -> BtClientBuilder<reqwest::blocking::Client> {
+        BtClientBuilder::new()
+    }
+}
+
+impl Default for BtClient<reqwest::blocking::Client> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -60,27 +256,440 @@ impl<T: HttpClient> BtClient<T> {
     pub fn with_client(client: T) -> Self {
         Self {
             client,
-            block_size: 16 * 1024,
+            block_size: Box::new(FixedBlockSize::default()),
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            window: DOWNLOAD_WINDOW,
+            choker: ChokerConfig::default(),
+            wire_trace: WireTrace::default(),
+            show_progress: false,
+            stats: Mutex::default(),
+            hasher: Box::new(RustCryptoSha1),
+            extra_trackers: Vec::new(),
+            corrupt_piece_dir: None,
+            retry: RetryPolicies::default(),
+            timeouts: Timeouts::default(),
+            peer_selection: Box::new(DefaultPeerSelection::default()),
+            resolver: Resolver::System,
+            extensions: ExtensionRegistry::default(),
+            hooks: Hooks::default(),
+            encryption: EncryptionPolicy::default(),
+            written: None,
+            ban_policy: BanPolicy::default(),
+            snub_timeout: SNUB_TIMEOUT,
+            unchoke_timeout: UNCHOKE_TIMEOUT,
+            stats_interval: None,
+            dht_port: None,
+            dht_nodes: Mutex::default(),
+        }
+    }
+
+    /// Whether connections to peers are encrypted, when downloading and seeding
+    pub fn with_encryption(mut self, encryption: EncryptionPolicy) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Log every message sent to and received from peers on stderr
+    pub fn with_wire_trace(mut self, enabled: bool) -> Self {
+        self.wire_trace = WireTrace::new(enabled);
+        self
+    }
+
+    /// How many peers we upload to at once when seeding, one of them being the optimistic
+    /// unchoke
+    pub fn with_upload_slots(mut self, slots: usize) -> Self {
+        self.choker.slots = slots.max(1);
+        self
+    }
+
+    /// How often the peers we upload to are chosen again when seeding
+    pub fn with_rechoke_interval(mut self, interval: Duration) -> Self {
+        self.choker.interval = interval;
+        self
+    }
+
+    /// Use another implementation to verify downloaded pieces
+    pub fn with_hasher<H: PieceHasher + 'static>(mut self, hasher: H) -> Self {
+        self.hasher = Box::new(hasher);
+        self
+    }
+
+    /// Print a progress bar with rate and estimated time remaining on stderr while downloading
+    pub fn with_progress(mut self, enabled: bool) -> Self {
+        self.show_progress = enabled;
+        self
+    }
+
+    /// Also announce to these trackers, on top of the one from the torrent or magnet link
+    pub fn with_extra_trackers(mut self, trackers: Vec<Url>) -> Self {
+        self.extra_trackers = trackers;
+        self
+    }
+
+    /// Save pieces failing hash verification, along with what is known about them, in `dir`
+    pub fn with_corrupt_piece_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.corrupt_piece_dir = dir;
+        self
+    }
+
+    /// When the peers of a multi-peer download that send corrupt data or keep failing are banned
+    pub fn with_ban_policy(mut self, ban_policy: BanPolicy) -> Self {
+        self.ban_policy = ban_policy;
+        self
+    }
+
+    /// Print the state of each connected peer on stderr every `interval` during multi-peer
+    /// downloads
+    pub fn with_stats_interval(mut self, interval: Option<Duration>) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    /// Tell peers supporting the DHT that our node listens on `port`
+    pub fn with_dht_port(mut self, port: Option<u16>) -> Self {
+        self.dht_port = port;
+        self
+    }
+
+    /// How long a peer may go without delivering any of the blocks requested from it. Such a
+    /// peer is given back its blocks and only one at a time afterwards, or dropped for another
+    /// one when there are peers waiting; a single peer download moves on to the next peer.
+    pub fn with_snub_timeout(mut self, timeout: Duration) -> Self {
+        self.snub_timeout = timeout;
+        self
+    }
+
+    /// How long a peer that chokes us while blocks are being downloaded from it may keep us
+    /// choked. Its blocks are requested again once it unchokes us, or from another peer after
+    /// that.
+    pub fn with_unchoke_timeout(mut self, timeout: Duration) -> Self {
+        self.unchoke_timeout = timeout;
+        self
+    }
+
+    /// How long connecting to peers, shaking hands with them and reading from or writing to them
+    /// may take before the peer is given up on
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// How tracker announces, peer connections and pieces failing verification are retried
+    pub fn with_retry_policies(mut self, retry: RetryPolicies) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Tell `written` about the pieces written to disk, for them to be read while downloading
+    pub fn with_written_pieces(mut self, written: Arc<WrittenPieces>) -> Self {
+        self.written = Some(written);
+        self
+    }
+
+    /// Use another strategy to decide which peers to download from first
+    pub fn with_peer_selection<S: PeerSelection + 'static>(mut self, peer_selection: S) -> Self {
+        self.peer_selection = Box::new(peer_selection);
+        self
+    }
+
+    /// Resolve UDP tracker host names with `resolver`. HTTP trackers go through the HTTP client,
+    /// see [`Resolver::http_client`].
+    pub fn with_resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Handle the BEP 10 extension `name` with `handler`, advertising it in our handshake
+    pub fn with_extension<H: ExtensionHandler + 'static>(
+        mut self,
+        name: &str,
+        handler: H,
+    ) -> anyhow::Result<Self> {
+        self.extensions.register(name, handler)?;
+        Ok(self)
+    }
+
+    /// Let `hook` observe, and possibly veto, peer connections and messages
+    pub fn with_hook<H: MessageHook + 'static>(mut self, hook: H) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Snapshot of the statistics of the current (or last) download
+    pub fn stats(&self) -> DownloadStats {
+        self.stats.lock().expect("stats lock poisoned").clone()
+    }
+
+    fn reset_stats(&self, total_bytes: u64) {
+        *self.stats.lock().expect("stats lock poisoned") =
+            DownloadStats::new(total_bytes, Instant::now());
+    }
+
+    /// Starts the stats of a download of the `wanted` pieces of `torrent_info`, tracking each file
+    fn reset_download_stats<TI: TorrentInfo>(&self, torrent_info: &TI, wanted: &[bool]) {
+        let wanted_bytes = torrent_info
+            .pieces_info()
+            .iter()
+            .filter(|i| wanted.get(i.index) == Some(&true))
+            .map(|i| i.length)
+            .sum();
+        *self.stats.lock().expect("stats lock poisoned") =
+            DownloadStats::new(wanted_bytes, Instant::now()).with_files(&torrent_info.files_info());
+    }
+
+    fn register_peer(&self, peer: SocketAddr, handshake: &Handshake) {
+        let mut stats = self.stats.lock().expect("stats lock poisoned");
+        stats.record_connected(peer, Instant::now()).client = handshake.client_name();
+    }
+
+    /// DHT nodes peers told about with Port messages, which lookups start from along with the
+    /// bootstrap nodes
+    pub fn dht_nodes(&self) -> Vec<SocketAddr> {
+        self.dht_nodes
+            .lock()
+            .expect("dht nodes lock poisoned")
+            .clone()
+    }
+
+    fn record_dht_node(&self, peer: SocketAddr, port: u16) {
+        let node = SocketAddr::new(peer.ip(), port);
+        let mut nodes = self.dht_nodes.lock().expect("dht nodes lock poisoned");
+        if port != 0 && !nodes.contains(&node) {
+            nodes.push(node);
+        }
+    }
+
+    /// Tells `peer` the port of our DHT node, if both ends run one. Comes after the bitfield,
+    /// which has to be the first message.
+    fn send_dht_port<S: Write>(
+        &self,
+        stream: &mut S,
+        peer: SocketAddr,
+        handshake: &Handshake,
+    ) -> anyhow::Result<()> {
+        match self.dht_port {
+            Some(port) if handshake.supports_dht() => self
+                .send(stream, peer, &Message::Port { port })
+                .context("writing port message to stream"),
+            _ => Ok(()),
+        }
+    }
+
+    fn record_choked(&self, peer: SocketAddr, choked: bool) {
+        let mut stats = self.stats.lock().expect("stats lock poisoned");
+        stats.peer_mut(peer, Instant::now()).choking_us = choked;
+    }
+
+    fn record_hash_failure(&self, peer: SocketAddr, bytes: u64) {
+        let mut stats = self.stats.lock().expect("stats lock poisoned");
+        stats.record_hash_failure(peer, bytes, Instant::now());
+    }
+
+    /// Bans `peer` from the swarm: its session ends, and the pieces it contributed to are
+    /// downloaded again from scratch
+    fn ban(&self, swarm: &mut Swarm, peer: SocketAddr, reason: &str) {
+        logging::warn("peers", &format!("banning {peer}: {reason}"));
+        self.stats
+            .lock()
+            .expect("stats lock poisoned")
+            .record_peer_banned();
+        let tainted = swarm
+            .pieces
+            .iter()
+            .filter(|(_, i)| i.contributions.contains_key(&peer))
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>();
+        for index in tainted {
+            if let Some(piece) = swarm.pieces.remove(&index) {
+                swarm.scheduler.requeue(piece.blocks);
+            }
+        }
+        swarm.scheduler.release(peer);
+    }
+
+    /// Gives the blocks `peer` did not deliver in time to the other peers. Fails when it keeps
+    /// snubbing us or peers are waiting to take its place, ending its session.
+    fn snubbed(&self, peer: SocketAddr, swarm: &Mutex<Swarm>) -> anyhow::Result<()> {
+        let mut swarm = lock(swarm);
+        let in_flight = swarm.scheduler.in_flight(peer);
+        if !swarm.scheduler.snubbed(peer) {
+            bail!("{peer} keeps snubbing us");
+        }
+        self.stats
+            .lock()
+            .expect("stats lock poisoned")
+            .record_rerequested_blocks(in_flight);
+        if swarm.scores.has_pooled() {
+            bail!("{peer} snubs us, making room for another peer");
+        }
+        logging::warn("peers", &format!("{peer} snubs us"));
+        Ok(())
+    }
+
+    fn record_duplicate_block(&self, bytes: u64) {
+        let mut stats = self.stats.lock().expect("stats lock poisoned");
+        stats.record_duplicate_block(bytes);
+    }
+
+    fn record_block(&self, peer: SocketAddr, bytes: u64) {
+        let now = Instant::now();
+        let mut stats = self.stats.lock().expect("stats lock poisoned");
+        stats.record_block(peer, bytes, now);
+        if self.show_progress && stats.progress_due(now) {
+            eprint!("\r{}", stats.progress_line(30));
+            if stats.remaining_bytes() == 0 {
+                eprintln!();
+            }
+        }
+    }
+
+    /// Peers from every tracker tier that answers, without duplicates. Fails only when no
+    /// tracker answers. Peers are looked up in the DHT when there is no tracker at all.
+    pub fn get_peers<I: TrackerInfo>(&self, tracker_info: &I) -> anyhow::Result<Vec<SocketAddr>> {
+        let mut tracker_manager = self.tracker_manager(tracker_info);
+        if tracker_manager.tiers().is_empty() {
+            return self.dht_peers(tracker_info.announce_request()?.info_hash);
+        }
+        self.announce_to(&mut tracker_manager, tracker_info)
+    }
+
+    /// Peers of the torrent `info_hash` found in the DHT, starting from its bootstrap nodes
+    pub fn dht_peers(&self, info_hash: [u8; 20]) -> anyhow::Result<Vec<SocketAddr>> {
+        let mut bootstrap = self.dht_nodes();
+        bootstrap.extend(Dht::bootstrap_nodes(&self.resolver));
+        if bootstrap.is_empty() {
+            bail!("no DHT bootstrap node could be resolved");
         }
+        Dht::new(std::array::from_fn(|_| tracker_client::random_u32() as u8))?.get_peers(
+            info_hash,
+            &bootstrap,
+            DHT_WANTED_PEERS,
+        )
+    }
+
+    /// Manager for the trackers of `tracker_info`, extra trackers each being a tier of their own.
+    /// Trackers are shuffled within their tier.
+    pub fn tracker_manager<I: TrackerInfo>(&self, tracker_info: &I) -> TrackerManager {
+        let mut tiers = tracker_info.announce_tiers();
+        tiers.extend(self.extra_trackers.iter().map(|i| vec![i.to_string()]));
+        let mut tracker_manager = TrackerManager::new(&tiers);
+        tracker_manager.shuffle(tracker_client::random_u32);
+        tracker_manager
+    }
+
+    /// Announces to the trackers of `tracker_manager` that are due. Trackers returning no peer
+    /// are asked again with the alternative info hash of the torrent, if it has one.
+    pub fn announce_to<I: TrackerInfo + ?Sized>(
+        &self,
+        tracker_manager: &mut TrackerManager,
+        tracker_info: &I,
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        let request = self.announce_request(tracker_info)?;
+        let alternative = tracker_info.alternative_info_hash()?;
+        tracker_manager.announce(Instant::now(), |url, event| {
+            let res = self.announce_with_retries(url, &AnnounceRequest { event, ..request })?;
+            match alternative {
+                Some(info_hash) if res.peers.is_empty() => Ok(self
+                    .announce_with_retries(
+                        url,
+                        &AnnounceRequest {
+                            info_hash,
+                            event,
+                            ..request
+                        },
+                    )
+                    .unwrap_or(res)),
+                _ => Ok(res),
+            }
+        })
+    }
+
+    /// Tells the trackers of `tracker_manager` that were announced to about `event`
+    pub fn announce_event_to<I: TrackerInfo + ?Sized>(
+        &self,
+        tracker_manager: &mut TrackerManager,
+        tracker_info: &I,
+        event: AnnounceEvent,
+    ) -> anyhow::Result<()> {
+        let request = self.announce_request(tracker_info)?;
+        tracker_manager.notify(Instant::now(), event, |url, event| {
+            self.announce_with_retries(url, &AnnounceRequest { event, ..request })
+        })
+    }
+
+    /// What to tell trackers about `tracker_info`, with the counters of the download in progress
+    /// if there is one
+    fn announce_request<I: TrackerInfo + ?Sized>(
+        &self,
+        tracker_info: &I,
+    ) -> anyhow::Result<AnnounceRequest> {
+        let mut request = tracker_info.announce_request()?;
+        let stats = self.stats.lock().expect("stats lock poisoned");
+        if stats.total_bytes > 0 {
+            // wasted bytes were downloaded all the same
+            request.downloaded = stats.downloaded_bytes + stats.integrity.bytes_wasted;
+            request.left = stats.remaining_bytes();
+        }
+        Ok(request)
     }
 
-    fn with_client_and_block_size(client: T, block_size: u32) -> Self {
-        Self { client, block_size }
+    fn announce_with_retries(
+        &self,
+        url: &Url,
+        request: &AnnounceRequest,
+    ) -> anyhow::Result<AnnounceResponse> {
+        self.retry.announce.retry(|_| {
+            tracker_client::for_url(&self.client, &self.resolver, url)?.announce(url, request)
+        })
     }
 
-    pub fn get_peers<I: TrackerInfo>(&self, tracker_info: &I) -> anyhow::Result<Vec<SocketAddrV4>> {
-        let res = self.client.get(tracker_info.tracker_url()?)?;
+    /// Announces to the tracker at `url` only, without retrying
+    pub fn announce_once(
+        &self,
+        url: &Url,
+        request: &AnnounceRequest,
+    ) -> anyhow::Result<AnnounceResponse> {
+        tracker_client::for_url(&self.client, &self.resolver, url)?.announce(url, request)
+    }
 
-        let res: tracker::Response =
-            serde_bencode::from_bytes(&res).context("parse tracker get response")?;
+    /// Connects to `peer`, with reads timing out as the peer's handshake would
+    fn connect(&self, peer: SocketAddr) -> anyhow::Result<TcpStream> {
+        let stream = self.retry.connect.retry(|_| {
+            TcpStream::connect_timeout(&peer, self.timeouts.connect)
+                .context("opening socket to peer")
+        })?;
+        if self.hooks.peer_connected(peer) == Verdict::Veto {
+            bail!("connection to {peer} vetoed by a hook");
+        }
+        stream.set_read_timeout(Some(self.timeouts.handshake))?;
+        stream.set_write_timeout(Some(self.timeouts.write))?;
+        Ok(stream)
+    }
 
-        Ok(res.peers.0)
+    /// Connects to `peer` of the torrent `info_hash`, encrypting the connection as the encryption
+    /// policy asks. With `Prefer`, peers that fail the MSE handshake are connected to again
+    /// without it.
+    fn connect_encrypted(
+        &self,
+        peer: SocketAddr,
+        info_hash: [u8; 20],
+    ) -> anyhow::Result<PeerStream> {
+        let stream = self.connect(peer)?;
+        match self.encryption {
+            EncryptionPolicy::Disable => Ok(PeerStream::plain(stream)),
+            EncryptionPolicy::Require => mse::connect(stream, info_hash, true)
+                .with_context(|| format!("encrypting connection to {peer}")),
+            EncryptionPolicy::Prefer => match mse::connect(stream, info_hash, false) {
+                Ok(stream) => Ok(stream),
+                Err(_) => Ok(PeerStream::plain(self.connect(peer)?)),
+            },
+        }
     }
 
-    pub fn handshake(&self, info_hash: [u8; 20], peer: SocketAddrV4) -> anyhow::Result<[u8; 20]> {
-        let mut tcp_stream = TcpStream::connect(peer).context("opening socket to peer")?;
+    pub fn handshake(&self, info_hash: [u8; 20], peer: SocketAddr) -> anyhow::Result<[u8; 20]> {
+        let mut tcp_stream = self.connect(peer)?;
 
-        let res = self.shake_hands(&mut tcp_stream, info_hash, PEER_ID, &Extension::None)?;
+        let res = self.shake_hands(&mut tcp_stream, info_hash, PEER_ID, &Extension::None, false)?;
 
         Ok(Handshake::from(&res).peer_id)
     }
@@ -88,12 +697,12 @@ impl<T: HttpClient> BtClient<T> {
     pub fn handshake_with_extension(
         &self,
         info_hash: [u8; 20],
-        peer: SocketAddrV4,
+        peer: SocketAddr,
         extension: Extension,
     ) -> anyhow::Result<[u8; 20]> {
-        let mut tcp_stream = TcpStream::connect(peer).context("opening socket to peer")?;
+        let mut tcp_stream = self.connect(peer)?;
 
-        let res = self.shake_hands(&mut tcp_stream, info_hash, PEER_ID, &extension)?;
+        let res = self.shake_hands(&mut tcp_stream, info_hash, PEER_ID, &extension, false)?;
 
         Ok(Handshake::from(&res).peer_id)
     }
@@ -101,28 +710,28 @@ impl<T: HttpClient> BtClient<T> {
     pub fn handshake_with_magnet_extension_for_codecrafters(
         &self,
         info_hash: [u8; 20],
-        peer: SocketAddrV4,
+        peer: SocketAddr,
         extension: Extension,
     ) -> anyhow::Result<([u8; 20], u8)> {
-        let mut tcp_stream = TcpStream::connect(peer).context("opening socket to peer")?;
+        let mut tcp_stream = self.connect(peer)?;
 
-        let res = self.shake_hands(&mut tcp_stream, info_hash, PEER_ID, &extension)?;
+        let res = self.shake_hands(&mut tcp_stream, info_hash, PEER_ID, &extension, false)?;
 
-        let mut msg = Message::read_from(&mut tcp_stream).context("reading message from stream")?;
+        let mut msg = self.receive(&mut tcp_stream, peer)?;
         assert!(matches!(msg, Message::BitField { .. }));
 
-        tcp_stream
-            .write_all(
-                &Message::Extension {
-                    message: ExtensionMessage::Info {
-                        info: ExtensionsInfo::new(16),
-                    },
-                }
-                .to_bytes()?,
-            )
-            .context("writing extension message to stream")?;
+        self.send(
+            &mut tcp_stream,
+            peer,
+            &Message::Extension {
+                message: ExtensionMessage::Info {
+                    info: self.extensions_info(),
+                },
+            },
+        )
+        .context("writing extension message to stream")?;
 
-        msg = Message::read_from(&mut tcp_stream).context("reading message from stream")?;
+        msg = self.receive(&mut tcp_stream, peer)?;
         match msg {
             Message::Extension {
                 message: ExtensionMessage::Info { info },
@@ -134,60 +743,81 @@ impl<T: HttpClient> BtClient<T> {
         }
     }
 
+    /// Fetches the info from the `.torrent` the magnet link's exact source points to, checking
+    /// that it matches the magnet link's info hash
+    pub fn get_exact_source_info(&self, magnet_link: &MagnetLink) -> anyhow::Result<Info> {
+        let url = magnet_link
+            .exact_source
+            .clone()
+            .context("magnet link has no exact source")?;
+        let content = self.client.get(url).context("fetching exact source")?;
+        let info_hash = raw_info_hash(&content)?;
+        if info_hash != magnet_link.info_hash {
+            bail!(
+                "exact source has info hash {} instead of {}",
+                hex::encode(info_hash),
+                hex::encode(magnet_link.info_hash)
+            );
+        }
+        Ok(Torrent::from_bytes(&content)?.info)
+    }
+
     pub fn get_magnet_info(
         &self,
         info_hash: [u8; 20],
-        peer: SocketAddrV4,
+        peer: SocketAddr,
         extension: Extension,
     ) -> anyhow::Result<Info> {
-        let mut tcp_stream = TcpStream::connect(peer).context("opening socket to peer")?;
+        let mut tcp_stream = self.connect(peer)?;
 
-        let _ = self.shake_hands(&mut tcp_stream, info_hash, PEER_ID, &extension)?;
+        let _ = self.shake_hands(&mut tcp_stream, info_hash, PEER_ID, &extension, false)?;
 
-        let mut msg = Message::read_from(&mut tcp_stream).context("reading message from stream")?;
+        let mut msg = self.receive(&mut tcp_stream, peer)?;
         assert!(matches!(msg, Message::BitField { .. }));
 
-        tcp_stream
-            .write_all(
-                &Message::Extension {
-                    message: ExtensionMessage::Info {
-                        info: ExtensionsInfo::new(16),
-                    },
-                }
-                .to_bytes()?,
-            )
-            .context("writing extension message to stream")?;
+        self.send(
+            &mut tcp_stream,
+            peer,
+            &Message::Extension {
+                message: ExtensionMessage::Info {
+                    info: self.extensions_info(),
+                },
+            },
+        )
+        .context("writing extension message to stream")?;
 
-        msg = Message::read_from(&mut tcp_stream).context("reading message from stream")?;
-        let _ = match msg {
+        msg = self.receive(&mut tcp_stream, peer)?;
+        let mut extensions = match msg {
             Message::Extension {
                 message: ExtensionMessage::Info { info },
-            } => info.metdata.ut_metadata.unwrap(),
+            } => ExtensionSender::new(info.metdata.others),
             _ => return Err(anyhow!("unexpected message received")),
         };
+        self.extensions.handshake(&mut extensions)?;
+        self.flush_extensions(&mut tcp_stream, peer, &mut extensions)?;
 
-        tcp_stream
-            .write_all(
-                &Message::Extension {
-                    message: ExtensionMessage::Data {
-                        data: ExtensionsData {
-                            msg_type: 0,
-                            piece: 0,
-                            total_size: 0,
-                        },
-                        info: None,
+        self.send(
+            &mut tcp_stream,
+            peer,
+            &Message::Extension {
+                message: ExtensionMessage::Data {
+                    data: ExtensionsData {
+                        msg_type: 0,
+                        piece: 0,
+                        total_size: 0,
                     },
-                }
-                .to_bytes()?,
-            )
-            .context("writing extension message to stream")?;
+                    info: None,
+                },
+            },
+        )
+        .context("writing extension message to stream")?;
 
-        msg = Message::read_from(&mut tcp_stream).context("reading message from stream")?;
+        msg = self.receive_with_extensions(&mut tcp_stream, peer, &mut extensions)?;
         match msg {
             Message::Extension {
                 message: ExtensionMessage::Data { info, .. },
             } => Ok(info.unwrap()),
-            _ => return Err(anyhow!("unexpected message received")),
+            _ => Err(anyhow!("unexpected message received")),
         }
     }
 
@@ -197,288 +827,3741 @@ impl<T: HttpClient> BtClient<T> {
         info_hash: [u8; 20],
         peer_id: &str,
         extension: &Extension,
+        fast: bool,
     ) -> anyhow::Result<[u8; 68]> {
         let message = Handshake::with_extension(
             info_hash,
             peer_id.as_bytes().try_into().context("invalid peer id")?,
             extension.clone(),
-        );
+        )
+        .with_fast(fast)
+        .with_dht(self.dht_port.is_some());
 
         stream.write_all(&message.to_bytes())?;
         stream.flush()?;
+        self.wire_trace.sent_handshake(&message);
         let mut buf = [0u8; 68];
         stream.read_exact(&mut buf)?;
+        self.wire_trace.received_handshake(&Handshake::from(&buf));
 
         Ok(buf)
     }
 
-    pub fn download_piece<TI: TorrentInfo>(
+    fn send<S: Write>(
         &self,
-        torrent_info: &TI,
-        peer: SocketAddrV4,
-        index: u32,
-    ) -> anyhow::Result<Vec<u8>> {
-        let mut tcp_stream = TcpStream::connect(peer).context("opening socket to peer")?;
-        self.shake_hands(
-            &mut tcp_stream,
-            torrent_info.info_hash()?,
-            PEER_ID,
-            &Extension::None,
-        )
-        .context("shaking hands with peer")?;
-        self.piece_download(&mut tcp_stream, torrent_info, index)
+        stream: &mut S,
+        peer: SocketAddr,
+        message: &Message,
+    ) -> anyhow::Result<()> {
+        if self.hooks.outgoing_message(peer, message) == Verdict::Veto {
+            return Ok(());
+        }
+        let bytes = message.to_bytes()?;
+        stream.write_all(&bytes)?;
+        self.wire_trace.sent(message, bytes.len());
+        Ok(())
     }
 
-    fn piece_download<S: Read + Write + Debug, TI: TorrentInfo>(
-        &self,
-        stream: &mut S,
-        torrent_info: &TI,
-        index: u32,
-    ) -> anyhow::Result<Vec<u8>> {
-        use state::State::*;
-        let mut state = WaitingForBitField;
-        let piece_size = torrent_info.pieces_info();
-        let piece_size = piece_size
-            .get(index as usize)
-            .context("no piece at this index")?;
-        let mut piece = vec![0u8; piece_size.length];
-        let mut collected_blocks = HashSet::new();
+    fn receive<S: Read>(&self, stream: &mut S, peer: SocketAddr) -> anyhow::Result<Message> {
         loop {
-            if collected_blocks
-                .iter()
-                .map(|k: &(u32, u32)| k.1 as usize)
-                .sum::<usize>()
-                == piece.len()
-            {
-                break;
+            let (message, size) =
+                Message::read_with_len_from(stream).context("reading message from stream")?;
+            self.wire_trace.received(&message, size);
+            if self.hooks.incoming_message(peer, &message) == Verdict::Allow {
+                return Ok(message);
             }
+        }
+    }
 
-            let msg = Message::read_from(stream).context("reading message from stream")?;
-
-            match (&state, msg) {
-                (WaitingForBitField, Message::BitField { .. }) => {
-                    stream
-                        .write_all(&Message::Interested.to_bytes()?)
-                        .context("writing interested message to stream")?;
-                    state = WaitingForUnchoke;
-                }
-                (WaitingForUnchoke, Message::Unchoke) => {
-                    for block_info in torrent_info
-                        .blocks_info(
-                            index.try_into().context("u32 does not fit in usize")?,
-                            self.block_size
-                                .try_into()
-                                .context("u32 does not fit in usize")?,
-                        )
-                        .context("no piece at this index")?
-                    {
-                        stream
-                            .write_all(
-                                &Message::Request {
-                                    index,
-                                    begin: block_info
-                                        .offset
-                                        .try_into()
-                                        .context("usize does not fit in u32")?,
-                                    length: block_info
-                                        .length
-                                        .try_into()
-                                        .context("usize does not fit in u32")?,
-                                }
-                                .to_bytes()?,
-                            )
-                            .context("writing request message to stream")?;
-                    }
-
-                    state = WaitingForPieceBlock;
-                }
-                (
-                    WaitingForPieceBlock,
-                    Message::Piece {
-                        index: piece_index,
-                        begin,
-                        block,
-                    },
-                ) if piece_index == index => {
-                    let key = (begin, block.len() as u32);
-                    let begin = begin as usize;
-                    piece[begin..begin + block.len()].copy_from_slice(&block);
-                    collected_blocks.insert(key);
+    /// Receives the next message not handled by a registered extension
+    fn receive_with_extensions<S: Read + Write>(
+        &self,
+        stream: &mut S,
+        peer: SocketAddr,
+        extensions: &mut ExtensionSender,
+    ) -> anyhow::Result<Message> {
+        loop {
+            match self.receive(stream, peer)? {
+                Message::Extension {
+                    message: ExtensionMessage::Custom { id, payload },
+                } if self.extensions.dispatch(id, &payload, extensions)? => {
+                    self.flush_extensions(stream, peer, extensions)?;
                 }
-                (_, msg) => return Err(anyhow!("unexpected message received: '{}'", &msg)),
+                message => return Ok(message),
             }
         }
-
-        Ok(piece)
     }
 
-    pub fn download<TI: TorrentInfo>(
+    fn flush_extensions<S: Write>(
         &self,
-        torrent_info: &TI,
-        peer: SocketAddrV4,
-    ) -> anyhow::Result<Vec<u8>> {
-        let mut file = vec![0u8; torrent_info.total_len()];
-        for piece_info in torrent_info.pieces_info() {
-            let mut tcp_stream = TcpStream::connect(peer).context("opening socket to peer")?;
-            self.shake_hands(
-                &mut tcp_stream,
-                torrent_info.info_hash()?,
-                PEER_ID,
-                &Extension::None,
-            )
-            .context("shaking hands with peer")?;
-            let piece = self.piece_download(
-                &mut tcp_stream,
-                torrent_info,
-                piece_info.index.try_into().context("usize to u32")?,
-            )?;
-            file[piece_info.offset..piece_info.offset + piece_info.length].copy_from_slice(&piece);
+        stream: &mut S,
+        peer: SocketAddr,
+        extensions: &mut ExtensionSender,
+    ) -> anyhow::Result<()> {
+        for message in extensions.take() {
+            self.send(stream, peer, &message)
+                .context("writing extension message to stream")?;
         }
+        Ok(())
+    }
 
-        Ok(file)
+    fn extensions_info(&self) -> ExtensionsInfo {
+        let mut info = ExtensionsInfo::new(UT_METADATA_ID);
+        info.metdata.others = self.extensions.advertised();
+        info
     }
-}
 
-mod state {
-    pub enum State {
-        WaitingForBitField,
-        WaitingForUnchoke,
-        WaitingForPieceBlock,
+    /// Time it takes to connect to `peer`, encrypting the connection as the encryption policy
+    /// asks, and complete the handshake
+    pub fn handshake_latency(
+        &self,
+        info_hash: [u8; 20],
+        peer: SocketAddr,
+    ) -> anyhow::Result<Duration> {
+        let started = Instant::now();
+        let mut stream = self.connect_encrypted(peer, info_hash)?;
+        self.shake_hands(&mut stream, info_hash, PEER_ID, &Extension::None, false)?;
+        Ok(started.elapsed())
     }
-}
 
-#[cfg(test)]
+    /// Peers that complete a handshake, fastest first, along with their handshake latency. Up to
+    /// `MAX_LATENCY_PROBES` peers are measured at once; those not answering in time are left out.
+    pub fn peers_by_latency(
+        &self,
+        info_hash: [u8; 20],
+        peers: &[SocketAddr],
+    ) -> Vec<(SocketAddr, Duration)>
+    where
+        T: Sync,
+    {
+        let latencies = Mutex::new(Vec::new());
+        let next = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..peers.len().min(MAX_LATENCY_PROBES) {
+                scope.spawn(|| {
+                    while let Some(&peer) = peers.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        if let Ok(latency) = self.handshake_latency(info_hash, peer) {
+                            latencies
+                                .lock()
+                                .expect("latencies lock poisoned")
+                                .push((peer, latency));
+                        }
+                    }
+                });
+            }
+        });
+        let mut latencies = latencies.into_inner().expect("latencies lock poisoned");
+        latencies.sort_by_key(|(_, latency)| *latency);
+        latencies
+    }
+
+    /// Peers answering the handshake, in the order the peer selection strategy prefers them
+    pub fn select_peers(&self, info_hash: [u8; 20], peers: &[SocketAddr]) -> Vec<SocketAddr>
+    where
+        T: Sync,
+    {
+        let candidates = self
+            .peers_by_latency(info_hash, peers)
+            .into_iter()
+            .map(|(address, latency)| PeerCandidate { address, latency })
+            .collect();
+        self.peer_selection.order(candidates)
+    }
+
+    pub fn download_piece<TI: TorrentInfo>(
+        &self,
+        torrent_info: &TI,
+        peer: SocketAddr,
+        index: u32,
+    ) -> anyhow::Result<DownloadedPiece> {
+        self.download_piece_from_peers(torrent_info, &[peer], index)
+    }
+
+    /// Downloads a piece and checks its hash. A piece that cannot be downloaded or does not match
+    /// its hash is downloaded again from the next peer, in turn, until the piece retry policy
+    /// gives up.
+    pub fn download_piece_from_peers<TI: TorrentInfo>(
+        &self,
+        torrent_info: &TI,
+        peers: &[SocketAddr],
+        index: u32,
+    ) -> anyhow::Result<DownloadedPiece> {
+        torrent_info.validate_layout()?;
+        if peers.is_empty() {
+            bail!("no peer to download from");
+        }
+        self.reset_stats(
+            torrent_info
+                .pieces_info()
+                .get(index as usize)
+                .context("no piece at this index")?
+                .length,
+        );
+        // peers are taken in turn, skipping those found not to have the piece
+        let mut turn = 0;
+        let mut lacking = HashSet::new();
+        self.retry.piece.retry(|_| loop {
+            let Some(offset) =
+                (0..peers.len()).find(|i| !lacking.contains(&peers[(turn + i) % peers.len()]))
+            else {
+                bail!("none of the peers has piece {index}");
+            };
+            let peer = peers[(turn + offset) % peers.len()];
+            turn += offset + 1;
+            match self.piece_from_peer(torrent_info, peer, index) {
+                // trying another peer does not count as an attempt
+                Err(err) if err.is::<MissingPiece>() => {
+                    logging::warn("peers", &format!("{err}, trying another peer"));
+                    lacking.insert(peer);
+                }
+                res => break res,
+            }
+        })
+    }
+
+    /// Downloads a piece from `peer` over a new connection and checks its hash
+    fn piece_from_peer<TI: TorrentInfo>(
+        &self,
+        torrent_info: &TI,
+        peer: SocketAddr,
+        index: u32,
+    ) -> anyhow::Result<DownloadedPiece> {
+        let (mut tcp_stream, mut state) = self.open_download_stream(torrent_info, peer)?;
+        let piece =
+            self.piece_download_with(&mut tcp_stream, &mut state, torrent_info, peer, index)?;
+        // the connection is dropped anyway, this is only a courtesy
+        let _ = self.send(&mut tcp_stream, peer, &Message::NotInterested);
+        if !piece.hash_ok {
+            bail!(
+                "piece {} received from {} does not match its hash",
+                piece.index,
+                piece.from_peer
+            );
+        }
+        Ok(piece)
+    }
+
+    /// Connects to `peer` and shakes hands, ready for pieces to be downloaded
+    fn open_download_stream<TI: TorrentInfo>(
+        &self,
+        torrent_info: &TI,
+        peer: SocketAddr,
+    ) -> anyhow::Result<(PeerStream, state::State)> {
+        let info_hash = torrent_info.info_hash()?;
+        let mut tcp_stream = self.connect_encrypted(peer, info_hash)?;
+        let res = self
+            .shake_hands(&mut tcp_stream, info_hash, PEER_ID, &Extension::None, true)
+            .context("shaking hands with peer")?;
+        let handshake = Handshake::from(&res);
+        self.register_peer(peer, &handshake);
+        self.send_dht_port(&mut tcp_stream, peer, &handshake)?;
+        tcp_stream
+            .tcp()
+            .set_read_timeout(Some(self.timeouts.read))?;
+        Ok((tcp_stream, state::State::new(handshake.supports_fast())))
+    }
+
+    /// Downloads a piece over a stream on which hands were just shaken, without the Fast
+    /// extension
+    #[cfg(test)]
+    fn piece_download<S: Read + Write + Debug, TI: TorrentInfo>(
+        &self,
+        stream: &mut S,
+        torrent_info: &TI,
+        peer: SocketAddr,
+        index: u32,
+    ) -> anyhow::Result<DownloadedPiece> {
+        let mut state = state::State::new(false);
+        self.piece_download_with(stream, &mut state, torrent_info, peer, index)
+    }
+
+    /// Downloads a piece over a stream in `state`, which is updated so that following pieces can
+    /// be downloaded over the same stream. The block size policy learns how blocks of the size
+    /// it gave for the peer went.
+    fn piece_download_with<S: Read + Write + Debug, TI: TorrentInfo>(
+        &self,
+        stream: &mut S,
+        state: &mut state::State,
+        torrent_info: &TI,
+        peer: SocketAddr,
+        index: u32,
+    ) -> anyhow::Result<DownloadedPiece> {
+        let block_size = self.block_size.block_size(Some(peer));
+        let res = self.sized_piece_download(stream, state, torrent_info, peer, index, block_size);
+        match &res {
+            Ok(piece) if piece.hash_ok => self.block_size.served(peer, block_size),
+            // nothing was requested from the peer
+            Err(err) if err.is::<MissingPiece>() => {}
+            Ok(_) | Err(_) => self.block_size.failed(peer, block_size),
+        }
+        res
+    }
+
+    fn sized_piece_download<S: Read + Write + Debug, TI: TorrentInfo>(
+        &self,
+        stream: &mut S,
+        state: &mut state::State,
+        torrent_info: &TI,
+        peer: SocketAddr,
+        index: u32,
+        block_size: u32,
+    ) -> anyhow::Result<DownloadedPiece> {
+        use state::Stage::*;
+        let started = Instant::now();
+        let piece_size = torrent_info.pieces_info();
+        let piece_count = piece_size.len();
+        let piece_size = piece_size
+            .get(index as usize)
+            .context("no piece at this index")?;
+        let mut piece =
+            vec![0u8; usize::try_from(piece_size.length).context("piece does not fit in memory")?];
+        let mut collected_blocks = HashSet::new();
+        let mut digest = IncrementalHash::new(self.hasher.as_ref());
+        let mut blocks: VecDeque<_> = torrent_info
+            .blocks_info(
+                index.try_into().context("u32 does not fit in usize")?,
+                block_size.into(),
+            )
+            .context("no piece at this index")?
+            .into();
+        // requested and neither received nor rejected yet
+        let mut in_flight: Vec<BlockInfo> = Vec::new();
+        // requested, then dropped by the peer choking us, which may still have sent them
+        let mut dropped: Vec<BlockInfo> = Vec::new();
+        // since the peer last delivered a block, or had none requested
+        let mut last_block = Instant::now();
+        loop {
+            if collected_blocks
+                .iter()
+                .map(|k: &(u32, u32)| k.1 as usize)
+                .sum::<usize>()
+                == piece.len()
+            {
+                break;
+            }
+
+            // the peer told what it has, and more is not waited for
+            if !matches!(state.stage, WaitingForBitField)
+                && in_flight.is_empty()
+                && !state.pieces.contains(&index)
+            {
+                return Err(MissingPiece { peer, index }.into());
+            }
+
+            // allowed fast pieces may be requested while choked
+            let may_request = match state.stage {
+                WaitingForPieceBlock => true,
+                WaitingForUnchoke => state.allowed_fast.contains(&index),
+                WaitingForBitField => false,
+            };
+            if may_request {
+                while in_flight.len() < self.pipeline_depth {
+                    let Some(block_info) = blocks.pop_front() else {
+                        break;
+                    };
+                    self.request_block(stream, peer, index, &block_info)?;
+                    in_flight.push(block_info);
+                }
+            }
+            if in_flight.is_empty() {
+                last_block = Instant::now();
+            } else if last_block.elapsed() > self.snub_timeout {
+                // the peer keeps talking without sending what was asked for
+                bail!(
+                    "{peer} snubs us, no block received in {:?}",
+                    self.snub_timeout
+                );
+            }
+            if state
+                .choked_at
+                .is_some_and(|i| i.elapsed() > self.unchoke_timeout)
+            {
+                bail!(
+                    "{peer} kept us choked for more than {:?}",
+                    self.unchoke_timeout
+                );
+            }
+
+            let msg = self.receive(stream, peer)?;
+
+            match (&state.stage, msg) {
+                (WaitingForBitField, Message::BitField { bitfield }) => {
+                    bitfield
+                        .validate(piece_count)
+                        .with_context(|| format!("invalid bitfield from {peer}"))?;
+                    state.pieces.extend(bitfield.pieces());
+                    self.send(stream, peer, &Message::Interested)
+                        .context("writing interested message to stream")?;
+                    state.stage = WaitingForUnchoke;
+                }
+                (WaitingForBitField, Message::HaveAll) => {
+                    state.pieces.extend(0..piece_count as u32);
+                    self.send(stream, peer, &Message::Interested)
+                        .context("writing interested message to stream")?;
+                    state.stage = WaitingForUnchoke;
+                }
+                // peers with few pieces may announce them one by one instead of sending a
+                // bitfield
+                (WaitingForBitField, Message::Have { index }) => {
+                    state.pieces.insert(index);
+                    self.send(stream, peer, &Message::Interested)
+                        .context("writing interested message to stream")?;
+                    state.stage = WaitingForUnchoke;
+                }
+                (_, Message::Have { index }) => {
+                    state.pieces.insert(index);
+                }
+                (_, Message::Port { port }) => self.record_dht_node(peer, port),
+                (_, Message::KeepAlive | Message::Unknown { .. }) => {}
+                (WaitingForBitField, Message::HaveNone) => {
+                    bail!("{peer} has no piece to download")
+                }
+                (_, Message::AllowedFast { index }) if state.fast => {
+                    state.allowed_fast.insert(index);
+                }
+                (_, Message::SuggestPiece { .. }) if state.fast => {}
+                (WaitingForUnchoke, Message::Unchoke) => {
+                    state.stage = WaitingForPieceBlock;
+                    state.choked_at = None;
+                    self.record_choked(peer, false);
+                }
+                // with the Fast extension, a choke does not cancel requests: those the peer will
+                // not serve are rejected one by one
+                (WaitingForPieceBlock, Message::Choke) if state.fast => {
+                    state.stage = WaitingForUnchoke;
+                    self.record_choked(peer, true);
+                }
+                // without it, the peer dropped our requests: they are made again once unchoked
+                (WaitingForPieceBlock, Message::Choke) => {
+                    state.stage = WaitingForUnchoke;
+                    state.choked_at = Some(Instant::now());
+                    self.record_choked(peer, true);
+                    for block_info in in_flight.drain(..).rev() {
+                        dropped.push(block_info.clone());
+                        blocks.push_front(block_info);
+                    }
+                }
+                (
+                    stage,
+                    Message::RejectRequest {
+                        index: piece_index,
+                        begin,
+                        length,
+                    },
+                ) if state.fast && piece_index == index => {
+                    let Some(position) = in_flight.iter().position(|i| {
+                        i.offset == u64::from(begin) && i.length == u64::from(length)
+                    }) else {
+                        bail!("{peer} rejected a block that was not requested");
+                    };
+                    if matches!(stage, WaitingForPieceBlock) {
+                        bail!("{peer} rejected a request while unchoked");
+                    }
+                    // requested again once unchoked
+                    blocks.push_front(in_flight.remove(position));
+                }
+                (
+                    WaitingForPieceBlock | WaitingForUnchoke,
+                    Message::Piece {
+                        index: piece_index,
+                        begin,
+                        block,
+                    },
+                ) if piece_index == index => {
+                    last_block = Instant::now();
+                    let key = (begin, block.len() as u32);
+                    let requested = |i: &BlockInfo| {
+                        i.offset == u64::from(begin) && i.length == block.len() as u64
+                    };
+                    if let Some(position) = in_flight.iter().position(requested) {
+                        in_flight.remove(position);
+                    } else if let Some(position) = dropped.iter().position(requested) {
+                        // sent before a choke dropped its request
+                        dropped.remove(position);
+                        blocks.retain(|i| !requested(i));
+                    } else if collected_blocks.contains(&key) {
+                        self.record_duplicate_block(block.len() as u64);
+                        continue;
+                    } else {
+                        bail!(
+                            "{peer} sent block {begin}+{} that was not requested",
+                            block.len()
+                        );
+                    }
+                    let begin = begin as usize;
+                    if begin + block.len() > piece.len() {
+                        bail!("{peer} sent a block past the end of piece {index}");
+                    }
+                    piece[begin..begin + block.len()].copy_from_slice(&block);
+                    if collected_blocks.insert(key) {
+                        digest.add(begin as u64, &block);
+                        self.record_block(peer, block.len() as u64);
+                    } else {
+                        self.record_duplicate_block(block.len() as u64);
+                    }
+                }
+                (_, msg) => return Err(anyhow!("unexpected message received: '{}'", &msg)),
+            }
+        }
+
+        let hash_ok = self.verify_piece(torrent_info, index, &piece, digest.finish(), peer);
+
+        Ok(DownloadedPiece {
+            index,
+            data: piece,
+            hash_ok,
+            from_peer: peer,
+            duration: started.elapsed(),
+        })
+    }
+
+    fn request_block<S: Read + Write + Debug>(
+        &self,
+        stream: &mut S,
+        peer: SocketAddr,
+        index: u32,
+        block_info: &BlockInfo,
+    ) -> anyhow::Result<()> {
+        self.send(
+            stream,
+            peer,
+            &Message::Request {
+                index,
+                begin: block_info
+                    .offset
+                    .try_into()
+                    .context("u64 does not fit in u32")?,
+                length: block_info
+                    .length
+                    .try_into()
+                    .context("u64 does not fit in u32")?,
+            },
+        )
+        .context("writing request message to stream")
+    }
+
+    /// Checks the `actual` hash of `piece` against the one in the torrent, recording and dumping
+    /// the piece when they do not match
+    fn verify_piece<TI: TorrentInfo>(
+        &self,
+        torrent_info: &TI,
+        index: u32,
+        piece: &[u8],
+        actual: [u8; 20],
+        peer: SocketAddr,
+    ) -> bool {
+        let expected = torrent_info.info().pieces.0.get(index as usize);
+        let hash_ok = expected.is_some_and(|expected| *expected == actual);
+        if hash_ok {
+            self.stats
+                .lock()
+                .expect("stats lock poisoned")
+                .record_verified_bytes(
+                    u64::from(index) * torrent_info.piece_length(),
+                    piece.len() as u64,
+                );
+        } else {
+            self.record_hash_failure(peer, piece.len() as u64);
+            if let Some(dir) = &self.corrupt_piece_dir {
+                if let Err(err) = dump_corrupt_piece(dir, index, expected, actual, peer, piece) {
+                    logging::warn(
+                        "storage",
+                        &format!("could not dump corrupt piece {index}: {err:#}"),
+                    );
+                }
+            }
+        }
+        hash_ok
+    }
+
+    pub fn download<TI: TorrentInfo>(
+        &self,
+        torrent_info: &TI,
+        peer: SocketAddr,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut file = Vec::with_capacity(
+            usize::try_from(torrent_info.total_len()).context("torrent does not fit in memory")?,
+        );
+        self.download_to(torrent_info, peer, &mut file)?;
+        Ok(file)
+    }
+
+    /// Downloads the torrent piece by piece, writing each piece to `writer` in order as soon as
+    /// it is verified, so that only one piece is held in memory
+    pub fn download_to<TI: TorrentInfo, W: Write>(
+        &self,
+        torrent_info: &TI,
+        peer: SocketAddr,
+        writer: &mut W,
+    ) -> anyhow::Result<()> {
+        torrent_info.validate_layout()?;
+        self.reset_download_stats(torrent_info, &torrent_info.wanted_pieces());
+        // kept open across pieces, and reopened only after a failure
+        let mut connection = None;
+        for piece_info in torrent_info.pieces_info() {
+            let index = piece_info.index.try_into().context("usize to u32")?;
+            let piece = self.retry.piece.retry(|_| {
+                let (mut tcp_stream, mut state) = match connection.take() {
+                    Some(connection) => connection,
+                    None => self.open_download_stream(torrent_info, peer)?,
+                };
+                let piece = self.piece_download_with(
+                    &mut tcp_stream,
+                    &mut state,
+                    torrent_info,
+                    peer,
+                    index,
+                )?;
+                connection = Some((tcp_stream, state));
+                if !piece.hash_ok {
+                    return Err(anyhow!(
+                        "piece {} received from {} does not match its hash",
+                        piece.index,
+                        piece.from_peer
+                    ));
+                }
+                Ok(piece)
+            })?;
+            writer
+                .write_all(&piece.data)
+                .and_then(|_| writer.flush())
+                .context("writing piece")?;
+            // a connection that cannot take it is of no use for the next pieces either
+            if let Some((tcp_stream, _)) = &mut connection {
+                if let Err(err) = self.send(tcp_stream, peer, &Message::Have { index }) {
+                    logging::warn(
+                        "peers",
+                        &format!("telling {peer} about piece {index}: {err:#}"),
+                    );
+                    connection = None;
+                }
+            }
+        }
+        // the connection is dropped anyway, this is only a courtesy
+        if let Some((mut tcp_stream, _)) = connection {
+            let _ = self.send(&mut tcp_stream, peer, &Message::NotInterested);
+        }
+
+        Ok(())
+    }
+
+    /// Serves the torrent found at `path` to the peers connecting to `listener`, until accepting
+    /// a connection fails. `path` is the file itself for single-file torrents and the directory
+    /// named after the torrent for multi-file ones. Only pieces matching their hash are
+    /// advertised and served.
+    pub fn seed<TI: TorrentInfo + Sync>(
+        &self,
+        torrent_info: &TI,
+        path: &Path,
+        listener: &TcpListener,
+    ) -> anyhow::Result<()>
+    where
+        T: Sync,
+    {
+        let have = verify::verify_pieces(
+            torrent_info,
+            path,
+            self.hasher.as_ref(),
+            verify::default_threads(),
+        )?;
+        if !have.contains(&true) {
+            bail!("no piece of the torrent found at {}", path.display());
+        }
+        let content = DiskContent::new(torrent_info, path);
+        let info_hash = torrent_info.info_hash()?;
+        let choker = Mutex::new(Choker::new(self.choker));
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let stream = stream.context("accepting connection")?;
+                let (have, content, choker) = (&have, &content, &choker);
+                scope.spawn(move || {
+                    if let Err(err) =
+                        self.seed_session(torrent_info, info_hash, have, content, choker, stream)
+                    {
+                        logging::warn("seed", &format!("{err:#}"));
+                    }
+                });
+            }
+            Ok(())
+        })
+    }
+
+    /// Answers the requests of a peer that connected to us, until it leaves
+    fn seed_session<TI: TorrentInfo>(
+        &self,
+        torrent_info: &TI,
+        info_hash: [u8; 20],
+        have: &[bool],
+        content: &DiskContent,
+        choker: &Mutex<Choker>,
+        stream: TcpStream,
+    ) -> anyhow::Result<()> {
+        let peer = stream.peer_addr()?;
+        if self.hooks.peer_connected(peer) == Verdict::Veto {
+            return Ok(());
+        }
+        stream.set_read_timeout(Some(self.timeouts.handshake))?;
+        stream.set_write_timeout(Some(self.timeouts.write))?;
+        let plain = self.encryption == EncryptionPolicy::Disable
+            || mse::is_plain_handshake(&stream, self.timeouts.handshake)?;
+        let mut stream = match (plain, self.encryption) {
+            (true, EncryptionPolicy::Require) => bail!("{peer} did not encrypt its connection"),
+            (true, _) => PeerStream::plain(stream),
+            (false, policy) => mse::accept(stream, info_hash, policy == EncryptionPolicy::Prefer)
+                .with_context(|| format!("encrypting connection from {peer}"))?,
+        };
+        let mut buf = [0u8; 68];
+        stream
+            .read_exact(&mut buf)
+            .with_context(|| format!("reading handshake from {peer}"))?;
+        let handshake = Handshake::from(&buf);
+        self.wire_trace.received_handshake(&handshake);
+        if buf[0] != 19 || &buf[1..20] != b"BitTorrent protocol" {
+            bail!("{peer} did not send a BitTorrent handshake");
+        }
+        if handshake.info_hash != info_hash {
+            bail!(
+                "{peer} asked for unknown info hash {}",
+                hex::encode(handshake.info_hash)
+            );
+        }
+        let fast = handshake.supports_fast();
+        let reply = Handshake::new(
+            info_hash,
+            PEER_ID.as_bytes().try_into().context("invalid peer id")?,
+        )
+        .with_fast(fast)
+        .with_dht(self.dht_port.is_some());
+        stream.write_all(&reply.to_bytes())?;
+        self.wire_trace.sent_handshake(&reply);
+        self.register_peer(peer, &handshake);
+
+        let pieces = (0u32..).zip(have).filter(|(_, i)| **i).map(|(i, _)| i);
+        let bitfield = if fast && have.iter().all(|i| *i) {
+            Message::HaveAll
+        } else {
+            Message::BitField {
+                bitfield: Bitfield::from_pieces(have.len(), pieces),
+            }
+        };
+        self.send(&mut stream, peer, &bitfield)
+            .context("writing bitfield message to stream")?;
+        self.send_dht_port(&mut stream, peer, &handshake)?;
+        stream.tcp().set_read_timeout(Some(INBOUND_READ_TIMEOUT))?;
+
+        choker
+            .lock()
+            .expect("choker lock poisoned")
+            .add_peer(peer, Instant::now());
+        let res = self.serve_peer(torrent_info, peer, fast, have, content, choker, &mut stream);
+        choker
+            .lock()
+            .expect("choker lock poisoned")
+            .remove_peer(peer);
+        self.stats
+            .lock()
+            .expect("stats lock poisoned")
+            .record_disconnected(peer, Instant::now());
+        match res {
+            Err(err) if is_disconnection(&err) => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Sends blocks to `peer` while the choker lets us, re-evaluating that between messages.
+    /// Requests are queued and served once the messages received so far are handled, so that
+    /// those the peer cancels in the meantime are not served. Requests that are not served are
+    /// rejected when `fast`, as the Fast extension requires.
+    #[allow(clippy::too_many_arguments)]
+    fn serve_peer<TI: TorrentInfo>(
+        &self,
+        torrent_info: &TI,
+        peer: SocketAddr,
+        fast: bool,
+        have: &[bool],
+        content: &DiskContent,
+        choker: &Mutex<Choker>,
+        stream: &mut PeerStream,
+    ) -> anyhow::Result<()> {
+        let pieces_info = torrent_info.pieces_info();
+        // what the peer was last told
+        let mut choked = true;
+        let mut last_message = Instant::now();
+        let mut queued = VecDeque::new();
+        loop {
+            let unchoked = {
+                let mut choker = choker.lock().expect("choker lock poisoned");
+                choker.rechoke(Instant::now());
+                choker.is_unchoked(peer)
+            };
+            if unchoked == choked {
+                let message = if unchoked {
+                    Message::Unchoke
+                } else {
+                    Message::Choke
+                };
+                self.send(stream, peer, &message)
+                    .context("writing choke message to stream")?;
+                choked = !unchoked;
+                // choking drops the requests not served yet
+                for block in std::mem::take(&mut queued) {
+                    self.reject(stream, peer, fast, block)?;
+                }
+            }
+            if !has_data(stream)? {
+                if let Some(block) = queued.pop_front() {
+                    self.serve_block(stream, peer, content, choker, &pieces_info, block)?;
+                    continue;
+                }
+                if last_message.elapsed() > INBOUND_READ_TIMEOUT {
+                    bail!("{peer} stayed silent for too long");
+                }
+                thread::sleep(IDLE_PEER_POLL);
+                continue;
+            }
+            last_message = Instant::now();
+
+            match self.receive(stream, peer)? {
+                Message::Interested => choker
+                    .lock()
+                    .expect("choker lock poisoned")
+                    .set_interested(peer, true),
+                Message::NotInterested => choker
+                    .lock()
+                    .expect("choker lock poisoned")
+                    .set_interested(peer, false),
+                Message::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let block = Block {
+                        piece: index,
+                        begin,
+                        length,
+                    };
+                    let piece = pieces_info
+                        .get(index as usize)
+                        .filter(|_| have[index as usize]);
+                    // requests sent before our choke arrived are dropped, and so are those for
+                    // pieces we did not advertise, which the peer should not have asked for
+                    let Some(piece) = piece.filter(|_| !choked) else {
+                        self.reject(stream, peer, fast, block)?;
+                        continue;
+                    };
+                    if length > MAX_REQUEST_LENGTH
+                        || u64::from(begin) + u64::from(length) > piece.length
+                    {
+                        bail!("{peer} requested an invalid block of piece {index}");
+                    }
+                    if !queued.contains(&block) {
+                        queued.push_back(block);
+                    }
+                }
+                Message::Cancel {
+                    index,
+                    begin,
+                    length,
+                } => queued.retain(|i| {
+                    *i != Block {
+                        piece: index,
+                        begin,
+                        length,
+                    }
+                }),
+                Message::Port { port } => self.record_dht_node(peer, port),
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads `block` from `content` and sends it to `peer`
+    fn serve_block(
+        &self,
+        stream: &mut PeerStream,
+        peer: SocketAddr,
+        content: &DiskContent,
+        choker: &Mutex<Choker>,
+        pieces_info: &[PieceInfo],
+        block: Block,
+    ) -> anyhow::Result<()> {
+        let offset = pieces_info[block.piece as usize].offset + u64::from(block.begin);
+        let mut data = vec![0u8; block.length as usize];
+        content.read_at(offset, &mut data)?;
+        self.send(
+            stream,
+            peer,
+            &Message::Piece {
+                index: block.piece,
+                begin: block.begin,
+                block: data,
+            },
+        )
+        .context("writing piece message to stream")?;
+        let now = Instant::now();
+        choker
+            .lock()
+            .expect("choker lock poisoned")
+            .record(peer, block.length.into(), now);
+        self.stats
+            .lock()
+            .expect("stats lock poisoned")
+            .record_upload(peer, block.length.into(), now);
+        Ok(())
+    }
+
+    /// Tells `peer` that `block` will not be served, which only peers supporting the Fast
+    /// extension expect
+    fn reject(
+        &self,
+        stream: &mut PeerStream,
+        peer: SocketAddr,
+        fast: bool,
+        block: Block,
+    ) -> anyhow::Result<()> {
+        if !fast {
+            return Ok(());
+        }
+        self.send(
+            stream,
+            peer,
+            &Message::RejectRequest {
+                index: block.piece,
+                begin: block.begin,
+                length: block.length,
+            },
+        )
+        .context("writing reject request message to stream")
+    }
+
+    /// Downloads the torrent from all of `peers` at once, in memory
+    pub fn download_from_peers<TI: TorrentInfo + Sync>(
+        &self,
+        torrent_info: &TI,
+        peers: &[SocketAddr],
+    ) -> anyhow::Result<Vec<u8>>
+    where
+        T: Sync,
+    {
+        let mut file = Vec::with_capacity(
+            usize::try_from(torrent_info.total_len()).context("torrent does not fit in memory")?,
+        );
+        self.download_from_peers_to(torrent_info, peers, &mut file)?;
+        Ok(file)
+    }
+
+    /// Downloads the torrent from all of `peers` at once, each peer in its own thread. Blocks are
+    /// handed out by a [`BlockScheduler`] and pieces are written to `writer` in order as soon as
+    /// they are verified. Peers failing are dropped, the download fails when none is left.
+    pub fn download_from_peers_to<TI: TorrentInfo + Sync, W: Write>(
+        &self,
+        torrent_info: &TI,
+        peers: &[SocketAddr],
+        writer: &mut W,
+    ) -> anyhow::Result<()>
+    where
+        T: Sync,
+    {
+        self.download_swarm_to(torrent_info, peers, None, Sink::Stream(writer))
+    }
+
+    /// Downloads the torrent from `peers` like [`Self::download_from_peers`], announcing again to
+    /// the trackers of `reannounce` during the download
+    pub fn download_with_trackers<TI: TorrentInfo + Sync>(
+        &self,
+        torrent_info: &TI,
+        peers: &[SocketAddr],
+        reannounce: Reannounce,
+    ) -> anyhow::Result<Vec<u8>>
+    where
+        T: Sync,
+    {
+        let mut file = Vec::with_capacity(
+            usize::try_from(torrent_info.total_len()).context("torrent does not fit in memory")?,
+        );
+        self.download_with_trackers_to(torrent_info, peers, reannounce, &mut file)?;
+        Ok(file)
+    }
+
+    /// Downloads the torrent from `peers` like [`Self::download_from_peers_to`], announcing
+    /// again to the trackers of `reannounce` whenever their interval elapses. The peers they
+    /// return join the download.
+    pub fn download_with_trackers_to<TI: TorrentInfo + Sync, W: Write>(
+        &self,
+        torrent_info: &TI,
+        peers: &[SocketAddr],
+        reannounce: Reannounce,
+        writer: &mut W,
+    ) -> anyhow::Result<()>
+    where
+        T: Sync,
+    {
+        self.download_swarm_to(torrent_info, peers, Some(reannounce), Sink::Stream(writer))
+    }
+
+    /// Downloads the torrent from all of `peers` at once like [`Self::download_from_peers_to`],
+    /// each piece being written to its files in `content` as soon as it is verified rather than
+    /// in order. The files must have been created. Pieces that `resume` records as written are
+    /// hashed again and only downloaded if they no longer match, and every piece written is
+    /// recorded in `resume`.
+    pub fn download_from_peers_to_disk<TI: TorrentInfo + Sync>(
+        &self,
+        torrent_info: &TI,
+        peers: &[SocketAddr],
+        content: &DiskContent,
+        resume: &mut Resume,
+    ) -> anyhow::Result<()>
+    where
+        T: Sync,
+    {
+        self.download_swarm_to(
+            torrent_info,
+            peers,
+            None,
+            Sink::<io::Sink>::Disk(content, resume),
+        )
+    }
+
+    /// Downloads the torrent to disk like [`Self::download_from_peers_to_disk`], announcing again
+    /// to the trackers of `reannounce` like [`Self::download_with_trackers_to`]
+    pub fn download_with_trackers_to_disk<TI: TorrentInfo + Sync>(
+        &self,
+        torrent_info: &TI,
+        peers: &[SocketAddr],
+        reannounce: Reannounce,
+        content: &DiskContent,
+        resume: &mut Resume,
+    ) -> anyhow::Result<()>
+    where
+        T: Sync,
+    {
+        self.download_swarm_to(
+            torrent_info,
+            peers,
+            Some(reannounce),
+            Sink::<io::Sink>::Disk(content, resume),
+        )
+    }
+
+    fn download_swarm_to<TI: TorrentInfo + Sync, W: Write>(
+        &self,
+        torrent_info: &TI,
+        peers: &[SocketAddr],
+        mut reannounce: Option<Reannounce>,
+        mut sink: Sink<W>,
+    ) -> anyhow::Result<()>
+    where
+        T: Sync,
+    {
+        torrent_info.validate_layout()?;
+        let pieces_info = torrent_info.pieces_info();
+        let mut wanted = torrent_info.wanted_pieces();
+        if !wanted.contains(&true) {
+            bail!("no file selected for download");
+        }
+        // pieces written before the download was interrupted are not wanted anymore
+        if let Sink::Disk(content, resume) = &mut sink {
+            resume.verify(torrent_info, content, self.hasher.as_ref())?;
+            for (wanted, have) in wanted.iter_mut().zip(resume.have()) {
+                *wanted &= !have;
+            }
+        }
+        // as well as the blocks written of the pieces that were not complete
+        let resumed = match &mut sink {
+            Sink::Disk(content, resume) => {
+                self.resumed_pieces(torrent_info, content, resume, &mut wanted)?
+            }
+            Sink::Stream(_) => HashMap::new(),
+        };
+        if let (Some(written), Sink::Disk(_, resume)) = (&self.written, &sink) {
+            written.reset(resume.have());
+        }
+        self.reset_download_stats(torrent_info, &wanted);
+        if !wanted.contains(&true) {
+            return Ok(());
+        }
+        // pieces of higher priority files are requested first
+        let mut blocks = Vec::new();
+        for index in torrent_info
+            .piece_order()
+            .into_iter()
+            .filter(|i| wanted[*i])
+        {
+            let piece = index.try_into().context("usize to u32")?;
+            for block_info in torrent_info
+                .blocks_info(index, self.block_size.block_size(None).into())
+                .context("no piece at this index")?
+            {
+                let block = Block {
+                    piece,
+                    begin: block_info.offset.try_into().context("u64 to u32")?,
+                    length: block_info.length.try_into().context("u64 to u32")?,
+                };
+                if !resumed
+                    .get(&piece)
+                    .is_some_and(|i| i.blocks.contains(&block))
+                {
+                    blocks.push(block);
+                }
+            }
+        }
+        // every peer gets its share of the window from the start, rather than the first one to
+        // answer taking it all
+        let mut scheduler = BlockScheduler::new(blocks, self.window)
+            .with_priorities(torrent_info.piece_priorities());
+        for peer in peers {
+            scheduler.add_peer(*peer, Instant::now());
+        }
+        // web seeds have every piece
+        let web_seeds = torrent_info.web_seeds();
+        for index in 0..web_seeds.len() {
+            let address = web_seed::address(index);
+            scheduler.add_peer(address, Instant::now());
+            scheduler.add_available(address, 0..pieces_info.len() as u32);
+        }
+        let swarm = Mutex::new(Swarm {
+            scheduler,
+            piece_lengths: pieces_info.iter().map(|i| i.length).collect(),
+            pieces: resumed,
+            failures: HashMap::new(),
+            verified: Vec::new(),
+            connected: BTreeSet::new(),
+            failed: None,
+            persist_blocks: matches!(sink, Sink::Disk(..)),
+            scores: PeerScores::new(self.ban_policy),
+        });
+
+        // blocks reach the disk as contiguous runs rather than one write each
+        let mut cache = BlockCache::default();
+        let (sender, receiver) = mpsc::channel();
+        let (written, mut errors) = thread::scope(|scope| {
+            let spawn = |peer: SocketAddr| {
+                let sender = sender.clone();
+                let swarm = &swarm;
+                scope.spawn(move || {
+                    let res = self.peer_session(torrent_info, peer, swarm, &sender);
+                    self.stats
+                        .lock()
+                        .expect("stats lock poisoned")
+                        .record_disconnected(peer, Instant::now());
+                    {
+                        let mut swarm = lock(swarm);
+                        swarm.scheduler.release(peer);
+                        swarm.connected.remove(&peer);
+                        if res.is_err() && swarm.scores.record_error(peer) {
+                            self.ban(&mut swarm, peer, "too many failed sessions");
+                        }
+                    }
+                    let _ = sender.send(SwarmEvent::SessionEnded);
+                    res.with_context(|| format!("downloading from {peer}"))
+                })
+            };
+            let mut sessions = peers.iter().map(|&peer| spawn(peer)).collect::<Vec<_>>();
+            sessions.extend(web_seeds.iter().enumerate().map(|(index, seed)| {
+                let address = web_seed::address(index);
+                let (sender, swarm) = (sender.clone(), &swarm);
+                scope.spawn(move || {
+                    let res = self.web_seed_session(torrent_info, seed, address, swarm, &sender);
+                    lock(swarm).scheduler.release(address);
+                    res.with_context(|| format!("downloading from web seed {seed}"))
+                })
+            }));
+            // peers discovered along the way join the pool, as do those whose session failed until
+            // they are banned
+            let mut known = peers.iter().copied().collect::<HashSet<_>>();
+            let max_sessions = peers.len() + web_seeds.len() + MAX_PEX_PEERS;
+
+            let mut verified = BTreeMap::new();
+            // pieces written, or left out when they are not wanted: on disk they are left out
+            // from the start, as pieces are written in any order
+            let mut written = match sink {
+                Sink::Stream(_) => 0,
+                Sink::Disk(..) => wanted.iter().filter(|i| !**i).count() as u32,
+            };
+            let mut stats_printed = Instant::now();
+            loop {
+                if let Some(interval) = self.stats_interval {
+                    if stats_printed.elapsed() >= interval {
+                        stats_printed = Instant::now();
+                        let stats = self.stats.lock().expect("stats lock poisoned");
+                        eprint!("\n{}", stats.connected_peers_report(stats_printed));
+                    }
+                }
+                let mut announced = Vec::new();
+                if let Some(reannounce) = &mut reannounce {
+                    if reannounce
+                        .tracker_manager
+                        .next_announce()
+                        .is_some_and(|next| next <= Instant::now())
+                    {
+                        match self.announce_to(reannounce.tracker_manager, reannounce.tracker_info)
+                        {
+                            Ok(peers) => announced
+                                .extend(peers.into_iter().filter(|peer| !known.contains(peer))),
+                            Err(err) => logging::warn("tracker", &format!("{err:#}")),
+                        }
+                    }
+                }
+                // sessions come and go as peers are discovered, the download is over once they
+                // have all ended and what they sent is processed
+                let event = if !announced.is_empty() {
+                    SwarmEvent::Discovered(announced)
+                } else {
+                    match receiver.recv_timeout(IDLE_PEER_POLL) {
+                        Ok(event) => event,
+                        Err(RecvTimeoutError::Timeout)
+                            if sessions.iter().all(|i| i.is_finished()) =>
+                        {
+                            match receiver.try_recv() {
+                                Ok(event) => event,
+                                Err(_) => break,
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                };
+                match event {
+                    SwarmEvent::Verified(index, data, last) => match &mut sink {
+                        Sink::Stream(writer) => {
+                            verified.insert(index, data);
+                            // pieces that are not wanted are left zeroed
+                            while let Some(data) = verified.remove(&written).or_else(|| {
+                                pieces_info
+                                    .get(written as usize)
+                                    .filter(|i| !wanted[i.index])
+                                    .map(|i| vec![0; i.length as usize])
+                            }) {
+                                if let Err(err) = writer
+                                    .write_all(&data)
+                                    .and_then(|_| writer.flush())
+                                    .context("writing piece")
+                                {
+                                    lock(&swarm).failed.get_or_insert(err);
+                                }
+                                written += 1;
+                            }
+                        }
+                        Sink::Disk(content, resume) => {
+                            // blocks written as they arrived are not written again, those still
+                            // cached are written along with the piece
+                            cache.remove(index);
+                            let offset = pieces_info[index as usize].offset;
+                            match unwritten_ranges(&data, &resume.blocks(index), last)
+                                .into_iter()
+                                .try_for_each(|range| {
+                                    content.write_at(
+                                        offset + range.start as u64,
+                                        &data[range.start..range.end],
+                                    )
+                                })
+                                .with_context(|| format!("writing piece {index}"))
+                            {
+                                Ok(()) => {
+                                    if let Err(err) = resume.record(index as usize, content) {
+                                        logging::warn("resume", &format!("{err:#}"));
+                                    }
+                                    if let Some(written) = &self.written {
+                                        written.set(index as usize);
+                                    }
+                                }
+                                Err(err) => {
+                                    lock(&swarm).failed.get_or_insert(err);
+                                }
+                            }
+                            written += 1;
+                        }
+                    },
+                    SwarmEvent::Block(block, data) => {
+                        if let Sink::Disk(content, resume) = &mut sink {
+                            if let Some(run) = cache.insert(block.piece, block.begin, data) {
+                                let offset = pieces_info[run.piece as usize].offset;
+                                if let Err(err) = write_run(content, resume, offset, &run) {
+                                    logging::warn("resume", &format!("{err:#}"));
+                                }
+                            }
+                        }
+                    }
+                    SwarmEvent::Discovered(discovered) => lock(&swarm)
+                        .scores
+                        .add_to_pool(discovered.into_iter().filter(|i| known.insert(*i))),
+                    SwarmEvent::SessionEnded => {}
+                }
+                // peers of the pool take the room left
+                while sessions.iter().filter(|i| !i.is_finished()).count() < max_sessions {
+                    let mut locked = lock(&swarm);
+                    if locked.failed.is_some() || locked.scheduler.is_finished() {
+                        break;
+                    }
+                    let Some(peer) = locked.scores.next_from_pool() else {
+                        break;
+                    };
+                    locked.scheduler.add_peer(peer, Instant::now());
+                    drop(locked);
+                    sessions.push(spawn(peer));
+                }
+            }
+
+            let errors = sessions
+                .into_iter()
+                .filter_map(|session| match session.join() {
+                    Ok(Ok(())) => None,
+                    Ok(Err(err)) => Some(err),
+                    Err(_) => Some(anyhow!("peer session panicked")),
+                })
+                .collect::<Vec<_>>();
+            (written as usize, errors)
+        });
+
+        // blocks are not written nor recorded at once, those of an interrupted download must not
+        // be lost
+        if let Sink::Disk(content, resume) = &mut sink {
+            for run in cache.drain() {
+                let offset = pieces_info[run.piece as usize].offset;
+                if let Err(err) = write_run(content, resume, offset, &run) {
+                    logging::warn("resume", &format!("{err:#}"));
+                }
+            }
+            if let Err(err) = resume.save() {
+                logging::warn("resume", &format!("{err:#}"));
+            }
+        }
+        if let Some(err) = swarm.into_inner().expect("swarm lock poisoned").failed {
+            return Err(err);
+        }
+        if written < pieces_info.len() {
+            return Err(errors
+                .pop()
+                .unwrap_or_else(|| anyhow!("no peer to download from"))
+                .context(format!(
+                    "no peer left with {} of {} pieces downloaded",
+                    written,
+                    pieces_info.len()
+                )));
+        }
+        if let Some(reannounce) = reannounce {
+            if let Err(err) = self.announce_event_to(
+                reannounce.tracker_manager,
+                reannounce.tracker_info,
+                AnnounceEvent::Completed,
+            ) {
+                logging::warn("tracker", &format!("{err:#}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Pieces of which `resume` records blocks written to `content`, rebuilt from the disk. Blocks
+    /// of another size than the current one, or that cannot be read, are left out. Pieces found
+    /// complete are checked at once: recorded and no longer `wanted` when they match, downloaded
+    /// again otherwise.
+    fn resumed_pieces<'a, TI: TorrentInfo>(
+        &'a self,
+        torrent_info: &TI,
+        content: &DiskContent,
+        resume: &mut Resume,
+        wanted: &mut [bool],
+    ) -> anyhow::Result<HashMap<u32, PartialPiece<'a>>> {
+        let mut pieces = HashMap::new();
+        for piece_info in torrent_info.pieces_info() {
+            let index = piece_info.index.try_into().context("usize to u32")?;
+            let recorded = resume.blocks(index);
+            if !wanted[piece_info.index] || recorded.is_empty() {
+                continue;
+            }
+            let block_infos = torrent_info
+                .blocks_info(piece_info.index, self.block_size.block_size(None).into())
+                .context("no piece at this index")?;
+            let mut partial = PartialPiece {
+                data: vec![0; piece_info.length as usize],
+                digest: IncrementalHash::new(self.hasher.as_ref()),
+                remaining: piece_info.length,
+                blocks: Vec::new(),
+                contributions: BTreeMap::new(),
+            };
+            for (begin, length) in recorded.into_iter().filter(|(begin, length)| {
+                block_infos
+                    .iter()
+                    .any(|i| i.offset == u64::from(*begin) && i.length == u64::from(*length))
+            }) {
+                let range = begin as usize..(begin + length) as usize;
+                // blocks that cannot be read back, such as those of deleted files, are ignored
+                if content
+                    .read_at(
+                        piece_info.offset + u64::from(begin),
+                        &mut partial.data[range.clone()],
+                    )
+                    .is_err()
+                {
+                    continue;
+                }
+                partial.digest.add(begin.into(), &partial.data[range]);
+                partial.remaining -= u64::from(length);
+                partial.blocks.push(Block {
+                    piece: index,
+                    begin,
+                    length,
+                });
+            }
+            if partial.blocks.is_empty() {
+                resume.forget_blocks(index);
+            } else if partial.remaining > 0 {
+                pieces.insert(index, partial);
+            } else if torrent_info.info().pieces.0.get(piece_info.index)
+                == Some(&partial.digest.finish())
+            {
+                resume.record(piece_info.index, content)?;
+                wanted[piece_info.index] = false;
+            } else {
+                resume.forget_blocks(index);
+            }
+        }
+        Ok(pieces)
+    }
+
+    /// Requests blocks from `peer` as long as the scheduler has some for it, sending the pieces it
+    /// completes and verifies to `events`. Peers supporting PEX are told about the other peers
+    /// of the download, and the peers they tell about are reported to `events`.
+    fn peer_session<'a, TI: TorrentInfo>(
+        &'a self,
+        torrent_info: &TI,
+        peer: SocketAddr,
+        swarm: &Mutex<Swarm<'a>>,
+        events: &Sender<SwarmEvent>,
+    ) -> anyhow::Result<()> {
+        let connect = |info_hash| -> anyhow::Result<_> {
+            let mut stream = self.connect_encrypted(peer, info_hash)?;
+            stream.tcp().set_nodelay(true)?;
+            let res = self
+                .shake_hands(
+                    &mut stream,
+                    info_hash,
+                    PEER_ID,
+                    &Extension::MagnetLink,
+                    true,
+                )
+                .context("shaking hands with peer")?;
+            Ok((stream, res))
+        };
+        // peers of the v2 swarm of a hybrid torrent hang up on its v1 hash
+        let (mut stream, res) = match (
+            connect(torrent_info.info_hash()?),
+            torrent_info.truncated_info_hash_v2()?,
+        ) {
+            (Ok(connected), _) => connected,
+            (Err(_), Some(info_hash)) => connect(info_hash)?,
+            (Err(err), None) => return Err(err),
+        };
+        let handshake = Handshake::from(&res);
+        let fast = handshake.supports_fast();
+        self.register_peer(peer, &handshake);
+        lock(swarm).connected.insert(peer);
+
+        if supports_extension_protocol(&res) {
+            let info = ExtensionsInfo {
+                metdata: Metadata {
+                    ut_metadata: None,
+                    ut_pex: Some(UT_PEX_ID),
+                    others: BTreeMap::new(),
+                },
+            };
+            self.send(
+                &mut stream,
+                peer,
+                &Message::Extension {
+                    message: ExtensionMessage::Info { info },
+                },
+            )
+            .context("writing extension handshake to stream")?;
+        }
+
+        // peers assume we have nothing until told otherwise, unless the Fast extension requires
+        // saying so
+        let (bitfield, mut announced, piece_count) = {
+            let swarm = lock(swarm);
+            let piece_count = swarm.piece_lengths.len();
+            let bitfield = match swarm.verified.len() {
+                0 if fast => Some(Message::HaveNone),
+                0 => None,
+                n if fast && n == piece_count => Some(Message::HaveAll),
+                _ => Some(Message::BitField {
+                    bitfield: Bitfield::from_pieces(piece_count, swarm.verified.iter().copied()),
+                }),
+            };
+            (bitfield, swarm.verified.len(), piece_count)
+        };
+        if let Some(bitfield) = bitfield {
+            self.send(&mut stream, peer, &bitfield)
+                .context("writing bitfield message to stream")?;
+        }
+        self.send_dht_port(&mut stream, peer, &handshake)?;
+
+        stream.tcp().set_read_timeout(Some(self.timeouts.read))?;
+
+        let mut choked = true;
+        // when the peer choked us after unchoking us, until it unchokes us again
+        let mut choked_at: Option<Instant> = None;
+        let mut interested = false;
+        let mut last_message = Instant::now();
+        // since the peer last delivered a block, or had none requested
+        let mut last_block = Instant::now();
+        // when each block in flight was requested, to measure latency
+        let mut requested_at = HashMap::<Block, Instant>::new();
+        // pieces the peer lets us request while it chokes us
+        let mut allowed_fast = HashSet::new();
+        // the peer's id for ut_pex, once it advertised it
+        let mut peer_pex_id = None;
+        let mut pex = PexState::default();
+        loop {
+            let (haves, wanted, requests, cancels, in_flight, pex_message) = {
+                let mut swarm = lock(swarm);
+                if swarm.scores.is_banned(peer) {
+                    bail!("{peer} was banned");
+                }
+                if swarm.failed.is_some() || swarm.scheduler.is_finished() {
+                    drop(swarm);
+                    // the connection is dropped anyway, this is only a courtesy
+                    for block in requested_at.keys() {
+                        let _ = self.send(
+                            &mut stream,
+                            peer,
+                            &Message::Cancel {
+                                index: block.piece,
+                                begin: block.begin,
+                                length: block.length,
+                            },
+                        );
+                    }
+                    if interested {
+                        let _ = self.send(&mut stream, peer, &Message::NotInterested);
+                    }
+                    return Ok(());
+                }
+                let haves = swarm.verified[announced..].to_vec();
+                announced = swarm.verified.len();
+                let wanted = swarm.scheduler.wants_from(peer);
+                let requests = if wanted && !choked {
+                    std::iter::from_fn(|| swarm.scheduler.next(peer)).collect::<Vec<_>>()
+                } else if wanted && !allowed_fast.is_empty() {
+                    std::iter::from_fn(|| {
+                        swarm
+                            .scheduler
+                            .next_matching(peer, |i| allowed_fast.contains(&i.piece))
+                    })
+                    .collect::<Vec<_>>()
+                } else {
+                    Vec::new()
+                };
+                let pex_message = peer_pex_id
+                    .and_then(|id| Some((id, pex.update(peer, &swarm.connected, Instant::now())?)));
+                (
+                    haves,
+                    wanted,
+                    requests,
+                    swarm.scheduler.take_cancelled(peer),
+                    swarm.scheduler.in_flight(peer),
+                    pex_message,
+                )
+            };
+            if let Some((id, message)) = pex_message {
+                self.send(
+                    &mut stream,
+                    peer,
+                    &Message::Extension {
+                        message: ExtensionMessage::Custom {
+                            id,
+                            payload: message.to_bytes()?,
+                        },
+                    },
+                )
+                .context("writing pex message to stream")?;
+            }
+            for index in haves {
+                self.send(&mut stream, peer, &Message::Have { index })
+                    .context("writing have message to stream")?;
+            }
+            if wanted != interested {
+                let message = if wanted {
+                    Message::Interested
+                } else {
+                    Message::NotInterested
+                };
+                self.send(&mut stream, peer, &message)
+                    .context("writing interest message to stream")?;
+                interested = wanted;
+            }
+            // blocks another peer delivered first in endgame
+            for block in cancels {
+                requested_at.remove(&block);
+                self.send(
+                    &mut stream,
+                    peer,
+                    &Message::Cancel {
+                        index: block.piece,
+                        begin: block.begin,
+                        length: block.length,
+                    },
+                )
+                .context("writing cancel message to stream")?;
+            }
+            for block in requests {
+                self.send(
+                    &mut stream,
+                    peer,
+                    &Message::Request {
+                        index: block.piece,
+                        begin: block.begin,
+                        length: block.length,
+                    },
+                )
+                .context("writing request message to stream")?;
+                requested_at.insert(block, Instant::now());
+            }
+            if in_flight == 0 {
+                last_block = Instant::now();
+            } else if last_block.elapsed() > self.snub_timeout {
+                self.snubbed(peer, swarm)?;
+                last_block = Instant::now();
+                continue;
+            }
+            if choked_at.is_some_and(|i| i.elapsed() > self.unchoke_timeout) {
+                bail!(
+                    "{peer} kept us choked for more than {:?}",
+                    self.unchoke_timeout
+                );
+            }
+            // waiting a little at a time, for the download ending or blocks to be cancelled in
+            // endgame not to go unnoticed while a peer is slow to answer
+            if !wait_for_data(&stream, IDLE_PEER_POLL)? {
+                if in_flight == 0 {
+                    // nothing expected from this peer: wait for it to announce pieces we need,
+                    // or for blocks in flight with the others to be given back
+                    last_message = Instant::now();
+                } else if last_message.elapsed() > self.timeouts.read {
+                    bail!("{peer} did not send the blocks requested in time");
+                }
+                continue;
+            }
+            last_message = Instant::now();
+
+            match self.receive(&mut stream, peer)? {
+                Message::BitField { bitfield } => {
+                    bitfield
+                        .validate(piece_count)
+                        .with_context(|| format!("invalid bitfield from {peer}"))?;
+                    lock(swarm).scheduler.add_available(peer, bitfield.pieces())
+                }
+                Message::Have { index } => lock(swarm).scheduler.add_available(peer, [index]),
+                Message::Port { port } => self.record_dht_node(peer, port),
+                Message::KeepAlive | Message::Unknown { .. } => {}
+                Message::Unchoke => {
+                    choked = false;
+                    choked_at = None;
+                    self.record_choked(peer, false);
+                }
+                // with the Fast extension, a choke does not cancel requests: those the peer will
+                // not serve are rejected one by one
+                Message::Choke if fast => {
+                    choked = true;
+                    self.record_choked(peer, true);
+                }
+                // without it, the peer dropped our requests: their blocks go back to the
+                // scheduler, for this peer once it unchokes us or for the others
+                Message::Choke => {
+                    if !choked {
+                        choked_at = Some(Instant::now());
+                    }
+                    choked = true;
+                    self.record_choked(peer, true);
+                    for block in lock(swarm).scheduler.choked(peer) {
+                        requested_at.remove(&block);
+                    }
+                }
+                Message::HaveAll if fast => lock(swarm)
+                    .scheduler
+                    .add_available(peer, 0..piece_count as u32),
+                Message::HaveNone | Message::SuggestPiece { .. } if fast => {}
+                Message::AllowedFast { index } if fast => {
+                    allowed_fast.insert(index);
+                }
+                Message::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                } if fast => {
+                    let block = Block {
+                        piece: index,
+                        begin,
+                        length,
+                    };
+                    requested_at.remove(&block);
+                    if !lock(swarm).scheduler.rejected(peer, block) {
+                        bail!("{peer} rejected a block that was not requested");
+                    }
+                }
+                Message::Piece {
+                    index,
+                    begin,
+                    block,
+                } => {
+                    last_block = Instant::now();
+                    let sent = Block {
+                        piece: index,
+                        begin,
+                        length: block.len() as u32,
+                    };
+                    if let Some(requested) = requested_at.remove(&sent) {
+                        self.stats
+                            .lock()
+                            .expect("stats lock poisoned")
+                            .record_request_latency(peer, last_block - requested, last_block);
+                    }
+                    self.block_received(torrent_info, peer, swarm, events, index, begin, block)?
+                }
+                Message::Extension {
+                    message: ExtensionMessage::Info { info },
+                } => peer_pex_id = info.metdata.ut_pex.filter(|i| *i != 0),
+                Message::Extension {
+                    message: ExtensionMessage::Custom { id, payload },
+                } if id == UT_PEX_ID => {
+                    let message = PexMessage::from_bytes(&payload)?;
+                    let _ = events.send(SwarmEvent::Discovered(message.all_added()));
+                }
+                // other extensions are not used while downloading
+                Message::Extension { .. } => {}
+                msg => bail!("unexpected message received: '{msg}'"),
+            }
+        }
+    }
+
+    /// Fetches the blocks the scheduler has for `seed`, known to it as `address`. Runs of
+    /// consecutive blocks are fetched at once, within a piece for HTTP seeds. Blocks asked for
+    /// while an HTTP seed is busy are given back for the others to fetch.
+    fn web_seed_session<'a, TI: TorrentInfo>(
+        &'a self,
+        torrent_info: &TI,
+        seed: &WebSeed,
+        address: SocketAddr,
+        swarm: &Mutex<Swarm<'a>>,
+        events: &Sender<SwarmEvent>,
+    ) -> anyhow::Result<()> {
+        self.stats
+            .lock()
+            .expect("stats lock poisoned")
+            .peer_mut(address, Instant::now())
+            .client = Some(seed.to_string());
+        let info_hash = torrent_info.info_hash()?;
+        let files = torrent_info.files_info();
+        let multi_file = matches!(torrent_info.info().keys, Keys::MultiFile { .. });
+        let piece_length = torrent_info.piece_length();
+        let offset = |block: &Block| u64::from(block.piece) * piece_length + u64::from(block.begin);
+        'session: loop {
+            let blocks = {
+                let mut swarm = lock(swarm);
+                if swarm.scores.is_banned(address) {
+                    bail!("{seed} was banned");
+                }
+                if swarm.failed.is_some() || swarm.scheduler.is_finished() {
+                    return Ok(());
+                }
+                std::iter::from_fn(|| swarm.scheduler.next(address)).collect::<Vec<_>>()
+            };
+            if blocks.is_empty() {
+                thread::sleep(IDLE_PEER_POLL);
+                continue;
+            }
+            let runs = blocks.chunk_by(|a, b| {
+                offset(a) + u64::from(a.length) == offset(b)
+                    && (a.piece == b.piece || matches!(seed, WebSeed::UrlList(_)))
+            });
+            for run in runs {
+                let start = offset(&run[0]);
+                let end = run
+                    .last()
+                    .map_or(start, |i| offset(i) + u64::from(i.length));
+                let data = match seed {
+                    WebSeed::UrlList(url) => web_seed::fetch_from_files(
+                        &self.client,
+                        url,
+                        &files,
+                        multi_file,
+                        start..end,
+                    )?,
+                    WebSeed::HttpSeed(url) => {
+                        let piece_offset = u64::from(run[0].piece) * piece_length;
+                        let range = start - piece_offset..end - piece_offset;
+                        match web_seed::fetch_from_http_seed(
+                            &self.client,
+                            url,
+                            &info_hash,
+                            run[0].piece,
+                            range,
+                        )? {
+                            Fetched::Data(data) => data,
+                            Fetched::Busy(wait) => {
+                                {
+                                    let mut swarm = lock(swarm);
+                                    for block in &blocks {
+                                        swarm.scheduler.rejected(address, *block);
+                                    }
+                                }
+                                let until = Instant::now() + wait;
+                                while Instant::now() < until && !lock(swarm).scheduler.is_finished()
+                                {
+                                    thread::sleep(IDLE_PEER_POLL);
+                                }
+                                continue 'session;
+                            }
+                        }
+                    }
+                };
+                for block in run {
+                    let begin = (offset(block) - start) as usize;
+                    let block_data = data[begin..begin + block.length as usize].to_vec();
+                    self.block_received(
+                        torrent_info,
+                        address,
+                        swarm,
+                        events,
+                        block.piece,
+                        block.begin,
+                        block_data,
+                    )?;
+                }
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn block_received<'a, TI: TorrentInfo>(
+        &'a self,
+        torrent_info: &TI,
+        peer: SocketAddr,
+        swarm: &Mutex<Swarm<'a>>,
+        events: &Sender<SwarmEvent>,
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let bytes = data.len() as u64;
+        let block = Block {
+            piece: index,
+            begin,
+            length: data.len().try_into().context("usize to u32")?,
+        };
+        let completed = {
+            let mut swarm = lock(swarm);
+            if !swarm.scheduler.received(peer, block, Instant::now()) {
+                drop(swarm);
+                self.record_duplicate_block(bytes);
+                return Ok(());
+            }
+            swarm.scores.record_bytes(peer, bytes);
+            let length = swarm.piece_lengths[index as usize];
+            let partial = swarm.pieces.entry(index).or_insert_with(|| PartialPiece {
+                data: vec![0; length as usize],
+                digest: IncrementalHash::new(self.hasher.as_ref()),
+                remaining: length,
+                blocks: Vec::new(),
+                contributions: BTreeMap::new(),
+            });
+            let begin = begin as usize;
+            partial.data[begin..begin + data.len()].copy_from_slice(&data);
+            partial.remaining -= bytes;
+            partial.digest.add(begin as u64, &data);
+            partial.blocks.push(block);
+            *partial.contributions.entry(peer).or_default() += bytes;
+            if partial.remaining == 0 {
+                swarm.pieces.remove(&index)
+            } else {
+                // blocks of complete pieces are written along with the piece
+                if swarm.persist_blocks {
+                    let _ = events.send(SwarmEvent::Block(block, data));
+                }
+                None
+            }
+        };
+        self.record_block(peer, bytes);
+
+        let Some(piece) = completed else {
+            return Ok(());
+        };
+        // a corrupt piece is blamed on whoever sent most of it
+        let blamed = piece
+            .contributions
+            .iter()
+            .max_by_key(|(_, bytes)| **bytes)
+            .map_or(peer, |(peer, _)| *peer);
+        let actual = piece.digest.finish();
+        if self.verify_piece(torrent_info, index, &piece.data, actual, blamed) {
+            lock(swarm).verified.push(index);
+            // the receiving end is only gone once the download failed
+            let _ = events.send(SwarmEvent::Verified(index, piece.data, block));
+            return Ok(());
+        }
+
+        let mut swarm = lock(swarm);
+        if swarm.scores.record_hash_failure(blamed) {
+            self.ban(&mut swarm, blamed, "too many pieces failing verification");
+        }
+        let failures = swarm.failures.entry(index).or_default();
+        *failures += 1;
+        if *failures >= self.retry.piece.max_attempts {
+            let failures = *failures;
+            swarm.failed.get_or_insert(anyhow!(
+                "piece {index} failed verification {failures} times"
+            ));
+        } else {
+            swarm.scheduler.requeue(piece.blocks);
+        }
+        Ok(())
+    }
+}
+
+/// A piece being assembled from blocks, possibly coming from several peers
+struct PartialPiece<'a> {
+    data: Vec<u8>,
+    digest: IncrementalHash<'a>,
+    remaining: u64,
+    blocks: Vec<Block>,
+    contributions: BTreeMap<SocketAddr, u64>,
+}
+
+/// Trackers announced to again during a download, whenever their interval elapses
+pub struct Reannounce<'a> {
+    pub tracker_manager: &'a mut TrackerManager,
+    pub tracker_info: &'a dyn TrackerInfo,
+}
+
+/// State shared by the peer sessions of a multi-peer download
+struct Swarm<'a> {
+    scheduler: BlockScheduler,
+    piece_lengths: Vec<u64>,
+    pieces: HashMap<u32, PartialPiece<'a>>,
+    failures: HashMap<u32, u32>,
+    /// Pieces verified so far, in the order they were
+    verified: Vec<u32>,
+    /// Peers hands were shaken with and whose session is still running
+    connected: BTreeSet<SocketAddr>,
+    /// Set when the whole download has to stop
+    failed: Option<anyhow::Error>,
+    /// Whether blocks are reported as they arrive, to be written before their piece completes
+    persist_blocks: bool,
+    scores: PeerScores,
+}
+
+/// Where a swarm download writes the pieces it verifies
+enum Sink<'a, W> {
+    /// In order, holding pieces back until those before them are written
+    Stream(&'a mut W),
+    /// At their offset in the torrent's files, recording them for resuming
+    Disk(&'a DiskContent, &'a mut Resume),
+}
+
+/// What peer sessions report to the thread writing the download
+enum SwarmEvent {
+    /// A verified piece, along with the block that completed it, which was not reported on its own
+    Verified(u32, Vec<u8>, Block),
+    /// A block of a piece that is not complete yet
+    Block(Block, Vec<u8>),
+    /// Peers learnt through PEX or announces made during the download
+    Discovered(Vec<SocketAddr>),
+    /// A peer session is over, making room for another peer
+    SessionEnded,
+}
+
+/// Writes the `run` of blocks of the piece at `offset` in `content` at once, then records its
+/// blocks
+fn write_run(
+    content: &DiskContent,
+    resume: &mut Resume,
+    offset: u64,
+    run: &Run,
+) -> anyhow::Result<()> {
+    content.write_at(offset + u64::from(run.begin), &run.data)?;
+    run.blocks
+        .iter()
+        .try_for_each(|(begin, length)| resume.record_block(run.piece, *begin, *length))
+}
+
+/// Ranges of the verified piece `data` still to be written, knowing that the blocks `written`
+/// were: those left out, and the block `last` that completed the piece, which may be recorded
+/// from an earlier download of the piece that failed verification
+fn unwritten_ranges(data: &[u8], written: &[(u32, u32)], last: Block) -> Vec<Range<usize>> {
+    let mut written = written
+        .iter()
+        .map(|(begin, length)| *begin as usize..(begin + length) as usize)
+        .collect::<Vec<_>>();
+    written.sort_by_key(|i| i.start);
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    for range in written {
+        if range.start > start {
+            ranges.push(start..range.start);
+        }
+        start = start.max(range.end);
+    }
+    if start < data.len() {
+        ranges.push(start..data.len());
+    }
+    ranges.push(last.begin as usize..(last.begin + last.length) as usize);
+    ranges.sort_by_key(|i| i.start);
+    ranges.into_iter().fold(Vec::new(), |mut merged, range| {
+        match merged.last_mut() {
+            Some(previous) if range.start <= previous.end => {
+                previous.end = previous.end.max(range.end)
+            }
+            _ => merged.push(range),
+        }
+        merged
+    })
+}
+
+/// The peer a piece is being downloaded from does not have it
+#[derive(Debug, thiserror::Error)]
+#[error("{peer} does not have piece {index}")]
+struct MissingPiece {
+    peer: SocketAddr,
+    index: u32,
+}
+
+/// Whether `err` comes from the peer closing the connection
+fn is_disconnection(err: &anyhow::Error) -> bool {
+    err.chain().any(|i| {
+        i.downcast_ref::<std::io::Error>().is_some_and(|i| {
+            matches!(
+                i.kind(),
+                std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset
+            )
+        })
+    })
+}
+
+/// Whether a message can be read from `stream` without blocking
+fn has_data(stream: &PeerStream) -> anyhow::Result<bool> {
+    if stream.has_buffered() {
+        return Ok(true);
+    }
+    let stream = stream.tcp();
+    stream.set_nonblocking(true)?;
+    let mut buf = [0u8; 1];
+    let res = stream.peek(&mut buf);
+    stream.set_nonblocking(false)?;
+    match res {
+        Ok(0) => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+            .context("connection closed by peer"),
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Waits up to `timeout` for a message to be readable from `stream`
+fn wait_for_data(stream: &PeerStream, timeout: Duration) -> anyhow::Result<bool> {
+    if stream.has_buffered() {
+        return Ok(true);
+    }
+    let tcp = stream.tcp();
+    let read_timeout = tcp.read_timeout()?;
+    tcp.set_read_timeout(Some(timeout))?;
+    let mut buf = [0u8; 1];
+    let res = tcp.peek(&mut buf);
+    tcp.set_read_timeout(read_timeout)?;
+    match res {
+        Ok(0) => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+            .context("connection closed by peer"),
+        Ok(_) => Ok(true),
+        Err(err)
+            if matches!(
+                err.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn lock<'a, 's>(swarm: &'s Mutex<Swarm<'a>>) -> std::sync::MutexGuard<'s, Swarm<'a>> {
+    swarm.lock().expect("swarm lock poisoned")
+}
+
+/// Writes a piece that failed verification to `dir` as `piece-<index>-<unix ms>.bin`, with a
+/// `.txt` next to it giving its index, expected and actual hashes and the peer it came from
+fn dump_corrupt_piece(
+    dir: &Path,
+    index: u32,
+    expected: Option<&[u8; 20]>,
+    actual: [u8; 20],
+    peer: SocketAddr,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    fs::create_dir_all(dir).context("creating corrupt piece directory")?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path = dir.join(format!("piece-{index}-{timestamp}"));
+    fs::write(path.with_extension("bin"), data).context("writing corrupt piece")?;
+    fs::write(
+        path.with_extension("txt"),
+        format!(
+            "index: {index}\nlength: {}\nexpected: {}\nactual: {}\npeer: {peer}\n",
+            data.len(),
+            expected.map_or("none".to_string(), hex::encode),
+            hex::encode(actual)
+        ),
+    )
+    .context("writing corrupt piece metadata")
+}
+
+mod state {
+    use std::{collections::HashSet, time::Instant};
+
+    #[allow(clippy::enum_variant_names)]
+    pub enum Stage {
+        WaitingForBitField,
+        WaitingForUnchoke,
+        WaitingForPieceBlock,
+    }
+
+    /// Where a download connection stands, kept across the pieces downloaded over it
+    pub struct State {
+        pub stage: Stage,
+        /// Whether both ends support the Fast extension
+        pub fast: bool,
+        /// Pieces the peer lets us request while it chokes us
+        pub allowed_fast: HashSet<u32>,
+        /// Pieces the peer advertised, with its BitField, HaveAll or Have messages
+        pub pieces: HashSet<u32>,
+        /// When the peer choked us after unchoking us, until it unchokes us again
+        pub choked_at: Option<Instant>,
+    }
+
+    impl State {
+        pub fn new(fast: bool) -> Self {
+            State {
+                stage: Stage::WaitingForBitField,
+                fast,
+                allowed_fast: HashSet::new(),
+                pieces: HashSet::new(),
+                choked_at: None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
 mod test {
     use std::{
-        collections::VecDeque,
-        io::{Read, Write},
+        collections::{HashMap, HashSet, VecDeque},
+        fs,
+        io::{BufRead, BufReader, Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::{anyhow, Context};
+    use base64::{engine::general_purpose, Engine};
+    use reqwest::{Method, Url};
+    use reqwest_mock::{StubClient, StubDefault, StubSettings, StubStrictness};
+
+    use crate::{
+        bitfield::Bitfield,
+        block_size::LanProbing,
+        bt_client::{BtClient, BtClientBuilder, Reannounce, MAX_LATENCY_PROBES, PEER_ID},
+        cli::Preallocation,
+        file_selection::{FileList, Selected},
+        hooks::{MessageHook, Verdict},
+        magnet_links::MagnetLink,
+        mse::{self, EncryptionPolicy},
+        peer_messages::{Extension, ExtensionMessage, Handshake, Message},
+        peer_scores::BanPolicy,
+        pex::PexMessage,
+        resume::Resume,
+        retry::{RetryPolicies, RetryPolicy},
+        scheduler::Block,
+        sha1::{self, PieceHasher},
+        timeouts::Timeouts,
+        torrent::Torrent,
+        torrent_info::TorrentInfo,
+        tracker::Peers,
+        verify::DiskContent,
+        web_seed,
     };
 
-    use anyhow::{anyhow, Context};
-    use base64::{engine::general_purpose, Engine};
-    use reqwest::{Method, Url};
-    use reqwest_mock::{StubClient, StubDefault, StubSettings, StubStrictness};
+    use super::{unwritten_ranges, HttpClient};
+
+    impl HttpClient for StubClient {
+        fn get(&self, url: Url) -> anyhow::Result<Vec<u8>> {
+            match reqwest_mock::Client::get(self, url)
+                .send()
+                .map_err(|e| anyhow!("receiver is gone: {}", e.description()))
+            {
+                Ok(response) => Ok(response.body),
+                Err(err) => Err(err),
+            }
+        }
+    }
+
+    #[test]
+    fn get_peers() -> anyhow::Result<()> {
+        let torrent = Torrent::from_base64("ZDg6YW5ub3VuY2UzMTpodHRwOi8vMTI3LjAuMC4xOjQ0MzgxL2Fubm91bmNlNDppbmZvZDY6bGVuZ3RoaTIwOTcxNTJlNDpuYW1lMTU6ZmFrZXRvcnJlbnQuaXNvMTI6cGllY2UgbGVuZ3RoaTI2MjE0NGU2OnBpZWNlczE2MDrd8zFyWZ/ahPCiCaMDT3nwuKpeInlaYYoe5SdelShDsBpWrk4UJ1Lvza4u9TLWEaRrLPe2TVeMCbOsC24Jja3AwZQ28ZJ+onuQ6xixooIKI4+lNVQZiG2exW6GzXeRND6Ted4YHK6s6xX9ETSxtLIfrQQSWyJ7Tc/6WG4g1Xmk3nYJDhK9Cj2bHFOfPq7C1+sdtTnCqdJNAj+5FreSNLdpZWU=")?;
+
+        let mut client = StubClient::new(StubSettings {
+            default: StubDefault::Error,
+            strictness: StubStrictness::MethodUrl,
+        });
+
+        let response = b"d8:completei2e10:downloadedi1e10:incompletei1e8:intervali1921e12:min intervali960e5:peers18:tttt09eeee18xxxx27e";
+        let _ = client
+            .stub(
+                Url::parse("http://127.0.0.1:44381/announce?info_hash=%a1%8a%79%fa%44%e0%45%b1%e1%38%79%16%6d%35%82%3e%84%84%19%f8&peer_id=alice_is_1_feet_tall&port=6881&uploaded=0&downloaded=0&left=2097152&compact=1&event=started")
+                .unwrap(),
+            )
+            .method(Method::GET)
+            .response()
+            .body(response.to_vec())
+            .mock();
+
+        let bt_client = BtClient::with_client(client);
+
+        assert_eq!(
+            vec![
+                "116.116.116.116:12345",
+                "101.101.101.101:12600",
+                "120.120.120.120:12855"
+            ],
+            bt_client
+                .get_peers(&torrent)?
+                .iter()
+                .map(|i| format!("{i}"))
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_peers_from_extra_trackers() -> anyhow::Result<()> {
+        let torrent = Torrent::from_base64("ZDg6YW5ub3VuY2UzMTpodHRwOi8vMTI3LjAuMC4xOjQ0MzgxL2Fubm91bmNlNDppbmZvZDY6bGVuZ3RoaTIwOTcxNTJlNDpuYW1lMTU6ZmFrZXRvcnJlbnQuaXNvMTI6cGllY2UgbGVuZ3RoaTI2MjE0NGU2OnBpZWNlczE2MDrd8zFyWZ/ahPCiCaMDT3nwuKpeInlaYYoe5SdelShDsBpWrk4UJ1Lvza4u9TLWEaRrLPe2TVeMCbOsC24Jja3AwZQ28ZJ+onuQ6xixooIKI4+lNVQZiG2exW6GzXeRND6Ted4YHK6s6xX9ETSxtLIfrQQSWyJ7Tc/6WG4g1Xmk3nYJDhK9Cj2bHFOfPq7C1+sdtTnCqdJNAj+5FreSNLdpZWU=")?;
+
+        let mut client = StubClient::new(StubSettings {
+            default: StubDefault::Error,
+            strictness: StubStrictness::MethodUrl,
+        });
+        let query = "?info_hash=%a1%8a%79%fa%44%e0%45%b1%e1%38%79%16%6d%35%82%3e%84%84%19%f8&peer_id=alice_is_1_feet_tall&port=6881&uploaded=0&downloaded=0&left=2097152&compact=1&event=started";
+        for (announce, response) in [
+            (
+                "http://127.0.0.1:44381/announce",
+                &b"d8:intervali1921e5:peers12:tttt09eeee18e"[..],
+            ),
+            (
+                "http://127.0.0.1:44382/announce",
+                &b"d8:intervali1921e5:peers12:eeee18xxxx27e"[..],
+            ),
+        ] {
+            let _ = client
+                .stub(Url::parse(&format!("{announce}{query}"))?)
+                .method(Method::GET)
+                .response()
+                .body(response.to_vec())
+                .mock();
+        }
+
+        let bt_client = BtClient::with_client(client)
+            .with_retry_policies(RetryPolicies::uniform(RetryPolicy::none()))
+            .with_extra_trackers(vec![
+                Url::parse("http://127.0.0.1:44382/announce")?,
+                Url::parse("http://127.0.0.1:44383/announce")?,
+            ]);
+
+        assert_eq!(
+            vec![
+                "116.116.116.116:12345",
+                "101.101.101.101:12600",
+                "120.120.120.120:12855"
+            ],
+            bt_client
+                .get_peers(&torrent)?
+                .iter()
+                .map(|i| format!("{i}"))
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_exact_source_info() -> anyhow::Result<()> {
+        let content = b"d8:announce31:http://127.0.0.1:44381/announce4:infod6:lengthi3e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1eee";
+        let info_hash = hex::encode(crate::torrent::raw_info_hash(content)?);
+
+        let mut client = StubClient::new(StubSettings {
+            default: StubDefault::Error,
+            strictness: StubStrictness::MethodUrl,
+        });
+        let _ = client
+            .stub(Url::parse("http://127.0.0.1:44381/a.torrent")?)
+            .method(Method::GET)
+            .response()
+            .body(content.to_vec())
+            .mock();
+        let bt_client = BtClient::with_client(client);
+
+        let magnet_link = MagnetLink::parse(format!("magnet:?xt=urn:btih:{info_hash}&tr=http%3A%2F%2F127.0.0.1%3A44381%2Fannounce&xs=http%3A%2F%2F127.0.0.1%3A44381%2Fa.torrent"))?;
+        assert_eq!(
+            "a.txt",
+            bt_client
+                .get_exact_source_info(&magnet_link)?
+                .display_name()
+        );
+
+        let magnet_link = MagnetLink::parse("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&tr=http%3A%2F%2F127.0.0.1%3A44381%2Fannounce&xs=http%3A%2F%2F127.0.0.1%3A44381%2Fa.torrent")?;
+        assert!(bt_client.get_exact_source_info(&magnet_link).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn shake_hands() -> anyhow::Result<()> {
+        let torrent = Torrent::from_base64("ZDg6YW5ub3VuY2UzMTpodHRwOi8vMTI3LjAuMC4xOjQ0MzgxL2Fubm91bmNlNDppbmZvZDY6bGVuZ3RoaTIwOTcxNTJlNDpuYW1lMTU6ZmFrZXRvcnJlbnQuaXNvMTI6cGllY2UgbGVuZ3RoaTI2MjE0NGU2OnBpZWNlczE2MDrd8zFyWZ/ahPCiCaMDT3nwuKpeInlaYYoe5SdelShDsBpWrk4UJ1Lvza4u9TLWEaRrLPe2TVeMCbOsC24Jja3AwZQ28ZJ+onuQ6xixooIKI4+lNVQZiG2exW6GzXeRND6Ted4YHK6s6xX9ETSxtLIfrQQSWyJ7Tc/6WG4g1Xmk3nYJDhK9Cj2bHFOfPq7C1+sdtTnCqdJNAj+5FreSNLdpZWU=")?;
+
+        let bt_client = BtClient::new();
+
+        let mut mock_stream = VecDeque::new();
+        // Message that will be read by the client
+        let response_from_peer = [
+            19u8, 66, 105, 116, 84, 111, 114, 114, 101, 110, 116, 32, 112, 114, 111, 116, 111, 99,
+            111, 108, 0, 0, 0, 0, 0, 0, 0, 0, 161, 138, 121, 250, 68, 224, 69, 177, 225, 56, 121,
+            22, 109, 53, 130, 62, 132, 132, 25, 248, 48, 48, 48, 48, 48, 48, 48, 48, 48, 48, 48,
+            48, 48, 48, 48, 48, 48, 48, 48, 48,
+        ];
+        mock_stream.write_all(&response_from_peer)?;
+
+        let res = bt_client.shake_hands(
+            &mut mock_stream,
+            torrent.info_hash()?,
+            PEER_ID,
+            &Extension::None,
+            false,
+        )?;
+        assert_eq!(response_from_peer, res); // What is returned is what was initialy written in
+                                             // the "stream"
+        let mut buf = [0u8; 68];
+        mock_stream.read_exact(&mut buf)?;
+        assert_eq!(b"00000000000000000000", &res[48..68]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shake_hands_with_magnet_extension() -> anyhow::Result<()> {
+        let magnet_link = MagnetLink::parse("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce")?;
+
+        let bt_client = BtClient::new();
+
+        let mut mock_stream = VecDeque::new();
+        // Message that will be read by the client - note the extension 6th bytes extension flag!
+        let response_from_peer = [
+            19u8, 66, 105, 116, 84, 111, 114, 114, 101, 110, 116, 32, 112, 114, 111, 116, 111, 99,
+            111, 108, 0, 0, 0, 0, 0, 16, 0, 0, 161, 138, 121, 250, 68, 224, 69, 177, 225, 56, 121,
+            22, 109, 53, 130, 62, 132, 132, 25, 248, 48, 48, 48, 48, 48, 48, 48, 48, 48, 48, 48,
+            48, 48, 48, 48, 48, 48, 48, 48, 48,
+        ];
+        mock_stream
+            .write_all(&response_from_peer)
+            .context("writing request message to stream")?;
+
+        let res = bt_client.shake_hands(
+            &mut mock_stream,
+            magnet_link.info_hash,
+            PEER_ID,
+            &Extension::MagnetLink,
+            false,
+        )?;
+        assert_eq!(response_from_peer, res); // What is returned is what was initialy written in
+                                             // the "stream"
+        let mut buf = [0u8; 68];
+        mock_stream.read_exact(&mut buf)?;
+        assert_eq!(b"00000000000000000000", &res[48..68]);
+
+        Ok(())
+    }
+
+    /// A peer answering handshakes after `delay`
+    fn slow_peer(delay: Duration) -> anyhow::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0u8; 68];
+                if stream.read_exact(&mut buf).is_ok() {
+                    thread::sleep(delay);
+                    let _ = stream.write_all(&buf);
+                }
+            }
+        });
+        Ok(address)
+    }
+
+    #[test]
+    fn peers_sorted_by_handshake_latency() -> anyhow::Result<()> {
+        let slow = slow_peer(Duration::from_millis(200))?;
+        let fast = slow_peer(Duration::ZERO)?;
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            listener.local_addr()?
+        };
+
+        let connections = CountConnections::default();
+        let count = connections.0.clone();
+
+        let peers = BtClient::new()
+            .with_hook(connections)
+            .peers_by_latency([0; 20], &[slow, closed, fast]);
+
+        assert_eq!(
+            vec![fast, slow],
+            peers.iter().map(|(peer, _)| *peer).collect::<Vec<_>>()
+        );
+        assert!(peers[1].1 >= Duration::from_millis(200));
+        // measured over connections hooks see
+        assert_eq!(2, count.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[test]
+    fn latency_probes_are_bounded() -> anyhow::Result<()> {
+        // a peer recording how many handshakes it is answering at once
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let (current, max) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (peer_current, peer_max) = (current.clone(), max.clone());
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let (current, max) = (peer_current.clone(), peer_max.clone());
+                thread::spawn(move || {
+                    let mut buf = [0u8; 68];
+                    if stream.read_exact(&mut buf).is_ok() {
+                        max.fetch_max(current.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(20));
+                        current.fetch_sub(1, Ordering::SeqCst);
+                        let _ = stream.write_all(&buf);
+                    }
+                });
+            }
+        });
+
+        let peers = BtClient::new().peers_by_latency([0; 20], &[address; 40]);
+
+        assert_eq!(40, peers.len());
+        assert!(max.load(Ordering::SeqCst) <= MAX_LATENCY_PROBES);
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_latency_follows_the_encryption_policy() -> anyhow::Result<()> {
+        // a peer only accepting encrypted connections
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let Ok(mut stream) = mse::accept(stream, [0; 20], false) else {
+                    continue;
+                };
+                let mut buf = [0u8; 68];
+                if stream.read_exact(&mut buf).is_ok() {
+                    let _ = stream.write_all(&buf);
+                }
+            }
+        });
+
+        let plain = BtClient::new().with_timeouts(Timeouts::uniform(Duration::from_secs(1)));
+        assert!(plain.handshake_latency([0; 20], address).is_err());
+        let encrypted = BtClient::new().with_encryption(EncryptionPolicy::Require);
+        assert!(encrypted.handshake_latency([0; 20], address).is_ok());
+        Ok(())
+    }
+
+    struct ZeroHasher;
+
+    impl PieceHasher for ZeroHasher {
+        fn hash(&self, _bytes: &[u8]) -> [u8; 20] {
+            [0; 20]
+        }
+
+        fn name(&self) -> &'static str {
+            "zero"
+        }
+    }
+
+    #[test]
+    fn download_piece_with_custom_hasher() -> anyhow::Result<()> {
+        let content = b"0123456789";
+        let torrent = test_torrent(content, 10)?;
+
+        let mut mock_stream = VecDeque::new();
+        mock_stream.write_all(
+            &Message::BitField {
+                bitfield: Bitfield::from_pieces(1, [0]),
+            }
+            .to_bytes()?,
+        )?;
+        mock_stream.write_all(&Message::Unchoke.to_bytes()?)?;
+        mock_stream.write_all(
+            &Message::Piece {
+                index: 0,
+                begin: 0,
+                block: content.to_vec(),
+            }
+            .to_bytes()?,
+        )?;
+
+        let dump_dir = tempfile::tempdir()?;
+        let client = BtClient::new()
+            .with_hasher(ZeroHasher)
+            .with_corrupt_piece_dir(Some(dump_dir.path().to_path_buf()));
+        let peer = SocketAddr::from_str("127.0.0.1:6881")?;
+        let res = client.piece_download(&mut mock_stream, &torrent, peer, 0)?;
+
+        assert!(!res.hash_ok);
+        assert_eq!(1, client.stats().integrity.pieces_failed_verification);
+
+        let mut dumped = std::fs::read_dir(dump_dir.path())?
+            .map(|i| i.map(|i| i.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        dumped.sort();
+        assert_eq!(2, dumped.len());
+        assert_eq!(content.to_vec(), std::fs::read(&dumped[0])?);
+        let metadata = std::fs::read_to_string(&dumped[1])?;
+        assert!(metadata.contains(&format!("expected: {}", hex::encode(sha1::hash(content)))));
+        assert!(metadata.contains(&format!("actual: {}", hex::encode([0; 20]))));
+        assert!(metadata.contains("peer: 127.0.0.1:6881"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn allowed_fast_piece_is_requested_while_choked() -> anyhow::Result<()> {
+        let content = b"0123456789";
+        let torrent = test_torrent(content, 10)?;
+
+        let mut mock_stream = VecDeque::new();
+        for message in [
+            Message::HaveAll,
+            Message::AllowedFast { index: 0 },
+            // rejected while choked, requested again once unchoked
+            Message::RejectRequest {
+                index: 0,
+                begin: 0,
+                length: 10,
+            },
+            Message::Unchoke,
+            Message::Piece {
+                index: 0,
+                begin: 0,
+                block: content.to_vec(),
+            },
+        ] {
+            mock_stream.write_all(&message.to_bytes()?)?;
+        }
+
+        let client = BtClient::new();
+        let peer = SocketAddr::from_str("127.0.0.1:6881")?;
+        let mut state = super::state::State::new(true);
+        let res = client.piece_download_with(&mut mock_stream, &mut state, &torrent, peer, 0)?;
+
+        assert!(res.hash_ok);
+        assert_eq!(content.to_vec(), res.data);
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        assert_eq!(Message::Interested, Message::read_from(&mut mock_stream)?);
+        assert_eq!(request, Message::read_from(&mut mock_stream)?);
+        assert_eq!(request, Message::read_from(&mut mock_stream)?);
+        assert!(mock_stream.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn have_messages_update_what_the_peer_has() -> anyhow::Result<()> {
+        let content = b"0123456789abcdefghij";
+        let torrent = test_torrent(content, 10)?;
+
+        let mut mock_stream = VecDeque::new();
+        for message in [
+            // no bitfield, the peer announces its only piece instead
+            Message::Have { index: 0 },
+            Message::Unchoke,
+            Message::Have { index: 1 },
+            Message::Piece {
+                index: 0,
+                begin: 0,
+                block: content[..10].to_vec(),
+            },
+        ] {
+            mock_stream.write_all(&message.to_bytes()?)?;
+        }
+
+        let client = BtClient::new();
+        let peer = SocketAddr::from_str("127.0.0.1:6881")?;
+        let mut state = super::state::State::new(false);
+        let res = client.piece_download_with(&mut mock_stream, &mut state, &torrent, peer, 0)?;
+
+        assert!(res.hash_ok);
+        assert_eq!(HashSet::from([0, 1]), state.pieces);
+        assert_eq!(Message::Interested, Message::read_from(&mut mock_stream)?);
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_dropped_by_a_choke_are_requested_again() -> anyhow::Result<()> {
+        let content = b"0123456789";
+        let torrent = test_torrent(content, 10)?;
+
+        let mut mock_stream = VecDeque::new();
+        for message in [
+            Message::BitField {
+                bitfield: Bitfield::from_pieces(1, [0]),
+            },
+            Message::Unchoke,
+            Message::Piece {
+                index: 0,
+                begin: 0,
+                block: content[..5].to_vec(),
+            },
+            Message::Choke,
+            Message::Unchoke,
+            Message::Piece {
+                index: 0,
+                begin: 5,
+                block: content[5..].to_vec(),
+            },
+        ] {
+            mock_stream.write_all(&message.to_bytes()?)?;
+        }
+
+        let client = BtClient::builder().with_block_size(5).build();
+        let peer = SocketAddr::from_str("127.0.0.1:6881")?;
+        let res = client.piece_download(&mut mock_stream, &torrent, peer, 0)?;
+
+        assert!(res.hash_ok);
+        assert_eq!(content.to_vec(), res.data);
+        let request = |begin| Message::Request {
+            index: 0,
+            begin,
+            length: 5,
+        };
+        assert_eq!(Message::Interested, Message::read_from(&mut mock_stream)?);
+        assert_eq!(request(0), Message::read_from(&mut mock_stream)?);
+        assert_eq!(request(5), Message::read_from(&mut mock_stream)?);
+        assert_eq!(request(5), Message::read_from(&mut mock_stream)?);
+        assert!(mock_stream.is_empty());
+
+        let mut mock_stream = VecDeque::new();
+        for message in [
+            Message::BitField {
+                bitfield: Bitfield::from_pieces(1, [0]),
+            },
+            Message::Unchoke,
+            Message::Choke,
+            Message::KeepAlive,
+        ] {
+            mock_stream.write_all(&message.to_bytes()?)?;
+        }
+        let client = BtClient::builder()
+            .with_block_size(5)
+            .build()
+            .with_unchoke_timeout(Duration::ZERO);
+        let err = client
+            .piece_download(&mut mock_stream, &torrent, peer, 0)
+            .unwrap_err();
+        assert!(err.to_string().contains("kept us choked"));
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_that_were_not_requested_are_refused() -> anyhow::Result<()> {
+        let content = b"0123456789";
+        let torrent = test_torrent(content, 10)?;
+        let peer = SocketAddr::from_str("127.0.0.1:6881")?;
+
+        // past the end of the piece, then within it but with another length
+        for (begin, block) in [(8, b"89abc".to_vec()), (0, b"0123".to_vec())] {
+            let mut mock_stream = VecDeque::new();
+            for message in [
+                Message::BitField {
+                    bitfield: Bitfield::from_pieces(1, [0]),
+                },
+                Message::Unchoke,
+                Message::Piece {
+                    index: 0,
+                    begin,
+                    block,
+                },
+            ] {
+                mock_stream.write_all(&message.to_bytes()?)?;
+            }
+
+            let client = BtClient::builder().with_block_size(5).build();
+            let err = client
+                .piece_download(&mut mock_stream, &torrent, peer, 0)
+                .unwrap_err();
+            assert!(err.to_string().contains("was not requested"));
+        }
+        Ok(())
+    }
+
+    /// Single file torrent of `content`, cut into pieces of `piece_length`
+    fn test_torrent(content: &[u8], piece_length: usize) -> anyhow::Result<Torrent> {
+        let mut torrent_content = Vec::from(format!("d8:announce31:http://127.0.0.1:44381/announce4:infod6:lengthi{}e4:name15:faketorrent.iso12:piece lengthi{piece_length}e6:pieces{}:", content.len(), content.len().div_ceil(piece_length) * 20));
+        for piece in content.chunks(piece_length) {
+            torrent_content.extend_from_slice(&sha1::hash(piece));
+        }
+        torrent_content.extend_from_slice(b"ee");
+        Torrent::from_bytes(&torrent_content)
+    }
+
+    /// A peer serving `content`, answering each block request after `delay`
+    fn seeder(
+        content: Vec<u8>,
+        piece_length: usize,
+        delay: Duration,
+    ) -> anyhow::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.set_nodelay(true);
+                let mut buf = [0u8; 68];
+                if stream.read_exact(&mut buf).is_err() || stream.write_all(&buf).is_err() {
+                    continue;
+                }
+                let pieces = content.len().div_ceil(piece_length);
+                let bitfield = Message::BitField {
+                    bitfield: Bitfield::from_pieces(pieces, 0..pieces as u32),
+                };
+                let _ = stream.write_all(&bitfield.to_bytes().unwrap());
+                while let Ok(message) = Message::read_from(&mut stream) {
+                    let reply = match message {
+                        Message::Interested => Message::Unchoke,
+                        Message::Request {
+                            index,
+                            begin,
+                            length,
+                        } => {
+                            thread::sleep(delay);
+                            let start = index as usize * piece_length + begin as usize;
+                            Message::Piece {
+                                index,
+                                begin,
+                                block: content[start..start + length as usize].to_vec(),
+                            }
+                        }
+                        _ => continue,
+                    };
+                    if stream.write_all(&reply.to_bytes().unwrap()).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(address)
+    }
+
+    /// An HTTP server answering range requests for `files`, by path
+    fn web_seed(files: HashMap<String, Vec<u8>>) -> anyhow::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream);
+                let (mut path, mut range) = (String::new(), None);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|i| i > 0) && line != "\r\n" {
+                    if let Some(rest) = line.strip_prefix("GET ") {
+                        path = rest.split(' ').next().unwrap_or_default().to_string();
+                    }
+                    if let Some((start, end)) = line
+                        .to_ascii_lowercase()
+                        .strip_prefix("range: bytes=")
+                        .and_then(|i| i.trim().split_once('-'))
+                    {
+                        range = start.parse::<usize>().ok().zip(end.parse::<usize>().ok());
+                    }
+                    line.clear();
+                }
+                let stream = reader.get_mut();
+                let _ = match range.and_then(|(start, end)| files.get(&path)?.get(start..=end)) {
+                    Some(body) => write!(
+                        stream,
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .and_then(|_| stream.write_all(body)),
+                    None => stream.write_all(
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    ),
+                };
+            }
+        });
+        Ok(address)
+    }
+
+    #[test]
+    fn download_from_web_seed_and_peer() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 16).map(|i| i as u8).collect::<Vec<_>>();
+        let server = web_seed(HashMap::from([
+            ("/seed/dir/a".to_string(), content[..100].to_vec()),
+            ("/seed/dir/b%20c".to_string(), content[100..].to_vec()),
+        ]))?;
+        let url = format!("http://{server}/seed/");
+        let mut torrent_content = Vec::from(format!("d8:announce31:http://127.0.0.1:44381/announce4:infod5:filesld6:lengthi100e4:pathl1:aeed6:lengthi{}e4:pathl3:b ceee4:name3:dir12:piece lengthi{PIECE_LENGTH}e6:pieces{}:", content.len() - 100, content.len() / PIECE_LENGTH * 20));
+        for piece in content.chunks(PIECE_LENGTH) {
+            torrent_content.extend_from_slice(&sha1::hash(piece));
+        }
+        torrent_content.extend_from_slice(format!("e8:url-list{}:{url}e", url.len()).as_bytes());
+        let torrent = Torrent::from_bytes(&torrent_content)?;
+        let peer = seeder(content.clone(), PIECE_LENGTH, Duration::from_millis(5))?;
+        let client = BtClient::builder().with_block_size(16).build();
+
+        let downloaded = client.download_from_peers(&torrent, &[peer])?;
+
+        assert_eq!(content, downloaded);
+        let stats = client.stats();
+        let web_seed = &stats.peers[&web_seed::address(0)];
+        assert!(web_seed.bytes > 0);
+        assert_eq!(Some(url), web_seed.client);
+        Ok(())
+    }
+
+    /// An HTTP seed serving `content`, answering that it is busy the first time it is asked
+    fn http_seed(content: Vec<u8>, piece_length: usize) -> anyhow::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().flatten().enumerate() {
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let _ = reader.read_line(&mut request);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|i| i > 2) {
+                    line.clear();
+                }
+                let param = |name: &str| {
+                    request
+                        .split([' ', '?', '&'])
+                        .find_map(|i| i.strip_prefix(name)?.strip_prefix('='))
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                let piece = param("piece").parse::<usize>().unwrap_or_default();
+                let ranges = param("ranges");
+                let (start, end) = ranges.split_once('-').unwrap_or_default();
+                let (start, end) = (
+                    piece * piece_length + start.parse::<usize>().unwrap_or_default(),
+                    piece * piece_length + end.parse::<usize>().unwrap_or_default(),
+                );
+                let (status, body) = match i {
+                    0 => ("503 Service Unavailable", b"0".to_vec()),
+                    _ => ("200 OK", content[start..=end].to_vec()),
+                };
+                let stream = reader.get_mut();
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .and_then(|_| stream.write_all(&body));
+            }
+        });
+        Ok(address)
+    }
+
+    #[test]
+    fn download_from_busy_http_seed() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 4).map(|i| i as u8).collect::<Vec<_>>();
+        let server = http_seed(content.clone(), PIECE_LENGTH)?;
+        let url = format!("http://{server}/seed");
+        let mut torrent = test_torrent(&content, PIECE_LENGTH)?;
+        torrent.httpseeds = vec![url];
+        let client = BtClient::builder().with_block_size(16).build();
+
+        let downloaded = client.download_from_peers(&torrent, &[])?;
+
+        assert_eq!(content, downloaded);
+        Ok(())
+    }
+
+    #[test]
+    fn download_from_several_peers() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 16).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+
+        let fast = seeder(content.clone(), PIECE_LENGTH, Duration::from_millis(2))?;
+        let slow = seeder(content.clone(), PIECE_LENGTH, Duration::from_millis(20))?;
+        let gone = {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            listener.local_addr()?
+        };
+        let client = BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .with_retry_policies(RetryPolicies::uniform(RetryPolicy::none()));
+
+        let downloaded = client.download_from_peers(&torrent, &[fast, slow, gone])?;
+
+        assert_eq!(content, downloaded);
+        let stats = client.stats();
+        let bytes = |peer| stats.peers.get(&peer).map_or(0, |i| i.bytes);
+        assert!(bytes(slow) > 0);
+        assert!(bytes(fast) > bytes(slow));
+        assert_eq!(0, bytes(gone));
+        let (fast, slow) = (&stats.peers[&fast], &stats.peers[&slow]);
+        assert!(!fast.is_connected() && !fast.choking_us);
+        assert!(fast.request_latency.is_some());
+        assert!(slow.request_latency >= Some(Duration::from_millis(20)));
+        Ok(())
+    }
+
+    #[test]
+    fn endgame_does_not_wait_for_a_stalled_peer() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 4).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+
+        let stalled = seeder(content.clone(), PIECE_LENGTH, Duration::from_secs(20))?;
+        let fast = seeder(content.clone(), PIECE_LENGTH, Duration::ZERO)?;
+        let client = BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .with_retry_policies(RetryPolicies::uniform(RetryPolicy::none()));
+
+        let start = Instant::now();
+        let downloaded = client.download_from_peers(&torrent, &[stalled, fast])?;
+
+        assert_eq!(content, downloaded);
+        // the blocks requested from the stalled peer were downloaded from the other one as well
+        assert!(start.elapsed() < Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[test]
+    fn download_only_selected_files() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 16).map(|i| i as u8).collect::<Vec<_>>();
+        let mut torrent_content = Vec::from(format!("d8:announce31:http://127.0.0.1:44381/announce4:infod5:filesld6:lengthi100e4:pathl1:aeed6:lengthi{}e4:pathl1:beee4:name3:dir12:piece lengthi{PIECE_LENGTH}e6:pieces{}:", content.len() - 100, content.len() / PIECE_LENGTH * 20));
+        for piece in content.chunks(PIECE_LENGTH) {
+            torrent_content.extend_from_slice(&sha1::hash(piece));
+        }
+        torrent_content.extend_from_slice(b"ee");
+        let torrent = Torrent::from_bytes(&torrent_content)?;
+        let magnet_link = MagnetLink::parse(format!(
+            "magnet:?xt=urn:btih:{}&so=0",
+            hex::encode(torrent.info_hash()?)
+        ))?;
+        let peer = seeder(content.clone(), PIECE_LENGTH, Duration::ZERO)?;
+        let client = BtClient::builder().with_block_size(16).build();
+
+        let downloaded = client.download_from_peers(&(magnet_link, torrent.info), &[peer])?;
+
+        // the file shares its second piece with the next one, which is otherwise left out
+        assert_eq!(content[..PIECE_LENGTH * 2], downloaded[..PIECE_LENGTH * 2]);
+        assert!(downloaded[PIECE_LENGTH * 2..].iter().all(|i| *i == 0));
+        assert_eq!(content.len(), downloaded.len());
+        assert_eq!(PIECE_LENGTH as u64 * 2, client.stats().downloaded_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn download_selected_files_to_disk() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 16).map(|i| i as u8).collect::<Vec<_>>();
+        let mut torrent_content = Vec::from(format!("d8:announce31:http://127.0.0.1:44381/announce4:infod5:filesld6:lengthi100e4:pathl1:aeed6:lengthi{}e4:pathl3:sub1:beee4:name3:dir12:piece lengthi{PIECE_LENGTH}e6:pieces{}:", content.len() - 100, content.len() / PIECE_LENGTH * 20));
+        for piece in content.chunks(PIECE_LENGTH) {
+            torrent_content.extend_from_slice(&sha1::hash(piece));
+        }
+        torrent_content.extend_from_slice(b"ee");
+        let torrent = Torrent::from_bytes(&torrent_content)?;
+        let selected = Selected::new(&torrent, Some(&"1".parse::<FileList>()?), &[])?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dir");
+        let disk = DiskContent::new(&selected, &path);
+        disk.create(Preallocation::None)?;
+        let peer = seeder(content.clone(), PIECE_LENGTH, Duration::ZERO)?;
+
+        BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .download_from_peers_to_disk(
+                &selected,
+                &[peer],
+                &disk,
+                &mut Resume::new(&selected, &path)?,
+            )?;
+
+        assert!(!dir.path().join("dir/a").exists());
+        assert_eq!(content[100..], fs::read(dir.path().join("dir/sub/b"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn resume_interrupted_download() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 8).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("faketorrent.iso");
+        let disk = DiskContent::new(&torrent, &path);
+        disk.create(Preallocation::None)?;
+        // the first half was written before the interruption, a piece got corrupted since
+        let mut resume = Resume::new(&torrent, &path)?;
+        for index in 0..4 {
+            let offset = index * PIECE_LENGTH;
+            disk.write_at(offset as u64, &content[offset..offset + PIECE_LENGTH])?;
+            resume.record(index, &disk)?;
+        }
+        disk.write_at(0, &[0xff])?;
+        let mut resume = Resume::load(&torrent, &path)?.expect("resume data");
+        let peer = seeder(content.clone(), PIECE_LENGTH, Duration::ZERO)?;
+        let client = BtClient::builder().with_block_size(16).build();
+
+        client.download_from_peers_to_disk(&torrent, &[peer], &disk, &mut resume)?;
+
+        assert_eq!(content, fs::read(&path)?);
+        assert_eq!(PIECE_LENGTH as u64 * 5, client.stats().downloaded_bytes);
+        assert_eq!(&[true; 8], resume.have());
+        Ok(())
+    }
+
+    #[test]
+    fn resume_blocks_of_incomplete_pieces() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 4).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("faketorrent.iso");
+        let disk = DiskContent::new(&torrent, &path);
+        disk.create(Preallocation::None)?;
+        // half of the third piece and every block of the last one were written
+        let mut resume = Resume::new(&torrent, &path)?;
+        for (piece, begin) in [(2, 0), (2, 16), (3, 0), (3, 16), (3, 32), (3, 48)] {
+            let offset = piece as usize * PIECE_LENGTH + begin as usize;
+            disk.write_at(offset as u64, &content[offset..offset + 16])?;
+            resume.record_block(piece, begin, 16)?;
+        }
+        resume.save()?;
+        let mut resume = Resume::load(&torrent, &path)?.expect("resume data");
+        let peer = seeder(content.clone(), PIECE_LENGTH, Duration::ZERO)?;
+        let client = BtClient::builder().with_block_size(16).build();
+
+        client.download_from_peers_to_disk(&torrent, &[peer], &disk, &mut resume)?;
+
+        assert_eq!(content, fs::read(&path)?);
+        assert_eq!(
+            PIECE_LENGTH as u64 * 2 + 32,
+            client.stats().downloaded_bytes
+        );
+        assert_eq!(&[true; 4], resume.have());
+        Ok(())
+    }
+
+    #[test]
+    fn only_unwritten_ranges_of_verified_pieces_are_written() {
+        let data = [0u8; 64];
+        let last = |begin| Block {
+            piece: 0,
+            begin,
+            length: 16,
+        };
+
+        assert_eq!(vec![0..64], unwritten_ranges(&data, &[], last(16)));
+        assert_eq!(
+            vec![48..64],
+            unwritten_ranges(&data, &[(0, 16), (16, 16), (32, 16)], last(48))
+        );
+        // recorded from an earlier download of the piece that failed verification
+        assert_eq!(
+            vec![16..32, 48..64],
+            unwritten_ranges(&data, &[(0, 16), (16, 16), (32, 16)], last(16))
+        );
+        // the write of the second block failed
+        assert_eq!(
+            vec![16..32, 48..64],
+            unwritten_ranges(&data, &[(0, 16), (32, 16)], last(48))
+        );
+    }
+
+    /// A tracker answering every announce with `body`, counting them
+    struct FixedTracker {
+        body: Vec<u8>,
+        announces: Arc<AtomicUsize>,
+    }
 
-    use crate::{
-        bt_client::{BtClient, PEER_ID},
-        magnet_links::MagnetLink,
-        peer_messages::{Extension, Message},
-        sha1,
-        torrent::Torrent,
-    };
+    impl HttpClient for FixedTracker {
+        fn get(&self, _url: Url) -> anyhow::Result<Vec<u8>> {
+            self.announces.fetch_add(1, Ordering::SeqCst);
+            Ok(self.body.clone())
+        }
+    }
 
-    use super::HttpClient;
+    #[test]
+    fn peers_from_reannounces_join_the_download() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 16).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
 
-    impl HttpClient for StubClient {
-        fn get(&self, url: Url) -> anyhow::Result<Vec<u8>> {
-            match reqwest_mock::Client::get(self, url)
-                .send()
-                .map_err(|e| anyhow!("receiver is gone: {}", e.description()))
-            {
-                Ok(response) => Ok(response.body),
-                Err(err) => Err(err),
+        let slow = seeder(content.clone(), PIECE_LENGTH, Duration::from_millis(20))?;
+        let fast = seeder(content.clone(), PIECE_LENGTH, Duration::from_millis(2))?;
+        let mut body = b"d8:intervali0e5:peers".to_vec();
+        body.extend(serde_bencode::to_bytes(&Peers(vec![fast]))?);
+        body.push(b'e');
+        let announces = Arc::new(AtomicUsize::new(0));
+        let client = BtClientBuilder::with_client(FixedTracker {
+            body,
+            announces: announces.clone(),
+        })
+        .with_block_size(16)
+        .build();
+        let mut tracker_manager = client.tracker_manager(&torrent);
+        assert_eq!(
+            vec![fast],
+            client.announce_to(&mut tracker_manager, &torrent)?
+        );
+
+        let downloaded = client.download_with_trackers(
+            &torrent,
+            &[slow],
+            Reannounce {
+                tracker_manager: &mut tracker_manager,
+                tracker_info: &torrent,
+            },
+        )?;
+
+        assert_eq!(content, downloaded);
+        assert!(announces.load(Ordering::SeqCst) > 1);
+        let stats = client.stats();
+        assert!(stats.peers.get(&fast).is_some_and(|i| i.bytes > 0));
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_piece_is_downloaded_again_from_next_peer() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 2).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+
+        let corrupt = seeder(vec![0; content.len()], PIECE_LENGTH, Duration::ZERO)?;
+        let good = seeder(content.clone(), PIECE_LENGTH, Duration::ZERO)?;
+        let policy = |max_attempts| RetryPolicy {
+            max_attempts,
+            base_delay: Duration::ZERO,
+            ..Default::default()
+        };
+
+        let client = BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .with_retry_policies(RetryPolicies::uniform(policy(2)));
+        let piece = client.download_piece_from_peers(&torrent, &[corrupt, good], 1)?;
+
+        assert!(piece.hash_ok);
+        assert_eq!(good, piece.from_peer);
+        assert_eq!(content[PIECE_LENGTH..], piece.data);
+        assert_eq!(1, client.stats().integrity.pieces_failed_verification);
+
+        let client = BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .with_retry_policies(RetryPolicies::uniform(policy(1)));
+        let err = client
+            .download_piece_from_peers(&torrent, &[corrupt, good], 1)
+            .unwrap_err();
+        assert!(err.to_string().contains("does not match its hash"));
+        Ok(())
+    }
+
+    #[test]
+    fn pieces_are_downloaded_from_peers_that_have_them() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 2).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+
+        // a peer that only has the first piece
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let partial = listener.local_addr()?;
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0u8; 68];
+                if stream.read_exact(&mut buf).is_err() || stream.write_all(&buf).is_err() {
+                    continue;
+                }
+                let bitfield = Message::BitField {
+                    bitfield: Bitfield::from_pieces(2, [0]),
+                };
+                let _ = stream.write_all(&bitfield.to_bytes().unwrap());
+                while Message::read_from(&mut stream).is_ok() {}
             }
+        });
+        let good = seeder(content.clone(), PIECE_LENGTH, Duration::ZERO)?;
+
+        // a single attempt is enough, the partial peer is skipped
+        let client = BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .with_retry_policies(RetryPolicies::uniform(RetryPolicy {
+                max_attempts: 1,
+                ..Default::default()
+            }));
+        let piece = client.download_piece_from_peers(&torrent, &[partial, good], 1)?;
+
+        assert!(piece.hash_ok);
+        assert_eq!(good, piece.from_peer);
+        assert_eq!(content[PIECE_LENGTH..], piece.data);
+
+        let err = client
+            .download_piece_from_peers(&torrent, &[partial], 1)
+            .unwrap_err();
+        assert_eq!("none of the peers has piece 1", err.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn dead_peers_time_out() -> anyhow::Result<()> {
+        let content = b"0123456789";
+        let torrent = test_torrent(content, 10)?;
+
+        // accepting connections, then staying silent, optionally after shaking hands
+        let dead_peer = |shakes_hands: bool| -> anyhow::Result<SocketAddr> {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let address = listener.local_addr()?;
+            thread::spawn(move || {
+                let mut streams = Vec::new();
+                for mut stream in listener.incoming().flatten() {
+                    let mut buf = [0u8; 68];
+                    if shakes_hands
+                        && (stream.read_exact(&mut buf).is_err() || stream.write_all(&buf).is_err())
+                    {
+                        continue;
+                    }
+                    streams.push(stream);
+                }
+            });
+            Ok(address)
+        };
+        let client = BtClient::new()
+            .with_timeouts(Timeouts::uniform(Duration::from_millis(100)))
+            .with_retry_policies(RetryPolicies::uniform(RetryPolicy::none()));
+
+        for peer in [dead_peer(false)?, dead_peer(true)?] {
+            let started = Instant::now();
+            assert!(client.download_piece(&torrent, peer, 0).is_err());
+            assert!(started.elapsed() < Duration::from_secs(2));
         }
+        Ok(())
     }
 
     #[test]
-    fn get_peers() -> anyhow::Result<()> {
-        let torrent = Torrent::from_base64("ZDg6YW5ub3VuY2UzMTpodHRwOi8vMTI3LjAuMC4xOjQ0MzgxL2Fubm91bmNlNDppbmZvZDY6bGVuZ3RoaTIwOTcxNTJlNDpuYW1lMTU6ZmFrZXRvcnJlbnQuaXNvMTI6cGllY2UgbGVuZ3RoaTI2MjE0NGU2OnBpZWNlczE2MDrd8zFyWZ/ahPCiCaMDT3nwuKpeInlaYYoe5SdelShDsBpWrk4UJ1Lvza4u9TLWEaRrLPe2TVeMCbOsC24Jja3AwZQ28ZJ+onuQ6xixooIKI4+lNVQZiG2exW6GzXeRND6Ted4YHK6s6xX9ETSxtLIfrQQSWyJ7Tc/6WG4g1Xmk3nYJDhK9Cj2bHFOfPq7C1+sdtTnCqdJNAj+5FreSNLdpZWU=")?;
+    fn bitfields_with_spare_bits_set_are_rejected() -> anyhow::Result<()> {
+        let content = b"0123456789";
+        let torrent = test_torrent(content, 10)?;
 
-        let mut client = StubClient::new(StubSettings {
-            default: StubDefault::Error,
-            strictness: StubStrictness::MethodUrl,
+        let mut mock_stream = VecDeque::new();
+        mock_stream.write_all(
+            &Message::BitField {
+                bitfield: Bitfield::from_bytes(vec![0b1100_0000]),
+            }
+            .to_bytes()?,
+        )?;
+
+        let client = BtClient::new();
+        let peer = SocketAddr::from_str("127.0.0.1:6881")?;
+        let err = client
+            .piece_download(&mut mock_stream, &torrent, peer, 0)
+            .unwrap_err();
+
+        assert!(format!("{err:#}").contains("spare bit 1 set"));
+        Ok(())
+    }
+
+    #[test]
+    fn peers_choking_us_mid_download_are_waited_for() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 4).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+
+        // a peer without the Fast extension choking us on the first request, dropping it, then
+        // unchoking us a bit later
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let choking = listener.local_addr()?;
+        let served = content.clone();
+        thread::spawn(move || {
+            let Some(mut stream) = listener.incoming().flatten().next() else {
+                return;
+            };
+            let mut buf = [0u8; 68];
+            if stream.read_exact(&mut buf).is_err() {
+                return;
+            }
+            buf[20..28].fill(0);
+            if stream.write_all(&buf).is_err() {
+                return;
+            }
+            let bitfield = Message::BitField {
+                bitfield: Bitfield::from_pieces(4, 0..4),
+            };
+            let _ = stream.write_all(&bitfield.to_bytes().unwrap());
+            let mut choked_once = false;
+            while let Ok(message) = Message::read_from(&mut stream) {
+                let reply = match message {
+                    Message::Interested => Message::Unchoke,
+                    Message::Request { .. } if !choked_once => {
+                        choked_once = true;
+                        let _ = stream.write_all(&Message::Choke.to_bytes().unwrap());
+                        // the other requests made before the choke are dropped too
+                        let _ = stream.set_read_timeout(Some(Duration::from_millis(100)));
+                        while Message::read_from(&mut stream).is_ok() {}
+                        let _ = stream.set_read_timeout(None);
+                        Message::Unchoke
+                    }
+                    Message::Request {
+                        index,
+                        begin,
+                        length,
+                    } => {
+                        let start = index as usize * PIECE_LENGTH + begin as usize;
+                        Message::Piece {
+                            index,
+                            begin,
+                            block: served[start..start + length as usize].to_vec(),
+                        }
+                    }
+                    _ => continue,
+                };
+                if stream.write_all(&reply.to_bytes().unwrap()).is_err() {
+                    break;
+                }
+            }
         });
 
-        let response = b"d8:completei2e10:downloadedi1e10:incompletei1e8:intervali1921e12:min intervali960e5:peers18:tttt09eeee18xxxx27e";
-        let _ = client
-            .stub(
-                Url::parse("http://127.0.0.1:44381/announce?info_hash=%a1%8a%79%fa%44%e0%45%b1%e1%38%79%16%6d%35%82%3e%84%84%19%f8&peer_id=alice_is_1_feet_tall&port=6881&uploaded=0&downloaded=0&left=2097152&compact=1")
-                .unwrap(),
-            )
-            .method(Method::GET)
-            .response()
-            .body(response.to_vec())
-            .mock();
+        let client = BtClient::builder().with_block_size(16).build();
+        let downloaded = client.download_from_peers(&torrent, &[choking])?;
 
-        let bt_client = BtClient::with_client(client);
+        assert_eq!(content, downloaded);
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_of_snubbing_peers_go_to_the_others() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 16).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+
+        let silent = seeder(content.clone(), PIECE_LENGTH, Duration::from_secs(20))?;
+        let good = seeder(content.clone(), PIECE_LENGTH, Duration::from_millis(5))?;
+        // not connecting again to the silent peer once it is dropped
+        let client = BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .with_retry_policies(RetryPolicies::uniform(RetryPolicy::none()))
+            .with_snub_timeout(Duration::from_millis(100))
+            .with_ban_policy(BanPolicy {
+                max_hash_failures: 2,
+                max_errors: 1,
+            });
+
+        let start = Instant::now();
+        let downloaded = client.download_from_peers(&torrent, &[silent, good])?;
+
+        assert_eq!(content, downloaded);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let stats = client.stats();
+        assert!(stats.integrity.blocks_rerequested > 0);
+        assert_eq!(0, stats.peers.get(&silent).map_or(0, |i| i.bytes));
+        Ok(())
+    }
+
+    #[test]
+    fn peers_sending_corrupt_pieces_are_banned() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 16).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+
+        let corrupt = seeder(vec![0; content.len()], PIECE_LENGTH, Duration::ZERO)?;
+        let good = seeder(content.clone(), PIECE_LENGTH, Duration::from_millis(5))?;
+        // a piece is a single block, which puts the blame on a single peer
+        let client = BtClient::builder()
+            .with_block_size(PIECE_LENGTH as u32)
+            .build()
+            .with_retry_policies(RetryPolicies::uniform(RetryPolicy {
+                max_attempts: 16,
+                base_delay: Duration::ZERO,
+                ..Default::default()
+            }))
+            .with_ban_policy(BanPolicy {
+                max_hash_failures: 2,
+                max_errors: 3,
+            });
+
+        let downloaded = client.download_from_peers(&torrent, &[corrupt, good])?;
+
+        assert_eq!(content, downloaded);
+        let stats = client.stats();
+        assert_eq!(1, stats.integrity.peers_banned);
+        assert_eq!(2, stats.peers[&corrupt].hash_failures);
+        assert_eq!(0, stats.peers[&good].hash_failures);
+        Ok(())
+    }
+
+    #[test]
+    fn peers_learnt_through_pex_are_downloaded_from() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 4).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+        let seeder = seeder(content.clone(), PIECE_LENGTH, Duration::ZERO)?;
+
+        // a peer with no piece, only telling about the seeder
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let leecher = listener.local_addr()?;
+        let leecher_session = thread::spawn(move || -> anyhow::Result<()> {
+            let (mut stream, _) = listener.accept()?;
+            let mut buf = [0u8; 68];
+            stream.read_exact(&mut buf)?;
+            stream.write_all(&buf)?;
+            while let Ok(message) = Message::read_from(&mut stream) {
+                if let Message::Extension {
+                    message: ExtensionMessage::Info { info },
+                } = message
+                {
+                    let id = info.metdata.ut_pex.context("ut_pex not advertised")?;
+                    let pex = PexMessage::new(vec![seeder], vec![]);
+                    stream.write_all(
+                        &Message::Extension {
+                            message: ExtensionMessage::Custom {
+                                id,
+                                payload: pex.to_bytes()?,
+                            },
+                        }
+                        .to_bytes()?,
+                    )?;
+                }
+            }
+            Ok(())
+        });
+
+        let client = BtClient::builder().with_block_size(16).build();
+        let downloaded = client.download_from_peers(&torrent, &[leecher])?;
+
+        assert_eq!(content, downloaded);
+        assert_eq!(content.len() as u64, client.stats().peers[&seeder].bytes);
+        leecher_session.join().expect("leecher panicked")?;
+        Ok(())
+    }
+
+    #[test]
+    fn seed_from_disk() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 3 + 10)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("faketorrent.iso");
+        std::fs::write(&path, &content)?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let served = torrent.clone();
+        thread::spawn(move || BtClient::new().seed(&served, &path, &listener));
+
+        let client = BtClient::builder().with_block_size(16).build();
+        assert!(client.handshake([0; 20], address).is_err());
+        assert_eq!(content, client.download(&torrent, address)?);
+
+        let listener = TcpListener::bind("[::1]:0")?;
+        let address = listener.local_addr()?;
+        let served = torrent.clone();
+        let path = dir.path().join("faketorrent.iso");
+        thread::spawn(move || BtClient::new().seed(&served, &path, &listener));
+        assert_eq!(content, client.download_from_peers(&torrent, &[address])?);
+        Ok(())
+    }
+
+    #[test]
+    fn dht_ports_of_peers_are_recorded() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 2).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("faketorrent.iso");
+        std::fs::write(&path, &content)?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let served = torrent.clone();
+        thread::spawn(move || {
+            BtClient::new()
+                .with_dht_port(Some(6881))
+                .seed(&served, &path, &listener)
+        });
 
+        // the port is only sent to peers advertising a DHT node
+        let client = BtClient::builder().with_block_size(16).build();
+        assert_eq!(content, client.download(&torrent, address)?);
+        assert!(client.dht_nodes().is_empty());
+
+        let client = BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .with_dht_port(Some(6882));
+        assert_eq!(content, client.download_from_peers(&torrent, &[address])?);
         assert_eq!(
-            vec![
-                "116.116.116.116:12345",
-                "101.101.101.101:12600",
-                "120.120.120.120:12855"
-            ],
-            bt_client
-                .get_peers(&torrent)?
-                .iter()
-                .map(|i| format!("{i}"))
-                .collect::<Vec<_>>()
+            vec![SocketAddr::from_str("127.0.0.1:6881")?],
+            client.dht_nodes()
         );
+        Ok(())
+    }
+
+    #[test]
+    fn seeding_honors_cancelled_requests() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("faketorrent.iso");
+        std::fs::write(&path, &content)?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let served = torrent.clone();
+        thread::spawn(move || BtClient::new().seed(&served, &path, &listener));
+
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let handshake = Handshake::new(torrent.info_hash()?, *b"-TEST-cancelling-00-");
+        stream.write_all(&handshake.to_bytes())?;
+        stream.read_exact(&mut [0u8; 68])?;
+        assert!(matches!(
+            Message::read_from(&mut stream)?,
+            Message::BitField { .. }
+        ));
+        stream.write_all(&Message::Interested.to_bytes()?)?;
+        assert_eq!(Message::Unchoke, Message::read_from(&mut stream)?);
+        let request = |begin| Message::Request {
+            index: 0,
+            begin,
+            length: 16,
+        };
+        let mut messages = Vec::new();
+        for message in [
+            request(0),
+            request(16),
+            Message::Cancel {
+                index: 0,
+                begin: 16,
+                length: 16,
+            },
+            request(32),
+        ] {
+            messages.extend(message.to_bytes()?);
+        }
+        stream.write_all(&messages)?;
 
+        let begins = (0..2)
+            .map(|_| match Message::read_from(&mut stream)? {
+                Message::Piece { begin, .. } => Ok(begin),
+                message => Err(anyhow!("unexpected message {message}")),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(vec![0, 32], begins);
         Ok(())
     }
 
     #[test]
-    fn shake_hands() -> anyhow::Result<()> {
-        let torrent = Torrent::from_base64("ZDg6YW5ub3VuY2UzMTpodHRwOi8vMTI3LjAuMC4xOjQ0MzgxL2Fubm91bmNlNDppbmZvZDY6bGVuZ3RoaTIwOTcxNTJlNDpuYW1lMTU6ZmFrZXRvcnJlbnQuaXNvMTI6cGllY2UgbGVuZ3RoaTI2MjE0NGU2OnBpZWNlczE2MDrd8zFyWZ/ahPCiCaMDT3nwuKpeInlaYYoe5SdelShDsBpWrk4UJ1Lvza4u9TLWEaRrLPe2TVeMCbOsC24Jja3AwZQ28ZJ+onuQ6xixooIKI4+lNVQZiG2exW6GzXeRND6Ted4YHK6s6xX9ETSxtLIfrQQSWyJ7Tc/6WG4g1Xmk3nYJDhK9Cj2bHFOfPq7C1+sdtTnCqdJNAj+5FreSNLdpZWU=")?;
+    fn seed_and_download_encrypted() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 2).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("faketorrent.iso");
+        std::fs::write(&path, &content)?;
 
-        let bt_client = BtClient::new();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let served = torrent.clone();
+        thread::spawn(move || {
+            BtClient::new()
+                .with_encryption(EncryptionPolicy::Require)
+                .seed(&served, &path, &listener)
+        });
 
-        let mut mock_stream = VecDeque::new();
-        // Message that will be read by the client
-        let response_from_peer = [
-            19u8, 66, 105, 116, 84, 111, 114, 114, 101, 110, 116, 32, 112, 114, 111, 116, 111, 99,
-            111, 108, 0, 0, 0, 0, 0, 0, 0, 0, 161, 138, 121, 250, 68, 224, 69, 177, 225, 56, 121,
-            22, 109, 53, 130, 62, 132, 132, 25, 248, 48, 48, 48, 48, 48, 48, 48, 48, 48, 48, 48,
-            48, 48, 48, 48, 48, 48, 48, 48, 48,
-        ];
-        mock_stream.write_all(&response_from_peer)?;
+        // plain connections are refused
+        assert!(BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .with_retry_policies(RetryPolicies::uniform(RetryPolicy::none()))
+            .download(&torrent, address)
+            .is_err());
+        let client = BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .with_encryption(EncryptionPolicy::Require);
+        assert_eq!(content, client.download(&torrent, address)?);
+        let client = BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .with_encryption(EncryptionPolicy::Prefer);
+        assert_eq!(content, client.download_from_peers(&torrent, &[address])?);
+        Ok(())
+    }
 
-        let res = bt_client.shake_hands(
-            &mut mock_stream,
-            torrent.info_hash()?,
-            PEER_ID,
-            &Extension::None,
-        )?;
-        assert_eq!(response_from_peer, res); // What is returned is what was initialy written in
-                                             // the "stream"
-        let mut buf = [0u8; 68];
-        mock_stream.read_exact(&mut buf)?;
-        assert_eq!(b"00000000000000000000", &res[48..68]);
+    #[derive(Default)]
+    struct CountConnections(Arc<AtomicUsize>);
+
+    impl MessageHook for CountConnections {
+        fn on_peer_connected(&self, _peer: SocketAddr) -> Verdict {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Verdict::Allow
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordOutgoing(Arc<Mutex<Vec<String>>>);
 
+    impl MessageHook for RecordOutgoing {
+        fn on_outgoing_message(&self, _peer: SocketAddr, message: &Message) -> Verdict {
+            self.0.lock().unwrap().push(message.to_string());
+            Verdict::Allow
+        }
+    }
+
+    #[test]
+    fn download_over_a_single_connection() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 4).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+        let peer = seeder(content.clone(), PIECE_LENGTH, Duration::ZERO)?;
+        let connections = CountConnections::default();
+        let count = connections.0.clone();
+        let outgoing = RecordOutgoing::default();
+        let sent = outgoing.0.clone();
+
+        let client = BtClient::builder()
+            .with_block_size(16)
+            .build()
+            .with_hook(connections)
+            .with_hook(outgoing);
+        let downloaded = client.download(&torrent, peer)?;
+
+        assert_eq!(content, downloaded);
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        let sent = sent.lock().unwrap();
+        assert_eq!(Some("Interested"), sent.first().map(String::as_str));
+        // not interested anymore once every piece is downloaded
+        assert_eq!(Some("NotInterested"), sent.last().map(String::as_str));
         Ok(())
     }
 
+    #[derive(Default)]
+    struct RecordRequestLengths(Arc<Mutex<Vec<u32>>>);
+
+    impl MessageHook for RecordRequestLengths {
+        fn on_outgoing_message(&self, _peer: SocketAddr, message: &Message) -> Verdict {
+            if let Message::Request { length, .. } = message {
+                self.0.lock().unwrap().push(*length);
+            }
+            Verdict::Allow
+        }
+    }
+
     #[test]
-    fn shake_hands_with_magnet_extension() -> anyhow::Result<()> {
-        let magnet_link = MagnetLink::parse("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce")?;
+    fn block_size_grows_for_lan_peers() -> anyhow::Result<()> {
+        const PIECE_LENGTH: usize = 64;
+        let content = (0..PIECE_LENGTH * 4 - 8)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let torrent = test_torrent(&content, PIECE_LENGTH)?;
+        let peer = seeder(content.clone(), PIECE_LENGTH, Duration::ZERO)?;
+        let requests = RecordRequestLengths::default();
+        let lengths = requests.0.clone();
 
-        let bt_client = BtClient::new();
+        let client = BtClient::builder()
+            .with_block_size_policy(LanProbing::new(16, 64))
+            .build()
+            .with_hook(requests);
+        let downloaded = client.download(&torrent, peer)?;
 
-        let mut mock_stream = VecDeque::new();
-        // Message that will be read by the client - note the extension 6th bytes extension flag!
-        let response_from_peer = [
-            19u8, 66, 105, 116, 84, 111, 114, 114, 101, 110, 116, 32, 112, 114, 111, 116, 111, 99,
-            111, 108, 0, 0, 0, 0, 0, 16, 0, 0, 161, 138, 121, 250, 68, 224, 69, 177, 225, 56, 121,
-            22, 109, 53, 130, 62, 132, 132, 25, 248, 48, 48, 48, 48, 48, 48, 48, 48, 48, 48, 48,
-            48, 48, 48, 48, 48, 48, 48, 48, 48,
-        ];
-        mock_stream
-            .write_all(&response_from_peer)
-            .context("writing request message to stream")?;
+        assert_eq!(content, downloaded);
+        // the last block of the last piece is cut to what remains of it
+        assert_eq!(
+            vec![16, 16, 16, 16, 32, 32, 64, 56],
+            *lengths.lock().unwrap()
+        );
+        Ok(())
+    }
 
-        let res = bt_client.shake_hands(
-            &mut mock_stream,
-            magnet_link.info_hash,
-            PEER_ID,
-            &Extension::MagnetLink,
+    struct SilentInterest;
+
+    impl MessageHook for SilentInterest {
+        fn on_outgoing_message(&self, _peer: SocketAddr, message: &Message) -> Verdict {
+            match message {
+                Message::Interested => Verdict::Veto,
+                _ => Verdict::Allow,
+            }
+        }
+
+        fn on_incoming_message(&self, _peer: SocketAddr, message: &Message) -> Verdict {
+            match message {
+                Message::Choke => Verdict::Veto,
+                _ => Verdict::Allow,
+            }
+        }
+    }
+
+    #[test]
+    fn hooks_drop_vetoed_messages() -> anyhow::Result<()> {
+        let content = b"0123456789";
+        let torrent = test_torrent(content, 10)?;
+
+        let mut mock_stream = VecDeque::new();
+        mock_stream.write_all(
+            &Message::BitField {
+                bitfield: Bitfield::from_pieces(1, [0]),
+            }
+            .to_bytes()?,
         )?;
-        assert_eq!(response_from_peer, res); // What is returned is what was initialy written in
-                                             // the "stream"
-        let mut buf = [0u8; 68];
-        mock_stream.read_exact(&mut buf)?;
-        assert_eq!(b"00000000000000000000", &res[48..68]);
+        mock_stream.write_all(&Message::Choke.to_bytes()?)?;
+        mock_stream.write_all(&Message::Unchoke.to_bytes()?)?;
+        mock_stream.write_all(
+            &Message::Piece {
+                index: 0,
+                begin: 0,
+                block: content.to_vec(),
+            }
+            .to_bytes()?,
+        )?;
+
+        let client = BtClient::new().with_hook(SilentInterest);
+        let peer = SocketAddr::from_str("127.0.0.1:6881")?;
+        let res = client.piece_download(&mut mock_stream, &torrent, peer, 0)?;
+
+        assert!(res.hash_ok);
+        assert!(matches!(
+            Message::read_from(&mut mock_stream)?,
+            Message::Request { .. }
+        ));
+        assert!(mock_stream.is_empty());
 
         Ok(())
     }
@@ -513,30 +4596,34 @@ mod test {
 
                 let mut mock_stream = VecDeque::new();
 
-                mock_stream.write_all(&Message::BitField { payload: vec![] }.to_bytes()?)?;
+                let pieces = file_content.len().div_ceil(PIECES_SIZE);
+                mock_stream.write_all(&Message::BitField {
+                    bitfield: Bitfield::from_pieces(pieces, 0..pieces as u32),
+                }.to_bytes()?)?;
 
                 mock_stream.write_all(&Message::Unchoke.to_bytes()?)?;
 
                 let piece_info = torrent.pieces_info();
                 let piece_info = piece_info.get(PIECE_INDEX).context("no piece info")?;
-                let piece = &file_content[piece_info.offset..piece_info.offset + piece_info.length];
+                let piece = &file_content[piece_info.offset as usize..(piece_info.offset + piece_info.length) as usize];
 
                 for block_info in torrent
-                    .blocks_info(PIECE_INDEX, BLOCK_SIZE)
+                    .blocks_info(PIECE_INDEX, BLOCK_SIZE as u64)
                     .context("no piece at this index")?
                 {
                     mock_stream.write_all(
                         &Message::Piece {
                             index: PIECE_INDEX as u32,
                             begin: block_info.offset as u32,
-                            block: piece[block_info.offset..block_info.offset + block_info.length].to_vec(),
+                            block: piece[block_info.offset as usize..(block_info.offset + block_info.length) as usize].to_vec(),
                         }
                         .to_bytes()?,
                     )?;
                 }
 
-                let client = BtClient::with_block_size(BLOCK_SIZE as u32);
-                let res = client.piece_download(&mut mock_stream, &torrent, PIECE_INDEX as u32)?;
+                let client = BtClient::builder().with_block_size(BLOCK_SIZE as u32).build();
+                let peer = SocketAddr::from_str("127.0.0.1:6881")?;
+                let res = client.piece_download(&mut mock_stream, &torrent, peer, PIECE_INDEX as u32)?;
 
                 assert_eq!(Message::Interested, Message::read_from(&mut mock_stream)?);
                 for _ in 0..(PIECES_SIZE / BLOCK_SIZE) {
@@ -545,9 +4632,12 @@ mod test {
                         Message::Request { .. }
                     ));
                 }
+                assert_eq!(PIECE_INDEX as u32, res.index);
+                assert_eq!(peer, res.from_peer);
+                assert!(res.hash_ok);
                 assert_eq!(
-                    file_content[PIECE_INDEX * PIECES_SIZE..PIECE_INDEX * PIECES_SIZE + piece_info.length],
-                    res
+                    file_content[PIECE_INDEX * PIECES_SIZE..PIECE_INDEX * PIECES_SIZE + piece_info.length as usize],
+                    res.data
                 );
 
                 Ok(())
@@ -559,4 +4649,85 @@ mod test {
     download_piece!(first_piece: 100, 0, 19);
     download_piece!(second_piece: 100, 2, 19);
     download_piece!(download_last_block_of_last_piece: 160, 2, 43);
+
+    /// A peer answering requests one at a time, only once everything it sent before was read,
+    /// keeping track of how many requests were outstanding at most
+    #[derive(Debug)]
+    struct PipelinedPeer {
+        content: Vec<u8>,
+        incoming: VecDeque<u8>,
+        requests: VecDeque<(u32, u32, u32)>,
+        max_outstanding: usize,
+    }
+
+    impl Read for PipelinedPeer {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.incoming.is_empty() {
+                if let Some((index, begin, length)) = self.requests.pop_front() {
+                    let block = self.content[begin as usize..(begin + length) as usize].to_vec();
+                    let piece = Message::Piece {
+                        index,
+                        begin,
+                        block,
+                    };
+                    self.incoming
+                        .extend(piece.to_bytes().expect("valid message"));
+                }
+            }
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for PipelinedPeer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            match Message::read_from(&mut &buf[..]) {
+                Ok(Message::Interested) => {
+                    self.incoming
+                        .extend(Message::Unchoke.to_bytes().expect("valid message"));
+                }
+                Ok(Message::Request {
+                    index,
+                    begin,
+                    length,
+                }) => {
+                    self.requests.push_back((index, begin, length));
+                    self.max_outstanding = self.max_outstanding.max(self.requests.len());
+                }
+                _ => {}
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn piece_requests_are_pipelined() -> anyhow::Result<()> {
+        let content = (0..160).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&content, 160)?;
+        let mut peer_stream = PipelinedPeer {
+            content,
+            incoming: Message::BitField {
+                bitfield: Bitfield::from_pieces(1, [0]),
+            }
+            .to_bytes()?
+            .into(),
+            requests: VecDeque::new(),
+            max_outstanding: 0,
+        };
+
+        let client = BtClient::builder()
+            .with_block_size(16)
+            .with_pipeline_depth(3)
+            .build();
+        let peer = SocketAddr::from_str("127.0.0.1:6881")?;
+        let res = client.piece_download(&mut peer_stream, &torrent, peer, 0)?;
+
+        assert!(res.hash_ok);
+        assert_eq!(peer_stream.content, res.data);
+        assert_eq!(3, peer_stream.max_outstanding);
+        Ok(())
+    }
 }
@@ -0,0 +1,99 @@
+use std::{borrow::Cow, fmt};
+
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// A bencoded string, kept as raw bytes since torrents in the wild do not always encode theirs
+/// in UTF-8
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ByteString(pub Vec<u8>);
+
+impl ByteString {
+    /// The string, with invalid UTF-8 sequences replaced by `U+FFFD`
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&str> for ByteString {
+    fn from(value: &str) -> Self {
+        ByteString(value.as_bytes().to_vec())
+    }
+}
+
+impl fmt::Display for ByteString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_lossy())
+    }
+}
+
+struct ByteStringVisitor;
+
+impl<'de> Visitor<'de> for ByteStringVisitor {
+    type Value = ByteString;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a byte string")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(ByteString(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(ByteString(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(ByteString::from(v))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteString {
+    fn deserialize<D>(deserializer: D) -> Result<ByteString, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(ByteStringVisitor)
+    }
+}
+
+impl Serialize for ByteString {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ByteString;
+
+    #[test]
+    fn non_utf8_round_trip() -> anyhow::Result<()> {
+        let bencoded = b"4:a\xe9b\xff";
+        let value: ByteString = serde_bencode::from_bytes(bencoded)?;
+
+        assert_eq!(b"a\xe9b\xff".to_vec(), value.0);
+        assert_eq!("a\u{fffd}b\u{fffd}", value.to_string());
+        assert_eq!(bencoded.to_vec(), serde_bencode::to_bytes(&value)?);
+        Ok(())
+    }
+}
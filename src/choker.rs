@@ -0,0 +1,217 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::stats::RollingRate;
+
+/// How many peers may be unchoked at once and how often that is reconsidered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChokerConfig {
+    /// Peers unchoked at once, one of them being the optimistic unchoke
+    pub slots: usize,
+    /// Time between two re-evaluations of who is unchoked
+    pub interval: Duration,
+    /// Re-evaluations between two rotations of the optimistic unchoke
+    pub optimistic_rounds: u32,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            slots: 4,
+            interval: Duration::from_secs(10),
+            optimistic_rounds: 3,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ChokePeer {
+    interested: bool,
+    rate: RollingRate,
+}
+
+/// Decides which peers we upload to, tit-for-tat: the interested peers with the best rates get
+/// all the slots but one, which goes to an interested peer in turn so that newcomers get a chance
+/// to show what they are worth
+#[derive(Debug)]
+pub struct Choker {
+    config: ChokerConfig,
+    peers: BTreeMap<SocketAddr, ChokePeer>,
+    unchoked: BTreeSet<SocketAddr>,
+    optimistic: Option<SocketAddr>,
+    rounds: u32,
+    last: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(config: ChokerConfig) -> Self {
+        Choker {
+            config: ChokerConfig {
+                slots: config.slots.max(1),
+                optimistic_rounds: config.optimistic_rounds.max(1),
+                ..config
+            },
+            peers: BTreeMap::new(),
+            unchoked: BTreeSet::new(),
+            optimistic: None,
+            rounds: 0,
+            last: None,
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: SocketAddr, now: Instant) {
+        self.peers.entry(peer).or_insert_with(|| ChokePeer {
+            interested: false,
+            rate: RollingRate::new(now),
+        });
+    }
+
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        self.peers.remove(&peer);
+        self.unchoked.remove(&peer);
+        if self.optimistic == Some(peer) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Records whether `peer` wants data from us. A peer becoming interested is unchoked right
+    /// away when a slot is free, rather than waiting for the next re-evaluation.
+    pub fn set_interested(&mut self, peer: SocketAddr, interested: bool) {
+        let Some(slot) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        slot.interested = interested;
+        if !interested {
+            self.unchoked.remove(&peer);
+        } else if self.unchoked.len() < self.config.slots {
+            self.unchoked.insert(peer);
+        }
+    }
+
+    /// Records `bytes` exchanged with `peer`, which its rate is measured on
+    pub fn record(&mut self, peer: SocketAddr, bytes: u64, now: Instant) {
+        if let Some(slot) = self.peers.get_mut(&peer) {
+            slot.rate.record(bytes, now);
+        }
+    }
+
+    pub fn is_unchoked(&self, peer: SocketAddr) -> bool {
+        self.unchoked.contains(&peer)
+    }
+
+    /// Re-evaluates who is unchoked if the interval elapsed since the last time, returns whether
+    /// it did
+    pub fn rechoke(&mut self, now: Instant) -> bool {
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < self.config.interval)
+        {
+            return false;
+        }
+        self.last = Some(now);
+
+        let mut interested = self
+            .peers
+            .iter()
+            .filter(|(_, i)| i.interested)
+            .map(|(peer, i)| (*peer, i.rate.bytes_per_second().unwrap_or(0.0)))
+            .collect::<Vec<_>>();
+        // stable, so that peers with the same rate keep the address order
+        interested.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let regular = interested
+            .iter()
+            .take(self.config.slots - 1)
+            .map(|(peer, _)| *peer)
+            .collect::<BTreeSet<_>>();
+
+        let optimistic_valid = self
+            .optimistic
+            .is_some_and(|i| self.peers.get(&i).is_some_and(|i| i.interested));
+        if self.rounds.is_multiple_of(self.config.optimistic_rounds) || !optimistic_valid {
+            // the next candidate after the current one, in address order
+            let candidates = interested
+                .iter()
+                .map(|(peer, _)| *peer)
+                .filter(|i| !regular.contains(i))
+                .collect::<BTreeSet<_>>();
+            self.optimistic = self
+                .optimistic
+                .and_then(|current| candidates.range(current..).find(|i| **i != current))
+                .or_else(|| candidates.first())
+                .copied();
+        }
+        self.rounds += 1;
+
+        self.unchoked = regular;
+        self.unchoked.extend(self.optimistic);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
+    use super::{Choker, ChokerConfig};
+
+    #[test]
+    fn fastest_peers_and_a_rotating_optimistic_unchoke() -> anyhow::Result<()> {
+        let peers = (1..=5)
+            .map(|i| SocketAddr::from_str(&format!("10.0.0.{i}:6881")))
+            .collect::<Result<Vec<_>, _>>()?;
+        let start = Instant::now();
+        let mut choker = Choker::new(ChokerConfig {
+            slots: 3,
+            interval: Duration::from_secs(10),
+            optimistic_rounds: 2,
+        });
+        for peer in &peers {
+            choker.add_peer(*peer, start);
+            choker.set_interested(*peer, true);
+        }
+        // free slots go to the first peers asking
+        assert_eq!(
+            vec![true, true, true, false, false],
+            peers
+                .iter()
+                .map(|i| choker.is_unchoked(*i))
+                .collect::<Vec<_>>()
+        );
+
+        // the last two peers are the fastest
+        let measured = start + Duration::from_secs(1);
+        for (rate, peer) in peers.iter().enumerate() {
+            choker.record(*peer, rate as u64 * 1000, measured);
+        }
+        let unchoked = |choker: &Choker| {
+            peers
+                .iter()
+                .map(|i| choker.is_unchoked(*i))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(choker.rechoke(measured));
+        assert_eq!(vec![true, false, false, true, true], unchoked(&choker));
+        assert!(!choker.rechoke(measured + Duration::from_secs(5)));
+
+        // kept for a second round, then rotated
+        assert!(choker.rechoke(measured + Duration::from_secs(10)));
+        assert_eq!(vec![true, false, false, true, true], unchoked(&choker));
+        assert!(choker.rechoke(measured + Duration::from_secs(20)));
+        assert_eq!(vec![false, true, false, true, true], unchoked(&choker));
+
+        choker.set_interested(peers[4], false);
+        assert!(!choker.is_unchoked(peers[4]));
+        choker.remove_peer(peers[1]);
+        assert!(choker.rechoke(measured + Duration::from_secs(30)));
+        assert_eq!(vec![true, false, true, true, false], unchoked(&choker));
+        Ok(())
+    }
+}
@@ -1,12 +1,194 @@
-use std::{net::SocketAddrV4, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, sync::OnceLock};
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use reqwest::Url;
+
+use crate::{
+    block_size::{DEFAULT_BLOCK_SIZE, MAX_BLOCK_SIZE},
+    file_selection::{FileList, FilePriorityArg},
+    mse::EncryptionPolicy,
+    resolver::Resolver,
+    torrent_edit::TorrentEdit,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about= None)]
 pub struct Args {
     #[command(subcommand)]
     pub command: Command,
+    /// Log every peer message sent and received on stderr
+    #[arg(long, global = true)]
+    pub trace_wire: bool,
+    /// Save pieces failing hash verification, with their metadata, in this directory
+    #[arg(long, global = true, value_name = "DIR")]
+    pub dump_corrupt_pieces: Option<PathBuf>,
+    /// Remember peers that provided verified data in this file and try them first next time
+    #[arg(long, global = true, value_name = "FILE")]
+    pub peer_cache: Option<PathBuf>,
+    /// Resolver for tracker host names: `system`, comma separated DNS servers (`IP[:port]`) or
+    /// a DNS-over-HTTPS URL
+    #[arg(long, global = true, value_name = "RESOLVER", default_value_t = Resolver::System)]
+    pub dns: Resolver,
+    /// Whether connections to peers are encrypted (Message Stream Encryption)
+    #[arg(long, global = true, value_enum, default_value_t = EncryptionPolicy::Disable)]
+    pub encryption: EncryptionPolicy,
+    /// How log and trace events are written on stderr
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+}
+
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per event
+    Json,
+}
+
+/// Parses the command line, with `--version` also reporting which SHA-1 implementation is active
+pub fn parse_args() -> Args {
+    let matches = Args::command().long_version(long_version()).get_matches();
+    Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+}
+
+fn long_version() -> &'static str {
+    static LONG_VERSION: OnceLock<String> = OnceLock::new();
+    LONG_VERSION.get_or_init(|| {
+        format!(
+            "{}\nSHA-1: {}",
+            env!("CARGO_PKG_VERSION"),
+            crate::sha1::implementation()
+        )
+    })
+}
+
+/// Where downloaded data is stored while downloading and once complete
+#[derive(clap::Args, Debug, Default, Clone, PartialEq)]
+pub struct StorageArgs {
+    /// Directory holding the data while it is being downloaded
+    #[arg(long)]
+    pub incomplete_dir: Option<PathBuf>,
+    /// Directory the data is moved to once downloaded and verified
+    #[arg(long, visible_alias = "done-dir")]
+    pub complete_dir: Option<PathBuf>,
+    /// Hard-link completed data instead of moving it out of the incomplete directory
+    #[arg(long, requires = "incomplete_dir")]
+    pub link_complete: bool,
+    /// How disk space for the output is reserved before data is written
+    #[arg(long, value_enum, default_value_t = Preallocation::None)]
+    pub preallocation: Preallocation,
+    #[command(flatten)]
+    pub conflict: ConflictArgs,
+}
+
+impl StorageArgs {
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        self.conflict.conflict_policy()
+    }
+}
+
+/// What to do when the output destination already exists
+#[derive(clap::Args, Debug, Default, Clone, PartialEq)]
+pub struct ConflictArgs {
+    /// Replace whatever is at the destination (the default)
+    #[arg(long, group = "conflict")]
+    pub overwrite: bool,
+    /// Fail rather than replace an existing destination
+    #[arg(long, group = "conflict")]
+    pub no_clobber: bool,
+    /// Write to a new name such as `file (1).iso` when the destination exists
+    #[arg(long, group = "conflict")]
+    pub rename_on_conflict: bool,
+}
+
+impl ConflictArgs {
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        if self.no_clobber {
+            ConflictPolicy::NoClobber
+        } else if self.rename_on_conflict {
+            ConflictPolicy::Rename
+        } else {
+            ConflictPolicy::Overwrite
+        }
+    }
+}
+
+/// Trackers to announce to on top of those from the torrent or magnet link
+#[derive(clap::Args, Debug, Default, Clone, PartialEq)]
+pub struct TrackerArgs {
+    /// Also announce to this tracker, can be repeated
+    #[arg(long = "tracker-add", value_name = "URL")]
+    pub extra_trackers: Vec<Url>,
+    /// Also announce to the trackers listed in this file or at this URL, one per line
+    #[arg(long, value_name = "PATH_OR_URL")]
+    pub trackers_file: Option<String>,
+}
+
+/// Which files of a multi-file torrent are downloaded, and in which order
+#[derive(clap::Args, Debug, Default, Clone, PartialEq)]
+pub struct SelectionArgs {
+    /// Only download these files, given by index such as `0,2,4-6`
+    #[arg(long, value_name = "INDEXES")]
+    pub files: Option<FileList>,
+    /// Download priority of a file (skip, low, normal or high), can be repeated
+    #[arg(long = "file-priority", value_name = "INDEX=PRIORITY")]
+    pub file_priorities: Vec<FilePriorityArg>,
+    /// Download the first and last pieces of each file first, for media players to read headers
+    /// and trailers early
+    #[arg(long)]
+    pub first_last_pieces: bool,
+}
+
+/// Changes to apply to a torrent's metainfo
+#[derive(clap::Args, Debug, Default, Clone, PartialEq)]
+pub struct EditArgs {
+    /// Replace the main announce URL
+    #[arg(long)]
+    pub announce: Option<String>,
+    /// Add an announce URL as a new tier of the announce list
+    #[arg(long)]
+    pub add_announce: Vec<String>,
+    /// Remove an announce URL wherever it appears
+    #[arg(long)]
+    pub remove_announce: Vec<String>,
+    /// Replace the comment
+    #[arg(long)]
+    pub comment: Option<String>,
+    /// Set or clear the private flag, this changes the info hash
+    #[arg(long)]
+    pub private: Option<bool>,
+}
+
+impl From<EditArgs> for TorrentEdit {
+    fn from(args: EditArgs) -> Self {
+        Self {
+            announce: args.announce,
+            add_announce: args.add_announce,
+            remove_announce: args.remove_announce,
+            comment: args.comment,
+            private: args.private,
+        }
+    }
+}
+
+/// What to do when the output destination already exists
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    Overwrite,
+    NoClobber,
+    Rename,
+}
+
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq)]
+pub enum Preallocation {
+    /// Write the whole file upfront so the space is actually reserved on disk
+    Full,
+    /// Set the file length without allocating blocks, on filesystems supporting sparse files
+    Sparse,
+    /// Let the file grow as data is written
+    #[default]
+    None,
 }
 
 #[derive(Subcommand, Debug, PartialEq)]
@@ -22,7 +204,7 @@ pub enum Command {
     },
     Handshake {
         torrent: PathBuf,
-        peer: SocketAddrV4,
+        peer: SocketAddr,
     },
     #[command(name = "download_piece")]
     DownloadPiece {
@@ -31,16 +213,53 @@ pub enum Command {
         torrent: PathBuf,
         #[arg(default_value_t = 0)]
         start: u32,
+        /// Size of the blocks requested from peers, in bytes, for peers that only serve smaller
+        /// or larger ones than the usual 16 KiB
+        #[arg(long, default_value_t = DEFAULT_BLOCK_SIZE, value_parser = clap::value_parser!(u32).range(1..=i64::from(MAX_BLOCK_SIZE)))]
+        block_size: u32,
+        #[command(flatten)]
+        conflict: ConflictArgs,
+        #[command(flatten)]
+        trackers: TrackerArgs,
     },
     Download {
         #[arg(short, long)]
         output: Option<PathBuf>,
         torrent: PathBuf,
+        /// Show a progress bar with download rate and ETA on stderr
+        #[arg(long)]
+        progress: bool,
+        /// Print what each peer contributed on stderr once the download is over
+        #[arg(long)]
+        peer_report: bool,
+        /// Print integrity counters (failed pieces, wasted bytes...) on stderr once the download is
+        /// over
+        #[arg(long)]
+        integrity_report: bool,
+        /// Print the state of each connected peer (traffic, latency, choking...) on stderr every
+        /// few seconds while downloading
+        #[arg(long)]
+        stats: bool,
+        /// Size of the blocks requested from peers, in bytes, for peers that only serve smaller
+        /// or larger ones than the usual 16 KiB
+        #[arg(long, default_value_t = DEFAULT_BLOCK_SIZE, value_parser = clap::value_parser!(u32).range(1..=i64::from(MAX_BLOCK_SIZE)))]
+        block_size: u32,
+        #[command(flatten)]
+        storage: StorageArgs,
+        #[command(flatten)]
+        selection: SelectionArgs,
+        #[command(flatten)]
+        trackers: TrackerArgs,
     },
     #[command(name = "magnet_parse")]
     MagnetParse {
         magnet_link: String,
     },
+    /// Print a magnet link equivalent to a torrent
+    #[command(name = "magnet_generate")]
+    MagnetGenerate {
+        torrent: PathBuf,
+    },
     #[command(name = "magnet_handshake")]
     MagnetHandshake {
         magnet_link: String,
@@ -56,24 +275,110 @@ pub enum Command {
         magnet_link: String,
         #[arg(default_value_t = 0)]
         start: u32,
+        #[command(flatten)]
+        conflict: ConflictArgs,
+        #[command(flatten)]
+        trackers: TrackerArgs,
     },
     #[command(name = "magnet_download")]
     MagnetDownload {
         #[arg(short, long)]
         output: Option<PathBuf>,
         magnet_link: String,
+        /// Show a progress bar with download rate and ETA on stderr
+        #[arg(long)]
+        progress: bool,
+        /// Print what each peer contributed on stderr once the download is over
+        #[arg(long)]
+        peer_report: bool,
+        /// Print integrity counters (failed pieces, wasted bytes...) on stderr once the download is
+        /// over
+        #[arg(long)]
+        integrity_report: bool,
+        /// Print the state of each connected peer (traffic, latency, choking...) on stderr every
+        /// few seconds while downloading
+        #[arg(long)]
+        stats: bool,
+        #[command(flatten)]
+        storage: StorageArgs,
+        #[command(flatten)]
+        selection: SelectionArgs,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+    },
+    /// Check connectivity to the trackers and peers of a torrent or magnet link
+    Doctor {
+        /// Torrent file, URL, `-` for stdin, or magnet link
+        target: String,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+    },
+    /// Serve a downloaded torrent to the peers connecting to us, until interrupted
+    Seed {
+        torrent: PathBuf,
+        /// The downloaded file, or the directory named after the torrent for multi-file ones
+        path: PathBuf,
+        /// Address to accept connections on
+        #[arg(long, default_value = "0.0.0.0:6881")]
+        listen: SocketAddr,
+        /// Peers uploaded to at once
+        #[arg(long, default_value_t = 4)]
+        upload_slots: usize,
+    },
+    /// Download a torrent while serving one of its files over HTTP, range requests waiting for
+    /// the pieces they cover, and keep serving it once complete until interrupted
+    Stream {
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        torrent: PathBuf,
+        /// Address to serve the file on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+        /// Index of the file to serve, the largest one by default
+        #[arg(long)]
+        file: Option<usize>,
+        #[command(flatten)]
+        storage: StorageArgs,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+    },
+    /// Present the files of a torrent as a read-only filesystem, downloading the pieces that are
+    /// read, until unmounted
+    Mount {
+        torrent: PathBuf,
+        /// Existing directory to mount the files on
+        mountpoint: PathBuf,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+    },
+    /// Hash the data of a torrent found on disk and report which pieces match
+    Verify {
+        torrent: PathBuf,
+        /// The downloaded file, or the directory named after the torrent for multi-file ones
+        path: PathBuf,
+    },
+    /// Edit a torrent's metainfo, in place unless an output is given
+    Edit {
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        torrent: PathBuf,
+        #[command(flatten)]
+        edit: EditArgs,
     },
 }
 
 #[cfg(test)]
 mod test {
-    use std::{net::SocketAddrV4, str::FromStr};
+    use std::{net::SocketAddr, str::FromStr};
 
-    use clap::Parser;
+    use clap::{CommandFactory, Parser};
 
-    use crate::cli::Command;
+    use crate::{
+        cli::Command,
+        file_selection::{FilePriority, FilePriorityArg},
+    };
 
-    use super::Args;
+    use super::{long_version, Args, ConflictPolicy, SelectionArgs};
 
     #[test]
     fn parse_socket_addr_v4() -> anyhow::Result<()> {
@@ -81,10 +386,123 @@ mod test {
         assert_eq!(
             Command::Handshake {
                 torrent: "/tmp/sample.torrent".into(),
-                peer: SocketAddrV4::from_str("127.0.0.1:48845")?
+                peer: SocketAddr::from_str("127.0.0.1:48845")?
             },
             args.command
         );
         Ok(())
     }
+
+    #[test]
+    fn long_version_reports_sha1_implementation() {
+        let version = Args::command()
+            .long_version(long_version())
+            .render_long_version();
+        assert!(version.contains("SHA-1: "));
+    }
+
+    #[test]
+    fn conflict_policy_flags() {
+        let policy = |flags: &str| {
+            Args::try_parse_from(format!("x download x.torrent{flags}").split(" ")).map(|args| {
+                match args.command {
+                    Command::Download { storage, .. } => storage.conflict_policy(),
+                    _ => unreachable!(),
+                }
+            })
+        };
+
+        assert_eq!(ConflictPolicy::Overwrite, policy("").unwrap());
+        assert_eq!(ConflictPolicy::Overwrite, policy(" --overwrite").unwrap());
+        assert_eq!(ConflictPolicy::NoClobber, policy(" --no-clobber").unwrap());
+        assert_eq!(
+            ConflictPolicy::Rename,
+            policy(" --rename-on-conflict").unwrap()
+        );
+        assert!(policy(" --no-clobber --overwrite").is_err());
+    }
+
+    #[test]
+    fn piece_downloads_take_conflict_flags() {
+        let policy = |command: &str| {
+            Args::try_parse_from(format!("x {command} --no-clobber").split(" ")).map(|args| {
+                match args.command {
+                    Command::DownloadPiece { conflict, .. }
+                    | Command::MagnetDownloadPiece { conflict, .. } => conflict.conflict_policy(),
+                    _ => unreachable!(),
+                }
+            })
+        };
+
+        assert_eq!(
+            ConflictPolicy::NoClobber,
+            policy("download_piece x.torrent").unwrap()
+        );
+        assert_eq!(
+            ConflictPolicy::NoClobber,
+            policy("magnet_download_piece magnet:?xt=urn:btih:x").unwrap()
+        );
+    }
+
+    #[test]
+    fn file_selection_flags() {
+        let args = Args::parse_from(
+            "x download x.torrent --files 0,2-3 --file-priority 2=high --file-priority 3=low"
+                .split(" "),
+        );
+        let Command::Download { selection, .. } = args.command else {
+            unreachable!()
+        };
+
+        assert_eq!(
+            SelectionArgs {
+                files: Some("0,2-3".parse().unwrap()),
+                file_priorities: vec![
+                    FilePriorityArg {
+                        index: 2,
+                        priority: FilePriority::High
+                    },
+                    FilePriorityArg {
+                        index: 3,
+                        priority: FilePriority::Low
+                    }
+                ],
+                first_last_pieces: false,
+            },
+            selection
+        );
+        assert!(Args::try_parse_from("x download x.torrent --files 0,a".split(" ")).is_err());
+    }
+
+    #[test]
+    fn done_dir_is_complete_dir() {
+        let args = Args::parse_from("x download x.torrent --done-dir /tmp/done".split(" "));
+        let Command::Download { storage, .. } = args.command else {
+            unreachable!()
+        };
+
+        assert_eq!(Some("/tmp/done".into()), storage.complete_dir);
+    }
+
+    #[test]
+    fn block_size_flag() {
+        let block_size = |command: &str| {
+            Args::try_parse_from(format!("x {command} x.torrent").split(" ")).map(|args| match args
+                .command
+            {
+                Command::Download { block_size, .. }
+                | Command::DownloadPiece { block_size, .. } => block_size,
+                _ => unreachable!(),
+            })
+        };
+
+        assert_eq!(16 * 1024, block_size("download").unwrap());
+        assert_eq!(
+            8192,
+            block_size("download_piece --block-size 8192").unwrap()
+        );
+        assert_eq!(8192, block_size("download --block-size 8192").unwrap());
+        assert!(block_size("download --block-size 0").is_err());
+        assert!(block_size("download --block-size 262144").is_err());
+    }
 }
@@ -0,0 +1,320 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use serde_bencode::value::Value;
+
+use crate::{resolver::Resolver, tracker};
+
+/// Well-known nodes lookups start from
+pub const BOOTSTRAP_NODES: [(&str, u16); 3] = [
+    ("router.bittorrent.com", 6881),
+    ("dht.transmissionbt.com", 6881),
+    ("router.utorrent.com", 6881),
+];
+/// Closest nodes queried in each round of a lookup
+const ALPHA: usize = 8;
+/// Rounds of queries before a lookup gives up
+const MAX_ROUNDS: u8 = 12;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Length of a node in compact node info: its id, IPv4 address and port
+const COMPACT_NODE_LENGTH: usize = 26;
+
+/// A DHT node, as found in compact node info
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Node {
+    pub id: [u8; 20],
+    pub address: SocketAddr,
+}
+
+/// What a node answered to a get_peers query: peers of the torrent when it knows some, and
+/// nodes closer to the info hash
+#[derive(Debug, Default, PartialEq)]
+pub struct GetPeersResponse {
+    pub peers: Vec<SocketAddr>,
+    pub nodes: Vec<Node>,
+}
+
+/// Finds the peers of torrents through the mainline DHT (BEP 5). Nodes are only queried: this
+/// client does not answer queries nor store peers.
+pub struct Dht {
+    socket: UdpSocket,
+    id: [u8; 20],
+    timeout: Duration,
+}
+
+impl Dht {
+    pub fn new(id: [u8; 20]) -> anyhow::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind("0.0.0.0:0").context("binding DHT socket")?,
+            id,
+            timeout: QUERY_TIMEOUT,
+        })
+    }
+
+    /// How long the nodes of a round of queries are waited for
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Addresses of the bootstrap nodes, leaving out those that do not resolve
+    pub fn bootstrap_nodes(resolver: &Resolver) -> Vec<SocketAddr> {
+        BOOTSTRAP_NODES
+            .iter()
+            .filter_map(|(host, port)| resolver.resolve(host, *port).ok())
+            .flatten()
+            .collect()
+    }
+
+    /// Looks `info_hash` up starting from `bootstrap`, querying the closest nodes known in each
+    /// round, until `wanted` peers are found or the closest nodes were all queried
+    pub fn get_peers(
+        &self,
+        info_hash: [u8; 20],
+        bootstrap: &[SocketAddr],
+        wanted: usize,
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        // nodes by distance to the info hash, those whose id is not known yet coming first
+        let mut unknown = bootstrap.to_vec();
+        let mut nodes = BTreeMap::new();
+        let mut queried = HashSet::new();
+        let mut peers = Vec::new();
+        for round in 0..MAX_ROUNDS {
+            let batch = unknown
+                .drain(..)
+                .chain(nodes.values().take(ALPHA).copied())
+                .filter(|i| queried.insert(*i))
+                .collect::<Vec<SocketAddr>>();
+            if batch.is_empty() {
+                break;
+            }
+            for (index, node) in batch.iter().enumerate() {
+                let query = get_peers_query(&[round, index as u8], &self.id, &info_hash)?;
+                // unreachable nodes are simply not heard from
+                let _ = self.socket.send_to(&query, node);
+            }
+
+            let deadline = Instant::now() + self.timeout;
+            let mut answered = HashSet::new();
+            let mut buf = [0u8; 2048];
+            while answered.len() < batch.len() {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                self.socket.set_read_timeout(Some(remaining))?;
+                let Ok((len, from)) = self.socket.recv_from(&mut buf) else {
+                    break;
+                };
+                let Ok((transaction, res)) = parse_get_peers_response(&buf[..len]) else {
+                    continue;
+                };
+                if transaction.first() != Some(&round) || !batch.contains(&from) {
+                    continue;
+                }
+                answered.insert(from);
+                for peer in res.peers {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+                for node in res.nodes {
+                    nodes.insert(distance(&node.id, &info_hash), node.address);
+                }
+            }
+            if peers.len() >= wanted {
+                break;
+            }
+        }
+        if peers.is_empty() {
+            bail!("no peer found in the DHT");
+        }
+        Ok(peers)
+    }
+}
+
+/// XOR distance between two ids, which orders like the ids' closeness
+fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// A bencoded get_peers query
+pub fn get_peers_query(
+    transaction: &[u8],
+    id: &[u8; 20],
+    info_hash: &[u8; 20],
+) -> anyhow::Result<Vec<u8>> {
+    let arguments = HashMap::from([
+        (b"id".to_vec(), Value::Bytes(id.to_vec())),
+        (b"info_hash".to_vec(), Value::Bytes(info_hash.to_vec())),
+    ]);
+    let query = HashMap::from([
+        (b"t".to_vec(), Value::Bytes(transaction.to_vec())),
+        (b"y".to_vec(), Value::Bytes(b"q".to_vec())),
+        (b"q".to_vec(), Value::Bytes(b"get_peers".to_vec())),
+        (b"a".to_vec(), Value::Dict(arguments)),
+    ]);
+    serde_bencode::to_bytes(&Value::Dict(query)).context("encoding DHT query")
+}
+
+/// The transaction id and content of a response to a get_peers query
+pub fn parse_get_peers_response(bytes: &[u8]) -> anyhow::Result<(Vec<u8>, GetPeersResponse)> {
+    let Value::Dict(message) = serde_bencode::from_bytes(bytes).context("parsing DHT message")?
+    else {
+        bail!("DHT message is not a dictionary");
+    };
+    let Some(Value::Bytes(transaction)) = message.get(&b"t"[..]) else {
+        bail!("DHT message without transaction id");
+    };
+    match message.get(&b"y"[..]) {
+        Some(Value::Bytes(kind)) if kind == b"r" => {}
+        Some(Value::Bytes(kind)) if kind == b"e" => bail!("DHT node answered with an error"),
+        _ => bail!("DHT message is not a response"),
+    }
+    let Some(Value::Dict(response)) = message.get(&b"r"[..]) else {
+        bail!("DHT response without content");
+    };
+    let peers = match response.get(&b"values"[..]) {
+        Some(Value::List(values)) => values
+            .iter()
+            .filter_map(|i| match i {
+                Value::Bytes(peer) => tracker::decode_compact_peers(peer, 4).ok(),
+                _ => None,
+            })
+            .flatten()
+            .collect(),
+        _ => Vec::new(),
+    };
+    let nodes = match response.get(&b"nodes"[..]) {
+        Some(Value::Bytes(nodes)) => decode_compact_nodes(nodes),
+        _ => Vec::new(),
+    };
+    Ok((transaction.clone(), GetPeersResponse { peers, nodes }))
+}
+
+/// Nodes of compact node info, a trailing partial node being ignored
+fn decode_compact_nodes(bytes: &[u8]) -> Vec<Node> {
+    bytes
+        .chunks_exact(COMPACT_NODE_LENGTH)
+        .filter_map(|i| {
+            Some(Node {
+                id: i[..20].try_into().ok()?,
+                address: *tracker::decode_compact_peers(&i[20..], 4).ok()?.first()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        net::{SocketAddr, UdpSocket},
+        str::FromStr,
+        thread,
+        time::Duration,
+    };
+
+    use serde_bencode::value::Value;
+
+    use super::{get_peers_query, parse_get_peers_response, Dht, Node};
+
+    /// A bencoded get_peers response from the node `id`
+    fn response(transaction: &[u8], id: u8, values: &[[u8; 6]], nodes: &[u8]) -> Vec<u8> {
+        let mut content = HashMap::from([
+            (b"id".to_vec(), Value::Bytes(vec![id; 20])),
+            (b"nodes".to_vec(), Value::Bytes(nodes.to_vec())),
+        ]);
+        if !values.is_empty() {
+            content.insert(
+                b"values".to_vec(),
+                Value::List(values.iter().map(|i| Value::Bytes(i.to_vec())).collect()),
+            );
+        }
+        serde_bencode::to_bytes(&Value::Dict(HashMap::from([
+            (b"t".to_vec(), Value::Bytes(transaction.to_vec())),
+            (b"y".to_vec(), Value::Bytes(b"r".to_vec())),
+            (b"r".to_vec(), Value::Dict(content)),
+        ])))
+        .expect("valid response")
+    }
+
+    /// A node answering every query with `answer`, given the query's transaction id
+    fn node(answer: impl Fn(&[u8]) -> Vec<u8> + Send + 'static) -> anyhow::Result<SocketAddr> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        let address = socket.local_addr()?;
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                let Ok(Value::Dict(query)) = serde_bencode::from_bytes(&buf[..len]) else {
+                    continue;
+                };
+                let Some(Value::Bytes(transaction)) = query.get(&b"t"[..]) else {
+                    continue;
+                };
+                let _ = socket.send_to(&answer(transaction), from);
+            }
+        });
+        Ok(address)
+    }
+
+    #[test]
+    fn get_peers_query_encoding() -> anyhow::Result<()> {
+        assert_eq!(
+            format!(
+                "d1:ad2:id20:{}9:info_hash20:{}e1:q9:get_peers1:t2:aa1:y1:qe",
+                "a".repeat(20),
+                "b".repeat(20)
+            )
+            .into_bytes(),
+            get_peers_query(b"aa", &[b'a'; 20], &[b'b'; 20])?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_peers_response_parsing() -> anyhow::Result<()> {
+        let mut nodes = vec![7; 20];
+        nodes.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        nodes.extend_from_slice(&[1, 2, 3]);
+
+        let (transaction, res) =
+            parse_get_peers_response(&response(b"xy", 1, &[[10, 0, 0, 1, 0, 80]], &nodes))?;
+
+        assert_eq!(b"xy".to_vec(), transaction);
+        assert_eq!(vec![SocketAddr::from_str("10.0.0.1:80")?], res.peers);
+        assert_eq!(
+            vec![Node {
+                id: [7; 20],
+                address: SocketAddr::from_str("127.0.0.1:6881")?
+            }],
+            res.nodes
+        );
+        assert!(parse_get_peers_response(b"d1:t2:xy1:y1:ee").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn lookup_follows_closer_nodes() -> anyhow::Result<()> {
+        let info_hash = [0xff; 20];
+        let close = node(|transaction| response(transaction, 0xfe, &[[10, 0, 0, 1, 0, 80]], &[]))?;
+        let SocketAddr::V4(close_v4) = close else {
+            unreachable!("bound to IPv4");
+        };
+        let mut nodes = vec![0xfe; 20];
+        nodes.extend_from_slice(&close_v4.ip().octets());
+        nodes.extend_from_slice(&close_v4.port().to_be_bytes());
+        let bootstrap = node(move |transaction| response(transaction, 0, &[], &nodes))?;
+
+        let peers = Dht::new([0; 20])?
+            .with_timeout(Duration::from_millis(500))
+            .get_peers(info_hash, &[bootstrap], 1)?;
+
+        assert_eq!(vec![SocketAddr::from_str("10.0.0.1:80")?], peers);
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use reqwest::Url;
+
+use crate::{
+    bt_client::{BtClient, HttpClient},
+    resolver::Resolver,
+    tracker_info::TrackerInfo,
+};
+
+/// Peers handshaken at most when checking connectivity to peers
+const MAX_CHECKED_PEERS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Ok,
+    Warning,
+    Failed,
+    Skipped,
+}
+
+/// The result of one diagnostic, with what to do about it when it did not go well
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, outcome: Outcome, detail: impl Into<String>) -> Self {
+        Check {
+            name: name.into(),
+            outcome,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let outcome = match self.outcome {
+            Outcome::Ok => "ok",
+            Outcome::Warning => "warn",
+            Outcome::Failed => "FAIL",
+            Outcome::Skipped => "skip",
+        };
+        writeln!(f, "[{outcome:>4}] {}: {}", self.name, self.detail)?;
+        if let Some(hint) = &self.hint {
+            writeln!(f, "       hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs every connectivity check for `tracker_info`
+pub fn run<T: HttpClient + Sync, I: TrackerInfo>(
+    client: &BtClient<T>,
+    tracker_info: &I,
+    resolver: &Resolver,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let mut peers = BTreeSet::new();
+    let urls = client
+        .tracker_manager(tracker_info)
+        .tiers()
+        .iter()
+        .flatten()
+        .map(|i| i.url.clone())
+        .collect::<Vec<_>>();
+    if urls.is_empty() {
+        checks.push(
+            Check::new("trackers", Outcome::Failed, "no usable tracker URL")
+                .with_hint("add trackers with --tracker-add or --trackers-file"),
+        );
+    }
+    for url in &urls {
+        let (check, found) = check_tracker(client, tracker_info, resolver, url);
+        checks.push(check);
+        peers.extend(found);
+    }
+    let answering = checks
+        .iter()
+        .filter(|i| i.name.starts_with("tracker ") && i.outcome == Outcome::Ok)
+        .count();
+    if !urls.is_empty() && answering == 0 {
+        checks.push(
+            Check::new("trackers", Outcome::Failed, "no tracker answered")
+                .with_hint("check your network and firewall, or add trackers with --tracker-add"),
+        );
+    }
+
+    if !peers.is_empty() {
+        checks.push(check_peers(client, tracker_info, &peers));
+    }
+    checks.push(Check::new(
+        "listen port",
+        Outcome::Skipped,
+        "this client only opens outgoing connections, no port needs to be reachable",
+    ));
+    checks.push(Check::new(
+        "DHT",
+        Outcome::Skipped,
+        "DHT is not supported, peers only come from trackers",
+    ));
+    checks.push(check_proxy(
+        std::env::vars()
+            .find(|(name, _)| {
+                ["https_proxy", "http_proxy", "all_proxy"].contains(&name.to_lowercase().as_str())
+            })
+            .map(|(_, value)| value)
+            .as_deref(),
+        resolver,
+    ));
+    checks
+}
+
+fn check_tracker<T: HttpClient, I: TrackerInfo>(
+    client: &BtClient<T>,
+    tracker_info: &I,
+    resolver: &Resolver,
+    url: &Url,
+) -> (Check, Vec<std::net::SocketAddr>) {
+    let name = format!("tracker {url}");
+    if matches!(url.scheme(), "ws" | "wss") {
+        return (
+            Check::new(
+                name,
+                Outcome::Skipped,
+                "WebSocket trackers are not supported",
+            ),
+            Vec::new(),
+        );
+    }
+    if let Some(host) = url.host_str() {
+        if let Err(err) = resolver.resolve(host, url.port_or_known_default().unwrap_or(0)) {
+            return (
+                Check::new(name, Outcome::Failed, format!("{err:#}"))
+                    .with_hint("the host name does not resolve, check --dns or your DNS settings"),
+                Vec::new(),
+            );
+        }
+    }
+    let started = Instant::now();
+    let response = tracker_info
+        .announce_request()
+        .and_then(|request| client.announce_once(url, &request));
+    match response {
+        Ok(response) => (
+            Check::new(
+                name,
+                Outcome::Ok,
+                format!(
+                    "answered in {} with {} peers",
+                    format_millis(started.elapsed()),
+                    response.peers.len()
+                ),
+            ),
+            response.peers,
+        ),
+        Err(err) => {
+            let hint = match url.scheme() {
+                "udp" => "UDP is often blocked by firewalls and some networks, try an HTTP tracker",
+                _ => "the tracker may be down, or blocked by a proxy or firewall",
+            };
+            (
+                Check::new(name, Outcome::Failed, format!("{err:#}")).with_hint(hint),
+                Vec::new(),
+            )
+        }
+    }
+}
+
+fn check_peers<T: HttpClient + Sync, I: TrackerInfo>(
+    client: &BtClient<T>,
+    tracker_info: &I,
+    peers: &BTreeSet<std::net::SocketAddr>,
+) -> Check {
+    let peers = peers
+        .iter()
+        .copied()
+        .take(MAX_CHECKED_PEERS)
+        .collect::<Vec<_>>();
+    let info_hash = match tracker_info.announce_request() {
+        Ok(request) => request.info_hash,
+        Err(err) => return Check::new("peers", Outcome::Failed, format!("{err:#}")),
+    };
+    let answering = client.peers_by_latency(info_hash, &peers);
+    match answering.first() {
+        Some((_, fastest)) => Check::new(
+            "peers",
+            Outcome::Ok,
+            format!(
+                "{} of {} peers answered the handshake, fastest in {}",
+                answering.len(),
+                peers.len(),
+                format_millis(*fastest)
+            ),
+        ),
+        None => Check::new(
+            "peers",
+            Outcome::Failed,
+            format!("none of {} peers answered the handshake", peers.len()),
+        )
+        .with_hint("outgoing connections to peers may be blocked, or the swarm may be dead"),
+    }
+}
+
+/// Checks the proxy from the environment, which applies to HTTP trackers only
+pub fn check_proxy(proxy: Option<&str>, resolver: &Resolver) -> Check {
+    let Some(proxy) = proxy else {
+        return Check::new("proxy", Outcome::Skipped, "no proxy configured");
+    };
+    let url = match Url::parse(proxy) {
+        Ok(url) => url,
+        Err(err) => {
+            return Check::new(
+                "proxy",
+                Outcome::Failed,
+                format!("'{proxy}' is invalid: {err}"),
+            )
+            .with_hint("proxies are given as URLs such as http://proxy.example:3128")
+        }
+    };
+    let Some(host) = url.host_str() else {
+        return Check::new("proxy", Outcome::Failed, format!("'{proxy}' has no host"));
+    };
+    if let Err(err) = resolver.resolve(host, url.port_or_known_default().unwrap_or(0)) {
+        return Check::new("proxy", Outcome::Failed, format!("{err:#}"))
+            .with_hint("the proxy host name does not resolve");
+    }
+    Check::new(
+        "proxy",
+        Outcome::Warning,
+        format!("{url} is used for HTTP trackers only"),
+    )
+    .with_hint("UDP trackers and peer connections do not go through the proxy")
+}
+
+fn format_millis(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::resolver::Resolver;
+
+    use super::{check_proxy, Check, Outcome};
+
+    #[test]
+    fn display_check() {
+        let check = Check::new("tracker udp://t", Outcome::Failed, "timed out")
+            .with_hint("try an HTTP tracker");
+
+        assert_eq!(
+            "[FAIL] tracker udp://t: timed out\n       hint: try an HTTP tracker\n",
+            check.to_string()
+        );
+    }
+
+    #[test]
+    fn proxy_checks() {
+        assert_eq!(
+            Outcome::Skipped,
+            check_proxy(None, &Resolver::System).outcome
+        );
+        assert_eq!(
+            Outcome::Failed,
+            check_proxy(Some("not a url"), &Resolver::System).outcome
+        );
+        assert_eq!(
+            Outcome::Warning,
+            check_proxy(Some("http://127.0.0.1:3128"), &Resolver::System).outcome
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail};
+
+use crate::peer_messages::{ExtensionMessage, Message};
+
+/// Local id advertised for `ut_metadata` in our extension handshake
+pub const UT_METADATA_ID: u8 = 16;
+
+/// Handles the messages of a BEP 10 extension, identified by its name in the `m` dictionary
+pub trait ExtensionHandler: Send + Sync {
+    /// Called once the peer's extension handshake is received, to start talking first
+    fn on_handshake(&self, _sender: &mut ExtensionSender) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called with the payload of every message the peer sends for this extension
+    fn on_message(&self, payload: &[u8], sender: &mut ExtensionSender) -> anyhow::Result<()>;
+}
+
+/// Extensions registered by library users, with the local ids they are advertised under
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    handlers: Vec<(String, Box<dyn ExtensionHandler>)>,
+}
+
+impl ExtensionRegistry {
+    /// Registers `handler` for the extension `name`, returning its local id
+    pub fn register<H: ExtensionHandler + 'static>(
+        &mut self,
+        name: &str,
+        handler: H,
+    ) -> anyhow::Result<u8> {
+        if name == "ut_metadata" || self.handlers.iter().any(|(i, _)| i == name) {
+            bail!("extension '{name}' is already registered");
+        }
+        let id = self.id_at(self.handlers.len())?;
+        self.handlers.push((name.to_string(), Box::new(handler)));
+        Ok(id)
+    }
+
+    fn id_at(&self, position: usize) -> anyhow::Result<u8> {
+        u8::try_from(position + 1)
+            .ok()
+            .and_then(|i| i.checked_add(UT_METADATA_ID))
+            .ok_or_else(|| anyhow!("too many extensions registered"))
+    }
+
+    /// Name to local id of every registered extension, for the `m` dictionary
+    pub fn advertised(&self) -> BTreeMap<String, u8> {
+        self.handlers
+            .iter()
+            .enumerate()
+            .filter_map(|(position, (name, _))| Some((name.clone(), self.id_at(position).ok()?)))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Lets every handler start talking once the peer's extension ids are known
+    pub fn handshake(&self, sender: &mut ExtensionSender) -> anyhow::Result<()> {
+        self.handlers
+            .iter()
+            .try_for_each(|(_, handler)| handler.on_handshake(sender))
+    }
+
+    /// Passes a message received under local `id` to its handler, returns `false` when no
+    /// registered extension uses this id
+    pub fn dispatch(
+        &self,
+        id: u8,
+        payload: &[u8],
+        sender: &mut ExtensionSender,
+    ) -> anyhow::Result<bool> {
+        let position = match id.checked_sub(UT_METADATA_ID + 1) {
+            Some(position) => usize::from(position),
+            None => return Ok(false),
+        };
+        match self.handlers.get(position) {
+            Some((_, handler)) => handler.on_message(payload, sender).map(|_| true),
+            None => Ok(false),
+        }
+    }
+}
+
+/// Queues extension messages for a peer, using the ids the peer advertised
+pub struct ExtensionSender {
+    peer_ids: BTreeMap<String, u8>,
+    outgoing: Vec<Message>,
+}
+
+impl ExtensionSender {
+    pub fn new(peer_ids: BTreeMap<String, u8>) -> Self {
+        ExtensionSender {
+            peer_ids,
+            outgoing: Vec::new(),
+        }
+    }
+
+    /// Whether the peer supports the extension `name`
+    pub fn supports(&self, name: &str) -> bool {
+        self.peer_ids.get(name).is_some_and(|id| *id != 0)
+    }
+
+    pub fn send(&mut self, name: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+        let id = match self.peer_ids.get(name) {
+            Some(id) if *id != 0 => *id,
+            _ => bail!("peer does not support extension '{name}'"),
+        };
+        self.outgoing.push(Message::Extension {
+            message: ExtensionMessage::Custom { id, payload },
+        });
+        Ok(())
+    }
+
+    /// Messages queued since the last call
+    pub fn take(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.outgoing)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::peer_messages::{ExtensionMessage, Message};
+
+    use super::{ExtensionHandler, ExtensionRegistry, ExtensionSender};
+
+    struct Echo(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl ExtensionHandler for Echo {
+        fn on_message(&self, payload: &[u8], sender: &mut ExtensionSender) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(payload.to_vec());
+            sender.send("x_echo", payload.to_vec())
+        }
+    }
+
+    #[test]
+    fn register_and_dispatch() -> anyhow::Result<()> {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = ExtensionRegistry::default();
+
+        assert_eq!(17, registry.register("x_echo", Echo(received.clone()))?);
+        assert!(registry.register("x_echo", Echo(received.clone())).is_err());
+        assert!(registry
+            .register("ut_metadata", Echo(received.clone()))
+            .is_err());
+        assert_eq!(
+            BTreeMap::from([("x_echo".to_string(), 17)]),
+            registry.advertised()
+        );
+
+        let mut sender = ExtensionSender::new(BTreeMap::from([("x_echo".to_string(), 3)]));
+        assert!(registry.dispatch(17, b"hello", &mut sender)?);
+        assert!(!registry.dispatch(16, b"hello", &mut sender)?);
+        assert!(!registry.dispatch(18, b"hello", &mut sender)?);
+
+        assert_eq!(vec![b"hello".to_vec()], *received.lock().unwrap());
+        assert_eq!(
+            vec![Message::Extension {
+                message: ExtensionMessage::Custom {
+                    id: 3,
+                    payload: b"hello".to_vec()
+                }
+            }],
+            sender.take()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn send_to_unsupported_extension() {
+        let mut sender = ExtensionSender::new(BTreeMap::from([("x_off".to_string(), 0)]));
+
+        assert!(!sender.supports("x_off"));
+        assert!(sender.send("x_off", Vec::new()).is_err());
+        assert!(sender.send("x_missing", Vec::new()).is_err());
+    }
+}
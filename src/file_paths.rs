@@ -0,0 +1,196 @@
+use std::{
+    fmt::Display,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::torrent::FileInfo;
+
+/// Which platform's file naming rules paths from torrents must follow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathRules {
+    Unix,
+    Windows,
+}
+
+impl PathRules {
+    pub fn native() -> Self {
+        if cfg!(windows) {
+            PathRules::Windows
+        } else {
+            PathRules::Unix
+        }
+    }
+}
+
+/// A file whose path had to be changed to be valid on this platform
+#[derive(Debug, PartialEq)]
+pub struct Rename {
+    pub index: usize,
+    pub original: PathBuf,
+    pub sanitized: PathBuf,
+}
+
+impl Display for Rename {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "file {}: '{}' renamed to '{}'",
+            self.index,
+            self.original.display(),
+            self.sanitized.display()
+        )
+    }
+}
+
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Whether a name from the torrent is a single plain path component: not empty, not `.` or `..`,
+/// with no separator and no root or drive prefix, so that joining it cannot escape a directory
+pub fn is_safe_component(component: &str) -> bool {
+    let mut components = Path::new(component).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => name == component,
+        _ => false,
+    }
+}
+
+/// Makes a single path component valid under the given rules, replacing illegal characters
+/// with `_`
+pub fn sanitize_component(component: &str, rules: PathRules) -> String {
+    match rules {
+        PathRules::Unix => component.replace(['/', '\0'], "_"),
+        PathRules::Windows => {
+            let mut sanitized = component
+                .chars()
+                .map(|c| match c {
+                    '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+                    c if c.is_control() => '_',
+                    c => c,
+                })
+                .collect::<String>();
+            // Windows silently drops trailing dots and spaces
+            let trimmed_len = sanitized.trim_end_matches(['.', ' ']).len();
+            if trimmed_len != sanitized.len() {
+                sanitized.truncate(trimmed_len);
+                sanitized.push('_');
+            }
+            // Reserved device names are reserved whatever the extension
+            let stem = sanitized.split('.').next().unwrap_or_default();
+            if WINDOWS_RESERVED_NAMES
+                .iter()
+                .any(|i| i.eq_ignore_ascii_case(stem))
+            {
+                sanitized.insert(stem.len(), '_');
+            }
+            sanitized
+        }
+    }
+}
+
+/// Sanitizes the path of every file, returning the files with their new paths along with what
+/// had to be renamed
+pub fn sanitize_files(files: Vec<FileInfo>, rules: PathRules) -> (Vec<FileInfo>, Vec<Rename>) {
+    let mut renames = Vec::new();
+    let files = files
+        .into_iter()
+        .map(|file| {
+            let sanitized = file
+                .path
+                .iter()
+                .map(|i| sanitize_component(&i.to_string_lossy(), rules))
+                .collect::<PathBuf>();
+            if sanitized != file.path {
+                renames.push(Rename {
+                    index: file.index,
+                    original: file.path.clone(),
+                    sanitized: sanitized.clone(),
+                });
+            }
+            FileInfo {
+                path: sanitized,
+                ..file
+            }
+        })
+        .collect();
+    (files, renames)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::torrent::FileInfo;
+
+    use super::{is_safe_component, sanitize_component, sanitize_files, PathRules, Rename};
+
+    #[test]
+    fn unsafe_components() {
+        assert!(is_safe_component("file.txt"));
+        assert!(is_safe_component("..file"));
+        for component in ["", ".", "..", "/etc", "a/b", "a/"] {
+            assert!(!is_safe_component(component), "{component:?}");
+        }
+    }
+
+    #[test]
+    fn windows_illegal_characters() {
+        assert_eq!(
+            "a_b_c_d_",
+            sanitize_component("a:b*c?d\"", PathRules::Windows)
+        );
+        assert_eq!(
+            "a:b*c?d\"",
+            sanitize_component("a:b*c?d\"", PathRules::Unix)
+        );
+    }
+
+    #[test]
+    fn windows_trailing_dots_and_spaces() {
+        assert_eq!("name_", sanitize_component("name. .", PathRules::Windows));
+        assert_eq!("name. .", sanitize_component("name. .", PathRules::Unix));
+    }
+
+    #[test]
+    fn windows_reserved_names() {
+        assert_eq!("CON_", sanitize_component("CON", PathRules::Windows));
+        assert_eq!(
+            "con_.txt",
+            sanitize_component("con.txt", PathRules::Windows)
+        );
+        assert_eq!("COM10", sanitize_component("COM10", PathRules::Windows));
+        assert_eq!("CON", sanitize_component("CON", PathRules::Unix));
+    }
+
+    #[test]
+    fn sanitize_files_reports_renames() {
+        let files = vec![
+            FileInfo {
+                index: 0,
+                path: PathBuf::from("top/ok.txt"),
+                offset: 0,
+                length: 1,
+            },
+            FileInfo {
+                index: 1,
+                path: PathBuf::from("top/aux/what?.txt"),
+                offset: 1,
+                length: 1,
+            },
+        ];
+
+        let (files, renames) = sanitize_files(files, PathRules::Windows);
+
+        assert_eq!(PathBuf::from("top/ok.txt"), files[0].path);
+        assert_eq!(
+            vec![Rename {
+                index: 1,
+                original: PathBuf::from("top/aux/what?.txt"),
+                sanitized: PathBuf::from("top/aux_/what_.txt"),
+            }],
+            renames
+        );
+    }
+}
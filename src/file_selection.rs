@@ -0,0 +1,287 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+use crate::{
+    magnet_links::SelectOnly, torrent::Info, torrent_info::TorrentInfo, web_seed::WebSeed,
+};
+
+/// How eagerly a file is downloaded: pieces of higher priority files are requested first, those
+/// of skipped files are not requested at all
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Indexes of files to download, such as `0,2,4-6`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileList(pub SelectOnly);
+
+impl FromStr for FileList {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(FileList)
+    }
+}
+
+/// Priority of a single file, given as `INDEX=PRIORITY`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilePriorityArg {
+    pub index: usize,
+    pub priority: FilePriority,
+}
+
+impl FromStr for FilePriorityArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, priority) = s
+            .split_once('=')
+            .context("file priority must be INDEX=PRIORITY")?;
+        Ok(Self {
+            index: index
+                .parse()
+                .with_context(|| format!("invalid file index '{index}'"))?,
+            priority: clap::ValueEnum::from_str(priority, true)
+                .map_err(|_| anyhow::anyhow!("invalid file priority '{priority}'"))?,
+        })
+    }
+}
+
+/// A torrent downloaded with priorities of its own for each file, the files not listed in `files`
+/// being skipped when given
+pub struct Selected<'a, TI> {
+    torrent_info: &'a TI,
+    priorities: Vec<FilePriority>,
+    first_last_pieces: bool,
+}
+
+impl<'a, TI: TorrentInfo> Selected<'a, TI> {
+    /// `torrent_info` with its own file priorities when neither `files` nor `priorities` are
+    /// given. Otherwise `files`, if any, replaces the files selected by `torrent_info` (such as
+    /// a magnet's select-only list), then `priorities` apply.
+    pub fn new(
+        torrent_info: &'a TI,
+        files: Option<&FileList>,
+        priorities: &[FilePriorityArg],
+    ) -> anyhow::Result<Self> {
+        let count = torrent_info.files_info().len();
+        let mut file_priorities = match files {
+            Some(FileList(files)) => {
+                if let Some(index) = files.last().filter(|i| *i >= count) {
+                    bail!("no file {index} in a torrent of {count} files");
+                }
+                (0..count)
+                    .map(|i| match files.contains(i) {
+                        true => FilePriority::Normal,
+                        false => FilePriority::Skip,
+                    })
+                    .collect()
+            }
+            None => torrent_info.file_priorities(),
+        };
+        for arg in priorities {
+            *file_priorities.get_mut(arg.index).with_context(|| {
+                format!("no file {} in a torrent of {count} files", arg.index)
+            })? = arg.priority;
+        }
+        Ok(Self {
+            torrent_info,
+            priorities: file_priorities,
+            first_last_pieces: false,
+        })
+    }
+
+    /// Download the first and last pieces of each file before the others
+    pub fn with_first_last_pieces(mut self, enabled: bool) -> Self {
+        self.first_last_pieces = enabled;
+        self
+    }
+}
+
+impl<TI: TorrentInfo> TorrentInfo for Selected<'_, TI> {
+    fn info(&self) -> &Info {
+        self.torrent_info.info()
+    }
+
+    fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
+        self.torrent_info.info_hash()
+    }
+
+    fn info_hash_v2(&self) -> anyhow::Result<Option<[u8; 32]>> {
+        self.torrent_info.info_hash_v2()
+    }
+
+    fn web_seeds(&self) -> Vec<WebSeed> {
+        self.torrent_info.web_seeds()
+    }
+
+    /// The files that are not skipped
+    fn selected_files(&self) -> Option<Vec<usize>> {
+        Some(
+            self.priorities
+                .iter()
+                .enumerate()
+                .filter(|(_, priority)| **priority != FilePriority::Skip)
+                .map(|(index, _)| index)
+                .collect(),
+        )
+    }
+
+    fn file_priorities(&self) -> Vec<FilePriority> {
+        self.priorities.clone()
+    }
+
+    fn first_last_pieces_first(&self) -> bool {
+        self.first_last_pieces
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        file_selection::{FileList, FilePriority, FilePriorityArg, Selected},
+        magnet_links::MagnetLink,
+        torrent::Torrent,
+        torrent_info::TorrentInfo,
+    };
+
+    /// Three files of 100, 28 and 100 bytes in pieces of 64 bytes
+    fn torrent() -> anyhow::Result<Torrent> {
+        let mut content = Vec::from("d8:announce31:http://127.0.0.1:44381/announce4:infod5:filesld6:lengthi100e4:pathl1:aeed6:lengthi28e4:pathl1:beed6:lengthi100e4:pathl1:ceee4:name3:dir12:piece lengthi64e6:pieces80:");
+        content.extend_from_slice(&[0; 80]);
+        content.extend_from_slice(b"ee");
+        Torrent::from_bytes(&content)
+    }
+
+    #[test]
+    fn first_and_last_pieces_of_files_go_first() -> anyhow::Result<()> {
+        let mut content = Vec::from("d8:announce31:http://127.0.0.1:44381/announce4:infod6:lengthi256e4:name8:file.mkv12:piece lengthi64e6:pieces80:");
+        content.extend_from_slice(&[0; 80]);
+        content.extend_from_slice(b"ee");
+        let torrent = Torrent::from_bytes(&content)?;
+
+        let selected = Selected::new(&torrent, None, &[])?.with_first_last_pieces(true);
+
+        assert_eq!(
+            vec![
+                FilePriority::High,
+                FilePriority::Normal,
+                FilePriority::Normal,
+                FilePriority::High
+            ],
+            selected.piece_priorities()
+        );
+        assert_eq!(vec![0, 3, 1, 2], selected.piece_order());
+        Ok(())
+    }
+
+    #[test]
+    fn skipped_files_keep_their_first_and_last_pieces_skipped() -> anyhow::Result<()> {
+        let torrent = torrent()?;
+
+        let selected = Selected::new(&torrent, Some(&"0".parse::<FileList>()?), &[])?
+            .with_first_last_pieces(true);
+
+        assert_eq!(vec![0, 1], selected.piece_order());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_file_priority() -> anyhow::Result<()> {
+        assert_eq!(
+            FilePriorityArg {
+                index: 2,
+                priority: FilePriority::High
+            },
+            "2=high".parse()?
+        );
+        assert!("2".parse::<FilePriorityArg>().is_err());
+        assert!("x=high".parse::<FilePriorityArg>().is_err());
+        assert!("2=urgent".parse::<FilePriorityArg>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn only_pieces_of_selected_files_are_wanted() -> anyhow::Result<()> {
+        let torrent = torrent()?;
+
+        let selected = Selected::new(&torrent, Some(&"1".parse::<FileList>()?), &[])?;
+
+        // b lies in the second piece, along with the end of a
+        assert_eq!(vec![false, true, false, false], selected.wanted_pieces());
+        assert_eq!(Some(vec![1]), selected.selected_files());
+        Ok(())
+    }
+
+    #[test]
+    fn shared_pieces_take_the_highest_priority() -> anyhow::Result<()> {
+        let torrent = torrent()?;
+        let priorities = [
+            FilePriorityArg {
+                index: 0,
+                priority: FilePriority::Low,
+            },
+            FilePriorityArg {
+                index: 2,
+                priority: FilePriority::High,
+            },
+        ];
+
+        let selected = Selected::new(&torrent, None, &priorities)?;
+
+        assert_eq!(
+            vec![
+                FilePriority::Low,
+                FilePriority::Normal,
+                FilePriority::High,
+                FilePriority::High
+            ],
+            selected.piece_priorities()
+        );
+        assert_eq!(vec![2, 3, 1, 0], selected.piece_order());
+        Ok(())
+    }
+
+    #[test]
+    fn files_replace_the_select_only_list() -> anyhow::Result<()> {
+        let torrent = torrent()?;
+        let magnet_link = MagnetLink::parse(format!(
+            "magnet:?xt=urn:btih:{}&so=0",
+            hex::encode(torrent.info_hash()?)
+        ))?;
+        let torrent_info = (magnet_link, torrent.info);
+
+        assert_eq!(
+            Some(vec![0]),
+            Selected::new(&torrent_info, None, &[])?.selected_files()
+        );
+        assert_eq!(
+            Some(vec![1, 2]),
+            Selected::new(&torrent_info, Some(&"1-2".parse::<FileList>()?), &[])?.selected_files()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_files_are_rejected() -> anyhow::Result<()> {
+        let torrent = torrent()?;
+
+        assert!(Selected::new(&torrent, Some(&"3".parse::<FileList>()?), &[]).is_err());
+        assert!(Selected::new(
+            &torrent,
+            None,
+            &[FilePriorityArg {
+                index: 3,
+                priority: FilePriority::Skip
+            }]
+        )
+        .is_err());
+        Ok(())
+    }
+}
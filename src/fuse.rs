@@ -0,0 +1,645 @@
+//! A read-only FUSE filesystem presenting the files of a torrent, their pieces being downloaded
+//! as they are read. It speaks the kernel's protocol on `/dev/fuse` itself, mount(8) only
+//! attaching the connection to the mount point.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    os::unix::fs::MetadataExt,
+    path::Path,
+    process::{Command, Stdio},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    logging,
+    torrent::{FileInfo, PieceInfo},
+    torrent_info::TorrentInfo,
+};
+
+/// Version of the protocol we speak, the kernel's being used if older
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+/// Largest read the kernel is told to send us
+const MAX_READ: u32 = 128 * 1024;
+/// Room for a request, the largest being writes which a read-only filesystem never receives
+const BUFFER_SIZE: usize = MAX_READ as usize + 4096;
+/// Bytes of downloaded pieces kept in memory for the next reads
+const CACHE_SIZE: u64 = 64 * 1024 * 1024;
+/// How long the kernel may keep names and attributes, which never change
+const TTL_SECONDS: u64 = 3600;
+
+const ROOT_INODE: u64 = 1;
+
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const FLUSH: u32 = 25;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const ACCESS: u32 = 34;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const ENOTDIR: i32 = 20;
+const EISDIR: i32 = 21;
+const EINVAL: i32 = 22;
+const ENOSYS: i32 = 38;
+
+/// Size of the header of the requests from the kernel
+const IN_HEADER_SIZE: usize = 40;
+/// Size of the header of our replies
+const OUT_HEADER_SIZE: usize = 16;
+/// Tells the kernel that the pages it cached for a file are still valid when opened again
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// Where the pieces of the mounted torrent come from
+pub trait PieceSource {
+    /// Downloads piece `index` and checks its hash
+    fn fetch(&self, index: u32) -> anyhow::Result<Vec<u8>>;
+}
+
+impl<F: Fn(u32) -> anyhow::Result<Vec<u8>>> PieceSource for F {
+    fn fetch(&self, index: u32) -> anyhow::Result<Vec<u8>> {
+        self(index)
+    }
+}
+
+/// Mounts the files of `torrent_info` at `mountpoint` and answers the kernel until the filesystem
+/// is unmounted
+pub fn mount<TI: TorrentInfo, S: PieceSource>(
+    torrent_info: &TI,
+    source: S,
+    mountpoint: &Path,
+) -> anyhow::Result<()> {
+    torrent_info.validate_layout()?;
+    let owner = fs::metadata("/proc/self").context("reading our user and group")?;
+    let mut filesystem = Filesystem::new(torrent_info, source, owner.uid(), owner.gid());
+    let mut device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")
+        .context("opening /dev/fuse")?;
+    // mount(8) gets the connection as its stdin, which the kernel is told about by number
+    let output = Command::new("mount")
+        .arg("-t")
+        .arg("fuse.bittorrent")
+        .arg("-o")
+        .arg(format!(
+            "ro,nosuid,nodev,fd=0,rootmode=40000,user_id={},group_id={}",
+            owner.uid(),
+            owner.gid()
+        ))
+        .arg(torrent_info.info().display_name())
+        .arg(mountpoint)
+        .stdin(Stdio::from(device.try_clone()?))
+        .output()
+        .context("running mount")?;
+    if !output.status.success() {
+        bail!(
+            "mounting {}: {}",
+            mountpoint.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    filesystem.serve(&mut device)
+}
+
+/// A node of the mounted tree, its inode being its index in [`Tree::nodes`] plus one
+#[derive(Debug)]
+enum Node {
+    Dir { children: BTreeMap<String, u64> },
+    File(FileInfo),
+}
+
+/// The directories and files of a torrent, as they are laid out when downloaded
+#[derive(Debug)]
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    fn new(files: Vec<FileInfo>) -> Self {
+        let mut nodes = vec![Node::Dir {
+            children: BTreeMap::new(),
+        }];
+        for file in files {
+            let components = file
+                .path
+                .iter()
+                .map(|i| i.to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            let Some((name, dirs)) = components.split_last() else {
+                continue;
+            };
+            let mut parent = ROOT_INODE;
+            for dir in dirs {
+                parent = match Self::child(&nodes, parent, dir) {
+                    Some(inode) => inode,
+                    None => Self::add(
+                        &mut nodes,
+                        parent,
+                        dir,
+                        Node::Dir {
+                            children: BTreeMap::new(),
+                        },
+                    ),
+                };
+            }
+            Self::add(&mut nodes, parent, name, Node::File(file));
+        }
+        Self { nodes }
+    }
+
+    fn add(nodes: &mut Vec<Node>, parent: u64, name: &str, node: Node) -> u64 {
+        nodes.push(node);
+        let inode = nodes.len() as u64;
+        if let Some(Node::Dir { children }) = nodes.get_mut(parent as usize - 1) {
+            children.insert(name.to_string(), inode);
+        }
+        inode
+    }
+
+    fn child(nodes: &[Node], parent: u64, name: &str) -> Option<u64> {
+        match nodes.get(parent.checked_sub(1)? as usize)? {
+            Node::Dir { children } => children.get(name).copied(),
+            Node::File(_) => None,
+        }
+    }
+
+    fn get(&self, inode: u64) -> Option<&Node> {
+        self.nodes.get(inode.checked_sub(1)? as usize)
+    }
+
+    fn lookup(&self, parent: u64, name: &str) -> Option<u64> {
+        Self::child(&self.nodes, parent, name)
+    }
+}
+
+/// Pieces downloaded last, so that reads of neighbouring bytes do not download them again
+struct PieceCache {
+    pieces: VecDeque<(u32, Vec<u8>)>,
+    capacity: usize,
+}
+
+impl PieceCache {
+    fn new(piece_length: u64) -> Self {
+        Self {
+            pieces: VecDeque::new(),
+            capacity: (CACHE_SIZE / piece_length.max(1)).max(2) as usize,
+        }
+    }
+
+    fn get_or_fetch<S: PieceSource>(&mut self, source: &S, index: u32) -> anyhow::Result<&[u8]> {
+        let position = match self.pieces.iter().position(|(i, _)| *i == index) {
+            Some(position) => position,
+            None => {
+                let data = source.fetch(index)?;
+                if self.pieces.len() == self.capacity {
+                    self.pieces.pop_front();
+                }
+                self.pieces.push_back((index, data));
+                self.pieces.len() - 1
+            }
+        };
+        Ok(&self.pieces[position].1)
+    }
+}
+
+/// Answers the requests of the kernel about the files of a torrent
+struct Filesystem<S: PieceSource> {
+    tree: Tree,
+    pieces_info: Vec<PieceInfo>,
+    piece_length: u64,
+    total_len: u64,
+    source: S,
+    cache: PieceCache,
+    uid: u32,
+    gid: u32,
+    /// Time of the mount, given as the modification time of every file
+    mounted_at: u64,
+}
+
+impl<S: PieceSource> Filesystem<S> {
+    fn new<TI: TorrentInfo>(torrent_info: &TI, source: S, uid: u32, gid: u32) -> Self {
+        Self {
+            tree: Tree::new(torrent_info.files_info()),
+            pieces_info: torrent_info.pieces_info(),
+            piece_length: torrent_info.piece_length(),
+            total_len: torrent_info.total_len(),
+            source,
+            cache: PieceCache::new(torrent_info.piece_length()),
+            uid,
+            gid,
+            mounted_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    /// Reads requests from `device` and writes their replies, until unmounted
+    fn serve(&mut self, device: &mut File) -> anyhow::Result<()> {
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let len = match device.read(&mut buf) {
+                Ok(len) => len,
+                // the request was interrupted before we read it
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                // unmounted
+                Err(err) if err.raw_os_error() == Some(19) => return Ok(()),
+                Err(err) => return Err(err).context("reading from /dev/fuse"),
+            };
+            let Some(reply) = self.handle(&buf[..len]) else {
+                continue;
+            };
+            match device.write(&reply) {
+                Ok(_) => {}
+                // the request was interrupted while we answered it
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err).context("writing to /dev/fuse"),
+            }
+        }
+    }
+
+    /// The reply to `request`, `None` for the requests that get none
+    fn handle(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let header = Fields(request.get(..IN_HEADER_SIZE)?);
+        let opcode = header.u32(4);
+        let unique = header.u64(8);
+        let inode = header.u64(16);
+        let body = Fields(&request[IN_HEADER_SIZE..]);
+        let result = match opcode {
+            FORGET | BATCH_FORGET | INTERRUPT => return None,
+            INIT => Ok(self.init(&body)),
+            LOOKUP => self.lookup(inode, &body),
+            GETATTR => self.getattr(inode),
+            OPEN => self.open(inode),
+            READ => self.read(inode, &body),
+            OPENDIR => self.opendir(inode),
+            READDIR => self.readdir(inode, &body),
+            STATFS => Ok(self.statfs()),
+            RELEASE | RELEASEDIR | FLUSH | ACCESS | DESTROY => Ok(Vec::new()),
+            _ => Err(ENOSYS),
+        };
+        Some(reply(unique, result))
+    }
+
+    fn init(&self, body: &Fields) -> Vec<u8> {
+        let minor = body.u32(4).min(KERNEL_MINOR_VERSION);
+        let mut out = Vec::new();
+        put_u32(&mut out, KERNEL_VERSION);
+        put_u32(&mut out, minor);
+        // max_readahead, as the kernel proposes
+        put_u32(&mut out, body.u32(8));
+        // flags
+        put_u32(&mut out, 0);
+        // max_background and congestion_threshold
+        put_u16(&mut out, 16);
+        put_u16(&mut out, 12);
+        // max_write
+        put_u32(&mut out, MAX_READ);
+        // time_gran, max_pages, map_alignment, flags2 and unused fields
+        put_u32(&mut out, 1);
+        put_u16(&mut out, (MAX_READ / 4096) as u16);
+        put_u16(&mut out, 0);
+        out.resize(64, 0);
+        out
+    }
+
+    fn lookup(&self, parent: u64, body: &Fields) -> Result<Vec<u8>, i32> {
+        let name = body.0.split(|i| *i == 0).next().unwrap_or_default();
+        let name = std::str::from_utf8(name).map_err(|_| ENOENT)?;
+        let inode = self.tree.lookup(parent, name).ok_or(ENOENT)?;
+        let mut out = Vec::new();
+        put_u64(&mut out, inode);
+        // generation, entry_valid, attr_valid, entry_valid_nsec and attr_valid_nsec
+        put_u64(&mut out, 0);
+        put_u64(&mut out, TTL_SECONDS);
+        put_u64(&mut out, TTL_SECONDS);
+        put_u32(&mut out, 0);
+        put_u32(&mut out, 0);
+        self.put_attr(&mut out, inode)?;
+        Ok(out)
+    }
+
+    fn getattr(&self, inode: u64) -> Result<Vec<u8>, i32> {
+        let mut out = Vec::new();
+        // attr_valid, attr_valid_nsec and padding
+        put_u64(&mut out, TTL_SECONDS);
+        put_u32(&mut out, 0);
+        put_u32(&mut out, 0);
+        self.put_attr(&mut out, inode)?;
+        Ok(out)
+    }
+
+    fn put_attr(&self, out: &mut Vec<u8>, inode: u64) -> Result<(), i32> {
+        let (size, mode, nlink) = match self.tree.get(inode).ok_or(ENOENT)? {
+            Node::Dir { .. } => (0, 0o040555, 2),
+            Node::File(file) => (file.length, 0o100444, 1),
+        };
+        put_u64(out, inode);
+        put_u64(out, size);
+        put_u64(out, size.div_ceil(512));
+        // atime, mtime and ctime, then their nanoseconds
+        for _ in 0..3 {
+            put_u64(out, self.mounted_at);
+        }
+        for _ in 0..3 {
+            put_u32(out, 0);
+        }
+        put_u32(out, mode);
+        put_u32(out, nlink);
+        put_u32(out, self.uid);
+        put_u32(out, self.gid);
+        // rdev, blksize and flags
+        put_u32(out, 0);
+        put_u32(out, 4096);
+        put_u32(out, 0);
+        Ok(())
+    }
+
+    fn open(&self, inode: u64) -> Result<Vec<u8>, i32> {
+        match self.tree.get(inode).ok_or(ENOENT)? {
+            Node::Dir { .. } => Err(EISDIR),
+            Node::File(_) => Ok(open_reply(FOPEN_KEEP_CACHE)),
+        }
+    }
+
+    fn opendir(&self, inode: u64) -> Result<Vec<u8>, i32> {
+        match self.tree.get(inode).ok_or(ENOENT)? {
+            Node::Dir { .. } => Ok(open_reply(0)),
+            Node::File(_) => Err(ENOTDIR),
+        }
+    }
+
+    /// Bytes of a file, downloading the pieces holding them unless they are cached
+    fn read(&mut self, inode: u64, body: &Fields) -> Result<Vec<u8>, i32> {
+        let Node::File(file) = self.tree.get(inode).ok_or(ENOENT)? else {
+            return Err(EISDIR);
+        };
+        let offset = body.u64(8).min(file.length);
+        let end = (offset + u64::from(body.u32(16))).min(file.length);
+        let (start, end) = (file.offset + offset, file.offset + end);
+        if self.piece_length == 0 || end > self.total_len {
+            return Err(EINVAL);
+        }
+        let mut out = Vec::with_capacity((end - start) as usize);
+        let mut position = start;
+        while position < end {
+            let piece = &self.pieces_info[(position / self.piece_length) as usize];
+            let data = self
+                .cache
+                .get_or_fetch(&self.source, piece.index as u32)
+                .map_err(|err| {
+                    logging::warn("mount", &format!("piece {}: {err:#}", piece.index));
+                    EIO
+                })?;
+            let from = (position - piece.offset) as usize;
+            let to = ((end - piece.offset) as usize).min(data.len());
+            if from >= to {
+                return Err(EIO);
+            }
+            out.extend_from_slice(&data[from..to]);
+            position += (to - from) as u64;
+        }
+        Ok(out)
+    }
+
+    /// Entries of a directory from the offset of the request, as many as fit in its size. The
+    /// offset of an entry is the index of the next one, `.` and `..` coming first.
+    fn readdir(&self, inode: u64, body: &Fields) -> Result<Vec<u8>, i32> {
+        let Node::Dir { children } = self.tree.get(inode).ok_or(ENOENT)? else {
+            return Err(ENOTDIR);
+        };
+        let entries = [(".", inode), ("..", inode)]
+            .into_iter()
+            .chain(children.iter().map(|(name, inode)| (name.as_str(), *inode)));
+        let offset = body.u64(8) as usize;
+        let size = body.u32(16) as usize;
+        let mut out = Vec::new();
+        for (index, (name, inode)) in entries.enumerate().skip(offset) {
+            let kind = match self.tree.get(inode) {
+                Some(Node::File(_)) => 8,
+                _ => 4,
+            };
+            let entry_len = (24 + name.len()).next_multiple_of(8);
+            if out.len() + entry_len > size {
+                break;
+            }
+            put_u64(&mut out, inode);
+            put_u64(&mut out, index as u64 + 1);
+            put_u32(&mut out, name.len() as u32);
+            put_u32(&mut out, kind);
+            out.extend_from_slice(name.as_bytes());
+            out.resize(out.len().next_multiple_of(8), 0);
+        }
+        Ok(out)
+    }
+
+    fn statfs(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        // blocks, bfree, bavail, files and ffree
+        put_u64(&mut out, self.total_len.div_ceil(4096));
+        put_u64(&mut out, 0);
+        put_u64(&mut out, 0);
+        put_u64(&mut out, self.tree.nodes.len() as u64);
+        put_u64(&mut out, 0);
+        // bsize, namelen and frsize, then padding and spare fields
+        put_u32(&mut out, 4096);
+        put_u32(&mut out, 255);
+        put_u32(&mut out, 4096);
+        out.resize(80, 0);
+        out
+    }
+}
+
+/// The fields of a request, in native byte order
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn u32(&self, offset: usize) -> u32 {
+        self.0
+            .get(offset..offset + 4)
+            .map_or(0, |i| u32::from_ne_bytes(i.try_into().expect("4 bytes")))
+    }
+
+    fn u64(&self, offset: usize) -> u64 {
+        self.0
+            .get(offset..offset + 8)
+            .map_or(0, |i| u64::from_ne_bytes(i.try_into().expect("8 bytes")))
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn open_reply(open_flags: u32) -> Vec<u8> {
+    let mut out = Vec::new();
+    // fh, open_flags and padding
+    put_u64(&mut out, 0);
+    put_u32(&mut out, open_flags);
+    put_u32(&mut out, 0);
+    out
+}
+
+/// A reply to request `unique`: its body, or an errno without one
+fn reply(unique: u64, result: Result<Vec<u8>, i32>) -> Vec<u8> {
+    let (error, body) = match result {
+        Ok(body) => (0, body),
+        Err(errno) => (-errno, Vec::new()),
+    };
+    let mut out = Vec::with_capacity(OUT_HEADER_SIZE + body.len());
+    put_u32(&mut out, (OUT_HEADER_SIZE + body.len()) as u32);
+    out.extend_from_slice(&error.to_ne_bytes());
+    put_u64(&mut out, unique);
+    out.extend_from_slice(&body);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use crate::torrent::Torrent;
+
+    use super::{Fields, Filesystem, ROOT_INODE};
+
+    /// Files a=100, b/c=28 and b/d=100 in 64 bytes pieces, their content being the bytes' offsets
+    fn torrent() -> anyhow::Result<Torrent> {
+        let mut content = Vec::from(&b"d8:announce31:http://127.0.0.1:44381/announce4:infod5:filesld6:lengthi100e4:pathl1:aeed6:lengthi28e4:pathl1:b1:ceed6:lengthi100e4:pathl1:b1:deee4:name3:dir12:piece lengthi64e6:pieces80:"[..]);
+        content.extend_from_slice(&[0; 80]);
+        content.extend_from_slice(b"ee");
+        Torrent::from_bytes(&content)
+    }
+
+    fn request(opcode: u32, inode: u64, body: &[u8]) -> Vec<u8> {
+        let mut request = Vec::new();
+        request.extend_from_slice(&((40 + body.len()) as u32).to_ne_bytes());
+        request.extend_from_slice(&opcode.to_ne_bytes());
+        request.extend_from_slice(&7u64.to_ne_bytes());
+        request.extend_from_slice(&inode.to_ne_bytes());
+        request.extend_from_slice(&[0; 16]);
+        request.extend_from_slice(body);
+        request
+    }
+
+    fn read_body(offset: u64, size: u32) -> Vec<u8> {
+        let mut body = vec![0; 8];
+        body.extend_from_slice(&offset.to_ne_bytes());
+        body.extend_from_slice(&size.to_ne_bytes());
+        body.resize(40, 0);
+        body
+    }
+
+    /// Error and body of a reply
+    fn parse(reply: Vec<u8>) -> (i32, Vec<u8>) {
+        let header = Fields(&reply);
+        assert_eq!(reply.len(), header.u32(0) as usize);
+        assert_eq!(7, header.u64(8));
+        (header.u32(4) as i32, reply[16..].to_vec())
+    }
+
+    fn lookup<S: super::PieceSource>(fs: &mut Filesystem<S>, parent: u64, name: &str) -> u64 {
+        let (error, body) = parse(
+            fs.handle(&request(1, parent, format!("{name}\0").as_bytes()))
+                .unwrap(),
+        );
+        assert_eq!(0, error, "looking up {name}");
+        Fields(&body).u64(0)
+    }
+
+    #[test]
+    fn lookup_and_list_files() -> anyhow::Result<()> {
+        let torrent = torrent()?;
+        let mut fs = Filesystem::new(&torrent, |_| anyhow::Ok(vec![]), 1000, 1000);
+
+        let dir = lookup(&mut fs, ROOT_INODE, "dir");
+        let b = lookup(&mut fs, dir, "b");
+        let d = lookup(&mut fs, b, "d");
+        let (error, _) = parse(fs.handle(&request(1, b, b"e\0")).unwrap());
+        assert_eq!(-2, error);
+
+        // size and mode of the file
+        let (error, attr) = parse(fs.handle(&request(3, d, &[0; 16])).unwrap());
+        assert_eq!(0, error);
+        assert_eq!(100, Fields(&attr).u64(16 + 8));
+        assert_eq!(0o100444, Fields(&attr).u32(16 + 60));
+
+        let (error, entries) = parse(fs.handle(&request(28, b, &read_body(0, 4096))).unwrap());
+        assert_eq!(0, error);
+        let names = entries
+            .chunks(32)
+            .map(|i| String::from_utf8_lossy(&i[24..24 + Fields(i).u32(16) as usize]).into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![".", "..", "c", "d"], names);
+        // listing resumes after the last entry read
+        let (_, entries) = parse(fs.handle(&request(28, b, &read_body(3, 4096))).unwrap());
+        assert_eq!(32, entries.len());
+        assert_eq!(d, Fields(&entries).u64(0));
+        Ok(())
+    }
+
+    #[test]
+    fn read_fetches_pieces_once() -> anyhow::Result<()> {
+        let torrent = torrent()?;
+        let fetched = RefCell::new(Vec::new());
+        let source = |index: u32| {
+            fetched.borrow_mut().push(index);
+            let start = index * 64;
+            anyhow::Ok((start..(start + 64).min(228)).map(|i| i as u8).collect())
+        };
+        let mut fs = Filesystem::new(&torrent, source, 1000, 1000);
+        let dir = lookup(&mut fs, ROOT_INODE, "dir");
+        let b = lookup(&mut fs, dir, "b");
+        let d = lookup(&mut fs, b, "d");
+
+        // d starts at 128, in the third piece, and ends in the last one
+        let (error, data) = parse(fs.handle(&request(15, d, &read_body(50, 30))).unwrap());
+        assert_eq!(0, error);
+        assert_eq!((178..208).collect::<Vec<u8>>(), data);
+        let (_, data) = parse(fs.handle(&request(15, d, &read_body(90, 4096))).unwrap());
+        assert_eq!((218..228).collect::<Vec<u8>>(), data);
+        assert_eq!(vec![2, 3], *fetched.borrow());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_downloads_are_io_errors() -> anyhow::Result<()> {
+        let torrent = torrent()?;
+        let mut fs = Filesystem::new(
+            &torrent,
+            |_| -> anyhow::Result<Vec<u8>> { anyhow::bail!("no peer") },
+            1000,
+            1000,
+        );
+        let dir = lookup(&mut fs, ROOT_INODE, "dir");
+        let a = lookup(&mut fs, dir, "a");
+
+        let (error, _) = parse(fs.handle(&request(15, a, &read_body(0, 10))).unwrap());
+        assert_eq!(-5, error);
+        Ok(())
+    }
+}
@@ -21,7 +21,7 @@ impl<'de> Visitor<'de> for HashVisitor {
     where
         E: de::Error,
     {
-        if v.len() % 20 != 0 {
+        if !v.len().is_multiple_of(20) {
             return Err(E::custom(format!(
                 "length {} is not a multiple of 20",
                 v.len()
@@ -54,7 +54,7 @@ impl Serialize for Hashes {
             self.0
                 .iter()
                 .flatten()
-                .map(|i| *i)
+                .copied()
                 .collect::<Vec<_>>()
                 .as_slice(),
         )
@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+
+use crate::peer_messages::Message;
+
+/// What a hook wants done with the connection or message it was shown
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    #[default]
+    Allow,
+    /// Drop the message, or close the connection
+    Veto,
+}
+
+/// Observes peer connections and wire traffic, and may veto them. Every method defaults to
+/// allowing, so a hook only implements what it is interested in.
+pub trait MessageHook: Send + Sync {
+    /// Called once the TCP connection to `peer` is open, before the handshake
+    fn on_peer_connected(&self, _peer: SocketAddr) -> Verdict {
+        Verdict::Allow
+    }
+
+    /// Called before `message` is sent to `peer`, a veto drops it silently
+    fn on_outgoing_message(&self, _peer: SocketAddr, _message: &Message) -> Verdict {
+        Verdict::Allow
+    }
+
+    /// Called when `message` is received from `peer`, a veto drops it as if it never arrived
+    fn on_incoming_message(&self, _peer: SocketAddr, _message: &Message) -> Verdict {
+        Verdict::Allow
+    }
+}
+
+/// Hooks in registration order, all of them see every event and any veto wins
+#[derive(Default)]
+pub struct Hooks(Vec<Box<dyn MessageHook>>);
+
+impl Hooks {
+    pub fn push<H: MessageHook + 'static>(&mut self, hook: H) {
+        self.0.push(Box::new(hook));
+    }
+
+    fn verdict(&self, f: impl Fn(&dyn MessageHook) -> Verdict) -> Verdict {
+        self.0
+            .iter()
+            .map(|hook| f(hook.as_ref()))
+            .fold(Verdict::Allow, |verdict, i| match i {
+                Verdict::Veto => Verdict::Veto,
+                Verdict::Allow => verdict,
+            })
+    }
+
+    pub fn peer_connected(&self, peer: SocketAddr) -> Verdict {
+        self.verdict(|hook| hook.on_peer_connected(peer))
+    }
+
+    pub fn outgoing_message(&self, peer: SocketAddr, message: &Message) -> Verdict {
+        self.verdict(|hook| hook.on_outgoing_message(peer, message))
+    }
+
+    pub fn incoming_message(&self, peer: SocketAddr, message: &Message) -> Verdict {
+        self.verdict(|hook| hook.on_incoming_message(peer, message))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use crate::peer_messages::Message;
+
+    use super::{Hooks, MessageHook, Verdict};
+
+    struct Counter(Arc<AtomicUsize>);
+
+    impl MessageHook for Counter {
+        fn on_incoming_message(&self, _peer: SocketAddr, _message: &Message) -> Verdict {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Verdict::Allow
+        }
+    }
+
+    struct NoChoke;
+
+    impl MessageHook for NoChoke {
+        fn on_incoming_message(&self, _peer: SocketAddr, message: &Message) -> Verdict {
+            match message {
+                Message::Choke => Verdict::Veto,
+                _ => Verdict::Allow,
+            }
+        }
+    }
+
+    #[test]
+    fn every_hook_sees_events_and_veto_wins() -> anyhow::Result<()> {
+        let peer = SocketAddr::from_str("127.0.0.1:6881")?;
+        let seen = Arc::new(AtomicUsize::new(0));
+        let mut hooks = Hooks::default();
+        hooks.push(NoChoke);
+        hooks.push(Counter(seen.clone()));
+
+        assert_eq!(Verdict::Veto, hooks.incoming_message(peer, &Message::Choke));
+        assert_eq!(
+            Verdict::Allow,
+            hooks.incoming_message(peer, &Message::Unchoke)
+        );
+        assert_eq!(
+            Verdict::Allow,
+            hooks.outgoing_message(peer, &Message::Choke)
+        );
+        assert_eq!(Verdict::Allow, hooks.peer_connected(peer));
+        assert_eq!(2, seen.load(Ordering::Relaxed));
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    ops::Range,
+    sync::{Arc, Condvar, Mutex, RwLock},
+    thread,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{logging, torrent::FileInfo, verify::DiskContent};
+
+/// Bytes read from disk and sent at once when answering a request
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Pieces written to disk so far during a download, which readers of the content can wait for
+#[derive(Debug, Default)]
+pub struct WrittenPieces {
+    state: Mutex<WrittenState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct WrittenState {
+    have: Vec<bool>,
+    /// Set once no more piece is going to be written
+    closed: bool,
+}
+
+impl WrittenPieces {
+    pub fn new(pieces_count: usize) -> Self {
+        Self {
+            state: Mutex::new(WrittenState {
+                have: vec![false; pieces_count],
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Replaces what is known of the pieces on disk, such as those of an interrupted download
+    pub fn reset(&self, have: &[bool]) {
+        self.lock().have = have.to_vec();
+        self.changed.notify_all();
+    }
+
+    /// Records that piece `index` was verified and written
+    pub fn set(&self, index: usize) {
+        if let Some(have) = self.lock().have.get_mut(index) {
+            *have = true;
+        }
+        self.changed.notify_all();
+    }
+
+    /// Tells the readers still waiting that the pieces they miss are not coming
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.changed.notify_all();
+    }
+
+    /// Blocks until every piece of `pieces` is written, failing if they never will be
+    pub fn wait_for(&self, pieces: Range<usize>) -> anyhow::Result<()> {
+        let mut state = self.lock();
+        loop {
+            if state
+                .have
+                .get(pieces.clone())
+                .is_some_and(|i| i.iter().all(|i| *i))
+            {
+                return Ok(());
+            }
+            if state.closed {
+                bail!("pieces {pieces:?} are not available");
+            }
+            state = self
+                .changed
+                .wait(state)
+                .expect("written pieces lock poisoned");
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WrittenState> {
+        self.state.lock().expect("written pieces lock poisoned")
+    }
+}
+
+/// Serves a file of a torrent over HTTP while it downloads. Range requests are answered as soon
+/// as the pieces they cover are written, which lets players start before the download is over.
+pub struct StreamServer {
+    file: FileInfo,
+    piece_length: u64,
+    content: RwLock<DiskContent>,
+    written: Arc<WrittenPieces>,
+}
+
+impl StreamServer {
+    pub fn new(
+        file: FileInfo,
+        piece_length: u64,
+        content: DiskContent,
+        written: Arc<WrittenPieces>,
+    ) -> Self {
+        Self {
+            file,
+            piece_length,
+            content: RwLock::new(content),
+            written,
+        }
+    }
+
+    /// Moves the content with `move_content`, which tells where it can be read from afterwards,
+    /// such as once the download is complete. Requests wait for the move to be over.
+    pub fn move_content(
+        &self,
+        move_content: impl FnOnce() -> anyhow::Result<DiskContent>,
+    ) -> anyhow::Result<()> {
+        let mut content = self.content.write().expect("content lock poisoned");
+        *content = move_content()?;
+        Ok(())
+    }
+
+    /// Answers the requests of the clients connecting to `listener`, each in its own thread,
+    /// until accepting a connection fails
+    pub fn serve(self: Arc<Self>, listener: &TcpListener) -> anyhow::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream.context("accepting connection")?;
+            let server = self.clone();
+            thread::spawn(move || {
+                if let Err(err) = server.handle(stream) {
+                    logging::warn("stream", &format!("{err:#}"));
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Answers a single request, the connection being closed afterwards
+    fn handle(&self, stream: TcpStream) -> anyhow::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut range = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("range") {
+                    range = Some(value.trim().to_string());
+                }
+            }
+        }
+        let mut stream = stream;
+        let method = request_line.split(' ').next().unwrap_or_default();
+        if method != "GET" && method != "HEAD" {
+            return write_head(&mut stream, "405 Method Not Allowed", 0, &[]);
+        }
+        let length = self.file.length;
+        let (status, bytes) = match range.as_deref().map(|i| parse_range(i, length)) {
+            None => ("200 OK", 0..length),
+            Some(Some(bytes)) => ("206 Partial Content", bytes),
+            Some(None) => {
+                let content_range = format!("Content-Range: bytes */{length}");
+                return write_head(
+                    &mut stream,
+                    "416 Range Not Satisfiable",
+                    0,
+                    &[&content_range],
+                );
+            }
+        };
+        let content_range = format!(
+            "Content-Range: bytes {}-{}/{length}",
+            bytes.start,
+            bytes.end.saturating_sub(1)
+        );
+        let headers = match status {
+            "206 Partial Content" => vec![content_range.as_str()],
+            _ => vec![],
+        };
+        write_head(&mut stream, status, bytes.end - bytes.start, &headers)?;
+        if method == "HEAD" {
+            return Ok(());
+        }
+        self.write_body(&mut stream, bytes)
+    }
+
+    /// Sends the bytes of the file in `range`, waiting for the pieces holding each chunk
+    fn write_body(&self, stream: &mut TcpStream, range: Range<u64>) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        let mut position = range.start;
+        while position < range.end {
+            let len = CHUNK_SIZE.min(range.end - position);
+            let offset = self.file.offset + position;
+            let first = offset / self.piece_length;
+            let last = (offset + len - 1) / self.piece_length;
+            self.written
+                .wait_for(first as usize..last as usize + 1)
+                .with_context(|| format!("streaming {}", self.file.path.display()))?;
+            buf.resize(len as usize, 0);
+            self.content
+                .read()
+                .expect("content lock poisoned")
+                .read_at(offset, &mut buf)?;
+            stream.write_all(&buf)?;
+            position += len;
+        }
+        Ok(())
+    }
+}
+
+fn write_head(
+    stream: &mut TcpStream,
+    status: &str,
+    content_length: u64,
+    headers: &[&str],
+) -> anyhow::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/octet-stream\r\nAccept-Ranges: bytes\r\nContent-Length: {content_length}\r\nConnection: close\r\n"
+    );
+    for header in headers {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    Ok(())
+}
+
+/// Bytes of a file of `length` bytes asked for by a `Range` header: `bytes=start-end`,
+/// `bytes=start-` or `bytes=-suffix_length`. `None` when it cannot be satisfied; only the first of
+/// several ranges is considered.
+fn parse_range(header: &str, length: u64) -> Option<Range<u64>> {
+    let spec = header.strip_prefix("bytes=")?.split(',').next()?.trim();
+    let (start, end) = spec.split_once('-')?;
+    let range = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        (Some(start), Some(end)) if start <= end => start..(end + 1).min(length),
+        (Some(start), None) if end.is_empty() => start..length,
+        (None, Some(suffix)) if start.is_empty() && suffix > 0 => {
+            length.saturating_sub(suffix)..length
+        }
+        _ => return None,
+    };
+    (range.start < length).then_some(range)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    use crate::{
+        cli::Preallocation, torrent::Torrent, torrent_info::TorrentInfo, verify::DiskContent,
+    };
+
+    use super::{parse_range, StreamServer, WrittenPieces};
+
+    #[test]
+    fn parse_range_header() {
+        assert_eq!(Some(0..100), parse_range("bytes=0-", 100));
+        assert_eq!(Some(10..20), parse_range("bytes=10-19", 100));
+        assert_eq!(Some(90..100), parse_range("bytes=90-200", 100));
+        assert_eq!(Some(80..100), parse_range("bytes=-20", 100));
+        assert_eq!(Some(0..100), parse_range("bytes=-200", 100));
+        assert_eq!(Some(0..10), parse_range("bytes=0-9, 20-29", 100));
+        assert_eq!(None, parse_range("bytes=100-", 100));
+        assert_eq!(None, parse_range("bytes=20-10", 100));
+        assert_eq!(None, parse_range("items=0-9", 100));
+    }
+
+    fn get(address: std::net::SocketAddr, range: &str) -> anyhow::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(address)?;
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: localhost\r\nRange: {range}\r\n\r\n"
+        )?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        Ok(response)
+    }
+
+    #[test]
+    fn range_requests_wait_for_their_pieces() -> anyhow::Result<()> {
+        let mut torrent_content = Vec::from(&b"d8:announce31:http://127.0.0.1:44381/announce4:infod6:lengthi200e4:name8:data.bin12:piece lengthi64e6:pieces80:"[..]);
+        torrent_content.extend_from_slice(&[0; 80]);
+        torrent_content.extend_from_slice(b"ee");
+        let torrent = Torrent::from_bytes(&torrent_content)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("data.bin");
+        let content = (0..200u8).collect::<Vec<_>>();
+        let disk = DiskContent::new(&torrent, &path);
+        disk.create(Preallocation::None)?;
+        disk.write_at(0, &content[..64])?;
+        let written = Arc::new(WrittenPieces::new(4));
+        written.set(0);
+        let file = torrent.files_info().remove(0);
+        let server = Arc::new(StreamServer::new(
+            file,
+            64,
+            DiskContent::new(&torrent, &path),
+            written.clone(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || server.serve(&listener));
+
+        let response = get(address, "bytes=10-19")?;
+        assert!(response.starts_with(b"HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.ends_with(&content[10..20]));
+        assert!(String::from_utf8_lossy(&response).contains("Content-Range: bytes 10-19/200"));
+
+        // the second piece arrives after the request
+        let writer = thread::spawn(move || -> anyhow::Result<()> {
+            thread::sleep(Duration::from_millis(50));
+            disk.write_at(64, &content[64..128])?;
+            written.set(1);
+            Ok(())
+        });
+        let response = get(address, "bytes=60-69")?;
+        writer.join().expect("writer panicked")?;
+        assert!(response.ends_with(&(60..70u8).collect::<Vec<_>>()));
+
+        let response = get(address, "bytes=300-")?;
+        assert!(response.starts_with(b"HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn waiting_fails_once_closed() {
+        let written = WrittenPieces::new(2);
+        written.set(0);
+
+        assert!(written.wait_for(0..1).is_ok());
+        written.close();
+        assert!(written.wait_for(0..2).is_err());
+    }
+}
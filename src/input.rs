@@ -0,0 +1,70 @@
+use std::{
+    io::{stdin, Read},
+    path::Path,
+};
+
+use anyhow::Context;
+use reqwest::Url;
+
+use crate::bt_client::HttpClient;
+
+/// Reads a `.torrent` from a local path, from stdin when `source` is `-`, or over HTTP when
+/// `source` is an http(s) URL
+pub fn read_torrent<C: HttpClient>(client: &C, source: &Path) -> anyhow::Result<Vec<u8>> {
+    if is_stdin(source) {
+        let mut content = Vec::new();
+        stdin()
+            .read_to_end(&mut content)
+            .context("read torrent file from stdin")?;
+        return Ok(content);
+    }
+    match url(source) {
+        Some(url) => client.get(url).context("fetch torrent file"),
+        None => std::fs::read(source).context("read torrent file"),
+    }
+}
+
+pub fn is_stdin(source: &Path) -> bool {
+    source == Path::new("-")
+}
+
+/// Whether `source` is a file on disk, as opposed to stdin or a URL
+pub fn is_local_file(source: &Path) -> bool {
+    !is_stdin(source) && url(source).is_none()
+}
+
+/// `source` as an http(s) URL, `None` when it is a local path
+pub fn url(source: &Path) -> Option<Url> {
+    let url = Url::parse(source.to_str()?).ok()?;
+    matches!(url.scheme(), "http" | "https").then_some(url)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use reqwest::Url;
+
+    use super::{is_local_file, url};
+
+    #[test]
+    fn url_or_path() -> anyhow::Result<()> {
+        assert_eq!(
+            Some(Url::parse("https://example.org/a.torrent")?),
+            url(Path::new("https://example.org/a.torrent"))
+        );
+        assert_eq!(None, url(Path::new("/tmp/a.torrent")));
+        assert_eq!(None, url(Path::new("a.torrent")));
+        assert_eq!(None, url(Path::new("C:\\a.torrent")));
+        assert_eq!(None, url(Path::new("-")));
+        Ok(())
+    }
+
+    #[test]
+    fn local_files() {
+        assert!(is_local_file(Path::new("a.torrent")));
+        assert!(is_local_file(Path::new("./-")));
+        assert!(!is_local_file(Path::new("-")));
+        assert!(!is_local_file(Path::new("http://example.org/a.torrent")));
+    }
+}
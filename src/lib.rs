@@ -1,11 +1,45 @@
 pub mod bedecode;
+pub mod bitfield;
+pub mod block_cache;
+pub mod block_size;
 pub mod bt_client;
+pub mod byte_string;
+pub mod choker;
 pub mod cli;
+pub mod dht;
+pub mod doctor;
+pub mod extensions;
+pub mod file_paths;
+pub mod file_selection;
+pub mod fuse;
 pub mod hashes;
+pub mod hooks;
+pub mod http_stream;
+pub mod input;
+pub mod logging;
 pub mod magnet_links;
+pub mod mse;
+pub mod output;
+pub mod peer_cache;
 pub mod peer_messages;
+pub mod peer_scores;
+pub mod peer_selection;
+pub mod pex;
+pub mod resolver;
+pub mod resume;
+pub mod retry;
+pub mod scheduler;
 pub mod sha1;
+pub mod sha256;
+pub mod stats;
+pub mod timeouts;
 pub mod torrent;
+pub mod torrent_edit;
 pub mod torrent_info;
 pub mod tracker;
+pub mod tracker_client;
 pub mod tracker_info;
+pub mod tracker_manager;
+pub mod verify;
+pub mod web_seed;
+pub mod wire_trace;
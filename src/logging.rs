@@ -0,0 +1,72 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::cli::LogFormat;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Sets how log events are written to stderr, for the whole process
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+pub fn format() -> LogFormat {
+    if JSON.load(Ordering::Relaxed) {
+        LogFormat::Json
+    } else {
+        LogFormat::Text
+    }
+}
+
+/// Writes a warning to stderr
+pub fn warn(target: &str, message: &str) {
+    match format() {
+        LogFormat::Text => eprintln!("{message}"),
+        LogFormat::Json => eprintln!("{}", json_event("warn", target, message, Map::new())),
+    }
+}
+
+/// One log event as a single line JSON object
+pub fn json_event(level: &str, target: &str, message: &str, fields: Map<String, Value>) -> String {
+    let mut event = json!({
+        "timestamp_ms": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+        "level": level,
+        "target": target,
+        "message": message,
+    });
+    if !fields.is_empty() {
+        event["fields"] = Value::Object(fields);
+    }
+    event.to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Map, Value};
+
+    use super::json_event;
+
+    #[test]
+    fn json_event_is_one_line() -> anyhow::Result<()> {
+        let mut fields = Map::new();
+        fields.insert("size".to_string(), json!(68));
+
+        let line = json_event("trace", "wire", "Handshake\nwith newline", fields);
+        let event: Value = serde_json::from_str(&line)?;
+
+        assert!(!line.contains('\n'));
+        assert_eq!("wire", event["target"]);
+        assert_eq!("trace", event["level"]);
+        assert_eq!("Handshake\nwith newline", event["message"]);
+        assert_eq!(68, event["fields"]["size"]);
+        assert!(event["timestamp_ms"].is_u64());
+        Ok(())
+    }
+}
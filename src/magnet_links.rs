@@ -1,11 +1,24 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::RangeInclusive, str::FromStr};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use reqwest::Url;
 
+use crate::{torrent::Torrent, tracker_info::TrackerInfo};
+
 pub struct MagnetLink {
-    pub announce: Url,
+    /// The tracker (`tr`), peers of trackerless links are looked up in the DHT
+    pub announce: Option<Url>,
     pub info_hash: [u8; 20],
+    /// Where the `.torrent` can be downloaded from (`xs`)
+    pub exact_source: Option<Url>,
+    /// Name to display and save the download as (`dn`)
+    pub display_name: Option<String>,
+    /// Length of the content in bytes (`xl`)
+    pub exact_length: Option<u64>,
+    /// Peers to connect to directly (`x.pe`), as host and port
+    pub direct_peers: Vec<(String, u16)>,
+    /// Indexes of the only files to download (`so`), every file when absent
+    pub select_only: Option<SelectOnly>,
 }
 
 impl MagnetLink {
@@ -16,22 +29,163 @@ impl MagnetLink {
         let map = serde_urlencoded::from_bytes::<HashMap<String, String>>(payload.as_bytes())
             .context("turing magnet link to hashmap")?;
 
+        // x.pe may be repeated, unlike the other keys
+        let direct_peers =
+            serde_urlencoded::from_bytes::<Vec<(String, String)>>(payload.as_bytes())
+                .context("turning magnet link to pairs")?
+                .into_iter()
+                .filter(|(key, _)| key == "x.pe")
+                .map(|(_, peer)| parse_peer(&peer))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
         let hash = map.get("xt").context("getting xt key")?;
-        let hash = hex::decode(&hash.as_bytes()[9..])?;
+        let hash = hash
+            .strip_prefix("urn:btih:")
+            .with_context(|| format!("unsupported exact topic '{hash}'"))?;
 
         Ok(Self {
-            announce: Url::parse(map.get("tr").context("getting tr key")?)
+            announce: map
+                .get("tr")
+                .map(|i| Url::parse(i))
+                .transpose()
                 .context("parsing announce url")?,
-            info_hash: TryInto::<[u8; 20]>::try_into(&hash[..20]).expect("hash is not 20 bytes"),
+            info_hash: decode_info_hash(hash)?,
+            exact_source: map
+                .get("xs")
+                .map(|i| Url::parse(i))
+                .transpose()
+                .context("parsing exact source url")?,
+            display_name: map.get("dn").cloned(),
+            exact_length: map
+                .get("xl")
+                .map(|i| i.parse())
+                .transpose()
+                .context("parsing exact length")?,
+            direct_peers,
+            select_only: map.get("so").map(|i| i.parse()).transpose()?,
         })
     }
 }
 
+/// A magnet link equivalent to `torrent`: its info hash, name, length and every tracker of its
+/// announce list, each once
+pub fn generate(torrent: &Torrent) -> anyhow::Result<String> {
+    let mut trackers: Vec<String> = Vec::new();
+    for tracker in torrent.announce_tiers().into_iter().flatten() {
+        if !tracker.is_empty() && !trackers.contains(&tracker) {
+            trackers.push(tracker);
+        }
+    }
+    let mut params = vec![
+        ("dn", torrent.info.display_name()),
+        ("xl", torrent.total_len().to_string()),
+    ];
+    params.extend(trackers.into_iter().map(|i| ("tr", i)));
+    let params = serde_urlencoded::to_string(params).context("encoding magnet link")?;
+    Ok(format!(
+        "magnet:?xt=urn:btih:{}&{params}",
+        hex::encode(torrent.info_hash()?)
+    ))
+}
+
+/// Info hash of an exact topic, either 40 hex digits or, in older links, 32 base32 characters
+fn decode_info_hash(hash: &str) -> anyhow::Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("decoding hex info hash")?,
+        32 => decode_base32(hash).context("decoding base32 info hash")?,
+        _ => bail!("info hash '{hash}' is neither hex nor base32"),
+    };
+    Ok(bytes.try_into().expect("decoded info hash is 20 bytes"))
+}
+
+/// Bytes of unpadded base32 (RFC 4648), letters in either case
+fn decode_base32(encoded: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in encoded.chars() {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => bail!("invalid base32 character '{c}'"),
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Ok(bytes)
+}
+
+/// File indexes of a select-only list (BEP 53): comma separated indexes and inclusive ranges
+/// such as `0,2,4-6`. Ranges are kept as given rather than expanded, the list coming from
+/// untrusted links.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectOnly(Vec<RangeInclusive<usize>>);
+
+impl SelectOnly {
+    pub fn contains(&self, index: usize) -> bool {
+        self.0.iter().any(|i| i.contains(&index))
+    }
+
+    /// Largest index of the list
+    pub fn last(&self) -> Option<usize> {
+        self.0.iter().map(|i| *i.end()).max()
+    }
+
+    /// Indexes of the list among the first `count` files, in order
+    pub fn files(&self, count: usize) -> Vec<usize> {
+        (0..count).filter(|i| self.contains(*i)).collect()
+    }
+}
+
+impl FromStr for SelectOnly {
+    type Err = anyhow::Error;
+
+    fn from_str(list: &str) -> Result<Self, Self::Err> {
+        let parse = |index: &str| {
+            index
+                .parse::<usize>()
+                .with_context(|| format!("invalid file index '{index}' in select-only list"))
+        };
+        let mut ranges = Vec::new();
+        for item in list.split(',') {
+            let (first, last) = match item.split_once('-') {
+                Some((first, last)) => (parse(first)?, parse(last)?),
+                None => (parse(item)?, parse(item)?),
+            };
+            if first > last {
+                bail!("reversed range '{item}' in select-only list");
+            }
+            ranges.push(first..=last);
+        }
+        Ok(Self(ranges))
+    }
+}
+
+/// Host and port of `host:port`, the host of IPv6 addresses being in brackets
+fn parse_peer(peer: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = peer
+        .rsplit_once(':')
+        .with_context(|| format!("no port in peer address '{peer}'"))?;
+    let port = port
+        .parse()
+        .with_context(|| format!("invalid port in peer address '{peer}'"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok((host.to_string(), port))
+}
+
 #[cfg(test)]
 mod test {
+    use anyhow::Context;
     use reqwest::Url;
 
-    use crate::magnet_links::MagnetLink;
+    use crate::{magnet_links::MagnetLink, torrent::Torrent};
+
+    use super::{generate, SelectOnly};
 
     #[test]
     fn parse_link() -> anyhow::Result<()> {
@@ -41,11 +195,143 @@ mod test {
             "ad42ce8109f54c99613ce38f9b4d87e70f24a165",
             hex::encode(res.info_hash)
         );
+        assert_eq!(Some("magnet1.gif".to_string()), res.display_name);
+        assert_eq!(None, res.exact_length);
         assert_eq!(
-            Url::parse("http://bittorrent-test-tracker.codecrafters.io/announce")?,
+            Some(Url::parse(
+                "http://bittorrent-test-tracker.codecrafters.io/announce"
+            )?),
             res.announce
         );
 
         Ok(())
     }
+
+    #[test]
+    fn parse_link_with_exact_length() -> anyhow::Result<()> {
+        let res = MagnetLink::parse(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=a%20b.gif&xl=629944",
+        )?;
+
+        assert_eq!(Some("a b.gif".to_string()), res.display_name);
+        assert_eq!(Some(629944), res.exact_length);
+        assert!(MagnetLink::parse(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&xl=big"
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_link_with_direct_peers() -> anyhow::Result<()> {
+        let res = MagnetLink::parse("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&x.pe=10.0.0.1:6881&x.pe=%5B::1%5D:51413&x.pe=seed.example.org:1")?;
+
+        assert_eq!(
+            vec![
+                ("10.0.0.1".to_string(), 6881),
+                ("::1".to_string(), 51413),
+                ("seed.example.org".to_string(), 1)
+            ],
+            res.direct_peers
+        );
+        assert!(MagnetLink::parse(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&x.pe=10.0.0.1"
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn generate_link() -> anyhow::Result<()> {
+        let mut torrent = Torrent::from_base64("ZDg6YW5ub3VuY2U1NTpodHRwOi8vYml0dG9ycmVudC10ZXN0LXRyYWNrZXIuY29kZWNyYWZ0ZXJzLmlvL2Fubm91bmNlMTA6Y3JlYXRlZCBieTEzOm1rdG9ycmVudCAxLjE0OmluZm9kNjpsZW5ndGhpODIwODkyZTQ6bmFtZTE5OmNvbmdyYXR1bGF0aW9ucy5naWYxMjpwaWVjZSBsZW5ndGhpMjYyMTQ0ZTY6cGllY2VzODA6PUKiDtsc+EDNNSjTqekh22M4pGNp+IWzmIpS/7A1kZhUArbVKFlAq3aGnmycHxAflPOd4VPkaL5qY49Pve1o0C3gEaK2h/dbWDP0bM6OPpxlZQ==")?;
+        torrent.announce_list = Some(vec![
+            vec![torrent.announce.clone()],
+            vec!["udp://tracker.example.org:80".to_string()],
+        ]);
+
+        let link = generate(&torrent)?;
+        let res = MagnetLink::parse(&link)?;
+
+        assert!(link.starts_with("magnet:?xt=urn:btih:"));
+        assert!(link.ends_with(
+            "&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+             &tr=udp%3A%2F%2Ftracker.example.org%3A80"
+        ));
+        assert_eq!(torrent.info_hash()?, res.info_hash);
+        assert_eq!(Some(torrent.info.display_name()), res.display_name);
+        assert_eq!(Some(torrent.total_len()), res.exact_length);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_link_with_base32_info_hash() -> anyhow::Result<()> {
+        let res = MagnetLink::parse("magnet:?xt=urn:btih:vvbm5aij6vgjsyj44ohzwtmh44hsjilf")?;
+
+        assert_eq!(
+            "ad42ce8109f54c99613ce38f9b4d87e70f24a165",
+            hex::encode(res.info_hash)
+        );
+        assert!(MagnetLink::parse("magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJIL1").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:btih:ad42ce81").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_link_with_select_only() -> anyhow::Result<()> {
+        let res = MagnetLink::parse(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&so=0,2,4-6,5",
+        )?;
+
+        let select_only = res.select_only.context("select-only list")?;
+        assert_eq!(vec![0, 2, 4, 5, 6], select_only.files(10));
+        assert_eq!(Some(6), select_only.last());
+        assert_eq!(
+            None,
+            MagnetLink::parse("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165")?
+                .select_only
+        );
+        assert!(MagnetLink::parse(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&so=1,a-3"
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn select_only_ranges_are_not_expanded() -> anyhow::Result<()> {
+        let select_only = "1,3-18446744073709551615".parse::<SelectOnly>()?;
+
+        assert_eq!(vec![1, 3, 4], select_only.files(5));
+        assert_eq!(Some(usize::MAX), select_only.last());
+        assert!(select_only.contains(usize::MAX - 1));
+        assert!("6-4".parse::<SelectOnly>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_trackerless_link() -> anyhow::Result<()> {
+        let res =
+            MagnetLink::parse("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165")?;
+
+        assert_eq!(None, res.announce);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_link_with_exact_source() -> anyhow::Result<()> {
+        let res = MagnetLink::parse("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&tr=http%3A%2F%2Ftracker%2Fannounce&xs=https%3A%2F%2Fexample.org%2Fmagnet1.torrent")?;
+
+        assert_eq!(
+            Some(Url::parse("https://example.org/magnet1.torrent")?),
+            res.exact_source
+        );
+
+        Ok(())
+    }
 }
@@ -1,18 +1,59 @@
-use std::io::{stdout, Write};
+use std::{
+    io::stdout,
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use bittorrent_starter_rust::{
     bedecode::ItemIterator,
-    bt_client::BtClient,
-    cli::{Args, Command},
-    magnet_links::MagnetLink,
+    block_size::DEFAULT_BLOCK_SIZE,
+    bt_client::{BtClient, BtClientBuilder, Reannounce},
+    cli::{self, Command, StorageArgs, TrackerArgs},
+    doctor::{self, Outcome},
+    file_paths,
+    file_selection::{FilePriority, FilePriorityArg, Selected},
+    fuse,
+    http_stream::{StreamServer, WrittenPieces},
+    input, logging,
+    magnet_links::{self, MagnetLink},
+    output,
+    peer_cache::PeerCache,
     peer_messages::Extension,
+    resolver::Resolver,
+    sha1::RustCryptoSha1,
     torrent::{Info, Torrent},
+    torrent_edit::{self, TorrentEdit},
+    torrent_info::TorrentInfo,
+    tracker,
+    tracker_client::AnnounceEvent,
+    tracker_info::TrackerInfo,
+    tracker_manager::TrackerManager,
+    verify::{self, DiskContent},
+    web_seed,
 };
-use clap::Parser;
+use reqwest::Url;
+
+/// Peers downloaded from at the same time
+const MAX_DOWNLOAD_PEERS: usize = 8;
+/// How often `--stats` prints the state of the connected peers
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
 
 fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let args = cli::parse_args();
+    logging::set_format(args.log_format);
+    let trace_wire = args.trace_wire;
+    let dump_corrupt_pieces = args.dump_corrupt_pieces;
+    let dns = args.dns;
+    let encryption = args.encryption;
+    let mut peer_cache = args
+        .peer_cache
+        .as_deref()
+        .map(PeerCache::load)
+        .transpose()?;
 
     match args.command {
         Command::Decode { value } => {
@@ -21,12 +62,15 @@ fn main() -> anyhow::Result<()> {
             Ok(())
         }
         Command::Info { torrent } => {
-            let torrent = std::fs::read(torrent).context("read torrent file")?;
+            let torrent = input::read_torrent(&reqwest::blocking::Client::new(), &torrent)?;
             let torrent: Torrent =
                 serde_bencode::from_bytes(&torrent).context("parse torrent file")?;
             println!("Tracker URL: {}", torrent.announce);
             println!("Length: {}", torrent.total_len());
             println!("Info Hash: {}", hex::encode(torrent.info_hash()?));
+            if let Some(info_hash) = torrent.info_hash_v2()? {
+                println!("Info Hash v2: {}", hex::encode(info_hash));
+            }
             println!("Piece Length: {}", torrent.info.piece_length);
             println!("Piece Hashes:");
             for hash in torrent.info.pieces.0 {
@@ -34,21 +78,32 @@ fn main() -> anyhow::Result<()> {
             }
             Ok(())
         }
+        Command::MagnetGenerate { torrent } => {
+            let torrent = input::read_torrent(&reqwest::blocking::Client::new(), &torrent)?;
+            let torrent: Torrent =
+                serde_bencode::from_bytes(&torrent).context("parse torrent file")?;
+            println!("{}", magnet_links::generate(&torrent)?);
+            Ok(())
+        }
         Command::Peers { torrent } => {
-            let torrent = std::fs::read(torrent).context("read torrent file")?;
+            let torrent = input::read_torrent(&reqwest::blocking::Client::new(), &torrent)?;
             let torrent: Torrent =
                 serde_bencode::from_bytes(&torrent).context("parse torrent file")?;
-            let client = BtClient::new();
+            let client = bt_client(&dns, &torrent, vec![])?
+                .with_wire_trace(trace_wire)
+                .with_encryption(encryption);
             for peer in client.get_peers(&torrent)? {
                 println!("{peer}");
             }
             Ok(())
         }
         Command::Handshake { torrent, peer } => {
-            let torrent = std::fs::read(torrent).context("read torrent file")?;
+            let torrent = input::read_torrent(&reqwest::blocking::Client::new(), &torrent)?;
             let torrent: Torrent =
                 serde_bencode::from_bytes(&torrent).context("parse torrent file")?;
-            let client = BtClient::new();
+            let client = BtClient::new()
+                .with_wire_trace(trace_wire)
+                .with_encryption(encryption);
             let peer_id = client.handshake(torrent.info_hash()?, peer)?;
             println!("Peer ID: {}", hex::encode(peer_id));
             Ok(())
@@ -57,44 +112,129 @@ fn main() -> anyhow::Result<()> {
             output,
             torrent,
             start,
+            block_size,
+            conflict,
+            trackers,
         } => {
-            let torrent = std::fs::read(torrent).context("read torrent file")?;
+            let torrent = input::read_torrent(&reqwest::blocking::Client::new(), &torrent)?;
             let torrent: Torrent =
                 serde_bencode::from_bytes(&torrent).context("parse torrent file")?;
-            let client = BtClient::new();
-            let peers = client.get_peers(&torrent)?;
-            let peer = peers.first().expect("no peer after contacting tracker");
-            let content = client.download_piece(&torrent, *peer, start)?;
-            match output {
-                Some(file) => std::fs::write(file, &content)?,
-                None => stdout().write_all(&content)?,
-            }
-            Ok(())
+            let client = sized_bt_client(&dns, &torrent, extra_trackers(trackers)?, block_size)?
+                .with_wire_trace(trace_wire)
+                .with_encryption(encryption)
+                .with_corrupt_piece_dir(dump_corrupt_pieces.clone());
+            let info_hash = torrent.info_hash()?;
+            let mut trackers = client.tracker_manager(&torrent);
+            let peers = find_peers(
+                &client,
+                &torrent,
+                &mut trackers,
+                info_hash,
+                &[],
+                peer_cache.as_ref(),
+            )?;
+            let piece = client.download_piece_from_peers(&torrent, &peers, start);
+            stop_announcing(&client, &mut trackers, &torrent);
+            let piece = piece?;
+            remember_peer(peer_cache.as_mut(), info_hash, piece.from_peer)?;
+            let storage = StorageArgs {
+                conflict,
+                ..Default::default()
+            };
+            output::write_download(&piece.data, output.as_deref(), "piece", &storage)
         }
-        Command::Download { output, torrent } => {
-            let torrent = std::fs::read(torrent).context("read torrent file")?;
+        Command::Download {
+            output,
+            torrent,
+            progress,
+            peer_report,
+            integrity_report,
+            stats,
+            block_size,
+            storage,
+            selection,
+            trackers,
+        } => {
+            let torrent = input::read_torrent(&reqwest::blocking::Client::new(), &torrent)?;
             let torrent: Torrent =
                 serde_bencode::from_bytes(&torrent).context("parse torrent file")?;
-            let client = BtClient::new();
-            let peers = client.get_peers(&torrent)?;
-            let peer = peers.first().context("getting first peer")?;
-            let content = client.download(&torrent, *peer)?;
-            match output {
-                Some(file) => std::fs::write(file, &content)?,
-                None => stdout().write_all(&content)?,
+            let selected = Selected::new(
+                &torrent,
+                selection.files.as_ref(),
+                &selection.file_priorities,
+            )?
+            .with_first_last_pieces(selection.first_last_pieces);
+            let client = sized_bt_client(&dns, &torrent, extra_trackers(trackers)?, block_size)?
+                .with_wire_trace(trace_wire)
+                .with_encryption(encryption)
+                .with_corrupt_piece_dir(dump_corrupt_pieces.clone())
+                .with_progress(progress)
+                .with_stats_interval(stats.then_some(STATS_INTERVAL));
+            let info_hash = torrent.info_hash()?;
+            let mut trackers = client.tracker_manager(&torrent);
+            let peers = find_peers(
+                &client,
+                &torrent,
+                &mut trackers,
+                info_hash,
+                &[],
+                peer_cache.as_ref(),
+            )?;
+            let reannounce = Reannounce {
+                tracker_manager: &mut trackers,
+                tracker_info: &torrent,
+            };
+            let res = match output::create_torrent(
+                &selected,
+                output.as_deref(),
+                &torrent.info.display_name(),
+                &storage,
+            ) {
+                Ok(Some(mut download)) => client
+                    .download_with_trackers_to_disk(
+                        &selected,
+                        &peers,
+                        reannounce,
+                        &download.content,
+                        &mut download.resume,
+                    )
+                    .and_then(|_| download.complete()),
+                Ok(None) => client.download_with_trackers_to(
+                    &selected,
+                    &peers,
+                    reannounce,
+                    &mut stdout().lock(),
+                ),
+                Err(err) => Err(err),
+            };
+            stop_announcing(&client, &mut trackers, &torrent);
+            res?;
+            remember_contributors(peer_cache.as_mut(), info_hash, &client)?;
+            if peer_report {
+                eprint!("{}", client.stats().peer_report());
+            }
+            if integrity_report {
+                eprint!("{}", client.stats().integrity);
             }
             Ok(())
         }
         Command::MagnetParse { magnet_link } => {
             let magnet_link = MagnetLink::parse(magnet_link).context("parsing magnet link")?;
-            println!("Tracker URL: {}", magnet_link.announce);
+            if let Some(announce) = &magnet_link.announce {
+                println!("Tracker URL: {announce}");
+            }
             println!("Info Hash: {}", hex::encode(magnet_link.info_hash));
             Ok(())
         }
         Command::MagnetHandshake { magnet_link } => {
             let magnet_link = MagnetLink::parse(magnet_link).context("parsing magnet link")?;
-            let client = BtClient::new();
-            let peers = client.get_peers(&magnet_link)?;
+            let client = bt_client(&dns, &magnet_link, vec![])?
+                .with_wire_trace(trace_wire)
+                .with_encryption(encryption);
+            let mut peers = direct_peers(&dns, &magnet_link);
+            if peers.is_empty() {
+                peers = client.get_peers(&magnet_link)?;
+            }
             let peer = peers.first().context("getting first peer")?;
             let response = client.handshake_with_magnet_extension_for_codecrafters(
                 magnet_link.info_hash,
@@ -108,13 +248,19 @@ fn main() -> anyhow::Result<()> {
         }
         Command::MagnetInfo { magnet_link } => {
             let magnet_link = MagnetLink::parse(magnet_link).context("parsing magnet link")?;
-            let client = BtClient::new();
-            let peers = client.get_peers(&magnet_link)?;
+            let client = bt_client(&dns, &magnet_link, vec![])?
+                .with_wire_trace(trace_wire)
+                .with_encryption(encryption);
+            let mut peers = direct_peers(&dns, &magnet_link);
+            if peers.is_empty() {
+                peers = client.get_peers(&magnet_link)?;
+            }
             let peer = peers.first().context("getting first peer")?;
-            let info: Info =
-                client.get_magnet_info(magnet_link.info_hash, *peer, Extension::MagnetLink)?;
+            let info: Info = magnet_info(&client, &magnet_link, *peer)?;
 
-            println!("Tracker URL: {}", magnet_link.announce);
+            if let Some(announce) = &magnet_link.announce {
+                println!("Tracker URL: {announce}");
+            }
             println!("Length: {}", info.total_len());
             println!("Info Hash: {}", hex::encode(magnet_link.info_hash));
             println!("Piece Length: {}", info.piece_length);
@@ -129,36 +275,513 @@ fn main() -> anyhow::Result<()> {
             output,
             magnet_link,
             start,
+            conflict,
+            trackers,
         } => {
             let magnet_link = MagnetLink::parse(magnet_link).context("parsing magnet link")?;
-            let client = BtClient::new();
-            let peers = client.get_peers(&magnet_link)?;
-            let peer = peers.first().context("getting first peer")?;
-            let info: Info =
-                client.get_magnet_info(magnet_link.info_hash, *peer, Extension::MagnetLink)?;
-            let content = client.download_piece(&(magnet_link, info), *peer, start)?;
-            match output {
-                Some(file) => std::fs::write(file, &content)?,
-                None => stdout().write_all(&content)?,
-            }
-            Ok(())
+            let client = bt_client(&dns, &magnet_link, extra_trackers(trackers)?)?
+                .with_wire_trace(trace_wire)
+                .with_encryption(encryption)
+                .with_corrupt_piece_dir(dump_corrupt_pieces.clone());
+            let info_hash = magnet_link.info_hash;
+            let mut trackers = client.tracker_manager(&magnet_link);
+            let peers = find_peers(
+                &client,
+                &magnet_link,
+                &mut trackers,
+                info_hash,
+                &direct_peers(&dns, &magnet_link),
+                peer_cache.as_ref(),
+            )?;
+            let info: Info = match magnet_info(&client, &magnet_link, peers[0]) {
+                Ok(info) => info,
+                Err(err) => {
+                    stop_announcing(&client, &mut trackers, &magnet_link);
+                    return Err(err);
+                }
+            };
+            let torrent_info = (magnet_link, info);
+            let piece = client.download_piece_from_peers(&torrent_info, &peers, start);
+            stop_announcing(&client, &mut trackers, &torrent_info.0);
+            let piece = piece?;
+            remember_peer(peer_cache.as_mut(), info_hash, piece.from_peer)?;
+            let storage = StorageArgs {
+                conflict,
+                ..Default::default()
+            };
+            output::write_download(&piece.data, output.as_deref(), "piece", &storage)
         }
         Command::MagnetDownload {
             output,
             magnet_link,
+            progress,
+            peer_report,
+            integrity_report,
+            stats,
+            storage,
+            selection,
+            trackers,
         } => {
             let magnet_link = MagnetLink::parse(magnet_link).context("parsing magnet link")?;
-            let client = BtClient::new();
-            let peers = client.get_peers(&magnet_link)?;
-            let peer = peers.first().context("getting first peer")?;
-            let info: Info =
-                client.get_magnet_info(magnet_link.info_hash, *peer, Extension::MagnetLink)?;
-            let content = client.download(&(magnet_link, info), *peer)?;
-            match output {
-                Some(file) => std::fs::write(file, &content)?,
-                None => stdout().write_all(&content)?,
+            let client = bt_client(&dns, &magnet_link, extra_trackers(trackers)?)?
+                .with_wire_trace(trace_wire)
+                .with_encryption(encryption)
+                .with_corrupt_piece_dir(dump_corrupt_pieces.clone())
+                .with_progress(progress)
+                .with_stats_interval(stats.then_some(STATS_INTERVAL));
+            let info_hash = magnet_link.info_hash;
+            let mut trackers = client.tracker_manager(&magnet_link);
+            let peers = find_peers(
+                &client,
+                &magnet_link,
+                &mut trackers,
+                info_hash,
+                &direct_peers(&dns, &magnet_link),
+                peer_cache.as_ref(),
+            )?;
+            let info: Info = match magnet_info(&client, &magnet_link, peers[0]) {
+                Ok(info) => info,
+                Err(err) => {
+                    stop_announcing(&client, &mut trackers, &magnet_link);
+                    return Err(err);
+                }
+            };
+            // the link's name, unless it could escape the output directory
+            let name = magnet_link
+                .display_name
+                .clone()
+                .filter(|i| file_paths::is_safe_component(i))
+                .unwrap_or_else(|| info.display_name());
+            let torrent_info = (magnet_link, info);
+            let selected = match Selected::new(
+                &torrent_info,
+                selection.files.as_ref(),
+                &selection.file_priorities,
+            ) {
+                Ok(selected) => selected.with_first_last_pieces(selection.first_last_pieces),
+                Err(err) => {
+                    stop_announcing(&client, &mut trackers, &torrent_info.0);
+                    return Err(err);
+                }
+            };
+            let reannounce = Reannounce {
+                tracker_manager: &mut trackers,
+                tracker_info: &torrent_info.0,
+            };
+            let res = match output::create_torrent(&selected, output.as_deref(), &name, &storage) {
+                Ok(Some(mut download)) => client
+                    .download_with_trackers_to_disk(
+                        &selected,
+                        &peers,
+                        reannounce,
+                        &download.content,
+                        &mut download.resume,
+                    )
+                    .and_then(|_| download.complete()),
+                Ok(None) => client.download_with_trackers_to(
+                    &selected,
+                    &peers,
+                    reannounce,
+                    &mut stdout().lock(),
+                ),
+                Err(err) => Err(err),
+            };
+            stop_announcing(&client, &mut trackers, &torrent_info.0);
+            res?;
+            remember_contributors(peer_cache.as_mut(), info_hash, &client)?;
+            if peer_report {
+                eprint!("{}", client.stats().peer_report());
+            }
+            if integrity_report {
+                eprint!("{}", client.stats().integrity);
+            }
+            Ok(())
+        }
+        Command::Doctor { target, trackers } => {
+            let extra_trackers = extra_trackers(trackers)?;
+            let checks = if target.starts_with("magnet:") {
+                let magnet_link = MagnetLink::parse(target).context("parsing magnet link")?;
+                let client = bt_client(&dns, &magnet_link, extra_trackers)?;
+                doctor::run(&client, &magnet_link, &dns)
+            } else {
+                let torrent =
+                    input::read_torrent(&reqwest::blocking::Client::new(), Path::new(&target))?;
+                let torrent: Torrent =
+                    serde_bencode::from_bytes(&torrent).context("parse torrent file")?;
+                let client = bt_client(&dns, &torrent, extra_trackers)?;
+                doctor::run(&client, &torrent, &dns)
+            };
+            for check in &checks {
+                print!("{check}");
+            }
+            let failed = checks
+                .iter()
+                .filter(|i| i.outcome == Outcome::Failed)
+                .count();
+            if failed > 0 {
+                bail!("{failed} checks failed");
             }
             Ok(())
         }
+        Command::Seed {
+            torrent,
+            path,
+            listen,
+            upload_slots,
+        } => {
+            let torrent = input::read_torrent(&reqwest::blocking::Client::new(), &torrent)?;
+            let torrent: Torrent =
+                serde_bencode::from_bytes(&torrent).context("parse torrent file")?;
+            let listener =
+                TcpListener::bind(listen).with_context(|| format!("listening on {listen}"))?;
+            BtClient::new()
+                .with_wire_trace(trace_wire)
+                .with_encryption(encryption)
+                .with_upload_slots(upload_slots)
+                .seed(&torrent, &path, &listener)
+        }
+        Command::Stream {
+            output,
+            torrent,
+            listen,
+            file,
+            storage,
+            trackers,
+        } => {
+            let torrent = input::read_torrent(&reqwest::blocking::Client::new(), &torrent)?;
+            let torrent: Torrent =
+                serde_bencode::from_bytes(&torrent).context("parse torrent file")?;
+            let file = match file {
+                Some(index) => torrent
+                    .files_info()
+                    .into_iter()
+                    .nth(index)
+                    .with_context(|| format!("no file {index} in the torrent"))?,
+                None => torrent
+                    .files_info()
+                    .into_iter()
+                    .max_by_key(|i| i.length)
+                    .context("no file in the torrent")?,
+            };
+            // the streamed file is downloaded first, in order
+            let selected = Selected::new(
+                &torrent,
+                None,
+                &[FilePriorityArg {
+                    index: file.index,
+                    priority: FilePriority::High,
+                }],
+            )?;
+            let name = torrent.info.display_name();
+            let output = output.unwrap_or_else(|| PathBuf::from(&name));
+            let mut download = output::create_torrent(&selected, Some(&output), &name, &storage)?
+                .context("streaming requires downloading to disk")?;
+            let written = Arc::new(WrittenPieces::new(torrent.pieces_count()));
+            let listener =
+                TcpListener::bind(listen).with_context(|| format!("listening on {listen}"))?;
+            eprintln!(
+                "Streaming {} on http://{}/",
+                file.path.display(),
+                listener.local_addr()?
+            );
+            let server = Arc::new(StreamServer::new(
+                file,
+                torrent.piece_length(),
+                DiskContent::new(&selected, download.path()),
+                written.clone(),
+            ));
+            let serving = thread::spawn({
+                let server = server.clone();
+                move || server.serve(&listener)
+            });
+            let client = bt_client(&dns, &torrent, extra_trackers(trackers)?)?
+                .with_wire_trace(trace_wire)
+                .with_encryption(encryption)
+                .with_corrupt_piece_dir(dump_corrupt_pieces.clone())
+                .with_written_pieces(written.clone());
+            let info_hash = torrent.info_hash()?;
+            let mut trackers = client.tracker_manager(&torrent);
+            let peers = find_peers(
+                &client,
+                &torrent,
+                &mut trackers,
+                info_hash,
+                &[],
+                peer_cache.as_ref(),
+            )?;
+            let reannounce = Reannounce {
+                tracker_manager: &mut trackers,
+                tracker_info: &torrent,
+            };
+            let res = client.download_with_trackers_to_disk(
+                &selected,
+                &peers,
+                reannounce,
+                &download.content,
+                &mut download.resume,
+            );
+            // requests for pieces that did not make it fail rather than wait forever
+            written.close();
+            stop_announcing(&client, &mut trackers, &torrent);
+            res?;
+            remember_contributors(peer_cache.as_mut(), info_hash, &client)?;
+            server.move_content(|| {
+                let destination = download.destination().to_path_buf();
+                download.complete()?;
+                Ok(DiskContent::new(&selected, &destination))
+            })?;
+            eprintln!("Download complete, still streaming until interrupted");
+            serving.join().expect("stream server panicked")
+        }
+        Command::Mount {
+            torrent,
+            mountpoint,
+            trackers,
+        } => {
+            let torrent = input::read_torrent(&reqwest::blocking::Client::new(), &torrent)?;
+            let torrent: Torrent =
+                serde_bencode::from_bytes(&torrent).context("parse torrent file")?;
+            let client = bt_client(&dns, &torrent, extra_trackers(trackers)?)?
+                .with_wire_trace(trace_wire)
+                .with_encryption(encryption)
+                .with_corrupt_piece_dir(dump_corrupt_pieces.clone());
+            let info_hash = torrent.info_hash()?;
+            let mut trackers = client.tracker_manager(&torrent);
+            let peers = find_peers(
+                &client,
+                &torrent,
+                &mut trackers,
+                info_hash,
+                &[],
+                peer_cache.as_ref(),
+            )?;
+            eprintln!(
+                "Mounting on {}, unmount with `umount {0}` to stop",
+                mountpoint.display()
+            );
+            let res = fuse::mount(
+                &torrent,
+                |index| {
+                    client
+                        .download_piece_from_peers(&torrent, &peers, index)
+                        .map(|i| i.data)
+                },
+                &mountpoint,
+            );
+            stop_announcing(&client, &mut trackers, &torrent);
+            res
+        }
+        Command::Verify { torrent, path } => {
+            let torrent = input::read_torrent(&reqwest::blocking::Client::new(), &torrent)?;
+            let torrent: Torrent =
+                serde_bencode::from_bytes(&torrent).context("parse torrent file")?;
+            let have =
+                verify::verify_pieces(&torrent, &path, &RustCryptoSha1, verify::default_threads())?;
+            for (index, ok) in have.iter().enumerate() {
+                println!("Piece {index}: {}", if *ok { "pass" } else { "fail" });
+            }
+            println!("Complete: {:.1}%", verify::completion(&torrent, &have));
+            let failed = have.iter().filter(|i| !**i).count();
+            if failed > 0 {
+                bail!("{failed} pieces failed verification");
+            }
+            Ok(())
+        }
+        Command::Edit {
+            output,
+            torrent,
+            edit,
+        } => {
+            let content = input::read_torrent(&reqwest::blocking::Client::new(), &torrent)?;
+            let output = match output {
+                Some(output) => output,
+                None if input::is_local_file(&torrent) => torrent,
+                None => bail!("an output is required to edit a torrent from stdin or a URL"),
+            };
+            let edit = TorrentEdit::from(edit);
+            let edited = torrent_edit::edit(&content, &edit)?;
+            if edit.private.is_some() {
+                logging::warn(
+                    "edit",
+                    "the private flag is part of the info dictionary, the info hash changed",
+                );
+            }
+            std::fs::write(output, edited).context("write torrent file")
+        }
+    }
+}
+
+/// A client resolving the host names of the trackers of `tracker_info` and of the extra
+/// trackers with `resolver`
+fn bt_client<I: TrackerInfo>(
+    resolver: &Resolver,
+    tracker_info: &I,
+    extra_trackers: Vec<Url>,
+) -> anyhow::Result<BtClient<reqwest::blocking::Client>> {
+    sized_bt_client(resolver, tracker_info, extra_trackers, DEFAULT_BLOCK_SIZE)
+}
+
+/// A client requesting blocks of `block_size` from peers
+fn sized_bt_client<I: TrackerInfo>(
+    resolver: &Resolver,
+    tracker_info: &I,
+    extra_trackers: Vec<Url>,
+    block_size: u32,
+) -> anyhow::Result<BtClient<reqwest::blocking::Client>> {
+    let urls = tracker_info
+        .announce_tiers()
+        .iter()
+        .flatten()
+        .filter_map(|i| Url::parse(i).ok())
+        .chain(extra_trackers.iter().cloned())
+        .collect::<Vec<_>>();
+    Ok(BtClientBuilder::with_client(resolver.http_client(&urls)?)
+        .with_block_size(block_size)
+        .build()
+        .with_resolver(resolver.clone())
+        .with_extra_trackers(extra_trackers))
+}
+
+/// Trackers given on the command line along with those from the trackers file, if any
+fn extra_trackers(trackers: TrackerArgs) -> anyhow::Result<Vec<Url>> {
+    let mut urls = trackers.extra_trackers;
+    if let Some(source) = &trackers.trackers_file {
+        urls.extend(tracker::load_tracker_list(
+            &reqwest::blocking::Client::new(),
+            source,
+        )?);
+    }
+    Ok(urls)
+}
+
+/// The magnet link's info, from its exact source when it has one and that works, from the peer
+/// otherwise
+fn magnet_info(
+    client: &BtClient<reqwest::blocking::Client>,
+    magnet_link: &MagnetLink,
+    peer: SocketAddr,
+) -> anyhow::Result<Info> {
+    if magnet_link.exact_source.is_some() {
+        match client.get_exact_source_info(magnet_link) {
+            Ok(info) => return Ok(info),
+            Err(err) => logging::warn(
+                "magnet",
+                &format!("exact source unusable, asking peer instead: {err:#}"),
+            ),
+        }
+    }
+    client.get_magnet_info(magnet_link.info_hash, peer, Extension::MagnetLink)
+}
+
+/// The peers to download from, at most [`MAX_DOWNLOAD_PEERS`]: `direct_peers` first, then
+/// cached peers answering the handshake, then peers from the trackers of `tracker_manager` or
+/// from the DHT when there is no tracker, each group in preferred order
+fn find_peers<I: TrackerInfo>(
+    client: &BtClient<reqwest::blocking::Client>,
+    tracker_info: &I,
+    tracker_manager: &mut TrackerManager,
+    info_hash: [u8; 20],
+    direct_peers: &[SocketAddr],
+    peer_cache: Option<&PeerCache>,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let mut peers = direct_peers.to_vec();
+    if let Some(peer_cache) = peer_cache {
+        let cached = peer_cache
+            .peers(info_hash, SystemTime::now())
+            .into_iter()
+            .filter(|i| !peers.contains(i))
+            .collect::<Vec<_>>();
+        peers.extend(client.select_peers(info_hash, &cached));
+    }
+    if peers.len() < MAX_DOWNLOAD_PEERS {
+        let found = if tracker_manager.tiers().is_empty() {
+            client.dht_peers(info_hash)
+        } else {
+            client.announce_to(tracker_manager, tracker_info)
+        };
+        match found {
+            Ok(found) => {
+                let found = found
+                    .into_iter()
+                    .filter(|i| !peers.contains(i))
+                    .collect::<Vec<_>>();
+                peers.extend(client.select_peers(info_hash, &found));
+            }
+            Err(err) if !peers.is_empty() => {
+                logging::warn("peers", &format!("using known peers only: {err:#}"))
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    peers.truncate(MAX_DOWNLOAD_PEERS);
+    if peers.is_empty() {
+        bail!("no peer answered the handshake");
+    }
+    Ok(peers)
+}
+
+/// The `x.pe` peers of `magnet_link`, leaving out those that do not resolve
+fn direct_peers(resolver: &Resolver, magnet_link: &MagnetLink) -> Vec<SocketAddr> {
+    magnet_link
+        .direct_peers
+        .iter()
+        .filter_map(|(host, port)| match resolver.resolve(host, *port) {
+            Ok(addresses) => addresses.first().copied(),
+            Err(err) => {
+                logging::warn("magnet", &format!("{err:#}"));
+                None
+            }
+        })
+        .collect()
+}
+
+/// Tells the trackers that were announced to that we are leaving
+fn stop_announcing<I: TrackerInfo>(
+    client: &BtClient<reqwest::blocking::Client>,
+    tracker_manager: &mut TrackerManager,
+    tracker_info: &I,
+) {
+    if let Err(err) =
+        client.announce_event_to(tracker_manager, tracker_info, AnnounceEvent::Stopped)
+    {
+        logging::warn("tracker", &format!("{err:#}"));
+    }
+}
+
+/// Records in the peer cache, if any, every peer that contributed to a successful download
+fn remember_contributors(
+    peer_cache: Option<&mut PeerCache>,
+    info_hash: [u8; 20],
+    client: &BtClient<reqwest::blocking::Client>,
+) -> anyhow::Result<()> {
+    match peer_cache {
+        Some(peer_cache) => {
+            let now = SystemTime::now();
+            for peer in client
+                .stats()
+                .peers
+                .values()
+                .filter(|i| i.bytes > 0 && !web_seed::is_web_seed(i.address))
+            {
+                peer_cache.record_good(info_hash, peer.address, now);
+            }
+            peer_cache.save()
+        }
+        None => Ok(()),
+    }
+}
+
+/// Records in the peer cache, if any, that `peer` provided verified data
+fn remember_peer(
+    peer_cache: Option<&mut PeerCache>,
+    info_hash: [u8; 20],
+    peer: SocketAddr,
+) -> anyhow::Result<()> {
+    match peer_cache {
+        Some(peer_cache) => {
+            peer_cache.record_good(info_hash, peer, SystemTime::now());
+            peer_cache.save()
+        }
+        None => Ok(()),
     }
 }
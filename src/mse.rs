@@ -0,0 +1,558 @@
+use std::{
+    fmt::{self, Debug},
+    fs::File,
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+
+use crate::sha1;
+
+/// Whether connections to peers are encrypted with Message Stream Encryption (BEP 8)
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq)]
+pub enum EncryptionPolicy {
+    /// Plain BitTorrent connections only
+    #[default]
+    Disable,
+    /// Encrypt when the peer supports it, fall back to plain connections otherwise
+    Prefer,
+    /// Encrypted connections only
+    Require,
+}
+
+/// The 768-bit prime of the Diffie-Hellman exchange, the generator being 2
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_LENGTH: usize = 96;
+/// Private keys are 160 bits, as recommended
+const PRIVATE_KEY_LENGTH: usize = 20;
+const MAX_PAD_LENGTH: usize = 512;
+const VERIFICATION_CONSTANT: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+/// How often to look again at a handshake that is partly received
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A connection to a peer, encrypted with RC4 once MSE negotiated it
+pub struct PeerStream {
+    inner: TcpStream,
+    /// Ciphers for what is received and what is sent
+    ciphers: Option<(Rc4, Rc4)>,
+    /// Data already received and decrypted during the MSE handshake
+    buffered: Vec<u8>,
+}
+
+impl PeerStream {
+    pub fn plain(inner: TcpStream) -> Self {
+        PeerStream {
+            inner,
+            ciphers: None,
+            buffered: Vec::new(),
+        }
+    }
+
+    /// The underlying connection, to set its options. Reading from or writing to it directly
+    /// would break the encryption.
+    pub fn tcp(&self) -> &TcpStream {
+        &self.inner
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+
+    /// Whether data received during the handshake is still to be read
+    pub fn has_buffered(&self) -> bool {
+        !self.buffered.is_empty()
+    }
+}
+
+impl Debug for PeerStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerStream")
+            .field("inner", &self.inner)
+            .field("encrypted", &self.is_encrypted())
+            .finish()
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.buffered.is_empty() {
+            let n = buf.len().min(self.buffered.len());
+            buf[..n].copy_from_slice(&self.buffered[..n]);
+            self.buffered.drain(..n);
+            return Ok(n);
+        }
+        let n = self.inner.read(buf)?;
+        if let Some((incoming, _)) = &mut self.ciphers {
+            incoming.apply(&mut buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.ciphers {
+            Some((_, outgoing)) => {
+                // the cipher moved on, all of it must be written
+                let mut encrypted = buf.to_vec();
+                outgoing.apply(&mut encrypted);
+                self.inner.write_all(&encrypted)?;
+                Ok(buf.len())
+            }
+            None => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Runs the MSE handshake as the side opening `stream` to a peer of the torrent `info_hash`.
+/// The peer may choose a plain connection after the handshake unless `require_rc4`.
+pub fn connect(
+    mut stream: TcpStream,
+    info_hash: [u8; 20],
+    require_rc4: bool,
+) -> anyhow::Result<PeerStream> {
+    let private_key = random_bytes(PRIVATE_KEY_LENGTH)?;
+    let mut message = public_key(&private_key);
+    message.extend(random_bytes(random_pad_length()?)?);
+    stream.write_all(&message)?;
+
+    let mut peer_key = [0u8; KEY_LENGTH];
+    stream
+        .read_exact(&mut peer_key)
+        .context("reading public key from peer")?;
+    let secret = shared_secret(&private_key, &peer_key);
+
+    let mut outgoing = Rc4::with_key(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut incoming = Rc4::with_key(&hash(&[b"keyB", &secret, &info_hash]));
+    let provide = if require_rc4 {
+        CRYPTO_RC4
+    } else {
+        CRYPTO_RC4 | CRYPTO_PLAINTEXT
+    };
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(hash(&[b"req2", &info_hash]), hash(&[b"req3", &secret])));
+    let mut encrypted = VERIFICATION_CONSTANT.to_vec();
+    encrypted.extend(provide.to_be_bytes());
+    // no padding and no initial payload, the BitTorrent handshake follows the negotiation
+    encrypted.extend([0, 0, 0, 0]);
+    outgoing.apply(&mut encrypted);
+    message.extend(encrypted);
+    stream.write_all(&message)?;
+
+    // the peer's padding ends where the encrypted verification constant starts
+    let mut marker = VERIFICATION_CONSTANT;
+    incoming.apply(&mut marker);
+    sync_on(&mut stream, &marker).context("looking for the peer's verification constant")?;
+
+    let mut selection = [0u8; 6];
+    stream.read_exact(&mut selection)?;
+    incoming.apply(&mut selection);
+    let select = u32::from_be_bytes(selection[..4].try_into().expect("cannot fail"));
+    let pad_length = u16::from_be_bytes(selection[4..].try_into().expect("cannot fail")) as usize;
+    if pad_length > MAX_PAD_LENGTH {
+        bail!("peer sent a padding of {pad_length} bytes");
+    }
+    let mut pad = vec![0u8; pad_length];
+    stream.read_exact(&mut pad)?;
+    incoming.apply(&mut pad);
+
+    let ciphers = match select {
+        CRYPTO_RC4 => Some((incoming, outgoing)),
+        CRYPTO_PLAINTEXT if !require_rc4 => None,
+        _ => bail!("peer selected unsupported encryption method {select:#x}"),
+    };
+    Ok(PeerStream {
+        inner: stream,
+        ciphers,
+        buffered: Vec::new(),
+    })
+}
+
+/// Runs the MSE handshake as the side `stream` was opened to, for the torrent `info_hash`.
+/// RC4 is selected whenever the peer provides it, and a plain connection is only accepted
+/// when `allow_plaintext`.
+pub fn accept(
+    mut stream: TcpStream,
+    info_hash: [u8; 20],
+    allow_plaintext: bool,
+) -> anyhow::Result<PeerStream> {
+    let mut peer_key = [0u8; KEY_LENGTH];
+    stream
+        .read_exact(&mut peer_key)
+        .context("reading public key from peer")?;
+    let private_key = random_bytes(PRIVATE_KEY_LENGTH)?;
+    let mut message = public_key(&private_key);
+    message.extend(random_bytes(random_pad_length()?)?);
+    stream.write_all(&message)?;
+    let secret = shared_secret(&private_key, &peer_key);
+
+    sync_on(&mut stream, &hash(&[b"req1", &secret]))
+        .context("looking for the peer's synchronisation hash")?;
+    let mut skey_hash = [0u8; 20];
+    stream.read_exact(&mut skey_hash)?;
+    if xor(skey_hash, hash(&[b"req3", &secret])) != hash(&[b"req2", &info_hash]) {
+        bail!("peer asked for another torrent");
+    }
+
+    let mut incoming = Rc4::with_key(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut outgoing = Rc4::with_key(&hash(&[b"keyB", &secret, &info_hash]));
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header)?;
+    incoming.apply(&mut header);
+    if header[..8] != VERIFICATION_CONSTANT {
+        bail!("invalid verification constant");
+    }
+    let provide = u32::from_be_bytes(header[8..12].try_into().expect("cannot fail"));
+    let pad_length = u16::from_be_bytes(header[12..].try_into().expect("cannot fail")) as usize;
+    if pad_length > MAX_PAD_LENGTH {
+        bail!("peer sent a padding of {pad_length} bytes");
+    }
+    let mut pad = vec![0u8; pad_length + 2];
+    stream.read_exact(&mut pad)?;
+    incoming.apply(&mut pad);
+    let initial_length = u16::from_be_bytes(pad[pad_length..].try_into().expect("cannot fail"));
+    let mut initial_payload = vec![0u8; initial_length as usize];
+    stream.read_exact(&mut initial_payload)?;
+    incoming.apply(&mut initial_payload);
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && allow_plaintext {
+        CRYPTO_PLAINTEXT
+    } else {
+        bail!("peer provided no acceptable encryption method ({provide:#x})");
+    };
+    let mut reply = VERIFICATION_CONSTANT.to_vec();
+    reply.extend(select.to_be_bytes());
+    reply.extend([0, 0]);
+    outgoing.apply(&mut reply);
+    stream.write_all(&reply)?;
+
+    Ok(PeerStream {
+        inner: stream,
+        ciphers: (select == CRYPTO_RC4).then_some((incoming, outgoing)),
+        buffered: initial_payload,
+    })
+}
+
+/// Whether the peer that connected to `stream` starts with a plain BitTorrent handshake rather
+/// than an MSE one, without consuming anything. Gives up when the first 20 bytes take longer than
+/// `timeout` to arrive.
+pub fn is_plain_handshake(stream: &TcpStream, timeout: Duration) -> anyhow::Result<bool> {
+    const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 20];
+    loop {
+        // blocks until something is buffered, but not once part of the handshake is
+        let n = stream.peek(&mut buf)?;
+        if n == 0 {
+            bail!("connection closed by peer");
+        }
+        if buf[..n] != PROTOCOL[..n] {
+            return Ok(false);
+        }
+        if n == PROTOCOL.len() {
+            return Ok(true);
+        }
+        if Instant::now() >= deadline {
+            bail!("only {n} bytes of handshake received in {timeout:?}");
+        }
+        thread::sleep(PEEK_INTERVAL);
+    }
+}
+
+/// Reads from `stream` until `marker` was read, after at most `MAX_PAD_LENGTH` other bytes
+fn sync_on(stream: &mut TcpStream, marker: &[u8]) -> anyhow::Result<()> {
+    let mut window = Vec::with_capacity(MAX_PAD_LENGTH + marker.len());
+    let mut byte = [0u8; 1];
+    while window.len() < MAX_PAD_LENGTH + marker.len() {
+        stream.read_exact(&mut byte)?;
+        window.push(byte[0]);
+        if window.ends_with(marker) {
+            return Ok(());
+        }
+    }
+    bail!("not found after {} bytes", window.len())
+}
+
+fn public_key(private_key: &[u8]) -> Vec<u8> {
+    let prime = BigUint::from_hex(PRIME);
+    BigUint::from_u32(2)
+        .modpow(&BigUint::from_be_bytes(private_key), &prime)
+        .to_be_bytes(KEY_LENGTH)
+}
+
+fn shared_secret(private_key: &[u8], peer_key: &[u8]) -> Vec<u8> {
+    let prime = BigUint::from_hex(PRIME);
+    BigUint::from_be_bytes(peer_key)
+        .modpow(&BigUint::from_be_bytes(private_key), &prime)
+        .to_be_bytes(KEY_LENGTH)
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    sha1::hash(&parts.concat())
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+fn random_pad_length() -> anyhow::Result<usize> {
+    Ok(random_bytes(2)?
+        .iter()
+        .fold(0usize, |acc, i| acc << 8 | *i as usize)
+        % (MAX_PAD_LENGTH + 1))
+}
+
+/// Bytes from the operating system's secure random number generator, as private keys must not be
+/// guessed
+fn random_bytes(count: usize) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![0; count];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .context("reading random bytes")?;
+    Ok(bytes)
+}
+
+/// The RC4 stream cipher, used both ways by MSE
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state: [u8; 256] = std::array::from_fn(|i| i as u8);
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    /// A cipher for MSE, which discards the first 1024 bytes of the key stream
+    fn with_key(key: &[u8]) -> Self {
+        let mut rc4 = Rc4::new(key);
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    /// Encrypts or decrypts `data` in place
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+}
+
+/// Just enough unsigned big integer arithmetic for the Diffie-Hellman exchange, with little
+/// endian 32-bit limbs
+#[derive(Debug, Clone, PartialEq)]
+struct BigUint(Vec<u32>);
+
+impl BigUint {
+    fn from_u32(value: u32) -> Self {
+        BigUint(vec![value])
+    }
+
+    fn from_be_bytes(bytes: &[u8]) -> Self {
+        let limbs = bytes
+            .rchunks(4)
+            .map(|chunk| chunk.iter().fold(0u32, |acc, i| acc << 8 | *i as u32))
+            .collect();
+        BigUint(limbs)
+    }
+
+    fn from_hex(hex: &str) -> Self {
+        BigUint::from_be_bytes(&hex::decode(hex).expect("valid hex"))
+    }
+
+    /// The number on `length` big endian bytes, truncated if it does not fit
+    fn to_be_bytes(&self, length: usize) -> Vec<u8> {
+        let mut bytes = self
+            .0
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .chain(std::iter::repeat(0))
+            .take(length)
+            .collect::<Vec<_>>();
+        bytes.reverse();
+        bytes
+    }
+
+    fn bits(&self) -> usize {
+        self.0.iter().rposition(|i| *i != 0).map_or(0, |top| {
+            top * 32 + 32 - self.0[top].leading_zeros() as usize
+        })
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0
+            .get(index / 32)
+            .is_some_and(|i| i >> (index % 32) & 1 == 1)
+    }
+
+    fn mul(&self, other: &BigUint) -> BigUint {
+        let mut limbs = vec![0u32; self.0.len() + other.0.len()];
+        for (i, a) in self.0.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, b) in other.0.iter().enumerate() {
+                let current = limbs[i + j] as u64 + *a as u64 * *b as u64 + carry;
+                limbs[i + j] = current as u32;
+                carry = current >> 32;
+            }
+            limbs[i + other.0.len()] = carry as u32;
+        }
+        BigUint(limbs)
+    }
+
+    /// The remainder of the division by `modulus`, bit by bit
+    fn rem(&self, modulus: &BigUint) -> BigUint {
+        let mut remainder = vec![0u32; modulus.0.len() + 1];
+        for index in (0..self.bits()).rev() {
+            let mut carry = self.bit(index) as u32;
+            for limb in remainder.iter_mut() {
+                let next = *limb >> 31;
+                *limb = *limb << 1 | carry;
+                carry = next;
+            }
+            if !less_than(&remainder, &modulus.0) {
+                subtract(&mut remainder, &modulus.0);
+            }
+        }
+        while remainder.len() > 1 && remainder.last() == Some(&0) {
+            remainder.pop();
+        }
+        BigUint(remainder)
+    }
+
+    fn modpow(&self, exponent: &BigUint, modulus: &BigUint) -> BigUint {
+        let base = self.rem(modulus);
+        let mut result = BigUint::from_u32(1);
+        for index in (0..exponent.bits()).rev() {
+            result = result.mul(&result).rem(modulus);
+            if exponent.bit(index) {
+                result = result.mul(&base).rem(modulus);
+            }
+        }
+        result
+    }
+}
+
+/// Compares little endian limbs, of any lengths
+fn less_than(a: &[u32], b: &[u32]) -> bool {
+    let limb = |n: &[u32], i: usize| n.get(i).copied().unwrap_or(0);
+    for i in (0..a.len().max(b.len())).rev() {
+        if limb(a, i) != limb(b, i) {
+            return limb(a, i) < limb(b, i);
+        }
+    }
+    false
+}
+
+/// Subtracts `b` from `a`, which must be at least as large
+fn subtract(a: &mut [u32], b: &[u32]) {
+    let mut borrow = 0i64;
+    for (i, limb) in a.iter_mut().enumerate() {
+        let current = *limb as i64 - b.get(i).copied().unwrap_or(0) as i64 - borrow;
+        borrow = (current < 0) as i64;
+        *limb = current.rem_euclid(1 << 32) as u32;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{public_key, random_bytes, shared_secret, BigUint, Rc4};
+
+    #[test]
+    fn rc4_test_vector() {
+        let mut data = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut data);
+
+        assert_eq!(hex::decode("bbf316e8d940af0ad3").unwrap(), data);
+    }
+
+    #[test]
+    fn diffie_hellman_agrees_on_a_secret() -> anyhow::Result<()> {
+        assert_eq!(
+            BigUint::from_u32(445),
+            BigUint::from_u32(4).modpow(&BigUint::from_u32(13), &BigUint::from_u32(497))
+        );
+
+        let (a, b) = (random_bytes(20)?, random_bytes(20)?);
+        let secret = shared_secret(&a, &public_key(&b));
+
+        assert_ne!(a, b);
+        assert_eq!(secret, shared_secret(&b, &public_key(&a)));
+        assert_eq!(96, secret.len());
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_connection() -> anyhow::Result<()> {
+        let info_hash = [7u8; 20];
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let responder = thread::spawn(move || -> anyhow::Result<Vec<u8>> {
+            let (stream, _) = listener.accept()?;
+            assert!(!super::is_plain_handshake(&stream, Duration::from_secs(5))?);
+            let mut stream = super::accept(stream, info_hash, false)?;
+            assert!(stream.is_encrypted());
+            let mut buf = vec![0u8; 5];
+            stream.read_exact(&mut buf)?;
+            stream.write_all(b"world")?;
+            Ok(buf)
+        });
+
+        let mut stream = super::connect(TcpStream::connect(address)?, info_hash, true)?;
+        assert!(stream.is_encrypted());
+        stream.write_all(b"hello")?;
+        let mut buf = vec![0u8; 5];
+        stream.read_exact(&mut buf)?;
+
+        assert_eq!(b"world".to_vec(), buf);
+        assert_eq!(b"hello".to_vec(), responder.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn partial_handshake_times_out() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut peer = TcpStream::connect(listener.local_addr()?)?;
+        let (stream, _) = listener.accept()?;
+        peer.write_all(b"\x13BitTorrent")?;
+
+        let started = Instant::now();
+        assert!(super::is_plain_handshake(&stream, Duration::from_millis(100)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        peer.write_all(b" protocol")?;
+        assert!(super::is_plain_handshake(
+            &stream,
+            Duration::from_millis(100)
+        )?);
+        Ok(())
+    }
+}
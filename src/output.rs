@@ -0,0 +1,630 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{stdout, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    cli::{ConflictPolicy, Preallocation, StorageArgs},
+    file_paths::{self, PathRules},
+    resume::Resume,
+    torrent::{FileInfo, Keys},
+    torrent_info::TorrentInfo,
+    verify::DiskContent,
+};
+
+/// Appended to the name of downloads until they are complete
+const PART_EXTENSION: &str = ".part";
+
+/// Writes downloaded content to its destination: `output` if given, in the complete directory if
+/// one is configured (named after `output` or `name`), or stdout otherwise. The content lands in
+/// a `.part` file first, in the incomplete directory if any, and is then moved (or hard-linked) to
+/// its destination. An existing destination is handled according to the storage's conflict policy.
+pub fn write_download(
+    content: &[u8],
+    output: Option<&Path>,
+    name: &str,
+    storage: &StorageArgs,
+) -> anyhow::Result<()> {
+    store(content, output, name, storage, |path| {
+        write_file(path, content, storage.preallocation)
+    })
+}
+
+/// Writes the downloaded content of `torrent_info` like [`write_download`]. The selected files of
+/// multi-file torrents are written below the destination, which is a directory, their paths
+/// sanitized for the platform.
+pub fn write_torrent<TI: TorrentInfo>(
+    content: &[u8],
+    torrent_info: &TI,
+    output: Option<&Path>,
+    name: &str,
+    storage: &StorageArgs,
+) -> anyhow::Result<()> {
+    let info = torrent_info.info();
+    if let Keys::SingleFile { .. } = info.keys {
+        return write_download(content, output, name, storage);
+    }
+    let selected = torrent_info.selected_files();
+    let files = torrent_info
+        .files_info()
+        .into_iter()
+        .filter(|i| selected.as_ref().is_none_or(|s| s.contains(&i.index)))
+        .collect();
+    let (files, _) = file_paths::sanitize_files(files, PathRules::native());
+    store(content, output, name, storage, |dir| {
+        write_files(
+            dir,
+            &info.display_name(),
+            content,
+            &files,
+            storage.preallocation,
+        )
+    })
+}
+
+/// A download being written to disk, at its destination or in the incomplete directory
+pub struct DiskDownload {
+    pub content: DiskContent,
+    pub resume: Resume,
+    path: PathBuf,
+    destination: PathBuf,
+    link: bool,
+}
+
+impl DiskDownload {
+    /// Where the download is written until complete
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Where the download is moved once complete
+    pub fn destination(&self) -> &Path {
+        &self.destination
+    }
+
+    /// Drops the resume data and moves (or hard-links) the download to its destination
+    pub fn complete(self) -> anyhow::Result<()> {
+        self.resume.remove()?;
+        complete(&self.path, &self.destination, self.link)
+    }
+}
+
+/// Creates the files of `torrent_info` where [`write_torrent`] would write them, for its pieces to
+/// be written as they are downloaded. An interrupted download found there, as told by its resume
+/// data, is picked up instead, regardless of the conflict policy. `None` when the download goes
+/// to stdout.
+pub fn create_torrent<TI: TorrentInfo>(
+    torrent_info: &TI,
+    output: Option<&Path>,
+    name: &str,
+    storage: &StorageArgs,
+) -> anyhow::Result<Option<DiskDownload>> {
+    let Some(destination) = destination(output, name, storage)? else {
+        return Ok(None);
+    };
+    let path = download_path(&destination, storage)?;
+    if let Some(resume) = Resume::load(torrent_info, &path)? {
+        let content = DiskContent::new(torrent_info, &path);
+        content
+            .create_missing(storage.preallocation)
+            .context("reopening output")?;
+        return Ok(Some(DiskDownload {
+            content,
+            resume,
+            path,
+            destination,
+            link: storage.link_complete,
+        }));
+    }
+    let destination = resolve_conflict(destination, storage.conflict_policy())?;
+    let path = download_path(&destination, storage)?;
+    create_parent_dir(&path)?;
+    let content = DiskContent::new(torrent_info, &path);
+    content
+        .create(storage.preallocation)
+        .context("creating output")?;
+    let mut resume = Resume::new(torrent_info, &path)?;
+    resume.save()?;
+    Ok(Some(DiskDownload {
+        content,
+        resume,
+        path,
+        destination,
+        link: storage.link_complete,
+    }))
+}
+
+/// Where a download to `destination` is written until complete: a `.part` file (or directory)
+/// next to it, or in the incomplete directory if any, so that nothing half-written is ever found
+/// at the destination
+fn download_path(destination: &Path, storage: &StorageArgs) -> anyhow::Result<PathBuf> {
+    let mut file_name = destination
+        .file_name()
+        .context("destination has no file name")?
+        .to_owned();
+    file_name.push(PART_EXTENSION);
+    Ok(match &storage.incomplete_dir {
+        Some(incomplete_dir) => {
+            fs::create_dir_all(incomplete_dir).context("creating incomplete directory")?;
+            incomplete_dir.join(file_name)
+        }
+        None => destination.with_file_name(file_name),
+    })
+}
+
+/// Stores content at its destination, `write` writing it at the path it is given
+fn store(
+    content: &[u8],
+    output: Option<&Path>,
+    name: &str,
+    storage: &StorageArgs,
+    write: impl FnOnce(&Path) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let Some(destination) = destination(output, name, storage)? else {
+        stdout().write_all(content)?;
+        return Ok(());
+    };
+    let destination = resolve_conflict(destination, storage.conflict_policy())?;
+    let incomplete = download_path(&destination, storage)?;
+    create_parent_dir(&incomplete)?;
+    write(&incomplete).context("writing incomplete download")?;
+    complete(&incomplete, &destination, storage.link_complete)
+}
+
+/// Writes each of `files` below `dir`, which stands for the directory named `name` their paths
+/// start with, and takes their bytes from the torrent's `content`
+fn write_files(
+    dir: &Path,
+    name: &str,
+    content: &[u8],
+    files: &[FileInfo],
+    preallocation: Preallocation,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    for file in files {
+        let path = dir.join(file.path.strip_prefix(name).unwrap_or(&file.path));
+        create_parent_dir(&path)?;
+        let bytes = content
+            .get(file.offset as usize..(file.offset + file.length) as usize)
+            .with_context(|| format!("content too short for {}", file.path.display()))?;
+        write_file(&path, bytes, preallocation)?;
+    }
+    Ok(())
+}
+
+fn write_file(path: &Path, content: &[u8], preallocation: Preallocation) -> anyhow::Result<()> {
+    let mut file = open_preallocated(path, content.len() as u64, preallocation)?;
+    file.write_all(content)?;
+    file.set_len(content.len() as u64)?;
+    Ok(())
+}
+
+/// Creates (or truncates) a file and reserves `len` bytes for it according to `preallocation`
+pub fn open_preallocated(
+    path: &Path,
+    len: u64,
+    preallocation: Preallocation,
+) -> anyhow::Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("creating {}", path.display()))?;
+    match preallocation {
+        Preallocation::Full => {
+            static CHUNK: [u8; 64 * 1024] = [0; 64 * 1024];
+            let mut remaining = len;
+            while remaining > 0 {
+                let chunk = remaining.min(CHUNK.len() as u64) as usize;
+                file.write_all(&CHUNK[..chunk])
+                    .context("preallocating file")?;
+                remaining -= chunk as u64;
+            }
+            file.sync_all().context("preallocating file")?;
+            file.rewind()?;
+        }
+        Preallocation::Sparse => file.set_len(len).context("setting file length")?,
+        Preallocation::None => {}
+    }
+    Ok(file)
+}
+
+/// Moves a finished file or directory from where it was written to its destination, which is
+/// atomic on a single filesystem. When linking, the destination is hard-linked to it instead and
+/// it only loses its `.part` extension.
+fn complete(incomplete: &Path, destination: &Path, link: bool) -> anyhow::Result<()> {
+    create_parent_dir(destination)?;
+    if link {
+        copy_tree(incomplete, destination, |from, to| {
+            fs::hard_link(from, to).map(|_| ())
+        })
+        .context("hard-linking completed download")?;
+        let name = incomplete
+            .file_name()
+            .and_then(|i| i.to_str())
+            .and_then(|i| i.strip_suffix(PART_EXTENSION));
+        if let Some(name) = name {
+            fs::rename(incomplete, incomplete.with_file_name(name))
+                .context("renaming completed download")?;
+        }
+        return Ok(());
+    }
+    if fs::rename(incomplete, destination).is_err() {
+        // Most likely on another filesystem, fall back to copying
+        copy_tree(incomplete, destination, |from, to| {
+            fs::copy(from, to).map(|_| ())
+        })
+        .context("copying completed download")?;
+        if incomplete.is_dir() {
+            fs::remove_dir_all(incomplete)
+        } else {
+            fs::remove_file(incomplete)
+        }
+        .context("removing incomplete download")?;
+    }
+    Ok(())
+}
+
+/// Recreates `from` at `to`, directories being created and files handed to `copy`
+fn copy_tree(
+    from: &Path,
+    to: &Path,
+    copy: impl Fn(&Path, &Path) -> std::io::Result<()> + Copy,
+) -> anyhow::Result<()> {
+    if !from.is_dir() {
+        return copy(from, to).with_context(|| format!("copying {}", from.display()));
+    }
+    fs::create_dir_all(to).with_context(|| format!("creating {}", to.display()))?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_tree(&entry.path(), &to.join(entry.file_name()), copy)?;
+    }
+    Ok(())
+}
+
+fn create_parent_dir(path: &Path) -> anyhow::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            fs::create_dir_all(parent).context("creating output directory")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Applies `policy` when something already exists at `path`, returning the path to write to
+pub fn resolve_conflict(path: PathBuf, policy: ConflictPolicy) -> anyhow::Result<PathBuf> {
+    if !path.exists() {
+        return Ok(path);
+    }
+    match policy {
+        ConflictPolicy::Overwrite => Ok(path),
+        ConflictPolicy::NoClobber => bail!("{} already exists", path.display()),
+        ConflictPolicy::Rename => {
+            let stem = path
+                .file_stem()
+                .context("destination has no file name")?
+                .to_string_lossy()
+                .into_owned();
+            let extension = path.extension().map(|i| i.to_string_lossy().into_owned());
+            (1..)
+                .map(|i| {
+                    path.with_file_name(match &extension {
+                        Some(extension) => format!("{stem} ({i}).{extension}"),
+                        None => format!("{stem} ({i})"),
+                    })
+                })
+                .find(|i| !i.exists())
+                .context("no free name for destination")
+        }
+    }
+}
+
+/// Whether a download with this output and storage goes to stdout
+pub fn to_stdout(output: Option<&Path>, storage: &StorageArgs) -> bool {
+    output.is_none() && storage.complete_dir.is_none()
+}
+
+/// Path a download ends up at, `None` when it goes to stdout
+fn destination(
+    output: Option<&Path>,
+    name: &str,
+    storage: &StorageArgs,
+) -> anyhow::Result<Option<PathBuf>> {
+    Ok(match (&storage.complete_dir, output) {
+        (Some(dir), Some(output)) => {
+            Some(dir.join(output.file_name().context("output has no file name")?))
+        }
+        (Some(dir), None) => Some(dir.join(name)),
+        (None, output) => output.map(Path::to_path_buf),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{
+        cli::{ConflictArgs, Preallocation, StorageArgs},
+        file_selection::{FileList, Selected},
+        torrent::Torrent,
+    };
+
+    use super::{create_torrent, open_preallocated, to_stdout, write_download, write_torrent};
+
+    fn multi_file_torrent() -> anyhow::Result<Torrent> {
+        let mut torrent_content = Vec::from(&b"d8:announce31:http://127.0.0.1:44381/announce4:infod5:filesld6:lengthi0e4:pathl5:a.nfoeed6:lengthi100e4:pathl3:dir5:b.bineed6:lengthi28e4:pathl5:c.bineee4:name3:top12:piece lengthi64e6:pieces40:"[..]);
+        torrent_content.extend_from_slice(&[0; 40]);
+        torrent_content.extend_from_slice(b"ee");
+        Torrent::from_bytes(&torrent_content)
+    }
+
+    #[test]
+    fn stdout_only_without_output_or_complete_dir() {
+        let storage = StorageArgs {
+            complete_dir: Some("complete".into()),
+            ..Default::default()
+        };
+
+        assert!(to_stdout(None, &StorageArgs::default()));
+        assert!(!to_stdout(
+            Some("out.bin".as_ref()),
+            &StorageArgs::default()
+        ));
+        assert!(!to_stdout(None, &storage));
+    }
+
+    #[test]
+    fn no_clobber_keeps_existing_file() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("out.bin");
+        fs::write(&output, b"old")?;
+        let storage = StorageArgs {
+            conflict: ConflictArgs {
+                no_clobber: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert!(write_download(b"foo", Some(&output), "name", &storage).is_err());
+        assert_eq!(b"old".to_vec(), fs::read(output)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rename_on_conflict() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("out.bin");
+        fs::write(&output, b"old")?;
+        fs::write(dir.path().join("out (1).bin"), b"old")?;
+        let storage = StorageArgs {
+            conflict: ConflictArgs {
+                rename_on_conflict: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        write_download(b"foo", Some(&output), "name", &storage)?;
+
+        assert_eq!(b"old".to_vec(), fs::read(&output)?);
+        assert_eq!(b"foo".to_vec(), fs::read(dir.path().join("out (2).bin"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn write_to_output() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("out.bin");
+
+        write_download(b"foo", Some(&output), "name", &StorageArgs::default())?;
+
+        assert_eq!(b"foo".to_vec(), fs::read(output)?);
+        Ok(())
+    }
+
+    #[test]
+    fn move_from_incomplete_to_complete_dir() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = StorageArgs {
+            incomplete_dir: Some(dir.path().join("incomplete")),
+            complete_dir: Some(dir.path().join("complete")),
+            ..Default::default()
+        };
+
+        write_download(b"foo", None, "name.iso", &storage)?;
+
+        assert!(!dir.path().join("incomplete/name.iso").exists());
+        assert_eq!(
+            b"foo".to_vec(),
+            fs::read(dir.path().join("complete/name.iso"))?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hard_link_to_complete_dir() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = StorageArgs {
+            incomplete_dir: Some(dir.path().join("incomplete")),
+            complete_dir: Some(dir.path().join("complete")),
+            link_complete: true,
+            ..Default::default()
+        };
+
+        write_download(b"foo", Some("other.iso".as_ref()), "name.iso", &storage)?;
+
+        assert_eq!(
+            b"foo".to_vec(),
+            fs::read(dir.path().join("incomplete/other.iso"))?
+        );
+        assert_eq!(
+            b"foo".to_vec(),
+            fs::read(dir.path().join("complete/other.iso"))?
+        );
+        assert!(!dir.path().join("incomplete/other.iso.part").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn write_multi_file_tree() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let content = (0..128).collect::<Vec<u8>>();
+        let output = dir.path().join("out");
+
+        write_torrent(
+            &content,
+            &multi_file_torrent()?,
+            Some(&output),
+            "top",
+            &StorageArgs::default(),
+        )?;
+
+        assert_eq!(Vec::<u8>::new(), fs::read(output.join("a.nfo"))?);
+        assert_eq!(content[..100].to_vec(), fs::read(output.join("dir/b.bin"))?);
+        assert_eq!(content[100..].to_vec(), fs::read(output.join("c.bin"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn write_selected_files_only() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let content = (0..128).collect::<Vec<u8>>();
+        let output = dir.path().join("out");
+        let torrent = multi_file_torrent()?;
+
+        write_torrent(
+            &content,
+            &Selected::new(&torrent, Some(&"2".parse::<FileList>()?), &[])?,
+            Some(&output),
+            "top",
+            &StorageArgs::default(),
+        )?;
+
+        assert!(!output.join("a.nfo").exists());
+        assert!(!output.join("dir/b.bin").exists());
+        assert_eq!(content[100..].to_vec(), fs::read(output.join("c.bin"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn create_files_in_incomplete_dir_then_complete() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = StorageArgs {
+            incomplete_dir: Some(dir.path().join("incomplete")),
+            complete_dir: Some(dir.path().join("complete")),
+            ..Default::default()
+        };
+        let content = (0..128).collect::<Vec<u8>>();
+
+        let download = create_torrent(&multi_file_torrent()?, None, "top", &storage)?
+            .expect("written to disk");
+        download.content.write_at(64, &content[64..])?;
+        download.content.write_at(0, &content[..64])?;
+        download.complete()?;
+
+        assert!(!dir.path().join("incomplete/top.part").exists());
+        let output = dir.path().join("complete/top");
+        assert_eq!(Vec::<u8>::new(), fs::read(output.join("a.nfo"))?);
+        assert_eq!(content[..100].to_vec(), fs::read(output.join("dir/b.bin"))?);
+        assert_eq!(content[100..].to_vec(), fs::read(output.join("c.bin"))?);
+        assert!(
+            create_torrent(&multi_file_torrent()?, None, "top", &StorageArgs::default())?.is_none()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn resume_interrupted_download_regardless_of_conflict_policy() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("out");
+        let storage = StorageArgs {
+            conflict: ConflictArgs {
+                no_clobber: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let torrent = multi_file_torrent()?;
+
+        let download =
+            create_torrent(&torrent, Some(&output), "top", &storage)?.expect("written to disk");
+        download.content.write_at(0, b"foo")?;
+        let mut download =
+            create_torrent(&torrent, Some(&output), "top", &storage)?.expect("written to disk");
+
+        // the files are left as they were
+        let part = dir.path().join("out.part");
+        assert_eq!(b"foo".to_vec(), fs::read(part.join("dir/b.bin"))?);
+        assert!(!output.exists());
+        download.resume.record(0, &download.content)?;
+        download.complete()?;
+        assert!(!dir.path().join("out.part.resume").exists());
+        assert!(!part.exists());
+        assert_eq!(b"foo".to_vec(), fs::read(output.join("dir/b.bin"))?[..3]);
+        assert!(create_torrent(&torrent, Some(&output), "top", &storage).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn move_multi_file_tree_to_complete_dir() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let content = (0..128).collect::<Vec<u8>>();
+        for link_complete in [false, true] {
+            let storage = StorageArgs {
+                incomplete_dir: Some(dir.path().join("incomplete")),
+                complete_dir: Some(dir.path().join(format!("complete-{link_complete}"))),
+                link_complete,
+                ..Default::default()
+            };
+
+            write_torrent(&content, &multi_file_torrent()?, None, "top", &storage)?;
+
+            let complete = dir.path().join(format!("complete-{link_complete}/top"));
+            assert_eq!(
+                content[..100].to_vec(),
+                fs::read(complete.join("dir/b.bin"))?
+            );
+            assert_eq!(
+                link_complete,
+                dir.path().join("incomplete/top/dir/b.bin").exists()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn preallocation_modes() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        for (preallocation, expected_len) in [
+            (Preallocation::Full, 100_000),
+            (Preallocation::Sparse, 100_000),
+            (Preallocation::None, 0),
+        ] {
+            let path = dir.path().join(format!("{preallocation:?}"));
+            open_preallocated(&path, 100_000, preallocation)?;
+            assert_eq!(expected_len, fs::metadata(&path)?.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn write_with_full_preallocation() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("out.bin");
+        let storage = StorageArgs {
+            preallocation: Preallocation::Full,
+            ..Default::default()
+        };
+
+        write_download(b"foo", Some(&output), "name", &storage)?;
+
+        assert_eq!(b"foo".to_vec(), fs::read(output)?);
+        Ok(())
+    }
+}
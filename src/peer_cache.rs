@@ -0,0 +1,135 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// How long a peer stays in the cache after it last provided verified data
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Peers kept per info hash, the most recent ones
+const MAX_PEERS: usize = 50;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CachedPeer {
+    address: SocketAddr,
+    /// Seconds since the Unix epoch
+    last_good: u64,
+}
+
+/// Peers that recently provided verified data, by info hash, persisted as JSON
+#[derive(Debug, Default, PartialEq)]
+pub struct PeerCache {
+    path: PathBuf,
+    torrents: BTreeMap<String, Vec<CachedPeer>>,
+}
+
+impl PeerCache {
+    /// Loads the cache at `path`, empty if the file does not exist yet
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let torrents = match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content).context("parsing peer cache")?,
+            Err(err) if err.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err).context("reading peer cache"),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            torrents,
+        })
+    }
+
+    /// Writes the cache back to where it was loaded from
+    pub fn save(&self) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent().filter(|i| !i.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).context("creating peer cache directory")?;
+        }
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&self.torrents)?)
+            .context("writing peer cache")?;
+        fs::rename(&temporary, &self.path).context("writing peer cache")
+    }
+
+    /// Peers known to be good for `info_hash`, most recent first, ignoring stale ones
+    pub fn peers(&self, info_hash: [u8; 20], now: SystemTime) -> Vec<SocketAddr> {
+        let oldest = unix_secs(now).saturating_sub(MAX_AGE.as_secs());
+        self.torrents
+            .get(&hex::encode(info_hash))
+            .map(|peers| {
+                peers
+                    .iter()
+                    .filter(|i| i.last_good >= oldest)
+                    .map(|i| i.address)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Remembers that `peer` provided verified data for `info_hash`
+    pub fn record_good(&mut self, info_hash: [u8; 20], peer: SocketAddr, now: SystemTime) {
+        let peers = self.torrents.entry(hex::encode(info_hash)).or_default();
+        peers.retain(|i| i.address != peer);
+        peers.insert(
+            0,
+            CachedPeer {
+                address: peer,
+                last_good: unix_secs(now),
+            },
+        );
+        peers.truncate(MAX_PEERS);
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        time::{Duration, SystemTime},
+    };
+
+    use super::PeerCache;
+
+    #[test]
+    fn remember_peers_across_runs() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("cache/peers.json");
+        let now = SystemTime::now();
+        let a = SocketAddr::from_str("1.1.1.1:1")?;
+        let b = SocketAddr::from_str("2.2.2.2:2")?;
+
+        let mut cache = PeerCache::load(&path)?;
+        assert!(cache.peers([1; 20], now).is_empty());
+        cache.record_good([1; 20], a, now);
+        cache.record_good([1; 20], b, now);
+        cache.record_good([1; 20], a, now);
+        cache.save()?;
+
+        let cache = PeerCache::load(&path)?;
+        assert_eq!(vec![a, b], cache.peers([1; 20], now));
+        assert!(cache.peers([2; 20], now).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn stale_peers_are_ignored() -> anyhow::Result<()> {
+        let mut cache = PeerCache::default();
+        let then = SystemTime::now();
+        cache.record_good([1; 20], SocketAddr::from_str("1.1.1.1:1")?, then);
+
+        assert!(cache
+            .peers([1; 20], then + Duration::from_secs(8 * 24 * 60 * 60))
+            .is_empty());
+        Ok(())
+    }
+}
@@ -1,19 +1,24 @@
 use std::{
+    collections::BTreeMap,
     fmt::{Debug, Display},
     io::Read,
 };
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
 use bytes::BufMut;
 use serde::{Deserialize, Serialize};
 
-use crate::torrent::Info;
+use crate::{bitfield::Bitfield, torrent::Info};
 
 #[derive(Debug, PartialEq)]
 pub struct Handshake {
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
     extension: Extension,
+    /// Whether the Fast extension (BEP 6) is supported
+    fast: bool,
+    /// Whether a DHT node (BEP 5) runs along, its port being sent in a Port message
+    dht: bool,
 }
 
 impl Handshake {
@@ -26,18 +31,80 @@ impl Handshake {
             info_hash,
             peer_id,
             extension,
+            fast: false,
+            dht: false,
         }
     }
 
+    /// Sets whether the Fast extension (BEP 6) is advertised
+    pub fn with_fast(mut self, fast: bool) -> Self {
+        self.fast = fast;
+        self
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.fast
+    }
+
+    /// Sets whether a DHT node (BEP 5) is advertised
+    pub fn with_dht(mut self, dht: bool) -> Self {
+        self.dht = dht;
+        self
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.dht
+    }
+
     pub fn to_bytes(&self) -> [u8; 68] {
         let mut buf = Vec::new();
         buf.push(19u8);
         buf.extend_from_slice(b"BitTorrent protocol");
-        buf.put(self.extension.to_bytes().as_slice());
+        let mut reserved = self.extension.to_bytes();
+        if self.fast {
+            reserved[7] |= FAST_EXTENSION_BIT;
+        }
+        if self.dht {
+            reserved[7] |= DHT_BIT;
+        }
+        buf.put(reserved.as_slice());
         buf.put(&self.info_hash[..]);
         buf.put(&self.peer_id[..]);
         buf.try_into().expect("should always work")
     }
+
+    /// Client name and version decoded from an Azureus-style peer id, e.g. `-TR3000-...`
+    pub fn client_name(&self) -> Option<String> {
+        let id = &self.peer_id;
+        if id[0] != b'-' || id[7] != b'-' || !id[1..7].iter().all(|i| i.is_ascii_alphanumeric()) {
+            return None;
+        }
+        let code = std::str::from_utf8(&id[1..3]).ok()?;
+        let name = match code {
+            "AZ" => "Azureus",
+            "BC" => "BitComet",
+            "DE" => "Deluge",
+            "KT" => "KTorrent",
+            "LT" => "libtorrent",
+            "lt" => "libTorrent",
+            "qB" => "qBittorrent",
+            "TR" => "Transmission",
+            "UT" => "µTorrent",
+            "UW" => "µTorrent Web",
+            _ => code,
+        };
+        let version = id[3..7]
+            .iter()
+            .map(|i| (*i as char).to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        Some(format!("{name} {version}"))
+    }
+}
+
+/// Whether the reserved bytes of a raw handshake advertise the extension protocol (BEP 10)
+pub fn supports_extension_protocol(handshake: &[u8; 68]) -> bool {
+    handshake[25] & 0x10 != 0
 }
 
 impl From<&[u8; 68]> for Handshake {
@@ -47,9 +114,16 @@ impl From<&[u8; 68]> for Handshake {
             value[48..68].try_into().expect("should never fail"),
             Extension::from(&value[20..28].try_into().expect("should never fail")),
         )
+        .with_fast(value[27] & FAST_EXTENSION_BIT != 0)
+        .with_dht(value[27] & DHT_BIT != 0)
     }
 }
 
+/// Bit of the last reserved byte advertising the Fast extension
+const FAST_EXTENSION_BIT: u8 = 0x04;
+/// Bit of the last reserved byte advertising a DHT node
+const DHT_BIT: u8 = 0x01;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Extension {
     None,
@@ -67,10 +141,11 @@ impl Extension {
 
 //TODO Should be try_from
 impl From<&[u8; 8]> for Extension {
+    /// Other reserved bits, such as the Fast extension's, are ignored
     fn from(value: &[u8; 8]) -> Self {
-        match value {
-            [0, 0, 0, 0, 0, 16, 0, 0] => Extension::MagnetLink,
-            _ => Extension::None,
+        match value[5] & 16 {
+            0 => Extension::None,
+            _ => Extension::MagnetLink,
         }
     }
 }
@@ -104,6 +179,17 @@ mod handshake_test {
         assert_eq!(handshake, Handshake::from(&bytes));
     }
 
+    #[test]
+    fn client_name_from_azureus_style_peer_id() {
+        let handshake = Handshake::new(INFO_HASH, *b"-TR3000-abcdefghijkl");
+        assert_eq!(
+            Some("Transmission 3.0.0.0".to_owned()),
+            handshake.client_name()
+        );
+        let handshake = Handshake::new(INFO_HASH, PEER_ID);
+        assert_eq!(None, handshake.client_name());
+    }
+
     #[test]
     fn ser_deser_handshake_with_magnet_link_extension() {
         let handshake = Handshake::with_extension(INFO_HASH, PEER_ID, Extension::MagnetLink);
@@ -119,16 +205,53 @@ mod handshake_test {
         assert_eq!(bytes, handshake.to_bytes());
         assert_eq!(handshake, Handshake::from(&bytes));
     }
+
+    #[test]
+    fn ser_deser_handshake_with_fast_extension() {
+        let handshake =
+            Handshake::with_extension(INFO_HASH, PEER_ID, Extension::MagnetLink).with_fast(true);
+
+        let mut bytes = Vec::new();
+        bytes.push(19u8);
+        bytes.extend_from_slice(b"BitTorrent protocol");
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 16, 0, 4]);
+        bytes.put(&INFO_HASH[..]);
+        bytes.put(&PEER_ID[..]);
+        let bytes: [u8; 68] = bytes.try_into().expect("should not fail");
+
+        assert_eq!(bytes, handshake.to_bytes());
+        assert_eq!(handshake, Handshake::from(&bytes));
+        assert!(Handshake::from(&bytes).supports_fast());
+    }
+
+    #[test]
+    fn ser_deser_handshake_with_dht() {
+        let handshake = Handshake::new(INFO_HASH, PEER_ID)
+            .with_fast(true)
+            .with_dht(true);
+
+        let bytes = handshake.to_bytes();
+
+        assert_eq!([0, 0, 0, 0, 0, 0, 0, 5], bytes[20..28]);
+        assert_eq!(handshake, Handshake::from(&bytes));
+        assert!(Handshake::from(&bytes).supports_dht());
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Message {
+    /// A message without id nor payload, which peers send to keep idle connections open
+    KeepAlive,
     BitField {
-        payload: Vec<u8>,
+        bitfield: Bitfield,
     },
     Interested,
+    NotInterested,
     Choke,
     Unchoke,
+    Have {
+        index: u32,
+    },
     Request {
         index: u32,
         begin: u32,
@@ -139,9 +262,36 @@ pub enum Message {
         begin: u32,
         block: Vec<u8>,
     },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// Port of the DHT node of the peer (BEP 5)
+    Port {
+        port: u16,
+    },
     Extension {
         message: ExtensionMessage,
     },
+    // Fast extension (BEP 6) messages
+    SuggestPiece {
+        index: u32,
+    },
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast {
+        index: u32,
+    },
+    /// A message of a type this client does not implement, whose payload was skipped
+    Unknown {
+        id: u8,
+    },
 }
 
 #[derive(Debug, PartialEq)]
@@ -153,23 +303,68 @@ pub enum ExtensionMessage {
         data: ExtensionsData,
         info: Option<Info>,
     },
+    /// A message of an extension handled outside of this module, `id` being the receiver's id
+    Custom {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl Message {
     pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
         match self {
+            // keep-alive: <len=0000>
+            Message::KeepAlive => Ok(vec![0, 0, 0, 0]),
+            Message::Unknown { id } => Err(anyhow!("message of unknown id {id} cannot be sent")),
             // choke: <len=0001><id=0>
             Message::Choke => Ok(vec![0, 0, 0, 1, 0]),
             // unchoke: <len=0001><id=1>
             Message::Unchoke => Ok(vec![0, 0, 0, 1, 1]),
             // interested: <len=0001><id=2>
             Message::Interested => Ok(vec![0, 0, 0, 1, 2]),
+            // not interested: <len=0001><id=3>
+            Message::NotInterested => Ok(vec![0, 0, 0, 1, 3]),
+            // have: <len=0005><id=4><piece index>
+            Message::Have { index } => {
+                let mut buf = vec![0u8, 0, 0, 5, 4];
+                buf.extend_from_slice(&u32::to_be_bytes(*index));
+                Ok(buf)
+            }
+            // suggest piece: <len=0005><id=13><piece index>
+            Message::SuggestPiece { index } => {
+                let mut buf = vec![0u8, 0, 0, 5, 13];
+                buf.extend_from_slice(&u32::to_be_bytes(*index));
+                Ok(buf)
+            }
+            // have all: <len=0001><id=14>
+            Message::HaveAll => Ok(vec![0, 0, 0, 1, 14]),
+            // have none: <len=0001><id=15>
+            Message::HaveNone => Ok(vec![0, 0, 0, 1, 15]),
+            // reject request: <len=0013><id=16><index><begin><length>
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                let mut buf = vec![0u8, 0, 0, 13, 16];
+                buf.extend_from_slice(&u32::to_be_bytes(*index));
+                buf.extend_from_slice(&u32::to_be_bytes(*begin));
+                buf.extend_from_slice(&u32::to_be_bytes(*length));
+                Ok(buf)
+            }
+            // allowed fast: <len=0005><id=17><piece index>
+            Message::AllowedFast { index } => {
+                let mut buf = vec![0u8, 0, 0, 5, 17];
+                buf.extend_from_slice(&u32::to_be_bytes(*index));
+                Ok(buf)
+            }
             // bitfield: <len=0001+X><id=5><bitfield>
-            Message::BitField { payload } => {
+            Message::BitField { bitfield } => {
+                let payload = bitfield.as_bytes();
                 let mut buf = Vec::new();
                 buf.extend_from_slice(&Message::usize_to_u32_be_bytes(payload.len() + 1)?);
                 buf.push(5);
-                buf.extend_from_slice(&payload);
+                buf.extend_from_slice(payload);
                 Ok(buf)
             }
             // request: <len=0013><id=6><index><begin><length>
@@ -198,6 +393,24 @@ impl Message {
                 buf.extend_from_slice(block);
                 Ok(buf)
             }
+            // cancel: <len=0013><id=8><index><begin><length>
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let mut buf = vec![0u8, 0, 0, 13, 8];
+                buf.extend_from_slice(&u32::to_be_bytes(*index));
+                buf.extend_from_slice(&u32::to_be_bytes(*begin));
+                buf.extend_from_slice(&u32::to_be_bytes(*length));
+                Ok(buf)
+            }
+            // port: <len=0003><id=9><listen-port>
+            Message::Port { port } => {
+                let mut buf = vec![0u8, 0, 0, 3, 9];
+                buf.extend_from_slice(&u16::to_be_bytes(*port));
+                Ok(buf)
+            }
             // extension: <len=0001+X><id=20><extensions_stuff>
             Message::Extension {
                 message: ExtensionMessage::Info { info },
@@ -221,6 +434,16 @@ impl Message {
                 buf.extend_from_slice(&payload);
                 Ok(buf)
             }
+            Message::Extension {
+                message: ExtensionMessage::Custom { id, payload },
+            } => {
+                let mut buf = Vec::new();
+                buf.extend_from_slice(&Message::usize_to_u32_be_bytes(payload.len() + 2)?);
+                buf.push(20); // message id
+                buf.push(*id);
+                buf.extend_from_slice(payload);
+                Ok(buf)
+            }
         }
     }
 
@@ -237,8 +460,25 @@ impl Message {
             0 => Ok(Message::Choke),
             1 => Ok(Message::Unchoke),
             2 => Ok(Message::Interested),
+            3 => Ok(Message::NotInterested),
+            4 if input.len() == 9 => Ok(Message::Have {
+                index: u32::from_be_bytes(input[5..9].try_into().expect("cannot fail")),
+            }),
             5 => Ok(Message::BitField {
-                payload: input[5..].to_vec(),
+                bitfield: Bitfield::from_bytes(input[5..].to_vec()),
+            }),
+            13 if input.len() == 9 => Ok(Message::SuggestPiece {
+                index: u32::from_be_bytes(input[5..9].try_into().expect("cannot fail")),
+            }),
+            14 => Ok(Message::HaveAll),
+            15 => Ok(Message::HaveNone),
+            16 if input.len() == 17 => Ok(Message::RejectRequest {
+                index: u32::from_be_bytes(input[5..9].try_into().expect("cannot fail")),
+                begin: u32::from_be_bytes(input[9..13].try_into().expect("cannot fail")),
+                length: u32::from_be_bytes(input[13..17].try_into().expect("cannot fail")),
+            }),
+            17 if input.len() == 9 => Ok(Message::AllowedFast {
+                index: u32::from_be_bytes(input[5..9].try_into().expect("cannot fail")),
             }),
             6 if input.len() == 17 => Ok(Message::Request {
                 index: u32::from_be_bytes(input[5..9].try_into().expect("cannot fail")),
@@ -250,27 +490,30 @@ impl Message {
                 begin: u32::from_be_bytes(input[9..13].try_into().expect("cannot fail")),
                 block: input[13..].to_vec(),
             }),
-            20 => match input[5] {
+            8 if input.len() == 17 => Ok(Message::Cancel {
+                index: u32::from_be_bytes(input[5..9].try_into().expect("cannot fail")),
+                begin: u32::from_be_bytes(input[9..13].try_into().expect("cannot fail")),
+                length: u32::from_be_bytes(input[13..17].try_into().expect("cannot fail")),
+            }),
+            9 if input.len() == 7 => Ok(Message::Port {
+                port: u16::from_be_bytes(input[5..7].try_into().expect("cannot fail")),
+            }),
+            20 if input.len() >= 6 => match input[5] {
                 0 => Ok(Message::Extension {
                     message: ExtensionMessage::Info {
                         info: serde_bencode::from_bytes(&input[6..])?,
                     },
                 }),
-                _ => {
-                    // any other case is treated as the data message
-                    let end_data = &input[7..].iter().position(|i| *i == 100).unwrap() + 7; // find 'd', start of the next dict
-                    dbg!(String::from_utf8(input[6..end_data].to_vec())?);
-                    let data: ExtensionsData = serde_bencode::from_bytes(&input[6..end_data])
-                        .context("deserializing data dict")?;
-                    let info: Info = serde_bencode::from_bytes(&input[end_data..])
-                        .context("deserializing info dict")?;
-                    Ok(Message::Extension {
-                        message: ExtensionMessage::Data {
-                            data,
-                            info: Some(info),
-                        },
-                    })
-                }
+                // anything looking like a metadata piece is treated as the data message, the rest
+                // is left to registered extensions
+                id => Ok(Message::Extension {
+                    message: Message::metadata_data(&input[6..]).unwrap_or_else(|_| {
+                        ExtensionMessage::Custom {
+                            id,
+                            payload: input[6..].to_vec(),
+                        }
+                    }),
+                }),
             },
             id => Err(anyhow!(
                 "unrecognized message id: {id} or invalid message length"
@@ -278,23 +521,62 @@ impl Message {
         }
     }
 
+    fn metadata_data(payload: &[u8]) -> anyhow::Result<ExtensionMessage> {
+        // find 'd', start of the info dict following the data dict
+        let end_data = payload
+            .iter()
+            .skip(1)
+            .position(|i| *i == b'd')
+            .context("no info dict after the data dict")?
+            + 1;
+        let data: ExtensionsData =
+            serde_bencode::from_bytes(&payload[..end_data]).context("deserializing data dict")?;
+        let info: Info =
+            serde_bencode::from_bytes(&payload[end_data..]).context("deserializing info dict")?;
+        Ok(ExtensionMessage::Data {
+            data,
+            info: Some(info),
+        })
+    }
+
     pub fn read_from<T: Read>(input: &mut T) -> anyhow::Result<Message> {
-        let mut mark = [0u8; 5];
-        input.read_exact(&mut mark).context("reading from input")?;
-        let len: usize = u32::from_be_bytes(mark[0..4].try_into().context("cannot fail")?)
+        Message::read_with_len_from(input).map(|(message, _)| message)
+    }
+
+    /// Reads a message and also returns its size on the wire, length prefix included. The
+    /// payload of messages of unknown types is skipped, for the session to go on without them.
+    pub fn read_with_len_from<T: Read>(input: &mut T) -> anyhow::Result<(Message, usize)> {
+        let mut prefix = [0u8; 4];
+        input
+            .read_exact(&mut prefix)
+            .context("reading from input")?;
+        let len: usize = u32::from_be_bytes(prefix)
             .try_into()
             .context("converting u32 to usize")?;
-        match mark[4] {
-            0..=2 => Message::from_bytes(&mark),
-            5..=7 | 20 => {
+        if len == 0 {
+            return Ok((Message::KeepAlive, prefix.len()));
+        }
+        let mut id = [0u8; 1];
+        input.read_exact(&mut id).context("reading from input")?;
+        match id[0] {
+            0..=9 | 13..=17 | 20 => {
                 let mut message = vec![0u8; 4 + len];
-                message[..5].copy_from_slice(&mark);
+                message[..4].copy_from_slice(&prefix);
+                message[4] = id[0];
                 input
-                    .read_exact(&mut message[5..len + 4])
+                    .read_exact(&mut message[5..])
                     .context("reading exact number of bytes from the reader")?;
-                Message::from_bytes(&message)
+                Ok((Message::from_bytes(&message)?, message.len()))
+            }
+            id => {
+                let payload_len = len as u64 - 1;
+                let skipped = std::io::copy(&mut input.take(payload_len), &mut std::io::sink())
+                    .context("skipping message of unknown id")?;
+                if skipped < payload_len {
+                    bail!("message of unknown id {id} cut short");
+                }
+                Ok((Message::Unknown { id }, 4 + len))
             }
-            id => Err(anyhow!("unrecognized message id: {id}")),
         }
     }
 }
@@ -304,11 +586,22 @@ impl Display for Message {
         match self {
             Message::BitField { .. } => write!(f, "BitField"),
             Message::Interested => write!(f, "Interested"),
+            Message::NotInterested => write!(f, "NotInterested"),
+            Message::Have { .. } => write!(f, "Have"),
             Message::Choke => write!(f, "Choke"),
             Message::Unchoke => write!(f, "Unchoke"),
             Message::Request { .. } => write!(f, "Request"),
             Message::Piece { .. } => write!(f, "Piece"),
+            Message::Cancel { .. } => write!(f, "Cancel"),
+            Message::Port { .. } => write!(f, "Port"),
             Message::Extension { .. } => write!(f, "Extensions"),
+            Message::SuggestPiece { .. } => write!(f, "SuggestPiece"),
+            Message::HaveAll => write!(f, "HaveAll"),
+            Message::HaveNone => write!(f, "HaveNone"),
+            Message::RejectRequest { .. } => write!(f, "RejectRequest"),
+            Message::AllowedFast { .. } => write!(f, "AllowedFast"),
+            Message::KeepAlive => write!(f, "KeepAlive"),
+            Message::Unknown { id } => write!(f, "Unknown({id})"),
         }
     }
 }
@@ -323,6 +616,9 @@ pub struct ExtensionsInfo {
 pub struct Metadata {
     pub ut_metadata: Option<u8>,
     pub ut_pex: Option<u8>,
+    /// Any other extension, by name
+    #[serde(flatten)]
+    pub others: BTreeMap<String, u8>,
 }
 
 impl ExtensionsInfo {
@@ -331,6 +627,7 @@ impl ExtensionsInfo {
             metdata: Metadata {
                 ut_metadata: Some(ut_metadata),
                 ut_pex: None,
+                others: BTreeMap::new(),
             },
         }
     }
@@ -356,12 +653,17 @@ impl ExtensionsData {
 mod message_test {
     use bytes::BufMut;
 
-    use crate::peer_messages::{ExtensionMessage, ExtensionsInfo, Message};
+    use std::collections::BTreeMap;
+
+    use crate::{
+        bitfield::Bitfield,
+        peer_messages::{ExtensionMessage, ExtensionsInfo, Message},
+    };
 
     #[test]
     fn ser_deser_message_bitfield() -> anyhow::Result<()> {
         let msg = Message::BitField {
-            payload: b"foo".to_vec(),
+            bitfield: Bitfield::from_bytes(b"foo".to_vec()),
         };
         let bytes = vec![0, 0, 0, 4, 5, 102, 111, 111];
 
@@ -374,7 +676,7 @@ mod message_test {
     #[test]
     fn ser_deser_message_bitfield_empty() -> anyhow::Result<()> {
         let msg = Message::BitField {
-            payload: Vec::new(),
+            bitfield: Bitfield::default(),
         };
         let bytes = vec![0, 0, 0, 1, 5];
 
@@ -395,6 +697,62 @@ mod message_test {
         Ok(())
     }
 
+    #[test]
+    fn ser_deser_message_have() -> anyhow::Result<()> {
+        let msg = Message::Have { index: 258 };
+        let bytes = vec![0, 0, 0, 5, 4, 0, 0, 1, 2];
+
+        assert_eq!(bytes, msg.to_bytes()?);
+        assert_eq!(msg, Message::from_bytes(&bytes)?);
+        assert_eq!(msg, Message::read_from(&mut bytes.as_slice())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ser_deser_fast_extension_messages() -> anyhow::Result<()> {
+        let cases = [
+            (
+                Message::SuggestPiece { index: 3 },
+                vec![0, 0, 0, 5, 13, 0, 0, 0, 3],
+            ),
+            (Message::HaveAll, vec![0, 0, 0, 1, 14]),
+            (Message::HaveNone, vec![0, 0, 0, 1, 15]),
+            (
+                Message::RejectRequest {
+                    index: 1,
+                    begin: 16384,
+                    length: 16384,
+                },
+                vec![0, 0, 0, 13, 16, 0, 0, 0, 1, 0, 0, 64, 0, 0, 0, 64, 0],
+            ),
+            (
+                Message::AllowedFast { index: 258 },
+                vec![0, 0, 0, 5, 17, 0, 0, 1, 2],
+            ),
+        ];
+
+        for (msg, bytes) in cases {
+            assert_eq!(bytes, msg.to_bytes()?);
+            assert_eq!(msg, Message::from_bytes(&bytes)?);
+            assert_eq!(msg, Message::read_from(&mut bytes.as_slice())?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn ser_deser_message_not_interested() -> anyhow::Result<()> {
+        let msg = Message::NotInterested;
+        let bytes = vec![0, 0, 0, 1, 3];
+
+        assert_eq!(bytes, msg.to_bytes()?);
+        assert_eq!(msg, Message::from_bytes(&bytes)?);
+        assert_eq!(msg, Message::read_from(&mut bytes.as_slice())?);
+
+        Ok(())
+    }
+
     #[test]
     fn ser_deser_message_choke() -> anyhow::Result<()> {
         let msg = Message::Choke;
@@ -432,6 +790,56 @@ mod message_test {
         Ok(())
     }
 
+    #[test]
+    fn ser_deser_message_cancel() -> anyhow::Result<()> {
+        let msg = Message::Cancel {
+            index: 1,
+            begin: 3,
+            length: 42,
+        };
+        let bytes = vec![0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 42];
+
+        assert_eq!(bytes, msg.to_bytes()?);
+        assert_eq!(msg, Message::from_bytes(&bytes)?);
+        assert_eq!(msg, Message::read_from(&mut bytes.as_slice())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_messages_are_skipped() -> anyhow::Result<()> {
+        let mut bytes = vec![0, 0, 0, 4, 42, 1, 2, 3];
+        bytes.extend(Message::KeepAlive.to_bytes()?);
+        bytes.extend(Message::Unchoke.to_bytes()?);
+        let mut input = bytes.as_slice();
+
+        assert_eq!(
+            (Message::Unknown { id: 42 }, 8),
+            Message::read_with_len_from(&mut input)?
+        );
+        assert_eq!(
+            (Message::KeepAlive, 4),
+            Message::read_with_len_from(&mut input)?
+        );
+        assert_eq!(Message::Unchoke, Message::read_from(&mut input)?);
+        assert!(Message::Unknown { id: 42 }.to_bytes().is_err());
+        // the payload must be there to be skipped
+        assert!(Message::read_from(&mut [0u8, 0, 0, 4, 42, 1].as_slice()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn ser_deser_message_port() -> anyhow::Result<()> {
+        let msg = Message::Port { port: 6881 };
+        let bytes = vec![0, 0, 0, 3, 9, 0x1a, 0xe1];
+
+        assert_eq!(bytes, msg.to_bytes()?);
+        assert_eq!(msg, Message::from_bytes(&bytes)?);
+        assert_eq!(msg, Message::read_from(&mut bytes.as_slice())?);
+
+        Ok(())
+    }
+
     #[test]
     fn ser_deser_message_piece() -> anyhow::Result<()> {
         let msg = Message::Piece {
@@ -483,4 +891,45 @@ mod message_test {
 
         Ok(())
     }
+
+    #[test]
+    fn ser_deser_message_extension_with_others() -> anyhow::Result<()> {
+        let mut extensions_info = ExtensionsInfo::new(16);
+        extensions_info
+            .metdata
+            .others
+            .insert("x_echo".to_string(), 17);
+        let msg = Message::Extension {
+            message: ExtensionMessage::Info {
+                info: extensions_info,
+            },
+        };
+
+        let bytes = msg.to_bytes()?;
+
+        assert!(bytes.ends_with(b"d1:md11:ut_metadatai16e6:x_echoi17eee"));
+        assert_eq!(msg, Message::from_bytes(&bytes)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ser_deser_message_custom_extension() -> anyhow::Result<()> {
+        let msg = Message::Extension {
+            message: ExtensionMessage::Custom {
+                id: 17,
+                payload: b"hello".to_vec(),
+            },
+        };
+        let bytes = vec![0, 0, 0, 7, 20, 17, 104, 101, 108, 108, 111];
+
+        assert_eq!(bytes, msg.to_bytes()?);
+        assert_eq!(msg, Message::from_bytes(&bytes)?);
+        assert_eq!(
+            BTreeMap::<String, u8>::new(),
+            ExtensionsInfo::new(16).metdata.others
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,173 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+};
+
+/// When a peer of a download is banned: it is disconnected and never connected to again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BanPolicy {
+    /// Pieces failing verification a peer may send most of
+    pub max_hash_failures: u32,
+    /// Sessions with a peer that may end in an error, such as a stall or a disconnection
+    pub max_errors: u32,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        Self {
+            max_hash_failures: 2,
+            max_errors: 3,
+        }
+    }
+}
+
+/// How a peer behaved during a download
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerScore {
+    pub bytes: u64,
+    pub hash_failures: u32,
+    pub errors: u32,
+    pub banned: bool,
+}
+
+/// Scores of the peers of a download, along with the peers waiting to be connected to. Peers
+/// whose session ended in an error go back to the pool until they are banned, the pool handing
+/// out the most promising peers first.
+#[derive(Debug, Default)]
+pub struct PeerScores {
+    policy: BanPolicy,
+    scores: HashMap<SocketAddr, PeerScore>,
+    pool: VecDeque<SocketAddr>,
+}
+
+impl PeerScores {
+    pub fn new(policy: BanPolicy) -> Self {
+        Self {
+            policy,
+            ..Self::default()
+        }
+    }
+
+    pub fn get(&self, peer: SocketAddr) -> Option<&PeerScore> {
+        self.scores.get(&peer)
+    }
+
+    pub fn is_banned(&self, peer: SocketAddr) -> bool {
+        self.scores.get(&peer).is_some_and(|i| i.banned)
+    }
+
+    pub fn record_bytes(&mut self, peer: SocketAddr, bytes: u64) {
+        self.scores.entry(peer).or_default().bytes += bytes;
+    }
+
+    /// Records that `peer` sent most of a piece failing verification, returns `true` when that
+    /// gets it banned
+    pub fn record_hash_failure(&mut self, peer: SocketAddr) -> bool {
+        let score = self.scores.entry(peer).or_default();
+        score.hash_failures += 1;
+        self.ban_if(peer, |score, policy| {
+            score.hash_failures >= policy.max_hash_failures
+        })
+    }
+
+    /// Records that the session with `peer` ended in an error, returns `true` when that gets it
+    /// banned. Peers that are not banned go back to the pool.
+    pub fn record_error(&mut self, peer: SocketAddr) -> bool {
+        self.scores.entry(peer).or_default().errors += 1;
+        let banned = self.ban_if(peer, |score, policy| score.errors >= policy.max_errors);
+        if !self.scores[&peer].banned && !self.pool.contains(&peer) {
+            self.pool.push_back(peer);
+        }
+        banned
+    }
+
+    /// Adds peers to connect to once a session ends
+    pub fn add_to_pool(&mut self, peers: impl IntoIterator<Item = SocketAddr>) {
+        for peer in peers {
+            if !self.is_banned(peer) && !self.pool.contains(&peer) {
+                self.pool.push_back(peer);
+            }
+        }
+    }
+
+    /// Whether peers are waiting to be connected to
+    pub fn has_pooled(&self) -> bool {
+        !self.pool.is_empty()
+    }
+
+    /// The next peer of the pool to connect to: those with the fewest errors first, then those
+    /// that sent the most, then those that were added first
+    pub fn next_from_pool(&mut self) -> Option<SocketAddr> {
+        let (position, _) = self
+            .pool
+            .iter()
+            .enumerate()
+            .min_by_key(|(position, peer)| {
+                let score = self.scores.get(peer).cloned().unwrap_or_default();
+                (score.errors, Reverse(score.bytes), *position)
+            })?;
+        self.pool.remove(position)
+    }
+
+    fn ban_if(&mut self, peer: SocketAddr, ban: impl Fn(&PeerScore, &BanPolicy) -> bool) -> bool {
+        let score = self.scores.entry(peer).or_default();
+        if score.banned || !ban(score, &self.policy) {
+            return false;
+        }
+        score.banned = true;
+        self.pool.retain(|i| *i != peer);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, str::FromStr};
+
+    use super::{BanPolicy, PeerScores};
+
+    #[test]
+    fn peers_are_banned_after_too_many_failures() -> anyhow::Result<()> {
+        let corrupt = SocketAddr::from_str("127.0.0.1:1")?;
+        let flaky = SocketAddr::from_str("127.0.0.1:2")?;
+        let mut scores = PeerScores::new(BanPolicy::default());
+
+        assert!(!scores.record_hash_failure(corrupt));
+        assert!(scores.record_hash_failure(corrupt));
+        assert!(scores.is_banned(corrupt));
+        // banned only once
+        assert!(!scores.record_hash_failure(corrupt));
+
+        assert!(!scores.record_error(flaky));
+        assert!(!scores.record_error(flaky));
+        assert_eq!(Some(flaky), scores.next_from_pool());
+        assert!(scores.record_error(flaky));
+        assert_eq!(None, scores.next_from_pool());
+
+        scores.add_to_pool([corrupt, flaky]);
+        assert_eq!(None, scores.next_from_pool());
+        Ok(())
+    }
+
+    #[test]
+    fn pool_hands_out_most_promising_peers_first() -> anyhow::Result<()> {
+        let failed = SocketAddr::from_str("127.0.0.1:1")?;
+        let slow = SocketAddr::from_str("127.0.0.1:2")?;
+        let fast = SocketAddr::from_str("127.0.0.1:3")?;
+        let new = SocketAddr::from_str("127.0.0.1:4")?;
+        let mut scores = PeerScores::new(BanPolicy::default());
+        scores.record_bytes(fast, 1000);
+        scores.record_bytes(slow, 10);
+        scores.record_bytes(failed, 5000);
+        scores.record_error(failed);
+        scores.add_to_pool([slow, new, fast]);
+
+        assert_eq!(Some(fast), scores.next_from_pool());
+        assert_eq!(Some(slow), scores.next_from_pool());
+        assert_eq!(Some(new), scores.next_from_pool());
+        assert_eq!(Some(failed), scores.next_from_pool());
+        assert_eq!(None, scores.next_from_pool());
+        Ok(())
+    }
+}
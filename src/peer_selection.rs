@@ -0,0 +1,176 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+/// A peer that could be downloaded from, with what is known about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerCandidate {
+    pub address: SocketAddr,
+    pub latency: Duration,
+}
+
+/// Decides which peers to connect to first
+pub trait PeerSelection: Send + Sync {
+    /// Candidates in the order they should be tried
+    fn order(&self, candidates: Vec<PeerCandidate>) -> Vec<SocketAddr>;
+}
+
+/// Lowest latency first. Peers within the same latency bucket are ordered by their canonical
+/// priority (BEP 40) when our own address is known, so that the swarm converges on the same
+/// connections.
+pub struct DefaultPeerSelection {
+    own_address: Option<SocketAddr>,
+    latency_bucket: Duration,
+}
+
+impl Default for DefaultPeerSelection {
+    fn default() -> Self {
+        Self {
+            own_address: None,
+            latency_bucket: Duration::from_millis(50),
+        }
+    }
+}
+
+impl DefaultPeerSelection {
+    pub fn with_own_address(mut self, own_address: SocketAddr) -> Self {
+        self.own_address = Some(own_address);
+        self
+    }
+}
+
+impl PeerSelection for DefaultPeerSelection {
+    fn order(&self, mut candidates: Vec<PeerCandidate>) -> Vec<SocketAddr> {
+        let bucket = self.latency_bucket.as_nanos().max(1);
+        candidates.sort_by_key(|candidate| {
+            (
+                candidate.latency.as_nanos() / bucket,
+                std::cmp::Reverse(
+                    self.own_address
+                        .map_or(0, |own| canonical_priority(own, candidate.address)),
+                ),
+            )
+        });
+        candidates.into_iter().map(|i| i.address).collect()
+    }
+}
+
+/// Canonical peer priority of BEP 40, the same whichever side computes it
+pub fn canonical_priority(a: SocketAddr, b: SocketAddr) -> u32 {
+    if a.ip() == b.ip() {
+        let mut ports = [a.port(), b.port()];
+        ports.sort();
+        return crc32c(&[ports[0].to_be_bytes(), ports[1].to_be_bytes()].concat());
+    }
+    // IPv6 addresses are masked like IPv4 ones, on their /48 and /56 prefixes rather than /16
+    // and /24, and an IPv4 address compared to an IPv6 one is mapped to IPv6
+    let octets = |ip: IpAddr| match (ip, a.is_ipv4() && b.is_ipv4()) {
+        (IpAddr::V4(ip), true) => ip.octets().to_vec(),
+        (IpAddr::V4(ip), false) => ip.to_ipv6_mapped().octets().to_vec(),
+        (IpAddr::V6(ip), _) => ip.octets().to_vec(),
+    };
+    let (a_octets, b_octets) = (octets(a.ip()), octets(b.ip()));
+    let wide = if a_octets.len() == 4 { 3 } else { 7 };
+    let kept = if a_octets[..wide] == b_octets[..wide] {
+        a_octets.len()
+    } else if a_octets[..wide - 1] == b_octets[..wide - 1] {
+        wide
+    } else {
+        wide - 1
+    };
+    let mask = (0..a_octets.len())
+        .map(|i| if i < kept { 0xff } else { 0x55 })
+        .collect::<Vec<u8>>();
+    let masked =
+        |octets: &[u8]| -> Vec<u8> { octets.iter().zip(&mask).map(|(i, m)| i & m).collect() };
+    let mut ips = [masked(&a_octets), masked(&b_octets)];
+    ips.sort();
+    crc32c(&ips.concat())
+}
+
+/// CRC-32C (Castagnoli)
+fn crc32c(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ u32::from(*byte), |crc, _| {
+            (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, str::FromStr, time::Duration};
+
+    use super::{canonical_priority, crc32c, DefaultPeerSelection, PeerCandidate, PeerSelection};
+
+    fn addr(s: &str) -> SocketAddr {
+        SocketAddr::from_str(s).expect("valid address")
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(0xe306_9283, crc32c(b"123456789"));
+    }
+
+    #[test]
+    fn canonical_priority_is_symmetric() {
+        let a = addr("123.213.32.10:6881");
+        let b = addr("98.76.54.32:6881");
+
+        assert_eq!(0xec2d_7224, canonical_priority(a, b));
+        assert_eq!(canonical_priority(a, b), canonical_priority(b, a));
+        assert_eq!(
+            canonical_priority(addr("10.0.0.1:1"), addr("10.0.0.1:2")),
+            canonical_priority(addr("10.0.0.1:2"), addr("10.0.0.1:1"))
+        );
+        let (c, d) = (addr("[2001:db8::1]:6881"), addr("[2001:db8:1::2]:6881"));
+        assert_eq!(canonical_priority(c, d), canonical_priority(d, c));
+        assert_eq!(canonical_priority(a, c), canonical_priority(c, a));
+    }
+
+    #[test]
+    fn default_selection_prefers_low_latency() {
+        let candidates = vec![
+            PeerCandidate {
+                address: addr("1.1.1.1:1"),
+                latency: Duration::from_millis(300),
+            },
+            PeerCandidate {
+                address: addr("2.2.2.2:2"),
+                latency: Duration::from_millis(20),
+            },
+            PeerCandidate {
+                address: addr("3.3.3.3:3"),
+                latency: Duration::from_millis(120),
+            },
+        ];
+
+        assert_eq!(
+            vec![addr("2.2.2.2:2"), addr("3.3.3.3:3"), addr("1.1.1.1:1")],
+            DefaultPeerSelection::default().order(candidates)
+        );
+    }
+
+    #[test]
+    fn default_selection_breaks_ties_by_canonical_priority() {
+        let own = addr("123.213.32.10:6881");
+        let peers = [addr("98.76.54.32:6881"), addr("45.67.89.10:6881")];
+        let candidates = peers
+            .iter()
+            .map(|&address| PeerCandidate {
+                address,
+                latency: Duration::from_millis(10),
+            })
+            .collect();
+        let mut expected = peers.to_vec();
+        expected.sort_by_key(|peer| std::cmp::Reverse(canonical_priority(own, *peer)));
+
+        assert_eq!(
+            expected,
+            DefaultPeerSelection::default()
+                .with_own_address(own)
+                .order(candidates)
+        );
+    }
+}
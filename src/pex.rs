@@ -0,0 +1,151 @@
+use std::{
+    collections::BTreeSet,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::tracker::{Peers, Peers6};
+
+/// Local id advertised for `ut_pex` in our extension handshake
+pub const UT_PEX_ID: u8 = 1;
+/// Minimum time between two PEX messages to the same peer, as BEP 11 requires
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A peer exchange message (BEP 11): peers the sender connected to or dropped since its last
+/// message, IPv4 and IPv6 ones being listed apart. The flags are ignored.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: Peers,
+    #[serde(default, skip_serializing_if = "Peers6::is_empty")]
+    pub added6: Peers6,
+    #[serde(default)]
+    pub dropped: Peers,
+    #[serde(default, skip_serializing_if = "Peers6::is_empty")]
+    pub dropped6: Peers6,
+}
+
+impl PexMessage {
+    /// Lists `added` and `dropped` peers under the keys of their address family
+    pub fn new(added: Vec<SocketAddr>, dropped: Vec<SocketAddr>) -> Self {
+        let (added6, added) = added.into_iter().partition(SocketAddr::is_ipv6);
+        let (dropped6, dropped) = dropped.into_iter().partition(SocketAddr::is_ipv6);
+        PexMessage {
+            added: Peers(added),
+            added6: Peers6(added6),
+            dropped: Peers(dropped),
+            dropped6: Peers6(dropped6),
+        }
+    }
+
+    /// The added peers, whatever their address family
+    pub fn all_added(&self) -> Vec<SocketAddr> {
+        self.added.0.iter().chain(&self.added6.0).copied().collect()
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> anyhow::Result<Self> {
+        serde_bencode::from_bytes(payload).context("deserializing pex message")
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).context("serializing pex message")
+    }
+}
+
+/// What was last told to a peer through PEX, so that only changes are sent afterward
+#[derive(Debug, Default)]
+pub struct PexState {
+    sent: BTreeSet<SocketAddr>,
+    last: Option<Instant>,
+}
+
+impl PexState {
+    /// The message to send to `peer` now that we are connected to `connected`, `None` when the
+    /// last one is too recent or nothing changed
+    pub fn update(
+        &mut self,
+        peer: SocketAddr,
+        connected: &BTreeSet<SocketAddr>,
+        now: Instant,
+    ) -> Option<PexMessage> {
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < PEX_INTERVAL)
+        {
+            return None;
+        }
+        let current = connected
+            .iter()
+            .copied()
+            .filter(|i| *i != peer)
+            .collect::<BTreeSet<_>>();
+        let added = current.difference(&self.sent).copied().collect::<Vec<_>>();
+        let dropped = self.sent.difference(&current).copied().collect::<Vec<_>>();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        let message = PexMessage::new(added, dropped);
+        self.sent = current;
+        self.last = Some(now);
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeSet,
+        net::SocketAddr,
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
+    use super::{PexMessage, PexState, PEX_INTERVAL};
+
+    #[test]
+    fn ser_deser_pex_message() -> anyhow::Result<()> {
+        let message = PexMessage::new(vec![SocketAddr::from_str("10.0.0.1:6881")?], vec![]);
+        let bytes = b"d5:added6:\x0a\x00\x00\x01\x1a\xe17:dropped0:e".to_vec();
+
+        assert_eq!(bytes, message.to_bytes()?);
+        assert_eq!(message, PexMessage::from_bytes(&bytes)?);
+        assert_eq!(
+            message,
+            PexMessage::from_bytes(b"d5:added6:\x0a\x00\x00\x01\x1a\xe17:added.f1:\x00e")?
+        );
+
+        let ipv6 = PexMessage::new(vec![SocketAddr::from_str("[::1]:6881")?], vec![]);
+        assert_eq!(ipv6, PexMessage::from_bytes(&ipv6.to_bytes()?)?);
+        assert_eq!(vec![SocketAddr::from_str("[::1]:6881")?], ipv6.all_added());
+        Ok(())
+    }
+
+    #[test]
+    fn only_changes_are_sent_once_a_minute() -> anyhow::Result<()> {
+        let peer = SocketAddr::from_str("10.0.0.1:6881")?;
+        let a = SocketAddr::from_str("10.0.0.2:6881")?;
+        let b = SocketAddr::from_str("10.0.0.3:6881")?;
+        let start = Instant::now();
+        let mut state = PexState::default();
+
+        let first = state.update(peer, &BTreeSet::from([peer, a]), start);
+        assert_eq!(Some(vec![a]), first.map(|i| i.added.0));
+        assert_eq!(
+            None,
+            state.update(peer, &BTreeSet::from([b]), start + Duration::from_secs(1))
+        );
+
+        let later = start + PEX_INTERVAL;
+        let second = state.update(peer, &BTreeSet::from([b]), later).unwrap();
+        assert_eq!(vec![b], second.added.0);
+        assert_eq!(vec![a], second.dropped.0);
+        assert_eq!(
+            None,
+            state.update(peer, &BTreeSet::from([b]), later + PEX_INTERVAL)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Display,
+    hash::{BuildHasher, Hasher},
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use reqwest::Url;
+use serde::Deserialize;
+
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How tracker host names are resolved
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Resolver {
+    /// The operating system's resolver
+    #[default]
+    System,
+    /// Plain DNS queries to these servers, tried in order
+    Servers(Vec<SocketAddr>),
+    /// DNS-over-HTTPS with the JSON API served at this URL
+    DnsOverHttps(Url),
+}
+
+impl FromStr for Resolver {
+    type Err = anyhow::Error;
+
+    /// `system`, an `https://` DoH endpoint, or comma separated DNS servers (`IP` or `IP:port`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "system" {
+            return Ok(Resolver::System);
+        }
+        if s.starts_with("https://") {
+            return Ok(Resolver::DnsOverHttps(
+                Url::parse(s).context("invalid DoH URL")?,
+            ));
+        }
+        s.split(',')
+            .map(|server| {
+                server
+                    .parse::<SocketAddr>()
+                    .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .with_context(|| format!("invalid DNS server '{server}'"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(Resolver::Servers)
+    }
+}
+
+impl Display for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Resolver::System => write!(f, "system"),
+            Resolver::Servers(servers) => write!(
+                f,
+                "{}",
+                servers
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Resolver::DnsOverHttps(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+impl Resolver {
+    /// IPv4 addresses of `host`, which may already be an address
+    pub fn resolve(&self, host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let ips = match self {
+            Resolver::System => {
+                return Ok((host, port)
+                    .to_socket_addrs()
+                    .with_context(|| format!("resolving {host}"))?
+                    .filter(SocketAddr::is_ipv4)
+                    .collect())
+            }
+            Resolver::Servers(servers) => {
+                let mut last_error = None;
+                let mut ips = None;
+                for server in servers {
+                    match query_a(*server, host) {
+                        Ok(res) => {
+                            ips = Some(res);
+                            break;
+                        }
+                        Err(err) => last_error = Some(err),
+                    }
+                }
+                match (ips, last_error) {
+                    (Some(ips), _) => ips,
+                    (None, Some(err)) => return Err(err),
+                    (None, None) => bail!("no DNS server configured"),
+                }
+            }
+            Resolver::DnsOverHttps(url) => query_doh(url, host)?,
+        };
+        if ips.is_empty() {
+            bail!("{host} has no IPv4 address");
+        }
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(IpAddr::V4(ip), port))
+            .collect())
+    }
+
+    /// An HTTP client resolving the hosts of `urls` with this resolver
+    pub fn http_client(&self, urls: &[Url]) -> anyhow::Result<reqwest::blocking::Client> {
+        let mut builder = reqwest::blocking::Client::builder();
+        if *self != Resolver::System {
+            for url in urls {
+                let Some(host) = url.host_str() else {
+                    continue;
+                };
+                if let Some(address) = self.resolve(host, 0)?.first() {
+                    builder = builder.resolve(host, *address);
+                }
+            }
+        }
+        builder.build().context("creating HTTP client")
+    }
+}
+
+/// Builds a DNS query for the A records of `host`
+fn a_query(id: u16, host: &str) -> anyhow::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(host.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00]); // recursion desired
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question
+    for label in host.trim_end_matches('.').split('.') {
+        let len = u8::try_from(label.len())
+            .ok()
+            .filter(|i| (1..64).contains(i))
+            .with_context(|| format!("invalid host name {host}"))?;
+        query.push(len);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&[0, 1, 0, 1]); // type A, class IN
+    Ok(query)
+}
+
+/// A records found in the answer section of a DNS response to query `id`
+fn parse_a_response(id: u16, response: &[u8]) -> anyhow::Result<Vec<Ipv4Addr>> {
+    let read_u16 = |at: usize| -> anyhow::Result<u16> {
+        Ok(u16::from_be_bytes(
+            response
+                .get(at..at + 2)
+                .context("truncated DNS response")?
+                .try_into()?,
+        ))
+    };
+    if read_u16(0)? != id {
+        bail!("DNS response to another query");
+    }
+    let rcode = read_u16(2)? & 0x000f;
+    if rcode != 0 {
+        bail!("DNS error code {rcode}");
+    }
+    let (questions, answers) = (read_u16(4)?, read_u16(6)?);
+    let skip_name = |mut at: usize| -> anyhow::Result<usize> {
+        loop {
+            let len = *response.get(at).context("truncated DNS response")?;
+            match len {
+                0 => return Ok(at + 1),
+                len if len & 0xc0 == 0xc0 => return Ok(at + 2),
+                len => at += 1 + len as usize,
+            }
+        }
+    };
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(at)? + 4;
+    }
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        at = skip_name(at)?;
+        let (record_type, len) = (read_u16(at)?, read_u16(at + 8)? as usize);
+        at += 10;
+        let data = response
+            .get(at..at + len)
+            .context("truncated DNS response")?;
+        if record_type == 1 && len == 4 {
+            ips.push(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+        }
+        at += len;
+    }
+    Ok(ips)
+}
+
+fn query_a(server: SocketAddr, host: &str) -> anyhow::Result<Vec<Ipv4Addr>> {
+    let id = RandomState::new().build_hasher().finish() as u16;
+    let socket = UdpSocket::bind(match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    })?;
+    socket.set_read_timeout(Some(DNS_TIMEOUT))?;
+    socket.connect(server)?;
+    socket
+        .send(&a_query(id, host)?)
+        .with_context(|| format!("querying {server}"))?;
+    let mut buf = [0u8; 1500];
+    let len = socket
+        .recv(&mut buf)
+        .with_context(|| format!("waiting for {server}"))?;
+    parse_a_response(id, &buf[..len])
+}
+
+#[derive(Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+fn parse_doh_response(response: &[u8]) -> anyhow::Result<Vec<Ipv4Addr>> {
+    let response: DohResponse = serde_json::from_slice(response).context("parsing DoH response")?;
+    if response.status != 0 {
+        bail!("DNS error code {}", response.status);
+    }
+    Ok(response
+        .answer
+        .iter()
+        .filter(|i| i.record_type == 1)
+        .filter_map(|i| i.data.parse().ok())
+        .collect())
+}
+
+fn query_doh(url: &Url, host: &str) -> anyhow::Result<Vec<Ipv4Addr>> {
+    let mut url = url.clone();
+    url.query_pairs_mut()
+        .append_pair("name", host)
+        .append_pair("type", "A");
+    let response = reqwest::blocking::Client::builder()
+        .timeout(DNS_TIMEOUT)
+        .build()?
+        .get(url)
+        .header("accept", "application/dns-json")
+        .send()
+        .and_then(|i| i.error_for_status())
+        .context("querying DoH server")?
+        .bytes()?;
+    parse_doh_response(&response)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, UdpSocket},
+        thread,
+    };
+
+    use super::{a_query, parse_a_response, parse_doh_response, Resolver};
+
+    #[test]
+    fn parse_resolver_option() -> anyhow::Result<()> {
+        assert_eq!(Resolver::System, "system".parse()?);
+        assert_eq!(
+            Resolver::Servers(vec!["1.1.1.1:53".parse()?, "9.9.9.9:5353".parse()?]),
+            "1.1.1.1,9.9.9.9:5353".parse()?
+        );
+        assert!(matches!(
+            "https://cloudflare-dns.com/dns-query".parse()?,
+            Resolver::DnsOverHttps(_)
+        ));
+        assert!("not a server".parse::<Resolver>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dns_query_and_response() -> anyhow::Result<()> {
+        let query = a_query(0x1234, "tracker.example.org")?;
+        assert_eq!(
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07tracker\x07example\x03org\x00\x00\x01\x00\x01".to_vec(),
+            query
+        );
+
+        let mut response = query.clone();
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        response[6..8].copy_from_slice(&[0, 2]);
+        // A CNAME then an A record, both with compressed names
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 0x0c]);
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 7]);
+
+        assert_eq!(
+            vec![Ipv4Addr::new(10, 0, 0, 7)],
+            parse_a_response(0x1234, &response)?
+        );
+        assert!(parse_a_response(0x4321, &response).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_with_custom_server() -> anyhow::Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0")?;
+        let address = server.local_addr()?;
+        thread::spawn(move || -> anyhow::Result<()> {
+            let mut buf = [0u8; 512];
+            let (len, from) = server.recv_from(&mut buf)?;
+            let mut response = buf[..len].to_vec();
+            response[2..4].copy_from_slice(&[0x81, 0x80]);
+            response[6..8].copy_from_slice(&[0, 1]);
+            response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 7]);
+            server.send_to(&response, from)?;
+            Ok(())
+        });
+
+        assert_eq!(
+            vec![SocketAddr::from(([10, 0, 0, 7], 80))],
+            Resolver::Servers(vec![address]).resolve("tracker.example.org", 80)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn doh_json_response() -> anyhow::Result<()> {
+        let response = br#"{"Status":0,"Answer":[{"name":"a.org","type":5,"data":"b.org."},{"name":"b.org","type":1,"data":"10.0.0.8"}]}"#;
+
+        assert_eq!(
+            vec![Ipv4Addr::new(10, 0, 0, 8)],
+            parse_doh_response(response)?
+        );
+        assert!(parse_doh_response(br#"{"Status":3}"#).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bitfield::Bitfield,
+    logging,
+    sha1::PieceHasher,
+    torrent_info::TorrentInfo,
+    verify::{self, DiskContent, FileStamp},
+};
+
+/// What the sidecar records, as JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ResumeState {
+    /// Hex encoded info hash of the torrent
+    info_hash: String,
+    /// Paths of the torrent's files, as found in its info
+    files: Vec<PathBuf>,
+    /// Hex encoded bitfield of the pieces verified and written, as sent to peers
+    pieces: String,
+    /// Blocks written of the pieces that are not complete yet, as `[begin, length]` by piece
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    partial: BTreeMap<u32, Vec<[u32; 2]>>,
+    /// Stamps of the files whose pieces were all verified and written, by index in the torrent
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    complete: BTreeMap<usize, FileStamp>,
+}
+
+/// How often the sidecar is written at most as blocks arrive, pieces being recorded at once
+const BLOCK_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The pieces of a download to disk already verified and written, and the blocks written of the
+/// others, persisted in a `.resume` sidecar next to the download so that an interrupted download
+/// only fetches what is missing
+#[derive(Debug, PartialEq)]
+pub struct Resume {
+    path: PathBuf,
+    have: Vec<bool>,
+    piece_length: u64,
+    state: ResumeState,
+    saved: Option<Instant>,
+}
+
+impl Resume {
+    /// Path of the sidecar of the download at `download`
+    pub fn sidecar(download: &Path) -> PathBuf {
+        let mut path = download.as_os_str().to_owned();
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+
+    /// Progress of a download of `torrent_info` to `download` that has not started yet
+    pub fn new<TI: TorrentInfo>(torrent_info: &TI, download: &Path) -> anyhow::Result<Self> {
+        let have = vec![false; torrent_info.pieces_count()];
+        Ok(Self {
+            path: Self::sidecar(download),
+            state: ResumeState {
+                info_hash: hex::encode(torrent_info.info_hash()?),
+                files: torrent_info
+                    .files_info()
+                    .into_iter()
+                    .map(|i| i.path)
+                    .collect(),
+                pieces: hex::encode(Bitfield::new(have.len()).as_bytes()),
+                partial: BTreeMap::new(),
+                complete: BTreeMap::new(),
+            },
+            have,
+            piece_length: torrent_info.piece_length(),
+            saved: None,
+        })
+    }
+
+    /// Progress of an earlier download of `torrent_info` to `download`, `None` if there is no
+    /// sidecar or if it is about another torrent
+    pub fn load<TI: TorrentInfo>(
+        torrent_info: &TI,
+        download: &Path,
+    ) -> anyhow::Result<Option<Self>> {
+        let path = Self::sidecar(download);
+        let state: ResumeState = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).context("parsing resume data")?,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("reading resume data"),
+        };
+        let mut resume = Self::new(torrent_info, download)?;
+        let payload = hex::decode(&state.pieces).context("parsing resume data")?;
+        if state.info_hash != resume.state.info_hash
+            || state.files != resume.state.files
+            || payload.len() != resume.state.pieces.len() / 2
+        {
+            logging::warn(
+                "resume",
+                &format!("{} is about another torrent, ignoring it", path.display()),
+            );
+            return Ok(None);
+        }
+        for index in Bitfield::from_bytes(payload).pieces() {
+            if let Some(have) = resume.have.get_mut(index as usize) {
+                *have = true;
+            }
+        }
+        resume.state = state;
+        Ok(Some(resume))
+    }
+
+    /// Whether each piece was verified and written
+    pub fn have(&self) -> &[bool] {
+        &self.have
+    }
+
+    /// Hashes again the pieces recorded as written, forgetting those the content on disk no
+    /// longer matches. Only the files that exist and were not complete are read: pieces of
+    /// missing files are forgotten, and those of complete files that did not change since are
+    /// kept as they are.
+    pub fn verify<TI: TorrentInfo>(
+        &mut self,
+        torrent_info: &TI,
+        content: &DiskContent,
+        hasher: &dyn PieceHasher,
+    ) -> anyhow::Result<()> {
+        let files = content.file_pieces(self.piece_length);
+        let stamps = files
+            .iter()
+            .map(|(index, _)| content.stamp(*index))
+            .collect::<Vec<_>>();
+        let mut missing = vec![false; self.have.len()];
+        let mut changed = vec![false; self.have.len()];
+        for ((index, pieces), stamp) in files.iter().zip(&stamps) {
+            for piece in pieces.clone() {
+                missing[piece] |= stamp.is_none();
+                changed[piece] |=
+                    stamp.is_none() || self.state.complete.get(index) != stamp.as_ref();
+            }
+        }
+        let only = (0..self.have.len())
+            .map(|i| self.have[i] && changed[i] && !missing[i])
+            .collect::<Vec<_>>();
+        let hashed = verify::verify_content(
+            torrent_info,
+            content,
+            &only,
+            hasher,
+            verify::default_threads(),
+        );
+        let verified = (0..self.have.len())
+            .map(|i| hashed[i] || (self.have[i] && !changed[i]))
+            .collect::<Vec<_>>();
+        let complete = files
+            .into_iter()
+            .zip(stamps)
+            .filter(|((_, pieces), _)| !pieces.is_empty() && pieces.clone().all(|i| verified[i]))
+            .filter_map(|((index, _), stamp)| Some((index, stamp?)))
+            .collect::<BTreeMap<_, _>>();
+        if verified != self.have || complete != self.state.complete {
+            self.have = verified;
+            self.state.complete = complete;
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Blocks written of piece `index` when it is not complete, as `(begin, length)`
+    pub fn blocks(&self, index: u32) -> Vec<(u32, u32)> {
+        self.state
+            .partial
+            .get(&index)
+            .map(|i| i.iter().map(|[begin, length]| (*begin, *length)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Records that piece `index` was verified and written to `content`, along with the files it
+    /// completes, which are not read again on resume unless they change
+    pub fn record(&mut self, index: usize, content: &DiskContent) -> anyhow::Result<()> {
+        if let Some(have) = self.have.get_mut(index) {
+            *have = true;
+        }
+        self.state.partial.remove(&(index as u32));
+        for (file, pieces) in content.file_pieces(self.piece_length) {
+            if pieces.contains(&index) && pieces.clone().all(|i| self.have.get(i) == Some(&true)) {
+                if let Some(stamp) = content.stamp(file) {
+                    self.state.complete.insert(file, stamp);
+                }
+            }
+        }
+        self.save()
+    }
+
+    /// Records that a block of piece `index` was written, the sidecar being written at most
+    /// every [`BLOCK_SAVE_INTERVAL`]
+    pub fn record_block(&mut self, index: u32, begin: u32, length: u32) -> anyhow::Result<()> {
+        let blocks = self.state.partial.entry(index).or_default();
+        if !blocks.contains(&[begin, length]) {
+            blocks.push([begin, length]);
+        }
+        if self
+            .saved
+            .is_some_and(|saved| saved.elapsed() < BLOCK_SAVE_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// Forgets the blocks written of piece `index`, which are to be downloaded again
+    pub fn forget_blocks(&mut self, index: u32) {
+        self.state.partial.remove(&index);
+    }
+
+    /// Deletes the sidecar, once the download is complete
+    pub fn remove(self) -> anyhow::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(err).context("removing resume data")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the sidecar, replacing the previous one at once
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        self.state.pieces = hex::encode(
+            Bitfield::from_pieces(
+                self.have.len(),
+                (0..self.have.len() as u32).filter(|i| self.have[*i as usize]),
+            )
+            .as_bytes(),
+        );
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&self.state)?)
+            .context("writing resume data")?;
+        fs::rename(&temporary, &self.path).context("writing resume data")?;
+        self.saved = Some(Instant::now());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{self, File},
+        time::{Duration, SystemTime},
+    };
+
+    use crate::{
+        cli::Preallocation,
+        sha1::{self, RustCryptoSha1},
+        torrent::Torrent,
+        verify::DiskContent,
+    };
+
+    use super::Resume;
+
+    fn torrent(name: &str) -> anyhow::Result<Torrent> {
+        let mut content = Vec::from(format!("d8:announce31:http://127.0.0.1:44381/announce4:infod6:lengthi200e4:name{}:{name}12:piece lengthi64e6:pieces80:", name.len()));
+        content.extend_from_slice(&[0; 80]);
+        content.extend_from_slice(b"ee");
+        Torrent::from_bytes(&content)
+    }
+
+    #[test]
+    fn recorded_pieces_are_loaded() -> anyhow::Result<()> {
+        let torrent = torrent("a.iso")?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a.iso");
+        assert_eq!(None, Resume::load(&torrent, &path)?);
+
+        let content = DiskContent::new(&torrent, &path);
+        let mut resume = Resume::new(&torrent, &path)?;
+        resume.record(1, &content)?;
+        resume.record(3, &content)?;
+        let loaded = Resume::load(&torrent, &path)?.expect("resume data");
+
+        assert_eq!(&[false, true, false, true], loaded.have());
+        assert!(dir.path().join("a.iso.resume").exists());
+        loaded.remove()?;
+        assert_eq!(None, Resume::load(&torrent, &path)?);
+        Ok(())
+    }
+
+    #[test]
+    fn resume_data_of_another_torrent_is_ignored() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a.iso");
+        let a = torrent("a.iso")?;
+        Resume::new(&a, &path)?.record(0, &DiskContent::new(&a, &path))?;
+
+        let other = torrent("b.iso")?;
+        assert_ne!(a.info_hash()?, other.info_hash()?);
+        assert_eq!(None, Resume::load(&other, &path)?);
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_of_incomplete_pieces_are_loaded() -> anyhow::Result<()> {
+        let torrent = torrent("a.iso")?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a.iso");
+
+        let mut resume = Resume::new(&torrent, &path)?;
+        resume.record_block(2, 16, 16)?;
+        resume.record_block(2, 0, 16)?;
+        resume.record_block(1, 0, 16)?;
+        resume.record(1, &DiskContent::new(&torrent, &path))?;
+        let loaded = Resume::load(&torrent, &path)?.expect("resume data");
+
+        assert_eq!(vec![(16, 16), (0, 16)], loaded.blocks(2));
+        assert_eq!(Vec::<(u32, u32)>::new(), loaded.blocks(1));
+        Ok(())
+    }
+
+    #[test]
+    fn complete_files_are_not_hashed_again() -> anyhow::Result<()> {
+        let data = (0..192u8).collect::<Vec<_>>();
+        let mut content = Vec::from("d8:announce31:http://127.0.0.1:44381/announce4:infod5:filesld6:lengthi64e4:pathl1:aeed6:lengthi128e4:pathl1:beee4:name3:dir12:piece lengthi64e6:pieces60:");
+        for piece in data.chunks(64) {
+            content.extend_from_slice(&sha1::hash(piece));
+        }
+        content.extend_from_slice(b"ee");
+        let torrent = Torrent::from_bytes(&content)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dir");
+        let disk = DiskContent::new(&torrent, &path);
+        disk.create(Preallocation::None)?;
+        disk.write_at(0, &data[..128])?;
+        let mut resume = Resume::new(&torrent, &path)?;
+        resume.record(0, &disk)?;
+        resume.record(1, &disk)?;
+
+        // a is complete, it is not read again as long as it is left unchanged
+        let a = path.join("a");
+        let modified = fs::metadata(&a)?.modified()?;
+        fs::write(&a, [0; 64])?;
+        File::options()
+            .write(true)
+            .open(&a)?
+            .set_modified(modified)?;
+        let mut loaded = Resume::load(&torrent, &path)?.expect("resume data");
+        loaded.verify(&torrent, &disk, &RustCryptoSha1)?;
+        assert_eq!(&[true, true, false], loaded.have());
+
+        // unless it changed
+        let modified = SystemTime::now() + Duration::from_secs(1);
+        File::options()
+            .write(true)
+            .open(&a)?
+            .set_modified(modified)?;
+        loaded.verify(&torrent, &disk, &RustCryptoSha1)?;
+        assert_eq!(&[false, true, false], loaded.have());
+
+        // b is incomplete and hashed again, its pieces are forgotten once it is missing
+        fs::remove_file(path.join("b"))?;
+        let mut loaded = Resume::load(&torrent, &path)?.expect("resume data");
+        loaded.verify(&torrent, &disk, &RustCryptoSha1)?;
+        assert_eq!(&[false; 3], loaded.have());
+        Ok(())
+    }
+}
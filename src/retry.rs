@@ -0,0 +1,141 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    thread,
+    time::Duration,
+};
+
+/// How many times an operation is attempted and how long to wait between attempts. Delays grow
+/// exponentially from `base_delay` up to `max_delay`, each randomly spread by `jitter` (a
+/// fraction of the delay) so that retries do not all happen in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A single attempt, failing fast
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Delay before the given retry, 1 being the first retry
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+        let spread = (random_unit() * 2.0 - 1.0) * self.jitter.clamp(0.0, 1.0);
+        exponential.mul_f64(1.0 + spread)
+    }
+
+    /// Runs `operation` until it succeeds or `max_attempts` is reached, sleeping in between.
+    /// The attempt number, starting at 1, is given to `operation`; the last error is returned.
+    pub fn retry<T>(
+        &self,
+        mut operation: impl FnMut(u32) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut attempt = 1;
+        loop {
+            match operation(attempt) {
+                Ok(value) => return Ok(value),
+                Err(err) if attempt >= self.max_attempts => return Err(err),
+                Err(_) => {
+                    thread::sleep(self.delay(attempt));
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// The retry policy of each kind of operation
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetryPolicies {
+    pub announce: RetryPolicy,
+    pub connect: RetryPolicy,
+    pub piece: RetryPolicy,
+}
+
+impl RetryPolicies {
+    /// The same policy for every operation
+    pub fn uniform(policy: RetryPolicy) -> Self {
+        Self {
+            announce: policy,
+            connect: policy,
+            piece: policy,
+        }
+    }
+}
+
+/// A random number in `[0, 1)`, good enough to spread retries
+fn random_unit() -> f64 {
+    (RandomState::new().build_hasher().finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+
+    use super::RetryPolicy;
+
+    #[test]
+    fn exponential_delay_with_jitter() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            jitter: 0.1,
+        };
+
+        for (retry, expected) in [(1, 100), (2, 200), (3, 300), (4, 300)] {
+            let delay = policy.delay(retry).as_secs_f64() * 1000.0;
+            assert!(
+                (expected as f64 * 0.9..=expected as f64 * 1.1).contains(&delay),
+                "retry {retry} waited {delay}ms"
+            );
+        }
+    }
+
+    #[test]
+    fn retry_until_success_or_max_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::ZERO,
+            ..Default::default()
+        };
+
+        let mut attempts = Vec::new();
+        let res = policy.retry(|attempt| {
+            attempts.push(attempt);
+            match attempt {
+                2 => Ok(attempt),
+                _ => Err(anyhow!("attempt {attempt} failed")),
+            }
+        });
+        assert_eq!(2, res.unwrap());
+        assert_eq!(vec![1, 2], attempts);
+
+        let res: anyhow::Result<()> = policy.retry(|attempt| Err(anyhow!("attempt {attempt}")));
+        assert_eq!("attempt 3", res.unwrap_err().to_string());
+    }
+}
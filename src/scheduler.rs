@@ -0,0 +1,571 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    time::Instant,
+};
+
+use crate::{file_selection::FilePriority, stats::RollingRate};
+
+/// A block of a piece, as requested from a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug)]
+struct PeerSlot {
+    in_flight: Vec<Block>,
+    rate: RollingRate,
+    /// Pieces the peer advertised, with its BitField or Have messages
+    pieces: HashSet<u32>,
+    /// Blocks requested from the peer that another peer delivered first, to be cancelled
+    cancelled: Vec<Block>,
+    /// Set when the peer stopped delivering the blocks requested from it, until it delivers one
+    snubbed: bool,
+}
+
+/// Hands out blocks to the peers of a download. Each peer may have a share of `window` blocks
+/// in flight proportional to its measured rate, and at least one, so that fast peers get more
+/// work without slow peers being starved. Peers are only given blocks of pieces they advertised,
+/// whatever piece other peers are working on, so a peer never waits for another one to finish a
+/// piece.
+///
+/// Among the blocks a peer may request, those of the pieces with the highest priority go first,
+/// then those of pieces already started so that they complete, then those of the rarest pieces
+/// among the connected peers, which keeps them in the swarm. Ties go in the order blocks were
+/// given.
+///
+/// Once every block was handed out, the download is in endgame: peers with room are also given
+/// the blocks in flight with others, so that the last pieces do not wait on a slow peer. The
+/// first copy of a block to arrive wins, the requests for the others are to be cancelled.
+///
+/// Peers that snub us, delivering none of the blocks requested from them for a while, have
+/// their blocks given to the others and may only have one block in flight until they deliver it.
+/// Peers choking us without the Fast extension drop our requests, their blocks are given back.
+#[derive(Debug)]
+pub struct BlockScheduler {
+    pending: VecDeque<Block>,
+    peers: BTreeMap<SocketAddr, PeerSlot>,
+    window: usize,
+    /// How many peers advertised each piece
+    availability: HashMap<u32, usize>,
+    /// Pieces of which blocks were handed out
+    started: HashSet<u32>,
+    priorities: Vec<FilePriority>,
+}
+
+impl BlockScheduler {
+    pub fn new(blocks: impl IntoIterator<Item = Block>, window: usize) -> Self {
+        BlockScheduler {
+            pending: blocks.into_iter().collect(),
+            peers: BTreeMap::new(),
+            window: window.max(1),
+            availability: HashMap::new(),
+            started: HashSet::new(),
+            priorities: Vec::new(),
+        }
+    }
+
+    /// Priority of each piece, pieces without one being of normal priority
+    pub fn with_priorities(mut self, priorities: Vec<FilePriority>) -> Self {
+        self.priorities = priorities;
+        self
+    }
+
+    pub fn add_peer(&mut self, peer: SocketAddr, now: Instant) {
+        self.peers.entry(peer).or_insert_with(|| PeerSlot {
+            in_flight: Vec::new(),
+            rate: RollingRate::new(now),
+            pieces: HashSet::new(),
+            cancelled: Vec::new(),
+            snubbed: false,
+        });
+    }
+
+    /// Records that `peer` has `pieces`
+    pub fn add_available(&mut self, peer: SocketAddr, pieces: impl IntoIterator<Item = u32>) {
+        if let Some(slot) = self.peers.get_mut(&peer) {
+            for piece in pieces {
+                if slot.pieces.insert(piece) {
+                    *self.availability.entry(piece).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    /// How many connected peers advertised `piece`
+    pub fn availability(&self, piece: u32) -> usize {
+        self.availability.get(&piece).copied().unwrap_or_default()
+    }
+
+    /// Whether `peer` has something we still need: blocks requested from it, or blocks nobody
+    /// was asked for yet in pieces it has
+    pub fn wants_from(&self, peer: SocketAddr) -> bool {
+        self.peers.get(&peer).is_some_and(|slot| {
+            !slot.in_flight.is_empty()
+                || self.pending.iter().any(|i| slot.pieces.contains(&i.piece))
+                || self.endgame_block(peer, |_| true).is_some()
+        })
+    }
+
+    /// How many blocks `peer` may have in flight. Peers not measured yet weigh as much as the
+    /// average measured peer, snubbing peers are left out.
+    pub fn allowance(&self, peer: SocketAddr) -> usize {
+        let rates = self
+            .peers
+            .values()
+            .filter(|i| !i.snubbed)
+            .filter_map(|i| i.rate.bytes_per_second())
+            .collect::<Vec<_>>();
+        let average = if rates.is_empty() {
+            1.0
+        } else {
+            rates.iter().sum::<f64>() / rates.len() as f64
+        };
+        let weight = |slot: &PeerSlot| slot.rate.bytes_per_second().unwrap_or(average);
+        let total = self
+            .peers
+            .values()
+            .filter(|i| !i.snubbed)
+            .map(weight)
+            .sum::<f64>();
+        match self.peers.get(&peer) {
+            Some(slot) if slot.snubbed => 1,
+            Some(slot) if total > 0.0 => {
+                ((self.window as f64 * weight(slot) / total).round() as usize).max(1)
+            }
+            Some(_) => 1,
+            None => 0,
+        }
+    }
+
+    /// The next block `peer` should request, if it has room for one
+    pub fn next(&mut self, peer: SocketAddr) -> Option<Block> {
+        self.next_matching(peer, |_| true)
+    }
+
+    /// The next block `peer` should request among those `filter` accepts, such as the blocks of
+    /// the pieces it allows us to request while choked
+    pub fn next_matching(
+        &mut self,
+        peer: SocketAddr,
+        filter: impl Fn(&Block) -> bool,
+    ) -> Option<Block> {
+        let allowance = self.allowance(peer);
+        let slot = self.peers.get(&peer)?;
+        if slot.in_flight.len() >= allowance {
+            return None;
+        }
+        let block = if self.pending.is_empty() {
+            self.endgame_block(peer, filter)?
+        } else {
+            let (position, _) = self
+                .pending
+                .iter()
+                .enumerate()
+                .filter(|(_, i)| slot.pieces.contains(&i.piece) && filter(i))
+                .min_by_key(|(position, i)| {
+                    (
+                        Reverse(
+                            self.priorities
+                                .get(i.piece as usize)
+                                .copied()
+                                .unwrap_or_default(),
+                        ),
+                        !self.started.contains(&i.piece),
+                        self.availability.get(&i.piece).copied().unwrap_or_default(),
+                        *position,
+                    )
+                })?;
+            self.pending.remove(position)?
+        };
+        self.started.insert(block.piece);
+        self.peers.get_mut(&peer)?.in_flight.push(block);
+        Some(block)
+    }
+
+    /// A block in flight with other peers that `peer` could request too, the one requested from
+    /// the fewest peers first. Only when every block was handed out.
+    fn endgame_block(&self, peer: SocketAddr, filter: impl Fn(&Block) -> bool) -> Option<Block> {
+        if !self.pending.is_empty() {
+            return None;
+        }
+        let slot = self.peers.get(&peer)?;
+        let mut copies = HashMap::<Block, usize>::new();
+        for (_, other) in self.peers.iter().filter(|(i, _)| **i != peer) {
+            for block in &other.in_flight {
+                *copies.entry(*block).or_default() += 1;
+            }
+        }
+        copies
+            .into_iter()
+            .filter(|(i, _)| {
+                slot.pieces.contains(&i.piece) && !slot.in_flight.contains(i) && filter(i)
+            })
+            .min_by_key(|(i, copies)| (*copies, i.piece, i.begin))
+            .map(|(i, _)| i)
+    }
+
+    /// Whether the last blocks are requested from several peers at once
+    pub fn in_endgame(&self) -> bool {
+        self.pending.is_empty() && !self.is_finished()
+    }
+
+    /// Records that `peer` delivered `block`, returns `false` when it was not requested from it
+    pub fn received(&mut self, peer: SocketAddr, block: Block, now: Instant) -> bool {
+        let Some(slot) = self.peers.get_mut(&peer) else {
+            return false;
+        };
+        let Some(position) = slot.in_flight.iter().position(|i| *i == block) else {
+            return false;
+        };
+        slot.in_flight.remove(position);
+        slot.rate.record(block.length.into(), now);
+        slot.snubbed = false;
+        // in endgame, the other peers asked for it do not have to send it anymore
+        for (_, other) in self.peers.iter_mut().filter(|(i, _)| **i != peer) {
+            if let Some(position) = other.in_flight.iter().position(|i| *i == block) {
+                other.in_flight.remove(position);
+                other.cancelled.push(block);
+            }
+        }
+        true
+    }
+
+    /// Blocks requested from `peer` that another peer delivered since last asked, for their
+    /// requests to be cancelled
+    pub fn take_cancelled(&mut self, peer: SocketAddr) -> Vec<Block> {
+        self.peers
+            .get_mut(&peer)
+            .map(|i| std::mem::take(&mut i.cancelled))
+            .unwrap_or_default()
+    }
+
+    /// Records that `peer` will not deliver `block`, making it available again. Returns `false`
+    /// when it was not requested from it.
+    pub fn rejected(&mut self, peer: SocketAddr, block: Block) -> bool {
+        let Some(slot) = self.peers.get_mut(&peer) else {
+            return false;
+        };
+        let Some(position) = slot.in_flight.iter().position(|i| *i == block) else {
+            return false;
+        };
+        slot.in_flight.remove(position);
+        self.requeue([block]);
+        true
+    }
+
+    /// Records that `peer` snubs us: the blocks it has in flight are made available to the
+    /// others and are to be cancelled. Returns `false` when it was already snubbing us.
+    pub fn snubbed(&mut self, peer: SocketAddr) -> bool {
+        let Some(slot) = self.peers.get_mut(&peer) else {
+            return false;
+        };
+        if slot.snubbed {
+            return false;
+        }
+        slot.snubbed = true;
+        let blocks = std::mem::take(&mut slot.in_flight);
+        slot.cancelled.extend(&blocks);
+        self.requeue(blocks);
+        true
+    }
+
+    /// Records that `peer` choked us, dropping our requests: the blocks it has in flight are
+    /// made available again, and returned
+    pub fn choked(&mut self, peer: SocketAddr) -> Vec<Block> {
+        let Some(slot) = self.peers.get_mut(&peer) else {
+            return Vec::new();
+        };
+        let blocks = std::mem::take(&mut slot.in_flight);
+        self.requeue(blocks.clone());
+        blocks
+    }
+
+    pub fn is_snubbed(&self, peer: SocketAddr) -> bool {
+        self.peers.get(&peer).is_some_and(|i| i.snubbed)
+    }
+
+    /// Forgets `peer`, making the blocks it had in flight available to the others
+    pub fn release(&mut self, peer: SocketAddr) {
+        if let Some(slot) = self.peers.remove(&peer) {
+            for piece in &slot.pieces {
+                if let Some(count) = self.availability.get_mut(piece) {
+                    *count -= 1;
+                }
+            }
+            self.requeue(slot.in_flight);
+        }
+    }
+
+    /// Makes blocks available again, ahead of those never requested. Blocks still in flight with
+    /// a peer, as they can be in endgame, are left to it.
+    pub fn requeue(&mut self, blocks: impl IntoIterator<Item = Block>) {
+        let mut blocks = blocks
+            .into_iter()
+            .filter(|i| !self.peers.values().any(|slot| slot.in_flight.contains(i)))
+            .collect::<Vec<_>>();
+        blocks.sort_by_key(|i| (i.piece, i.begin));
+        for block in blocks.into_iter().rev() {
+            self.pending.push_front(block);
+        }
+    }
+
+    pub fn in_flight(&self, peer: SocketAddr) -> usize {
+        self.peers.get(&peer).map_or(0, |i| i.in_flight.len())
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Whether every block was received
+    pub fn is_finished(&self) -> bool {
+        self.pending.is_empty() && self.peers.values().all(|i| i.in_flight.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
+    use crate::file_selection::FilePriority;
+
+    use super::{Block, BlockScheduler};
+
+    fn blocks(count: u32) -> Vec<Block> {
+        (0..count)
+            .map(|i| Block {
+                piece: i / 4,
+                begin: (i % 4) * 16,
+                length: 16,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn allowance_follows_measured_rates() -> anyhow::Result<()> {
+        let fast = SocketAddr::from_str("127.0.0.1:1")?;
+        let slow = SocketAddr::from_str("127.0.0.1:2")?;
+        let start = Instant::now();
+        let mut scheduler = BlockScheduler::new(blocks(64), 10);
+        scheduler.add_peer(fast, start);
+        scheduler.add_peer(slow, start);
+        scheduler.add_available(fast, 0..16);
+        scheduler.add_available(slow, 0..16);
+
+        assert_eq!(5, scheduler.allowance(fast));
+        assert_eq!(5, scheduler.allowance(slow));
+
+        // 5 blocks from the fast peer and 1 from the slow one over the same second
+        let fast_blocks = (0..5)
+            .map(|_| scheduler.next(fast).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(None, scheduler.next(fast));
+        let slow_block = scheduler.next(slow).unwrap();
+        let later = start + Duration::from_secs(1);
+        for (i, block) in fast_blocks.into_iter().enumerate() {
+            assert!(scheduler.received(fast, block, if i < 4 { start } else { later }));
+        }
+        assert!(scheduler.received(slow, slow_block, later));
+        assert!(!scheduler.received(slow, slow_block, later));
+
+        assert_eq!(8, scheduler.allowance(fast));
+        assert_eq!(2, scheduler.allowance(slow));
+        Ok(())
+    }
+
+    #[test]
+    fn released_blocks_go_first() -> anyhow::Result<()> {
+        let first = SocketAddr::from_str("127.0.0.1:1")?;
+        let second = SocketAddr::from_str("127.0.0.1:2")?;
+        let mut scheduler = BlockScheduler::new(blocks(3), 4);
+        scheduler.add_peer(first, Instant::now());
+        scheduler.add_peer(second, Instant::now());
+        scheduler.add_available(first, [0]);
+        scheduler.add_available(second, [0]);
+
+        let requested = [scheduler.next(first), scheduler.next(first)];
+        assert_eq!(None, scheduler.next(first));
+        scheduler.release(first);
+
+        assert_eq!(requested[0], scheduler.next(second));
+        assert_eq!(requested[1], scheduler.next(second));
+        assert!(!scheduler.is_finished());
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_blocks_are_requested_again() -> anyhow::Result<()> {
+        let first = SocketAddr::from_str("127.0.0.1:1")?;
+        let second = SocketAddr::from_str("127.0.0.1:2")?;
+        let mut scheduler = BlockScheduler::new(blocks(8), 4);
+        scheduler.add_peer(first, Instant::now());
+        scheduler.add_peer(second, Instant::now());
+        scheduler.add_available(first, [0, 1]);
+        scheduler.add_available(second, [0, 1]);
+
+        let block = scheduler.next_matching(first, |i| i.piece == 1).unwrap();
+        assert_eq!(1, block.piece);
+        assert!(scheduler.rejected(first, block));
+        assert!(!scheduler.rejected(first, block));
+
+        assert_eq!(Some(block), scheduler.next(second));
+        Ok(())
+    }
+
+    #[test]
+    fn only_advertised_pieces_are_requested() -> anyhow::Result<()> {
+        let peer = SocketAddr::from_str("127.0.0.1:1")?;
+        let mut scheduler = BlockScheduler::new(blocks(8), 4);
+        scheduler.add_peer(peer, Instant::now());
+
+        assert!(!scheduler.wants_from(peer));
+        assert_eq!(None, scheduler.next(peer));
+
+        scheduler.add_available(peer, [1]);
+
+        assert!(scheduler.wants_from(peer));
+        assert_eq!(1, scheduler.next(peer).unwrap().piece);
+        Ok(())
+    }
+
+    #[test]
+    fn rarest_pieces_go_first() -> anyhow::Result<()> {
+        let peers = (1..=3)
+            .map(|i| SocketAddr::from_str(&format!("127.0.0.1:{i}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut scheduler = BlockScheduler::new(blocks(12), 8);
+        for peer in &peers {
+            scheduler.add_peer(*peer, Instant::now());
+        }
+        scheduler.add_available(peers[0], [0, 1, 2]);
+        scheduler.add_available(peers[1], [0, 2]);
+        scheduler.add_available(peers[2], [0]);
+        assert_eq!(3, scheduler.availability(0));
+
+        // the only piece nobody else has, then the rarer of the others
+        assert_eq!(1, scheduler.next(peers[0]).unwrap().piece);
+        assert_eq!(2, scheduler.next(peers[1]).unwrap().piece);
+        // started pieces are completed before others are started
+        assert_eq!(1, scheduler.next(peers[0]).unwrap().piece);
+
+        // piece 2 becomes the rarest once a peer having it leaves
+        scheduler.release(peers[1]);
+        assert_eq!(1, scheduler.availability(2));
+        assert_eq!(0, scheduler.next(peers[2]).unwrap().piece);
+        Ok(())
+    }
+
+    #[test]
+    fn priority_goes_before_rarity() -> anyhow::Result<()> {
+        let first = SocketAddr::from_str("127.0.0.1:1")?;
+        let second = SocketAddr::from_str("127.0.0.1:2")?;
+        let mut scheduler = BlockScheduler::new(blocks(8), 4)
+            .with_priorities(vec![FilePriority::High, FilePriority::Normal]);
+        scheduler.add_peer(first, Instant::now());
+        scheduler.add_peer(second, Instant::now());
+        scheduler.add_available(first, [0, 1]);
+        scheduler.add_available(second, [0]);
+
+        assert_eq!(0, scheduler.next(first).unwrap().piece);
+        Ok(())
+    }
+
+    #[test]
+    fn endgame_requests_last_blocks_twice() -> anyhow::Result<()> {
+        let slow = SocketAddr::from_str("127.0.0.1:1")?;
+        let fast = SocketAddr::from_str("127.0.0.1:2")?;
+        let mut scheduler = BlockScheduler::new(blocks(2), 4);
+        scheduler.add_peer(slow, Instant::now());
+        scheduler.add_peer(fast, Instant::now());
+        scheduler.add_available(slow, [0]);
+
+        let first = scheduler.next(slow).unwrap();
+        let second = scheduler.next(slow).unwrap();
+        assert!(scheduler.in_endgame());
+        assert!(!scheduler.wants_from(fast));
+        scheduler.add_available(fast, [0]);
+        assert!(scheduler.wants_from(fast));
+
+        assert_eq!(Some(first), scheduler.next(fast));
+        assert_eq!(Some(second), scheduler.next(fast));
+        assert_eq!(None, scheduler.next(fast));
+
+        assert!(scheduler.received(fast, first, Instant::now()));
+        assert_eq!(vec![first], scheduler.take_cancelled(slow));
+        assert!(scheduler.take_cancelled(slow).is_empty());
+        // the copy that arrives late is not expected anymore
+        assert!(!scheduler.received(slow, first, Instant::now()));
+
+        // the block stays in flight with the other peer when one leaves
+        scheduler.release(fast);
+        assert!(!scheduler.has_pending());
+        assert_eq!(1, scheduler.in_flight(slow));
+        assert!(scheduler.received(slow, second, Instant::now()));
+        assert!(scheduler.is_finished());
+        Ok(())
+    }
+
+    #[test]
+    fn snubbing_peers_give_back_their_blocks() -> anyhow::Result<()> {
+        let silent = SocketAddr::from_str("127.0.0.1:1")?;
+        let other = SocketAddr::from_str("127.0.0.1:2")?;
+        let mut scheduler = BlockScheduler::new(blocks(8), 8);
+        scheduler.add_peer(silent, Instant::now());
+        scheduler.add_peer(other, Instant::now());
+        scheduler.add_available(silent, [0, 1]);
+        scheduler.add_available(other, [0, 1]);
+
+        let requested = [scheduler.next(silent), scheduler.next(silent)];
+        assert!(scheduler.snubbed(silent));
+        assert!(!scheduler.snubbed(silent));
+        assert!(scheduler.is_snubbed(silent));
+        assert_eq!(0, scheduler.in_flight(silent));
+        assert_eq!(
+            requested.iter().flatten().copied().collect::<Vec<_>>(),
+            scheduler.take_cancelled(silent)
+        );
+        assert_eq!(requested[0], scheduler.next(other));
+
+        // one block at a time until it delivers, the others sharing the whole window
+        assert_eq!(1, scheduler.allowance(silent));
+        assert_eq!(8, scheduler.allowance(other));
+        let block = scheduler.next(silent).unwrap();
+        assert_eq!(None, scheduler.next(silent));
+        assert!(scheduler.received(silent, block, Instant::now()));
+        assert!(!scheduler.is_snubbed(silent));
+        Ok(())
+    }
+
+    #[test]
+    fn choking_peers_give_back_their_blocks() -> anyhow::Result<()> {
+        let choking = SocketAddr::from_str("127.0.0.1:1")?;
+        let other = SocketAddr::from_str("127.0.0.1:2")?;
+        let mut scheduler = BlockScheduler::new(blocks(8), 8);
+        scheduler.add_peer(choking, Instant::now());
+        scheduler.add_peer(other, Instant::now());
+        scheduler.add_available(choking, [0, 1]);
+        scheduler.add_available(other, [0, 1]);
+
+        let requested = [scheduler.next(choking), scheduler.next(choking)];
+        assert_eq!(
+            requested.iter().flatten().copied().collect::<Vec<_>>(),
+            scheduler.choked(choking)
+        );
+        assert_eq!(0, scheduler.in_flight(choking));
+        // nothing to cancel, the peer dropped the requests itself
+        assert!(scheduler.take_cancelled(choking).is_empty());
+        assert_eq!(requested[0], scheduler.next(other));
+        assert_eq!(requested[1], scheduler.next(choking));
+        assert_eq!(vec![requested[0].unwrap()], scheduler.choked(other));
+        Ok(())
+    }
+}
@@ -1,7 +1,178 @@
+use std::collections::BTreeMap;
+
 use sha1::{Digest, Sha1};
 
+/// Computes the SHA-1 digests used to verify pieces, so that faster implementations can be
+/// plugged into the client
+pub trait PieceHasher: Send + Sync {
+    fn hash(&self, bytes: &[u8]) -> [u8; 20];
+
+    /// Human readable name of the implementation
+    fn name(&self) -> &'static str;
+
+    /// A digest fed bit by bit. Implementations without a streaming API keep the default, which
+    /// buffers everything and hashes it at the end.
+    fn start(&self) -> Box<dyn HashState + '_> {
+        Box::new(Buffered {
+            hasher: self,
+            bytes: Vec::new(),
+        })
+    }
+}
+
+/// A digest being computed
+pub trait HashState: Send {
+    fn update(&mut self, bytes: &[u8]);
+
+    fn finalize(self: Box<Self>) -> [u8; 20];
+}
+
+struct Buffered<'a, H: PieceHasher + ?Sized> {
+    hasher: &'a H,
+    bytes: Vec<u8>,
+}
+
+impl<H: PieceHasher + ?Sized> HashState for Buffered<'_, H> {
+    fn update(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn finalize(self: Box<Self>) -> [u8; 20] {
+        self.hasher.hash(&self.bytes)
+    }
+}
+
+impl HashState for Sha1 {
+    fn update(&mut self, bytes: &[u8]) {
+        Digest::update(self, bytes);
+    }
+
+    fn finalize(self: Box<Self>) -> [u8; 20] {
+        Digest::finalize(*self).into()
+    }
+}
+
+/// Hashes a piece as its blocks arrive, in whatever order: blocks are fed to the digest as soon
+/// as everything before them was, only those arriving ahead of a gap are kept aside
+pub struct IncrementalHash<'a> {
+    state: Box<dyn HashState + 'a>,
+    hashed: u64,
+    ahead: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<'a> IncrementalHash<'a> {
+    pub fn new(hasher: &'a dyn PieceHasher) -> Self {
+        IncrementalHash {
+            state: hasher.start(),
+            hashed: 0,
+            ahead: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the block starting at `offset` in the piece, each block must be added only once
+    pub fn add(&mut self, offset: u64, block: &[u8]) {
+        if offset != self.hashed {
+            self.ahead.insert(offset, block.to_vec());
+            return;
+        }
+        self.state.update(block);
+        self.hashed += block.len() as u64;
+        while let Some(block) = self.ahead.remove(&self.hashed) {
+            self.state.update(&block);
+            self.hashed += block.len() as u64;
+        }
+    }
+
+    /// Bytes fed to the digest so far
+    pub fn hashed(&self) -> u64 {
+        self.hashed
+    }
+
+    pub fn finish(self) -> [u8; 20] {
+        self.state.finalize()
+    }
+}
+
+/// Implementation from the `sha1` crate
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RustCryptoSha1;
+
+impl PieceHasher for RustCryptoSha1 {
+    fn hash(&self, bytes: &[u8]) -> [u8; 20] {
+        Sha1::digest(bytes).into()
+    }
+
+    fn name(&self) -> &'static str {
+        "sha1 crate"
+    }
+
+    fn start(&self) -> Box<dyn HashState + '_> {
+        Box::new(Sha1::new())
+    }
+}
+
 pub fn hash(bytes: &[u8]) -> [u8; 20] {
-    let mut hasher = Sha1::new();
-    hasher.update(&bytes);
-    TryInto::<[u8; 20]>::try_into(hasher.finalize()).expect("hash is not 20 bytes")
+    RustCryptoSha1.hash(bytes)
+}
+
+/// Which compression function the `sha1` crate picks at runtime: it uses the SHA extensions
+/// (SHA-NI) on x86 CPUs that have them, and a software implementation otherwise
+pub fn implementation() -> &'static str {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("sha")
+            && is_x86_feature_detected!("sse2")
+            && is_x86_feature_detected!("ssse3")
+            && is_x86_feature_detected!("sse4.1")
+        {
+            return "hardware (SHA-NI)";
+        }
+    }
+    "software"
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash, implementation, IncrementalHash, PieceHasher, RustCryptoSha1};
+
+    #[test]
+    fn hash_known_value() {
+        assert_eq!(
+            "a9993e364706816aba3e25717850c26c9cd0d89d",
+            hex::encode(hash(b"abc"))
+        );
+    }
+
+    #[test]
+    fn implementation_is_reported() {
+        assert!(["hardware (SHA-NI)", "software"].contains(&implementation()));
+    }
+
+    #[test]
+    fn incremental_hash_out_of_order() {
+        struct Buffering;
+
+        impl PieceHasher for Buffering {
+            fn hash(&self, bytes: &[u8]) -> [u8; 20] {
+                hash(bytes)
+            }
+
+            fn name(&self) -> &'static str {
+                "buffering"
+            }
+        }
+
+        let content = b"0123456789abcdef";
+        for hasher in [&RustCryptoSha1 as &dyn PieceHasher, &Buffering] {
+            let mut incremental = IncrementalHash::new(hasher);
+            incremental.add(8, &content[8..12]);
+            incremental.add(4, &content[4..8]);
+            assert_eq!(0, incremental.hashed());
+            incremental.add(0, &content[0..4]);
+            assert_eq!(12, incremental.hashed());
+            incremental.add(12, &content[12..]);
+
+            assert_eq!(hash(content), incremental.finish());
+        }
+    }
 }
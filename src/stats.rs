@@ -0,0 +1,590 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::torrent::FileInfo;
+
+/// Time constant of the rolling rate, samples older than this weigh less and less
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+/// Samples closer than this are accumulated, to avoid dividing by tiny durations
+const MIN_SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+/// Progress output is not refreshed more often than this
+const PROGRESS_REFRESH: Duration = Duration::from_millis(200);
+
+/// Download rate, smoothed with an exponential moving average over time
+#[derive(Debug, Clone)]
+pub struct RollingRate {
+    rate: Option<f64>,
+    pending_bytes: u64,
+    last_sample: Instant,
+}
+
+impl RollingRate {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            rate: None,
+            pending_bytes: 0,
+            last_sample: start,
+        }
+    }
+
+    pub fn record(&mut self, bytes: u64, now: Instant) {
+        self.pending_bytes += bytes;
+        let elapsed = now.saturating_duration_since(self.last_sample);
+        if elapsed < MIN_SAMPLE_INTERVAL {
+            return;
+        }
+
+        let elapsed = elapsed.as_secs_f64();
+        let sample = self.pending_bytes as f64 / elapsed;
+        let alpha = 1.0 - (-elapsed / RATE_WINDOW.as_secs_f64()).exp();
+        self.rate = Some(match self.rate {
+            Some(rate) => rate + alpha * (sample - rate),
+            None => sample,
+        });
+        self.pending_bytes = 0;
+        self.last_sample = now;
+    }
+
+    /// Bytes per second, if enough time passed to have a measure
+    pub fn bytes_per_second(&self) -> Option<f64> {
+        self.rate
+    }
+}
+
+/// What a single peer contributed to a download, and how the connection to it is going
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    pub address: SocketAddr,
+    pub client: Option<String>,
+    /// Bytes received from the peer
+    pub bytes: u64,
+    /// Bytes sent to the peer
+    pub uploaded: u64,
+    pub hash_failures: usize,
+    /// Whether the peer refuses our requests, as it does until it unchokes us
+    pub choking_us: bool,
+    /// Time between requesting a block and receiving it, smoothed
+    pub request_latency: Option<Duration>,
+    pub connected: Instant,
+    pub disconnected: Option<Instant>,
+    pub last_block: Option<Instant>,
+}
+
+impl PeerStats {
+    pub fn new(address: SocketAddr, connected: Instant) -> Self {
+        Self {
+            address,
+            client: None,
+            bytes: 0,
+            uploaded: 0,
+            hash_failures: 0,
+            choking_us: true,
+            request_latency: None,
+            connected,
+            disconnected: None,
+            last_block: None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.disconnected.is_none()
+    }
+
+    /// How long the peer was connected for, up to `now` if it still is
+    pub fn connection_age(&self, now: Instant) -> Duration {
+        self.disconnected
+            .unwrap_or(now)
+            .saturating_duration_since(self.connected)
+    }
+
+    /// Average rate between the connection and the last block received from this peer
+    pub fn average_rate(&self) -> Option<f64> {
+        let elapsed = self
+            .last_block?
+            .saturating_duration_since(self.connected)
+            .as_secs_f64();
+        if elapsed > 0.0 {
+            Some(self.bytes as f64 / elapsed)
+        } else {
+            None
+        }
+    }
+}
+
+/// Counters quantifying how much work was wasted, either by the swarm or by us
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IntegrityStats {
+    pub blocks_rerequested: usize,
+    pub pieces_failed_verification: usize,
+    pub peers_banned: usize,
+    pub bytes_wasted: u64,
+}
+
+impl Display for IntegrityStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "blocks re-requested:        {}", self.blocks_rerequested)?;
+        writeln!(
+            f,
+            "pieces failed verification: {}",
+            self.pieces_failed_verification
+        )?;
+        writeln!(f, "peers banned:               {}", self.peers_banned)?;
+        writeln!(
+            f,
+            "bytes wasted:               {}",
+            format_bytes(self.bytes_wasted as f64)
+        )
+    }
+}
+
+/// How much of one of the torrent's files is downloaded and verified
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileProgress {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+    pub verified_bytes: u64,
+}
+
+impl FileProgress {
+    pub fn completion(&self) -> f64 {
+        match self.length {
+            0 => 1.0,
+            length => self.verified_bytes as f64 / length as f64,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.verified_bytes >= self.length
+    }
+}
+
+/// Statistics about an ongoing (or finished) download
+#[derive(Debug, Clone)]
+pub struct DownloadStats {
+    pub total_bytes: u64,
+    pub downloaded_bytes: u64,
+    pub started: Instant,
+    pub peers: BTreeMap<SocketAddr, PeerStats>,
+    pub integrity: IntegrityStats,
+    /// Per file progress, empty when only pieces are downloaded
+    pub files: Vec<FileProgress>,
+    rate: RollingRate,
+    last_progress: Option<Instant>,
+}
+
+impl Default for DownloadStats {
+    fn default() -> Self {
+        DownloadStats::new(0, Instant::now())
+    }
+}
+
+impl DownloadStats {
+    pub fn new(total_bytes: u64, started: Instant) -> Self {
+        Self {
+            total_bytes,
+            downloaded_bytes: 0,
+            started,
+            peers: BTreeMap::new(),
+            integrity: IntegrityStats::default(),
+            files: Vec::new(),
+            rate: RollingRate::new(started),
+            last_progress: None,
+        }
+    }
+
+    /// Tracks the progress of each of `files`
+    pub fn with_files(mut self, files: &[FileInfo]) -> Self {
+        self.files = files
+            .iter()
+            .map(|file| FileProgress {
+                path: file.path.clone(),
+                offset: file.offset,
+                length: file.length,
+                verified_bytes: 0,
+            })
+            .collect();
+        self
+    }
+
+    /// The bytes from `offset` to `offset + length` in the torrent passed verification, credits
+    /// them to the files they belong to
+    pub fn record_verified_bytes(&mut self, offset: u64, length: u64) {
+        let end = offset + length;
+        for file in &mut self.files {
+            let overlap = end
+                .min(file.offset + file.length)
+                .saturating_sub(offset.max(file.offset));
+            file.verified_bytes += overlap;
+        }
+    }
+
+    /// Completion of each file, e.g. `100.0% Season 1/E01.mkv`
+    pub fn file_report(&self) -> String {
+        self.files
+            .iter()
+            .map(|file| {
+                format!(
+                    "{:>5.1}% {}\n",
+                    file.completion() * 100.0,
+                    file.path.display()
+                )
+            })
+            .collect()
+    }
+
+    pub fn peer_mut(&mut self, address: SocketAddr, now: Instant) -> &mut PeerStats {
+        self.peers
+            .entry(address)
+            .or_insert_with(|| PeerStats::new(address, now))
+    }
+
+    /// A connection to `peer` was established, again if it was connected before
+    pub fn record_connected(&mut self, peer: SocketAddr, now: Instant) -> &mut PeerStats {
+        let peer = self.peer_mut(peer, now);
+        if peer.disconnected.take().is_some() {
+            peer.connected = now;
+            peer.choking_us = true;
+        }
+        peer
+    }
+
+    pub fn record_disconnected(&mut self, peer: SocketAddr, now: Instant) {
+        self.peer_mut(peer, now).disconnected = Some(now);
+    }
+
+    /// A block requested from `peer` arrived after `latency`. Samples are averaged the way TCP
+    /// smoothes round-trip times, each weighing an eighth.
+    pub fn record_request_latency(&mut self, peer: SocketAddr, latency: Duration, now: Instant) {
+        let peer = self.peer_mut(peer, now);
+        peer.request_latency = Some(match peer.request_latency {
+            Some(smoothed) => (smoothed * 7 + latency) / 8,
+            None => latency,
+        });
+    }
+
+    pub fn record_upload(&mut self, peer: SocketAddr, bytes: u64, now: Instant) {
+        self.peer_mut(peer, now).uploaded += bytes;
+    }
+
+    pub fn record_block(&mut self, peer: SocketAddr, bytes: u64, now: Instant) {
+        self.downloaded_bytes += bytes;
+        self.rate.record(bytes, now);
+        let peer = self.peer_mut(peer, now);
+        peer.bytes += bytes;
+        peer.last_block = Some(now);
+    }
+
+    /// A piece failed verification, all of its bytes are wasted and will have to be downloaded
+    /// again
+    pub fn record_hash_failure(&mut self, peer: SocketAddr, bytes: u64, now: Instant) {
+        self.downloaded_bytes = self.downloaded_bytes.saturating_sub(bytes);
+        self.peer_mut(peer, now).hash_failures += 1;
+        self.integrity.pieces_failed_verification += 1;
+        self.integrity.bytes_wasted += bytes;
+    }
+
+    /// A block that was already received came in again
+    pub fn record_duplicate_block(&mut self, bytes: u64) {
+        self.integrity.bytes_wasted += bytes;
+    }
+
+    pub fn record_rerequested_blocks(&mut self, count: usize) {
+        self.integrity.blocks_rerequested += count;
+    }
+
+    pub fn record_peer_banned(&mut self) {
+        self.integrity.peers_banned += 1;
+    }
+
+    /// Table of what each peer contributed, biggest contributors first
+    pub fn peer_report(&self) -> String {
+        let mut peers = self.peers.values().collect::<Vec<_>>();
+        peers.sort_by_key(|i| std::cmp::Reverse(i.bytes));
+        let mut report = format!(
+            "{:<21} {:<20} {:>12} {:>14} {:>13}\n",
+            "peer", "client", "bytes", "average rate", "hash failures"
+        );
+        for peer in peers {
+            report.push_str(&format!(
+                "{:<21} {:<20} {:>12} {:>14} {:>13}\n",
+                peer.address.to_string(),
+                peer.client.as_deref().unwrap_or("unknown"),
+                peer.bytes,
+                format!("{}/s", format_bytes(peer.average_rate().unwrap_or(0.0))),
+                peer.hash_failures
+            ));
+        }
+        report
+    }
+
+    /// Table of the peers connected at `now`, with the state of their connection
+    pub fn connected_peers_report(&self, now: Instant) -> String {
+        let mut report = format!(
+            "{:<21} {:<20} {:>10} {:>10} {:>8} {:>7} {:>9}\n",
+            "peer", "client", "down", "up", "latency", "choked", "connected"
+        );
+        for peer in self.peers.values().filter(|i| i.is_connected()) {
+            report.push_str(&format!(
+                "{:<21} {:<20} {:>10} {:>10} {:>8} {:>7} {:>9}\n",
+                peer.address.to_string(),
+                peer.client.as_deref().unwrap_or("unknown"),
+                format_bytes(peer.bytes as f64),
+                format_bytes(peer.uploaded as f64),
+                peer.request_latency
+                    .map_or("--".to_owned(), |i| format!("{}ms", i.as_millis())),
+                if peer.choking_us { "yes" } else { "no" },
+                format_duration(peer.connection_age(now))
+            ));
+        }
+        report
+    }
+
+    pub fn bytes_per_second(&self) -> Option<f64> {
+        self.rate.bytes_per_second()
+    }
+
+    pub fn remaining_bytes(&self) -> u64 {
+        self.total_bytes.saturating_sub(self.downloaded_bytes)
+    }
+
+    /// Estimated time remaining, based on the rolling rate
+    pub fn eta(&self) -> Option<Duration> {
+        match self.remaining_bytes() {
+            0 => Some(Duration::ZERO),
+            remaining => self
+                .bytes_per_second()
+                .filter(|rate| *rate > 0.0)
+                .map(|rate| Duration::from_secs_f64(remaining as f64 / rate)),
+        }
+    }
+
+    pub fn completion(&self) -> f64 {
+        match self.total_bytes {
+            0 => 1.0,
+            total => self.downloaded_bytes as f64 / total as f64,
+        }
+    }
+
+    /// Whether progress should be printed again, so the output is not flooded
+    pub fn progress_due(&mut self, now: Instant) -> bool {
+        let due = self.downloaded_bytes >= self.total_bytes
+            || self
+                .last_progress
+                .is_none_or(|last| now.saturating_duration_since(last) >= PROGRESS_REFRESH);
+        if due {
+            self.last_progress = Some(now);
+        }
+        due
+    }
+
+    /// Single line progress bar, e.g. `[#####     ]  50.0% 1.00 MiB/s ETA 00:00:12`, followed by
+    /// how many files are complete when there are several, e.g. `files 3/10`
+    pub fn progress_line(&self, width: usize) -> String {
+        let filled = ((self.completion() * width as f64) as usize).min(width);
+        let mut line = format!(
+            "[{}{}] {:>5.1}% {}/s ETA {}",
+            "#".repeat(filled),
+            " ".repeat(width - filled),
+            self.completion() * 100.0,
+            format_bytes(self.bytes_per_second().unwrap_or(0.0)),
+            self.eta().map_or("--:--:--".to_owned(), format_duration)
+        );
+        if self.files.len() > 1 {
+            let complete = self.files.iter().filter(|i| i.is_complete()).count();
+            line.push_str(&format!(" files {complete}/{}", self.files.len()));
+        }
+        line
+    }
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
+    use crate::torrent::FileInfo;
+
+    use super::{format_duration, DownloadStats, IntegrityStats, RollingRate};
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from_str("127.0.0.1:6881").expect("valid address")
+    }
+
+    #[test]
+    fn rolling_rate_waits_for_minimum_interval() {
+        let start = Instant::now();
+        let mut rate = RollingRate::new(start);
+        rate.record(1000, start + Duration::from_millis(10));
+        assert_eq!(None, rate.bytes_per_second());
+        rate.record(1000, start + Duration::from_secs(1));
+        assert_eq!(Some(2000.0), rate.bytes_per_second());
+    }
+
+    #[test]
+    fn rolling_rate_is_smoothed() {
+        let start = Instant::now();
+        let mut rate = RollingRate::new(start);
+        rate.record(1000, start + Duration::from_secs(1));
+        rate.record(3000, start + Duration::from_secs(2));
+        let smoothed = rate.bytes_per_second().unwrap();
+        assert!(smoothed > 1000.0 && smoothed < 3000.0);
+    }
+
+    #[test]
+    fn eta_from_rate() {
+        let start = Instant::now();
+        let mut stats = DownloadStats::new(10_000, start);
+        assert_eq!(None, stats.eta());
+        stats.record_block(peer(), 1000, start + Duration::from_secs(1));
+        assert_eq!(Some(Duration::from_secs(9)), stats.eta());
+        stats.record_block(peer(), 9000, start + Duration::from_secs(2));
+        assert_eq!(Some(Duration::ZERO), stats.eta());
+    }
+
+    #[test]
+    fn contribution_per_peer() {
+        let start = Instant::now();
+        let other = SocketAddr::from_str("10.0.0.1:51413").expect("valid address");
+        let mut stats = DownloadStats::new(10_000, start);
+        stats.peer_mut(other, start).client = Some("Transmission 3.00".to_owned());
+        stats.record_block(peer(), 1000, start + Duration::from_secs(1));
+        stats.record_block(other, 4000, start + Duration::from_secs(2));
+        stats.record_hash_failure(other, 4000, start + Duration::from_secs(2));
+
+        assert_eq!(1000, stats.downloaded_bytes);
+        assert_eq!(1000, stats.peers[&peer()].bytes);
+        assert_eq!(Some(2000.0), stats.peers[&other].average_rate());
+        assert_eq!(1, stats.peers[&other].hash_failures);
+        let report = stats.peer_report();
+        let mut lines = report.lines().skip(1);
+        assert!(lines.next().is_some_and(|i| i.starts_with("10.0.0.1:51413")
+            && i.contains("Transmission 3.00")
+            && i.contains("1.95 KiB/s")));
+        assert!(lines
+            .next()
+            .is_some_and(|i| i.starts_with("127.0.0.1:6881")));
+    }
+
+    #[test]
+    fn connected_peers_state() {
+        let start = Instant::now();
+        let other = SocketAddr::from_str("10.0.0.1:51413").expect("valid address");
+        let mut stats = DownloadStats::new(10_000, start);
+        stats.record_connected(peer(), start).client = Some("Transmission 3.00".to_owned());
+        stats.record_connected(other, start);
+        stats.peer_mut(peer(), start).choking_us = false;
+        stats.record_request_latency(peer(), Duration::from_millis(80), start);
+        stats.record_request_latency(peer(), Duration::from_millis(160), start);
+        stats.record_upload(peer(), 2048, start);
+        stats.record_disconnected(other, start + Duration::from_secs(5));
+
+        let now = start + Duration::from_secs(90);
+        let peer_stats = &stats.peers[&peer()];
+        assert_eq!(Some(Duration::from_millis(90)), peer_stats.request_latency);
+        assert_eq!(Duration::from_secs(90), peer_stats.connection_age(now));
+        assert_eq!(
+            Duration::from_secs(5),
+            stats.peers[&other].connection_age(now)
+        );
+        let report = stats.connected_peers_report(now);
+        let mut lines = report.lines().skip(1);
+        assert!(lines.next().is_some_and(|i| i.starts_with("127.0.0.1:6881")
+            && i.contains("2.00 KiB")
+            && i.contains("90ms")
+            && i.contains("00:01:30")));
+        assert_eq!(None, lines.next());
+
+        // reconnecting starts over
+        stats.record_connected(other, now);
+        assert!(stats.peers[&other].is_connected());
+        assert!(stats.peers[&other].choking_us);
+        assert_eq!(Duration::ZERO, stats.peers[&other].connection_age(now));
+    }
+
+    #[test]
+    fn integrity_counters() {
+        let start = Instant::now();
+        let mut stats = DownloadStats::new(10_000, start);
+        stats.record_hash_failure(peer(), 1000, start);
+        stats.record_duplicate_block(100);
+        stats.record_rerequested_blocks(3);
+        stats.record_peer_banned();
+
+        assert_eq!(
+            IntegrityStats {
+                blocks_rerequested: 3,
+                pieces_failed_verification: 1,
+                peers_banned: 1,
+                bytes_wasted: 1100,
+            },
+            stats.integrity
+        );
+        assert_eq!(0, stats.downloaded_bytes);
+    }
+
+    #[test]
+    fn format_eta() {
+        assert_eq!("01:02:03", format_duration(Duration::from_secs(3723)));
+    }
+
+    #[test]
+    fn progress_line() {
+        let start = Instant::now();
+        let mut stats = DownloadStats::new(2048, start);
+        stats.record_block(peer(), 1024, start + Duration::from_secs(1));
+        assert_eq!(
+            "[#####     ]  50.0% 1.00 KiB/s ETA 00:00:01",
+            stats.progress_line(10)
+        );
+    }
+
+    #[test]
+    fn verified_bytes_credited_to_files() {
+        let files =
+            [("a", 0, 10), ("b", 10, 5), ("c", 15, 10)].map(|(path, offset, length)| FileInfo {
+                index: 0,
+                path: path.into(),
+                offset,
+                length,
+            });
+        let mut stats = DownloadStats::new(25, Instant::now()).with_files(&files);
+
+        stats.record_verified_bytes(0, 16);
+
+        assert_eq!(
+            vec![10, 5, 1],
+            stats
+                .files
+                .iter()
+                .map(|i| i.verified_bytes)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("100.0% a\n100.0% b\n 10.0% c\n", stats.file_report());
+        assert!(stats.progress_line(10).ends_with(" files 2/3"));
+    }
+}
@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+/// How long IO with a peer may take before the peer is given up on, so that a dead peer cannot
+/// hang a download
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// Opening a connection to a peer
+    pub connect: Duration,
+    /// Reading the peer's side of the handshake, encryption included
+    pub handshake: Duration,
+    /// Reading a message from a peer blocks are requested from, once hands are shaken
+    pub read: Duration,
+    /// Writing to a peer
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(5),
+            handshake: Duration::from_secs(5),
+            read: Duration::from_secs(30),
+            write: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Timeouts {
+    /// The same timeout for every operation
+    pub fn uniform(timeout: Duration) -> Self {
+        Self {
+            connect: timeout,
+            handshake: timeout,
+            read: timeout,
+            write: timeout,
+        }
+    }
+}
@@ -1,120 +1,221 @@
-use anyhow::Context;
+use std::{ops::Range, path::PathBuf};
+
+use anyhow::{bail, Context};
 use base64::{engine::general_purpose, Engine};
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_bencode::value::Value;
 
-use crate::{hashes::Hashes, sha1};
+use crate::{byte_string::ByteString, hashes::Hashes, sha1, sha256};
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "BencodedTorrent")]
 pub struct Torrent {
     pub announce: String,
+    pub announce_list: Option<Vec<Vec<String>>>,
+    /// Web seeds (BEP 19)
+    pub url_list: UrlList,
+    /// HTTP seeds (BEP 17)
+    pub httpseeds: Vec<String>,
     pub info: Info,
+    /// The info dictionary as found in the torrent, keys this client does not understand
+    /// included, which the info hashes are computed over
+    raw_info: Vec<u8>,
+}
+
+/// A torrent as bencoded, its info dictionary being kept as is
+#[derive(Deserialize)]
+struct BencodedTorrent {
+    announce: String,
+    #[serde(rename = "announce-list", default)]
+    announce_list: Option<Vec<Vec<String>>>,
+    #[serde(rename = "url-list", default)]
+    url_list: UrlList,
+    #[serde(default)]
+    httpseeds: Vec<String>,
+    info: Value,
+}
+
+impl TryFrom<BencodedTorrent> for Torrent {
+    type Error = serde_bencode::Error;
+
+    fn try_from(torrent: BencodedTorrent) -> Result<Self, Self::Error> {
+        let raw_info = serde_bencode::to_bytes(&torrent.info)?;
+        Ok(Torrent {
+            announce: torrent.announce,
+            announce_list: torrent.announce_list,
+            url_list: torrent.url_list,
+            httpseeds: torrent.httpseeds,
+            info: serde_bencode::from_bytes(&raw_info)?,
+            raw_info,
+        })
+    }
 }
 
 impl Torrent {
     pub fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
-        let bytes = serde_bencode::to_bytes(&self.info)?;
-        Ok(sha1::hash(&bytes))
+        Ok(sha1::hash(&self.raw_info))
+    }
+
+    /// SHA-256 info hash of hybrid torrents (BEP 52), computed over the info dictionary as is
+    pub fn info_hash_v2(&self) -> anyhow::Result<Option<[u8; 32]>> {
+        Ok((self.info.meta_version == Some(2)).then(|| sha256::hash(&self.raw_info)))
     }
 
-    pub fn total_len(&self) -> usize {
+    pub fn total_len(&self) -> u64 {
         self.info.total_len()
     }
 
-    fn piece_length(&self) -> usize {
-        self.info
-            .piece_length
-            .try_into()
-            .expect("usize can't hold a u32, what kind of architecture are you running this on?")
+    #[allow(dead_code)]
+    pub(crate) fn from_base64(content: &str) -> anyhow::Result<Torrent> {
+        serde_bencode::from_bytes(&general_purpose::STANDARD.decode(content)?)
+            .context("parse torrent file")
     }
 
-    pub fn pieces_count(&self) -> usize {
-        self.info.pieces.0.len()
+    #[allow(dead_code)]
+    pub(crate) fn from_bytes(content: &[u8]) -> anyhow::Result<Torrent> {
+        serde_bencode::from_bytes(content).context("parse torrent file")
     }
+}
 
-    fn last_piece_size(&self) -> usize {
-        match self.total_len() % self.piece_length() {
-            0 => self.piece_length(),
-            len => len,
-        }
+/// Info hash of a bencoded torrent, computed over its info dictionary as is rather than over
+/// what this client understands of it, so that unknown keys are accounted for
+pub fn raw_info_hash(content: &[u8]) -> anyhow::Result<[u8; 20]> {
+    let Value::Dict(torrent) = serde_bencode::from_bytes(content).context("parse torrent file")?
+    else {
+        bail!("torrent is not a dictionary");
+    };
+    let info = torrent
+        .get(&b"info"[..])
+        .context("torrent has no info dictionary")?;
+    Ok(sha1::hash(&serde_bencode::to_bytes(info)?))
+}
+
+/// URLs of the `url-list` key, which is either a single URL or a list of them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UrlList(pub Vec<String>);
+
+struct UrlListVisitor;
+
+impl<'de> Visitor<'de> for UrlListVisitor {
+    type Value = UrlList;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a URL or a list of URLs")
     }
 
-    pub fn pieces_info(&self) -> Vec<PieceInfo> {
-        let mut info = Vec::new();
-        for i in 0..self.pieces_count() {
-            info.push(PieceInfo {
-                index: i,
-                offset: i * self.piece_length(),
-                length: if i == self.pieces_count() - 1 {
-                    self.last_piece_size()
-                } else {
-                    self.piece_length()
-                },
-            })
-        }
-        info
-    }
-
-    /// A vector containing block division for the given piece in the given block size
-    pub fn blocks_info(&self, piece_index: usize, block_size: usize) -> Option<Vec<BlockInfo>> {
-        let pieces_info = self.pieces_info();
-        let pieces_info = pieces_info.get(piece_index)?;
-        let mut info = Vec::new();
-        let blocks_count = (pieces_info.length + block_size - 1) / block_size;
-        for i in 0..blocks_count {
-            info.push(BlockInfo {
-                offset: i * block_size,
-                length: if i == blocks_count - 1 {
-                    match pieces_info.length % block_size {
-                        0 => block_size,
-                        len => len,
-                    }
-                } else {
-                    block_size
-                },
-            })
-        }
-        Some(info)
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(UrlList(vec![String::from_utf8_lossy(v).into_owned()]))
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn from_base64(content: &str) -> anyhow::Result<Torrent> {
-        Ok(
-            serde_bencode::from_bytes(&general_purpose::STANDARD.decode(content)?)
-                .context("parse torrent file")?,
-        )
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(UrlList(vec![v.to_string()]))
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn from_bytes(content: &[u8]) -> anyhow::Result<Torrent> {
-        Ok(serde_bencode::from_bytes(&content).context("parse torrent file")?)
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut urls = Vec::new();
+        while let Some(url) = seq.next_element::<ByteString>()? {
+            urls.push(url.to_string());
+        }
+        Ok(UrlList(urls))
     }
 }
 
+impl<'de> Deserialize<'de> for UrlList {
+    fn deserialize<D>(deserializer: D) -> Result<UrlList, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(UrlListVisitor)
+    }
+}
+
+/// Inconsistencies in how a torrent splits its content into pieces
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum LayoutError {
+    #[error("piece length is zero")]
+    ZeroPieceLength,
+    #[error("torrent has {actual} piece hashes but its length requires {expected}")]
+    PieceCountMismatch { expected: u64, actual: u64 },
+    #[error("unsafe file path '{0}'")]
+    UnsafePath(String),
+}
+
 #[derive(Debug, PartialEq)]
 pub struct PieceInfo {
     pub index: usize,
-    pub offset: usize,
-    pub length: usize,
+    pub offset: u64,
+    pub length: u64,
 }
 
+/// Where a file sits in the torrent's contiguous byte stream
 #[derive(Debug, PartialEq)]
+pub struct FileInfo {
+    pub index: usize,
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl FileInfo {
+    /// Indexes of the pieces holding this file's bytes, empty for zero-length files
+    pub fn pieces(&self, piece_length: u64) -> Range<u64> {
+        match self.length {
+            0 => 0..0,
+            length => self.offset / piece_length..(self.offset + length - 1) / piece_length + 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct BlockInfo {
-    pub offset: usize,
-    pub length: usize,
+    pub offset: u64,
+    pub length: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Info {
-    pub name: String,
+    pub name: ByteString,
+    /// UTF-8 version of `name`, set by some clients when `name` uses another encoding
+    #[serde(
+        rename = "name.utf-8",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub name_utf8: Option<ByteString>,
     #[serde(rename = "piece length")]
-    pub piece_length: u32,
+    pub piece_length: u64,
     pub pieces: Hashes,
     #[serde(flatten)]
     pub keys: Keys,
+    /// 2 for v2 and hybrid torrents (BEP 52)
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u64>,
+    /// Files of v2 and hybrid torrents along with their Merkle roots, only kept for the info hash
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<Value>,
+    /// 1 for torrents only announced to their trackers, not to the DHT or through PEX (BEP 27)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
 }
 
 impl Info {
-    pub fn total_len(&self) -> usize {
+    pub fn total_len(&self) -> u64 {
         match &self.keys {
             Keys::SingleFile { length } => *length,
             Keys::MultiFile { files } => files.iter().map(|i| i.length).sum(),
@@ -124,26 +225,63 @@ impl Info {
     pub fn pieces_count(&self) -> usize {
         self.pieces.0.len()
     }
+
+    /// Name to display and write to disk: `name.utf-8` when present, `name` otherwise, with
+    /// invalid UTF-8 sequences replaced
+    pub fn display_name(&self) -> String {
+        self.name_utf8
+            .as_ref()
+            .unwrap_or(&self.name)
+            .to_string_lossy()
+            .into_owned()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Keys {
-    SingleFile { length: usize },
+    SingleFile { length: u64 },
     MultiFile { files: Vec<File> },
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct File {
-    pub length: usize,
-    pub path: Vec<String>,
+    pub length: u64,
+    pub path: Vec<ByteString>,
+    /// UTF-8 version of `path`, set by some clients when `path` uses another encoding
+    #[serde(
+        rename = "path.utf-8",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub path_utf8: Option<Vec<ByteString>>,
+    /// File attributes (BEP 47), such as `p` for the padding files of hybrid torrents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<ByteString>,
+}
+
+impl File {
+    /// Path components to display and write to disk, see [`Info::display_name`]
+    pub fn display_path(&self) -> Vec<String> {
+        self.path_utf8
+            .as_ref()
+            .unwrap_or(&self.path)
+            .iter()
+            .map(|i| i.to_string_lossy().into_owned())
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use anyhow::Context;
 
-    use crate::torrent::{BlockInfo, PieceInfo, Torrent};
+    use std::path::PathBuf;
+
+    use crate::{
+        torrent::{raw_info_hash, BlockInfo, FileInfo, LayoutError, PieceInfo, Torrent},
+        torrent_info::TorrentInfo,
+    };
 
     #[test]
     fn torrent_with_hash_and_pieces_1() -> anyhow::Result<()> {
@@ -156,7 +294,7 @@ mod test {
         assert_eq!(820892, torrent.total_len());
         assert_eq!(
             "1cad4a486798d952614c394eb15e75bec587fd08",
-            hex::encode(&torrent.info_hash()?)
+            hex::encode(torrent.info_hash()?)
         );
         assert_eq!(262144, torrent.info.piece_length);
         assert_eq!(
@@ -171,7 +309,7 @@ mod test {
                 .pieces
                 .0
                 .iter()
-                .map(|i| hex::encode(i))
+                .map(hex::encode)
                 .collect::<Vec<_>>()
         );
 
@@ -186,7 +324,7 @@ mod test {
         assert_eq!(2097152, torrent.total_len());
         assert_eq!(
             "a18a79fa44e045b1e13879166d35823e848419f8",
-            hex::encode(&torrent.info_hash()?)
+            hex::encode(torrent.info_hash()?)
         );
         assert_eq!(262144, torrent.info.piece_length);
         assert_eq!(
@@ -205,7 +343,7 @@ mod test {
                 .pieces
                 .0
                 .iter()
-                .map(|i| hex::encode(i))
+                .map(hex::encode)
                 .collect::<Vec<_>>()
         );
         Ok(())
@@ -215,7 +353,7 @@ mod test {
     fn torrent_shorthands_1() -> anyhow::Result<()> {
         const FILE_SIZE: usize = 450;
         const PIECES_SIZE: usize = 120;
-        let pieces_count: usize = (FILE_SIZE + PIECES_SIZE - 1) / PIECES_SIZE;
+        let pieces_count: usize = FILE_SIZE.div_ceil(PIECES_SIZE);
         let mut torrent_content = Vec::from(format!("d8:announce31:http://127.0.0.1:44381/announce4:infod6:lengthi{FILE_SIZE}e4:name15:faketorrent.iso12:piece lengthi{PIECES_SIZE}e6:pieces{}:", pieces_count * 20).as_bytes());
         torrent_content.extend_from_slice(&vec![0; pieces_count * 20]);
         torrent_content.extend_from_slice(b"ee");
@@ -231,7 +369,7 @@ mod test {
                 offset: 0,
                 length: 120
             }),
-            torrent.pieces_info().get(0)
+            torrent.pieces_info().first()
         );
         assert_eq!(
             Some(&PieceInfo {
@@ -249,7 +387,7 @@ mod test {
             torrent
                 .blocks_info(0, 60)
                 .context("requested piece does not exist")?
-                .get(0)
+                .first()
         );
         assert_eq!(
             Some(&BlockInfo {
@@ -269,7 +407,7 @@ mod test {
     fn torrent_shorthands_2() -> anyhow::Result<()> {
         const FILE_SIZE: usize = 300;
         const PIECES_SIZE: usize = 100;
-        let pieces_count: usize = (FILE_SIZE + PIECES_SIZE - 1) / PIECES_SIZE;
+        let pieces_count: usize = FILE_SIZE.div_ceil(PIECES_SIZE);
         let mut torrent_content = Vec::from(format!("d8:announce31:http://127.0.0.1:44381/announce4:infod6:lengthi{FILE_SIZE}e4:name15:faketorrent.iso12:piece lengthi{PIECES_SIZE}e6:pieces{}:", pieces_count * 20).as_bytes());
         torrent_content.extend_from_slice(&vec![0; pieces_count * 20]);
         torrent_content.extend_from_slice(b"ee");
@@ -303,7 +441,7 @@ mod test {
             torrent
                 .blocks_info(0, 41)
                 .context("requested piece does not exist")?
-                .get(0)
+                .first()
         );
         assert_eq!(
             Some(&BlockInfo {
@@ -318,4 +456,272 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn multi_file_torrent_with_zero_length_files() -> anyhow::Result<()> {
+        let mut torrent_content = Vec::from(&b"d8:announce31:http://127.0.0.1:44381/announce4:infod5:filesld6:lengthi0e4:pathl5:a.nfoeed6:lengthi100e4:pathl3:dir5:b.bineed6:lengthi0e4:pathl5:c.nfoeed6:lengthi28e4:pathl5:d.bineee4:name3:top12:piece lengthi64e6:pieces40:"[..]);
+        torrent_content.extend_from_slice(&[0; 40]);
+        torrent_content.extend_from_slice(b"ee");
+
+        let torrent = Torrent::from_bytes(&torrent_content)?;
+        let files = torrent.files_info();
+
+        assert_eq!(128, torrent.total_len());
+        assert_eq!(
+            vec![
+                FileInfo {
+                    index: 0,
+                    path: PathBuf::from("top/a.nfo"),
+                    offset: 0,
+                    length: 0
+                },
+                FileInfo {
+                    index: 1,
+                    path: PathBuf::from("top/dir/b.bin"),
+                    offset: 0,
+                    length: 100
+                },
+                FileInfo {
+                    index: 2,
+                    path: PathBuf::from("top/c.nfo"),
+                    offset: 100,
+                    length: 0
+                },
+                FileInfo {
+                    index: 3,
+                    path: PathBuf::from("top/d.bin"),
+                    offset: 100,
+                    length: 28
+                },
+            ],
+            files
+        );
+        assert!(files[0].pieces(64).is_empty());
+        assert_eq!(0..2, files[1].pieces(64));
+        assert!(files[2].pieces(64).is_empty());
+        assert_eq!(1..2, files[3].pieces(64));
+
+        Ok(())
+    }
+
+    #[test]
+    fn multi_file_torrent_with_unsafe_paths() {
+        for (path, name) in [
+            ("l2:..6:passwde", "3:top"),
+            ("l4:/etc6:passwde", "3:top"),
+            ("l0:6:passwde", "3:top"),
+            ("l1:.6:passwde", "3:top"),
+            ("l4:a/..6:passwde", "3:top"),
+            ("l6:passwde", "2:.."),
+        ] {
+            let mut torrent_content = Vec::from(format!("d8:announce31:http://127.0.0.1:44381/announce4:infod5:filesld6:lengthi10e4:path{path}ee4:name{name}12:piece lengthi64e6:pieces20:").as_bytes());
+            torrent_content.extend_from_slice(&[0; 20]);
+            torrent_content.extend_from_slice(b"ee");
+            let torrent = Torrent::from_bytes(&torrent_content).expect("valid torrent");
+
+            assert!(
+                matches!(torrent.validate_layout(), Err(LayoutError::UnsafePath(_))),
+                "{path} in {name} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn non_utf8_names() -> anyhow::Result<()> {
+        let mut torrent_content = Vec::from(&b"d8:announce31:http://127.0.0.1:44381/announce4:infod5:filesld6:lengthi10e4:pathl5:r\xe9sumeed6:lengthi10e4:pathl5:\xff.bine10:path.utf-8l5:x.bineee4:name3:t\xf6p10:name.utf-84:t\xc3\xb6p12:piece lengthi64e6:pieces20:"[..]);
+        torrent_content.extend_from_slice(&[0; 20]);
+        torrent_content.extend_from_slice(b"ee");
+
+        let torrent = Torrent::from_bytes(&torrent_content)?;
+
+        assert_eq!(b"t\xf6p".to_vec(), torrent.info.name.0);
+        assert_eq!("t\u{f6}p", torrent.info.display_name());
+        assert_eq!(
+            vec![
+                PathBuf::from("t\u{f6}p/r\u{fffd}sum"),
+                PathBuf::from("t\u{f6}p/x.bin")
+            ],
+            torrent
+                .files_info()
+                .into_iter()
+                .map(|i| i.path)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(raw_info_hash(&torrent_content)?, torrent.info_hash()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn hybrid_torrent_info_hashes() -> anyhow::Result<()> {
+        let mut info = Vec::from(&b"d9:file treed1:ad0:d6:lengthi100e11:pieces root32:"[..]);
+        info.extend_from_slice(&[1; 32]);
+        info.extend_from_slice(b"eee5:filesld6:lengthi100e4:pathl1:aeed4:attr1:p6:lengthi28e4:pathl4:.pad2:28eee12:meta versioni2e4:name3:top12:piece lengthi64e6:pieces40:");
+        info.extend_from_slice(&[0; 40]);
+        info.extend_from_slice(b"e");
+        let torrent_content = [
+            &b"d8:announce31:http://127.0.0.1:44381/announce4:info"[..],
+            &info,
+            b"e",
+        ]
+        .concat();
+
+        let torrent = Torrent::from_bytes(&torrent_content)?;
+        let v2 = crate::sha256::hash(&info);
+
+        assert_eq!(raw_info_hash(&torrent_content)?, torrent.info_hash()?);
+        assert_eq!(Some(v2), torrent.info_hash_v2()?);
+        assert_eq!(
+            Some(v2[..20].try_into()?),
+            torrent.truncated_info_hash_v2()?
+        );
+        assert_eq!(None, single_file_torrent(10, 64, 1).info_hash_v2()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn info_hashes_account_for_unknown_keys() -> anyhow::Result<()> {
+        let mut info =
+            Vec::from(&b"d5:filesld6:lengthi100e5:mtimei1700000000e4:pathl1:ae4:sha120:"[..]);
+        info.extend_from_slice(&[2; 20]);
+        info.extend_from_slice(b"ee12:meta versioni2e4:name3:top12:piece lengthi64e6:pieces40:");
+        info.extend_from_slice(&[0; 40]);
+        info.extend_from_slice(b"6:source7:trackere");
+        let torrent_content = [
+            &b"d8:announce31:http://127.0.0.1:44381/announce4:info"[..],
+            &info,
+            b"e",
+        ]
+        .concat();
+
+        let torrent = Torrent::from_bytes(&torrent_content)?;
+        let modelled = serde_bencode::to_bytes(&torrent.info)?;
+
+        assert_ne!(info, modelled);
+        assert_eq!(crate::sha1::hash(&info), TorrentInfo::info_hash(&torrent)?);
+        assert_eq!(
+            Some(crate::sha256::hash(&info)),
+            TorrentInfo::info_hash_v2(&torrent)?
+        );
+        Ok(())
+    }
+
+    fn single_file_torrent(length: usize, piece_length: usize, pieces_count: usize) -> Torrent {
+        let mut torrent_content = Vec::from(format!("d8:announce31:http://127.0.0.1:44381/announce4:infod6:lengthi{length}e4:name15:faketorrent.iso12:piece lengthi{piece_length}e6:pieces{}:", pieces_count * 20).as_bytes());
+        torrent_content.extend_from_slice(&vec![0; pieces_count * 20]);
+        torrent_content.extend_from_slice(b"ee");
+        Torrent::from_bytes(&torrent_content).expect("valid torrent")
+    }
+
+    #[test]
+    fn web_seed_lists() -> anyhow::Result<()> {
+        let info = "4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:e";
+        let single = Torrent::from_bytes(
+            format!("d8:announce1:a8:url-list18:http://example.org{info}e").as_bytes(),
+        )?;
+        let many = Torrent::from_bytes(
+            format!("d8:announce1:a8:url-listl18:http://example.org16:http://mirror.ioe{info}e")
+                .as_bytes(),
+        )?;
+        let none = Torrent::from_bytes(format!("d8:announce1:a{info}e").as_bytes())?;
+        let http_seeds = Torrent::from_bytes(
+            format!("d8:announce1:a9:httpseedsl18:http://example.orge{info}e").as_bytes(),
+        )?;
+
+        assert_eq!(vec!["http://example.org"], single.url_list.0);
+        assert_eq!(
+            vec!["http://example.org", "http://mirror.io"],
+            many.url_list.0
+        );
+        assert!(none.url_list.0.is_empty());
+        assert_eq!(vec!["http://example.org"], http_seeds.httpseeds);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_torrent() {
+        let torrent = single_file_torrent(0, 100, 0);
+
+        assert_eq!(Ok(()), torrent.validate_layout());
+        assert_eq!(0, torrent.last_piece_size());
+        assert!(torrent.pieces_info().is_empty());
+        assert_eq!(None, torrent.blocks_info(0, 16));
+    }
+
+    #[test]
+    fn piece_length_larger_than_file() {
+        let torrent = single_file_torrent(30, 100, 1);
+
+        assert_eq!(Ok(()), torrent.validate_layout());
+        assert_eq!(30, torrent.last_piece_size());
+        assert_eq!(
+            vec![PieceInfo {
+                index: 0,
+                offset: 0,
+                length: 30
+            }],
+            torrent.pieces_info()
+        );
+        assert_eq!(
+            Some(vec![
+                BlockInfo {
+                    offset: 0,
+                    length: 16
+                },
+                BlockInfo {
+                    offset: 16,
+                    length: 14
+                }
+            ]),
+            torrent.blocks_info(0, 16)
+        );
+    }
+
+    #[test]
+    fn zero_piece_length() {
+        let torrent = single_file_torrent(30, 0, 1);
+
+        assert_eq!(Err(LayoutError::ZeroPieceLength), torrent.validate_layout());
+        assert_eq!(0, torrent.last_piece_size());
+    }
+
+    #[test]
+    fn zero_block_size() {
+        let torrent = single_file_torrent(30, 100, 1);
+
+        assert_eq!(None, torrent.blocks_info(0, 0));
+    }
+
+    #[test]
+    fn too_many_piece_hashes() {
+        let torrent = single_file_torrent(150, 100, 3);
+
+        assert_eq!(
+            Err(LayoutError::PieceCountMismatch {
+                expected: 2,
+                actual: 3
+            }),
+            torrent.validate_layout()
+        );
+        assert_eq!(Some(0), torrent.pieces_info().last().map(|i| i.length));
+    }
+
+    #[test]
+    fn torrent_larger_than_4_gib() {
+        const GIB: usize = 1024 * 1024 * 1024;
+        const PIECE_LENGTH: usize = 4 * 1024 * 1024;
+        let torrent = single_file_torrent(5 * GIB + 1, PIECE_LENGTH, (5 * GIB) / PIECE_LENGTH + 1);
+
+        assert_eq!(Ok(()), torrent.validate_layout());
+        assert_eq!(5 * 1024 * 1024 * 1024 + 1, torrent.total_len());
+        assert_eq!(
+            Some(&PieceInfo {
+                index: 1280,
+                offset: 5 * 1024 * 1024 * 1024,
+                length: 1
+            }),
+            torrent.pieces_info().last()
+        );
+    }
 }
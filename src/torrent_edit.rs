@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use serde_bencode::value::Value;
+
+/// Changes to apply to a torrent's metainfo
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TorrentEdit {
+    /// Replaces the main announce URL
+    pub announce: Option<String>,
+    /// Announce URLs added as new tiers of the announce list
+    pub add_announce: Vec<String>,
+    /// Announce URLs removed wherever they appear
+    pub remove_announce: Vec<String>,
+    /// Replaces the comment
+    pub comment: Option<String>,
+    /// Sets or clears the private flag, which changes the info hash
+    pub private: Option<bool>,
+}
+
+/// Applies `edit` to a bencoded torrent. The torrent is handled as raw bencode so that keys
+/// this client does not know about survive, and the info dictionary is left untouched (and with
+/// it the info hash) unless the private flag is changed. The last tracker cannot be removed, a
+/// torrent needing an announce URL.
+pub fn edit(content: &[u8], edit: &TorrentEdit) -> anyhow::Result<Vec<u8>> {
+    let Value::Dict(mut torrent) =
+        serde_bencode::from_bytes(content).context("parse torrent file")?
+    else {
+        bail!("torrent is not a dictionary");
+    };
+
+    let mut tiers = announce_list(&torrent)?;
+    if let Some(announce) = &edit.announce {
+        let previous = announce_url(&torrent)?;
+        for url in tiers.iter_mut().flatten() {
+            if Some(&*url) == previous.as_ref() {
+                url.clone_from(announce);
+            }
+        }
+        torrent.insert(b"announce".to_vec(), Value::Bytes(announce.clone().into()));
+    }
+    if !edit.add_announce.is_empty() {
+        if tiers.is_empty() {
+            tiers.extend(announce_url(&torrent)?.map(|i| vec![i]));
+        }
+        tiers.extend(edit.add_announce.iter().map(|i| vec![i.clone()]));
+    }
+    for removed in &edit.remove_announce {
+        tiers
+            .iter_mut()
+            .for_each(|tier| tier.retain(|i| i != removed));
+        tiers.retain(|tier| !tier.is_empty());
+        if announce_url(&torrent)?.as_ref() == Some(removed) {
+            match tiers.first().and_then(|tier| tier.first()) {
+                Some(next) => {
+                    torrent.insert(b"announce".to_vec(), Value::Bytes(next.clone().into()))
+                }
+                None => bail!("cannot remove {removed}, the last tracker of the torrent"),
+            };
+        }
+    }
+    if tiers.is_empty() {
+        torrent.remove(&b"announce-list"[..]);
+    } else {
+        torrent.insert(
+            b"announce-list".to_vec(),
+            Value::List(
+                tiers
+                    .into_iter()
+                    .map(|tier| {
+                        Value::List(tier.into_iter().map(|i| Value::Bytes(i.into())).collect())
+                    })
+                    .collect(),
+            ),
+        );
+    }
+
+    if let Some(comment) = &edit.comment {
+        torrent.insert(b"comment".to_vec(), Value::Bytes(comment.clone().into()));
+    }
+
+    if let Some(private) = edit.private {
+        let Some(Value::Dict(info)) = torrent.get_mut(&b"info"[..]) else {
+            bail!("torrent has no info dictionary");
+        };
+        if private {
+            info.insert(b"private".to_vec(), Value::Int(1));
+        } else {
+            info.remove(&b"private"[..]);
+        }
+    }
+
+    serde_bencode::to_bytes(&Value::Dict(torrent)).context("serialize torrent file")
+}
+
+fn announce_url(torrent: &HashMap<Vec<u8>, Value>) -> anyhow::Result<Option<String>> {
+    match torrent.get(&b"announce"[..]) {
+        Some(Value::Bytes(url)) => Ok(Some(
+            String::from_utf8(url.clone()).context("announce is not UTF-8")?,
+        )),
+        Some(_) => bail!("announce is not a string"),
+        None => Ok(None),
+    }
+}
+
+fn announce_list(torrent: &HashMap<Vec<u8>, Value>) -> anyhow::Result<Vec<Vec<String>>> {
+    let Some(list) = torrent.get(&b"announce-list"[..]) else {
+        return Ok(vec![]);
+    };
+    let Value::List(tiers) = list else {
+        bail!("announce-list is not a list");
+    };
+    tiers
+        .iter()
+        .map(|tier| match tier {
+            Value::List(urls) => urls
+                .iter()
+                .map(|url| match url {
+                    Value::Bytes(url) => {
+                        String::from_utf8(url.clone()).context("announce URL is not UTF-8")
+                    }
+                    _ => bail!("announce URL is not a string"),
+                })
+                .collect(),
+            _ => bail!("announce-list tier is not a list"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use serde_bencode::value::Value;
+
+    use crate::{sha1, torrent::Torrent};
+
+    use super::{edit, TorrentEdit};
+
+    const TORRENT: &[u8] = b"d8:announce17:http://a/announce7:comment3:old4:infod6:lengthi3e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:unknowni1eee";
+
+    fn info_of(content: &[u8]) -> Vec<u8> {
+        let Ok(Value::Dict(torrent)) = serde_bencode::from_bytes(content) else {
+            panic!("not a dictionary");
+        };
+        serde_bencode::to_bytes(&torrent[&b"info"[..]]).expect("info serializes")
+    }
+
+    #[test]
+    fn edit_announce_and_comment_preserves_info() -> anyhow::Result<()> {
+        let edited = edit(
+            TORRENT,
+            &TorrentEdit {
+                announce: Some("http://b/announce".to_string()),
+                add_announce: vec!["http://c/announce".to_string()],
+                comment: Some("new".to_string()),
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(info_of(TORRENT), info_of(&edited));
+        assert_eq!(
+            b"d8:announce17:http://b/announce13:announce-listll17:http://b/announceel17:http://c/announceee7:comment3:new4:infod6:lengthi3e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:unknowni1eee".to_vec(),
+            edited
+        );
+        assert_eq!("http://b/announce", Torrent::from_bytes(&edited)?.announce);
+        Ok(())
+    }
+
+    #[test]
+    fn remove_announce_promotes_next_tracker() -> anyhow::Result<()> {
+        let with_list = edit(
+            TORRENT,
+            &TorrentEdit {
+                add_announce: vec!["http://c/announce".to_string()],
+                ..Default::default()
+            },
+        )?;
+        let edited = edit(
+            &with_list,
+            &TorrentEdit {
+                remove_announce: vec!["http://a/announce".to_string()],
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(
+            b"d8:announce17:http://c/announce13:announce-listll17:http://c/announceee7:comment3:old4:infod6:lengthi3e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:unknowni1eee".to_vec(),
+            edited
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn toggle_private_flag() -> anyhow::Result<()> {
+        let private = edit(
+            TORRENT,
+            &TorrentEdit {
+                private: Some(true),
+                ..Default::default()
+            },
+        )?;
+        assert!(info_of(&private)
+            .windows(b"7:privatei1e".len())
+            .any(|i| i == b"7:privatei1e"));
+
+        let public = edit(
+            &private,
+            &TorrentEdit {
+                private: Some(false),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(TORRENT.to_vec(), public);
+        Ok(())
+    }
+
+    #[test]
+    fn info_hash_follows_private_flag() -> anyhow::Result<()> {
+        // only keys the client knows of, for the info hash to be computed from them
+        let torrent = b"d8:announce17:http://a/announce4:infod6:lengthi3e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        for private in [true, false] {
+            let edited = edit(
+                torrent,
+                &TorrentEdit {
+                    private: Some(private),
+                    ..Default::default()
+                },
+            )?;
+
+            assert_eq!(
+                sha1::hash(&info_of(&edited)),
+                Torrent::from_bytes(&edited)?.info_hash()?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn last_tracker_is_kept() {
+        let res = edit(
+            TORRENT,
+            &TorrentEdit {
+                remove_announce: vec!["http://a/announce".to_string()],
+                ..Default::default()
+            },
+        );
+
+        assert!(res.is_err());
+    }
+}
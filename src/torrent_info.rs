@@ -1,12 +1,19 @@
+use std::path::PathBuf;
+
+use reqwest::Url;
+
 use crate::{
+    file_paths,
+    file_selection::FilePriority,
     magnet_links::MagnetLink,
-    torrent::{BlockInfo, Info, PieceInfo, Torrent},
+    torrent::{BlockInfo, FileInfo, Info, Keys, LayoutError, PieceInfo, Torrent},
+    web_seed::WebSeed,
 };
 
 pub trait TorrentInfo {
     fn info(&self) -> &Info;
 
-    fn total_len(&self) -> usize {
+    fn total_len(&self) -> u64 {
         self.info().total_len()
     }
 
@@ -15,59 +22,216 @@ pub trait TorrentInfo {
         Ok(crate::sha1::hash(&bytes))
     }
 
-    fn piece_length(&self) -> usize {
-        self.info()
-            .piece_length
-            .try_into()
-            .expect("usize can't hold a u32, what kind of architecture are you running this on?")
+    /// SHA-256 info hash of hybrid torrents (BEP 52), which also identifies them in v2 swarms
+    fn info_hash_v2(&self) -> anyhow::Result<Option<[u8; 32]>> {
+        if self.info().meta_version != Some(2) {
+            return Ok(None);
+        }
+        let bytes = serde_bencode::to_bytes(&self.info())?;
+        Ok(Some(crate::sha256::hash(&bytes)))
+    }
+
+    /// The v2 info hash truncated to 20 bytes, as trackers and peers are told about it
+    fn truncated_info_hash_v2(&self) -> anyhow::Result<Option<[u8; 20]>> {
+        Ok(self
+            .info_hash_v2()?
+            .map(|i| i[..20].try_into().expect("20 bytes of a 32 bytes hash")))
+    }
+
+    /// Web seeds to download from along with peers
+    fn web_seeds(&self) -> Vec<WebSeed> {
+        Vec::new()
+    }
+
+    fn piece_length(&self) -> u64 {
+        self.info().piece_length
     }
 
     fn pieces_count(&self) -> usize {
         self.info().pieces.0.len()
     }
 
-    fn last_piece_size(&self) -> usize {
-        match self.total_len() % self.piece_length() {
-            0 => self.piece_length(),
-            len => len,
+    fn last_piece_size(&self) -> u64 {
+        match (self.total_len(), self.piece_length()) {
+            (0, _) | (_, 0) => 0,
+            (total_len, piece_length) => match total_len % piece_length {
+                0 => piece_length,
+                len => len,
+            },
         }
     }
 
-    fn blocks_info(&self, piece_index: usize, block_size: usize) -> Option<Vec<BlockInfo>> {
+    /// Checks that pieces can be derived from the torrent: a non zero piece length and as many
+    /// piece hashes as the total length requires. Also rejects file names that would escape the
+    /// output directory.
+    fn validate_layout(&self) -> Result<(), LayoutError> {
+        let info = self.info();
+        let paths = match &info.keys {
+            Keys::SingleFile { .. } => vec![],
+            Keys::MultiFile { files } => files.iter().map(|i| i.display_path()).collect(),
+        };
+        let name = info.display_name();
+        if !file_paths::is_safe_component(&name) {
+            return Err(LayoutError::UnsafePath(name));
+        }
+        if let Some(path) = paths
+            .into_iter()
+            .find(|i| i.is_empty() || !i.iter().all(|i| file_paths::is_safe_component(i)))
+        {
+            return Err(LayoutError::UnsafePath(path.join("/")));
+        }
+        let piece_length = self.piece_length();
+        if piece_length == 0 {
+            return Err(LayoutError::ZeroPieceLength);
+        }
+        let expected = self.total_len().div_ceil(piece_length);
+        let actual = self.pieces_count() as u64;
+        if expected != actual {
+            return Err(LayoutError::PieceCountMismatch { expected, actual });
+        }
+        Ok(())
+    }
+
+    /// A vector containing block division for the given piece in the given block size, `None`
+    /// if the piece does not exist or the block size is zero
+    fn blocks_info(&self, piece_index: usize, block_size: u64) -> Option<Vec<BlockInfo>> {
+        if block_size == 0 {
+            return None;
+        }
         let pieces_info = self.pieces_info();
-        let pieces_info = pieces_info.get(piece_index)?;
-        let mut info = Vec::new();
-        let blocks_count = (pieces_info.length + block_size - 1) / block_size;
-        for i in 0..blocks_count {
-            info.push(BlockInfo {
-                offset: i * block_size,
-                length: if i == blocks_count - 1 {
-                    match pieces_info.length % block_size {
-                        0 => block_size,
-                        len => len,
+        let piece_info = pieces_info.get(piece_index)?;
+        Some(
+            (0..piece_info.length.div_ceil(block_size))
+                .map(|i| {
+                    let offset = i * block_size;
+                    BlockInfo {
+                        offset,
+                        length: block_size.min(piece_info.length - offset),
                     }
-                } else {
-                    block_size
-                },
-            })
-        }
-        Some(info)
+                })
+                .collect(),
+        )
     }
 
+    /// Pieces of the torrent, clamped to the total length so that an inconsistent number of
+    /// hashes never yields pieces past the end of the content
     fn pieces_info(&self) -> Vec<PieceInfo> {
-        let mut info = Vec::new();
-        for i in 0..self.pieces_count() {
-            info.push(PieceInfo {
-                index: i,
-                offset: i * self.piece_length(),
-                length: if i == self.pieces_count() - 1 {
-                    self.last_piece_size()
-                } else {
-                    self.piece_length()
-                },
+        let total_len = self.total_len();
+        let piece_length = self.piece_length();
+        (0..self.pieces_count())
+            .map(|index| {
+                let offset = index as u64 * piece_length;
+                PieceInfo {
+                    index,
+                    offset,
+                    length: piece_length.min(total_len.saturating_sub(offset)),
+                }
             })
+            .collect()
+    }
+
+    /// Indexes of the only files to download, every file when `None`
+    fn selected_files(&self) -> Option<Vec<usize>> {
+        None
+    }
+
+    /// Download priority of each file: normal for the selected files, skip for the others
+    fn file_priorities(&self) -> Vec<FilePriority> {
+        let selected = self.selected_files();
+        self.files_info()
+            .iter()
+            .map(|i| match &selected {
+                Some(selected) if !selected.contains(&i.index) => FilePriority::Skip,
+                _ => FilePriority::Normal,
+            })
+            .collect()
+    }
+
+    /// Whether the first and last pieces of the files downloaded come first, for media players
+    /// to read their headers and trailers early
+    fn first_last_pieces_first(&self) -> bool {
+        false
+    }
+
+    /// Priority of each piece, the highest of the files it holds bytes of, so that pieces shared
+    /// with files that are skipped are still downloaded. The first and last pieces of the files
+    /// downloaded are of high priority when [`Self::first_last_pieces_first`].
+    fn piece_priorities(&self) -> Vec<FilePriority> {
+        if self.piece_length() == 0 {
+            return vec![FilePriority::Normal; self.pieces_count()];
+        }
+        let file_priorities = self.file_priorities();
+        let first_last = self.first_last_pieces_first();
+        let mut priorities = vec![FilePriority::Skip; self.pieces_count()];
+        for file in self.files_info() {
+            let file_priority = file_priorities.get(file.index).copied().unwrap_or_default();
+            let pieces = file.pieces(self.piece_length());
+            for piece in pieces.clone() {
+                let piece_priority = match file_priority {
+                    FilePriority::Skip => FilePriority::Skip,
+                    _ if first_last && (piece == pieces.start || piece + 1 == pieces.end) => {
+                        FilePriority::High
+                    }
+                    priority => priority,
+                };
+                if let Some(priority) = priorities.get_mut(piece as usize) {
+                    *priority = piece_priority.max(*priority);
+                }
+            }
+        }
+        priorities
+    }
+
+    /// Whether each piece is to be downloaded, see [`Self::piece_priorities`]
+    fn wanted_pieces(&self) -> Vec<bool> {
+        self.piece_priorities()
+            .into_iter()
+            .map(|i| i != FilePriority::Skip)
+            .collect()
+    }
+
+    /// Indexes of the pieces to download, highest priority first and in order within a priority
+    fn piece_order(&self) -> Vec<usize> {
+        let priorities = self.piece_priorities();
+        let mut order = (0..priorities.len())
+            .filter(|i| priorities[*i] != FilePriority::Skip)
+            .collect::<Vec<_>>();
+        order.sort_by_key(|i| std::cmp::Reverse(priorities[*i]));
+        order
+    }
+
+    /// Files of the torrent, in order, with their offset in the torrent's byte stream. Multi-file
+    /// paths are rooted in the torrent's name; zero-length files get the offset of the next file.
+    fn files_info(&self) -> Vec<FileInfo> {
+        let info = self.info();
+        match &info.keys {
+            Keys::SingleFile { length } => vec![FileInfo {
+                index: 0,
+                path: PathBuf::from(info.display_name()),
+                offset: 0,
+                length: *length,
+            }],
+            Keys::MultiFile { files } => {
+                let name = info.display_name();
+                let mut offset = 0;
+                files
+                    .iter()
+                    .enumerate()
+                    .map(|(index, file)| {
+                        let file_info = FileInfo {
+                            index,
+                            path: std::iter::once(name.clone())
+                                .chain(file.display_path())
+                                .collect(),
+                            offset,
+                            length: file.length,
+                        };
+                        offset += file.length;
+                        file_info
+                    })
+                    .collect()
+            }
         }
-        info
     }
 }
 
@@ -75,10 +239,46 @@ impl TorrentInfo for Torrent {
     fn info(&self) -> &Info {
         &self.info
     }
+
+    /// Hash of the info dictionary as found in the torrent, see [`Torrent::info_hash`]
+    fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
+        Torrent::info_hash(self)
+    }
+
+    fn info_hash_v2(&self) -> anyhow::Result<Option<[u8; 32]>> {
+        Torrent::info_hash_v2(self)
+    }
+
+    /// Seeds of `url-list` and `httpseeds`, leaving out invalid URLs
+    fn web_seeds(&self) -> Vec<WebSeed> {
+        let url_list = self
+            .url_list
+            .0
+            .iter()
+            .filter_map(|i| Url::parse(i).ok())
+            .map(WebSeed::UrlList);
+        let http_seeds = self
+            .httpseeds
+            .iter()
+            .filter_map(|i| Url::parse(i).ok())
+            .map(WebSeed::HttpSeed);
+        url_list.chain(http_seeds).collect()
+    }
 }
 
 impl TorrentInfo for (MagnetLink, Info) {
     fn info(&self) -> &Info {
         &self.1
     }
+
+    /// The magnet link's hash, which the info was checked against when fetched
+    fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
+        Ok(self.0.info_hash)
+    }
+
+    /// The files of the link's select-only list
+    fn selected_files(&self) -> Option<Vec<usize>> {
+        let count = self.files_info().len();
+        self.0.select_only.as_ref().map(|i| i.files(count))
+    }
 }
@@ -1,48 +1,96 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use core::fmt;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use reqwest::Url;
+use std::net::{IpAddr, SocketAddr};
 
-use serde::{de::Visitor, Deserialize, Deserializer};
+use crate::bt_client::HttpClient;
+
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Debug, Deserialize)]
 pub struct Response {
     pub interval: Option<usize>,
+    /// Announcing again before it elapsed is not allowed
+    #[serde(rename = "min interval", default)]
+    pub min_interval: Option<usize>,
+    /// IPv4 peers, absent from the responses of trackers only sending `peers6`
+    #[serde(default)]
     pub peers: Peers,
+    /// IPv6 peers, from trackers supporting BEP 7
+    #[serde(default)]
+    pub peers6: Peers6,
 }
 
-#[derive(Debug)]
-pub struct Peers(pub Vec<SocketAddrV4>);
+/// IPv4 peers in compact form: 4 bytes of IP address and 2 of port each. IPv6 addresses are
+/// left out when serializing.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Peers(pub Vec<SocketAddr>);
+
+/// IPv6 peers in compact form: 16 bytes of IP address and 2 of port each. IPv4 addresses are
+/// left out when serializing.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Peers6(pub Vec<SocketAddr>);
+
+impl Peers6 {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
 
-struct PeersVisitor;
+/// Decodes compact peers of `ip_length` bytes of address followed by 2 bytes of port
+pub fn decode_compact_peers(bytes: &[u8], ip_length: usize) -> Result<Vec<SocketAddr>> {
+    let entry_length = ip_length + 2;
+    if !bytes.len().is_multiple_of(entry_length) {
+        anyhow::bail!("length {} is not a multiple of {entry_length}", bytes.len());
+    }
+    Ok(bytes
+        .chunks_exact(entry_length)
+        .map(|i| {
+            let ip = match ip_length {
+                4 => IpAddr::from(<[u8; 4]>::try_from(&i[..4]).expect("should not happen")),
+                _ => IpAddr::from(<[u8; 16]>::try_from(&i[..16]).expect("should not happen")),
+            };
+            let port = u16::from_be_bytes(i[ip_length..].try_into().expect("should not happen"));
+            SocketAddr::new(ip, port)
+        })
+        .collect())
+}
+
+/// Encodes the peers of `peers` whose address is IPv6 when `ipv6`, and IPv4 otherwise
+fn encode_compact_peers(peers: &[SocketAddr], ipv6: bool) -> Vec<u8> {
+    peers
+        .iter()
+        .filter(|i| i.is_ipv6() == ipv6)
+        .flat_map(|i| {
+            let ip = match i.ip() {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            [ip.as_slice(), &i.port().to_be_bytes()].concat()
+        })
+        .collect()
+}
+
+struct PeersVisitor {
+    ip_length: usize,
+}
 
 impl<'de> Visitor<'de> for PeersVisitor {
-    type Value = Peers;
+    type Value = Vec<SocketAddr>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("an integer between -2^31 and 2^31")
+        write!(
+            formatter,
+            "peers in compact form, {} bytes each",
+            self.ip_length + 2
+        )
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        if v.len() % 6 != 0 {
-            return Err(E::custom(format!(
-                "length {} is not a multiple of 6",
-                v.len()
-            )));
-        }
-
-        Ok(Peers(
-            v.chunks_exact(6)
-                .map(|i| {
-                    SocketAddrV4::new(
-                        Ipv4Addr::new(i[0], i[1], i[2], i[3]),
-                        u16::from_be_bytes(i[4..6].try_into().expect("should not happen")),
-                    )
-                })
-                .collect::<Vec<_>>(),
-        ))
+        decode_compact_peers(v, self.ip_length).map_err(|e| E::custom(e.to_string()))
     }
 }
 
@@ -51,6 +99,126 @@ impl<'de> Deserialize<'de> for Peers {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_bytes(PeersVisitor)
+        deserializer
+            .deserialize_bytes(PeersVisitor { ip_length: 4 })
+            .map(Peers)
+    }
+}
+
+impl Serialize for Peers {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&encode_compact_peers(&self.0, false))
+    }
+}
+
+impl<'de> Deserialize<'de> for Peers6 {
+    fn deserialize<D>(deserializer: D) -> Result<Peers6, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer
+            .deserialize_bytes(PeersVisitor { ip_length: 16 })
+            .map(Peers6)
+    }
+}
+
+impl Serialize for Peers6 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&encode_compact_peers(&self.0, true))
+    }
+}
+
+/// Parses a tracker list as published by the likes of trackerslist: one announce URL per line,
+/// lists being separated by blank lines. Lines starting with `#` are comments.
+pub fn parse_tracker_list(content: &str) -> Result<Vec<Url>> {
+    content
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            Url::parse(line).with_context(|| format!("invalid tracker on line {}", index + 1))
+        })
+        .collect()
+}
+
+/// Loads a tracker list from a local file, or over HTTP when `source` is an http(s) URL
+pub fn load_tracker_list<C: HttpClient>(client: &C, source: &str) -> Result<Vec<Url>> {
+    let content = match Url::parse(source) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => client
+            .get(url)
+            .with_context(|| format!("fetching tracker list {source}"))?,
+        _ => std::fs::read(source).with_context(|| format!("reading tracker list {source}"))?,
+    };
+    parse_tracker_list(&String::from_utf8_lossy(&content))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use reqwest::Url;
+
+    use super::{parse_tracker_list, Response};
+
+    #[test]
+    fn peers_and_peers6() -> anyhow::Result<()> {
+        let mut bytes = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:".to_vec();
+        bytes.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        bytes.extend_from_slice(&[0; 11]);
+        bytes.extend_from_slice(&[1, 0x1a, 0xe1]);
+        bytes.push(b'e');
+        let response: Response = serde_bencode::from_bytes(&bytes)?;
+
+        assert_eq!(
+            vec!["127.0.0.1:6881".parse::<SocketAddr>()?],
+            response.peers.0
+        );
+        assert_eq!(
+            vec!["[2001:db8::1]:6881".parse::<SocketAddr>()?],
+            response.peers6.0
+        );
+        assert!(serde_bencode::from_bytes::<Response>(b"d5:peers6:abcdefe")?
+            .peers6
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn peers6_only() -> anyhow::Result<()> {
+        let mut bytes = b"d8:intervali60e6:peers618:".to_vec();
+        bytes.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        bytes.extend_from_slice(&[0; 11]);
+        bytes.extend_from_slice(&[1, 0x1a, 0xe1]);
+        bytes.push(b'e');
+        let response: Response = serde_bencode::from_bytes(&bytes)?;
+
+        assert!(response.peers.0.is_empty());
+        assert_eq!(
+            vec!["[2001:db8::1]:6881".parse::<SocketAddr>()?],
+            response.peers6.0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_trackerslist_format() -> anyhow::Result<()> {
+        let content = "udp://tracker.opentrackr.org:1337/announce\n\n# comment\nhttp://tracker.example.org:80/announce\n\n";
+
+        assert_eq!(
+            vec![
+                Url::parse("udp://tracker.opentrackr.org:1337/announce")?,
+                Url::parse("http://tracker.example.org:80/announce")?
+            ],
+            parse_tracker_list(content)?
+        );
+        assert!(parse_tracker_list("http://ok/announce\nnot a url").is_err());
+        Ok(())
     }
 }
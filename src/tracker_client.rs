@@ -0,0 +1,469 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use reqwest::Url;
+use serde_bencode::value::Value;
+
+use crate::{bt_client::HttpClient, resolver::Resolver, tracker};
+
+/// What is sent to a tracker when announcing
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+}
+
+/// Why an announce is made, `None` for the regular ones made on the tracker's interval
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    #[default]
+    None,
+    Completed,
+    Started,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// Value of the `event` parameter of HTTP announces, which regular announces leave out
+    pub fn as_str(self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+
+    /// Identifier of the event in UDP announces (BEP 15)
+    fn udp_id(self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceResponse {
+    pub interval: Option<u64>,
+    pub min_interval: Option<u64>,
+    pub peers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScrapeResponse {
+    pub complete: u64,
+    pub downloaded: u64,
+    pub incomplete: u64,
+}
+
+/// A tracker protocol
+pub trait TrackerClient {
+    fn announce(&self, url: &Url, request: &AnnounceRequest) -> anyhow::Result<AnnounceResponse>;
+
+    fn scrape(&self, url: &Url, info_hash: [u8; 20]) -> anyhow::Result<ScrapeResponse>;
+}
+
+/// The client for the tracker at `url`, chosen after its scheme
+pub fn for_url<'a, C: HttpClient>(
+    http_client: &'a C,
+    resolver: &Resolver,
+    url: &Url,
+) -> anyhow::Result<Box<dyn TrackerClient + 'a>> {
+    match url.scheme() {
+        "http" | "https" => Ok(Box::new(HttpTracker {
+            client: http_client,
+        })),
+        "udp" => Ok(Box::new(UdpTracker {
+            resolver: resolver.clone(),
+            ..Default::default()
+        })),
+        // WebSocket trackers hand out WebRTC peers, which this client cannot talk to
+        "ws" | "wss" => bail!("WebSocket trackers are not supported: {url}"),
+        scheme => bail!("unknown tracker scheme '{scheme}': {url}"),
+    }
+}
+
+/// HTTP(S) trackers (BEP 3, compact peer lists from BEP 23)
+pub struct HttpTracker<'a, C: HttpClient> {
+    pub client: &'a C,
+}
+
+impl<C: HttpClient> TrackerClient for HttpTracker<'_, C> {
+    fn announce(&self, url: &Url, request: &AnnounceRequest) -> anyhow::Result<AnnounceResponse> {
+        let res = self.client.get(http_announce_url(url.as_str(), request)?)?;
+        check_failure_reason(&res)?;
+
+        let res: tracker::Response =
+            serde_bencode::from_bytes(&res).context("parse tracker get response")?;
+
+        Ok(AnnounceResponse {
+            interval: res.interval.map(|i| i as u64),
+            min_interval: res.min_interval.map(|i| i as u64),
+            peers: res.peers.0.into_iter().chain(res.peers6.0).collect(),
+        })
+    }
+
+    fn scrape(&self, url: &Url, info_hash: [u8; 20]) -> anyhow::Result<ScrapeResponse> {
+        let res = self
+            .client
+            .get(http_scrape_url(url, &info_hash)?)
+            .context("scraping tracker")?;
+        check_failure_reason(&res)?;
+        let Value::Dict(res) = serde_bencode::from_bytes(&res).context("parse scrape response")?
+        else {
+            bail!("scrape response is not a dictionary");
+        };
+        let Some(Value::Dict(files)) = res.get(&b"files"[..]) else {
+            bail!("scrape response has no files");
+        };
+        let Some(Value::Dict(stats)) = files.get(&info_hash[..]) else {
+            bail!("scrape response does not cover the info hash");
+        };
+        let count = |key: &[u8]| match stats.get(key) {
+            Some(Value::Int(count)) => u64::try_from(*count).unwrap_or_default(),
+            _ => 0,
+        };
+        Ok(ScrapeResponse {
+            complete: count(b"complete"),
+            downloaded: count(b"downloaded"),
+            incomplete: count(b"incomplete"),
+        })
+    }
+}
+
+fn check_failure_reason(res: &[u8]) -> anyhow::Result<()> {
+    if let Ok(Value::Dict(res)) = serde_bencode::from_bytes(res) {
+        if let Some(Value::Bytes(reason)) = res.get(&b"failure reason"[..]) {
+            bail!("tracker failure: {}", String::from_utf8_lossy(reason));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn url_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|i| format!("%{i:02x}")).collect()
+}
+
+/// Announce URL for an HTTP tracker
+pub fn http_announce_url(announce_url: &str, request: &AnnounceRequest) -> anyhow::Result<Url> {
+    let mut url = Url::parse_with_params(
+        format!(
+            "{}?info_hash={}",
+            announce_url,
+            url_encode(&request.info_hash)
+        )
+        .as_str(),
+        &[
+            (
+                "peer_id",
+                String::from_utf8_lossy(&request.peer_id).as_ref(),
+            ),
+            ("port", request.port.to_string().as_str()),
+            ("uploaded", request.uploaded.to_string().as_str()),
+            ("downloaded", request.downloaded.to_string().as_str()),
+            ("left", request.left.to_string().as_str()),
+            ("compact", "1"),
+        ],
+    )
+    .context("creating tracker url")?;
+    if let Some(event) = request.event.as_str() {
+        url.query_pairs_mut().append_pair("event", event);
+    }
+    Ok(url)
+}
+
+/// Scrape URL of an HTTP tracker, derived from its announce URL by convention
+fn http_scrape_url(announce_url: &Url, info_hash: &[u8; 20]) -> anyhow::Result<Url> {
+    let mut url = announce_url.clone();
+    let path = url.path().to_string();
+    let Some((directory, file)) = path.rsplit_once('/') else {
+        bail!("tracker does not support scrape: {announce_url}");
+    };
+    let Some(rest) = file.strip_prefix("announce") else {
+        bail!("tracker does not support scrape: {announce_url}");
+    };
+    url.set_path(&format!("{directory}/scrape{rest}"));
+    let query = match url.query() {
+        Some(query) => format!("{query}&info_hash={}", url_encode(info_hash)),
+        None => format!("info_hash={}", url_encode(info_hash)),
+    };
+    url.set_query(Some(&query));
+    Ok(url)
+}
+
+/// UDP trackers (BEP 15)
+pub struct UdpTracker {
+    pub timeout: Duration,
+    pub resolver: Resolver,
+}
+
+impl Default for UdpTracker {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(15),
+            resolver: Resolver::System,
+        }
+    }
+}
+
+const UDP_PROTOCOL_ID: u64 = 0x0417_2710_1980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+impl UdpTracker {
+    fn socket(&self, url: &Url) -> anyhow::Result<UdpSocket> {
+        let host = url.host_str().context("tracker url has no host")?;
+        let port = url.port().context("tracker url has no port")?;
+        let address = self
+            .resolver
+            .resolve(host, port)
+            .context("resolving tracker")?
+            .into_iter()
+            .find(|i| i.is_ipv4())
+            .context("tracker has no IPv4 address")?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).context("binding UDP socket")?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.connect(address).context("connecting to tracker")?;
+        Ok(socket)
+    }
+
+    /// Sends `request` and returns the payload of the response after action and transaction id
+    fn transact(
+        &self,
+        socket: &UdpSocket,
+        action: u32,
+        transaction_id: u32,
+        request: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        socket.send(request).context("sending to tracker")?;
+        let mut buf = [0u8; 2048];
+        let len = socket.recv(&mut buf).context("receiving from tracker")?;
+        if len < 8 {
+            bail!("tracker response too short");
+        }
+        let (res_action, res_transaction_id) = (read_u32(&buf[0..4]), read_u32(&buf[4..8]));
+        if res_transaction_id != transaction_id {
+            bail!("tracker answered another transaction");
+        }
+        match res_action {
+            ACTION_ERROR => bail!("tracker failure: {}", String::from_utf8_lossy(&buf[8..len])),
+            res_action if res_action != action => bail!("unexpected tracker action {res_action}"),
+            _ => Ok(buf[8..len].to_vec()),
+        }
+    }
+
+    fn connect(&self, socket: &UdpSocket) -> anyhow::Result<u64> {
+        let transaction_id = random_u32();
+        let mut request = Vec::with_capacity(16);
+        request.extend_from_slice(&UDP_PROTOCOL_ID.to_be_bytes());
+        request.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        request.extend_from_slice(&transaction_id.to_be_bytes());
+        let res = self.transact(socket, ACTION_CONNECT, transaction_id, &request)?;
+        Ok(u64::from_be_bytes(
+            res.get(0..8)
+                .context("connect response too short")?
+                .try_into()?,
+        ))
+    }
+}
+
+impl TrackerClient for UdpTracker {
+    fn announce(&self, url: &Url, request: &AnnounceRequest) -> anyhow::Result<AnnounceResponse> {
+        let socket = self.socket(url)?;
+        let connection_id = self.connect(&socket)?;
+        let transaction_id = random_u32();
+        let mut message = Vec::with_capacity(98);
+        message.extend_from_slice(&connection_id.to_be_bytes());
+        message.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        message.extend_from_slice(&transaction_id.to_be_bytes());
+        message.extend_from_slice(&request.info_hash);
+        message.extend_from_slice(&request.peer_id);
+        message.extend_from_slice(&request.downloaded.to_be_bytes());
+        message.extend_from_slice(&request.left.to_be_bytes());
+        message.extend_from_slice(&request.uploaded.to_be_bytes());
+        message.extend_from_slice(&request.event.udp_id().to_be_bytes());
+        message.extend_from_slice(&0u32.to_be_bytes()); // ip: the sender's
+        message.extend_from_slice(&random_u32().to_be_bytes()); // key
+        message.extend_from_slice(&(-1i32).to_be_bytes()); // num_want: default
+        message.extend_from_slice(&request.port.to_be_bytes());
+
+        let res = self.transact(&socket, ACTION_ANNOUNCE, transaction_id, &message)?;
+        if res.len() < 12 || (res.len() - 12) % 6 != 0 {
+            bail!("malformed announce response");
+        }
+        Ok(AnnounceResponse {
+            interval: Some(read_u32(&res[0..4]).into()),
+            min_interval: None,
+            peers: tracker::decode_compact_peers(&res[12..], 4)?,
+        })
+    }
+
+    fn scrape(&self, url: &Url, info_hash: [u8; 20]) -> anyhow::Result<ScrapeResponse> {
+        let socket = self.socket(url)?;
+        let connection_id = self.connect(&socket)?;
+        let transaction_id = random_u32();
+        let mut message = Vec::with_capacity(36);
+        message.extend_from_slice(&connection_id.to_be_bytes());
+        message.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+        message.extend_from_slice(&transaction_id.to_be_bytes());
+        message.extend_from_slice(&info_hash);
+
+        let res = self.transact(&socket, ACTION_SCRAPE, transaction_id, &message)?;
+        if res.len() < 12 {
+            bail!("malformed scrape response");
+        }
+        Ok(ScrapeResponse {
+            complete: read_u32(&res[0..4]).into(),
+            downloaded: read_u32(&res[4..8]).into(),
+            incomplete: read_u32(&res[8..12]).into(),
+        })
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().expect("4 bytes"))
+}
+
+pub(crate) fn random_u32() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        str::FromStr,
+        thread,
+    };
+
+    use reqwest::Url;
+
+    use crate::resolver::Resolver;
+
+    use super::{
+        for_url, http_announce_url, http_scrape_url, AnnounceEvent, AnnounceRequest, TrackerClient,
+        UdpTracker, ACTION_ANNOUNCE, ACTION_CONNECT, UDP_PROTOCOL_ID,
+    };
+
+    #[test]
+    fn announce_url_event() -> anyhow::Result<()> {
+        let mut request = AnnounceRequest {
+            info_hash: [0; 20],
+            peer_id: [b'a'; 20],
+            port: 6881,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: AnnounceEvent::None,
+        };
+        let url = http_announce_url("http://example.org/announce", &request)?;
+        assert!(url
+            .as_str()
+            .ends_with("&uploaded=1&downloaded=2&left=3&compact=1"));
+
+        request.event = AnnounceEvent::Stopped;
+        let url = http_announce_url("http://example.org/announce", &request)?;
+        assert!(url.as_str().ends_with("&compact=1&event=stopped"));
+        Ok(())
+    }
+
+    #[test]
+    fn scrape_url_from_announce_url() -> anyhow::Result<()> {
+        assert_eq!(
+            "http://example.org/scrape.php?passkey=x&info_hash=%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%01",
+            http_scrape_url(
+                &Url::parse("http://example.org/announce.php?passkey=x")?,
+                &[[0; 19].as_slice(), &[1]].concat().try_into().unwrap()
+            )?
+            .as_str()
+        );
+        assert!(http_scrape_url(&Url::parse("http://example.org/a")?, &[0; 20]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dispatch_by_scheme() -> anyhow::Result<()> {
+        let client = reqwest::blocking::Client::new();
+        let resolver = Resolver::System;
+        assert!(for_url(
+            &client,
+            &resolver,
+            &Url::parse("http://example.org/announce")?
+        )
+        .is_ok());
+        assert!(for_url(
+            &client,
+            &resolver,
+            &Url::parse("udp://example.org:1337/announce")?
+        )
+        .is_ok());
+        assert!(for_url(
+            &client,
+            &resolver,
+            &Url::parse("wss://example.org/announce")?
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn udp_announce() -> anyhow::Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0")?;
+        let port = server.local_addr()?.port();
+        thread::spawn(move || -> anyhow::Result<()> {
+            let mut buf = [0u8; 2048];
+            let (len, from) = server.recv_from(&mut buf)?;
+            assert_eq!(16, len);
+            assert_eq!(UDP_PROTOCOL_ID.to_be_bytes(), buf[0..8]);
+            assert_eq!(ACTION_CONNECT.to_be_bytes(), buf[8..12]);
+            let mut res = buf[8..16].to_vec();
+            res.extend_from_slice(&42u64.to_be_bytes());
+            server.send_to(&res, from)?;
+
+            let (len, from) = server.recv_from(&mut buf)?;
+            assert_eq!(98, len);
+            assert_eq!(42u64.to_be_bytes(), buf[0..8]);
+            assert_eq!(ACTION_ANNOUNCE.to_be_bytes(), buf[8..12]);
+            assert_eq!([7; 20], buf[16..36]);
+            assert_eq!(2u32.to_be_bytes(), buf[80..84]);
+            let mut res = buf[8..16].to_vec();
+            res.extend_from_slice(&1800u32.to_be_bytes());
+            res.extend_from_slice(&[0; 8]);
+            res.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+            server.send_to(&res, from)?;
+            Ok(())
+        });
+
+        let res = UdpTracker::default().announce(
+            &Url::parse(&format!("udp://127.0.0.1:{port}/announce"))?,
+            &AnnounceRequest {
+                info_hash: [7; 20],
+                peer_id: [1; 20],
+                port: 6881,
+                uploaded: 0,
+                downloaded: 0,
+                left: 100,
+                event: AnnounceEvent::Started,
+            },
+        )?;
+
+        assert_eq!(Some(1800), res.interval);
+        assert_eq!(vec![SocketAddr::from_str("127.0.0.1:6881")?], res.peers);
+        Ok(())
+    }
+}
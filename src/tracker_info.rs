@@ -1,45 +1,87 @@
 use anyhow::Context;
 use reqwest::Url;
 
-use crate::{magnet_links::MagnetLink, torrent::Torrent};
+use crate::{
+    magnet_links::MagnetLink,
+    torrent::Torrent,
+    torrent_info::TorrentInfo,
+    tracker_client::{self, AnnounceEvent, AnnounceRequest},
+};
 
 pub const PEER_ID: &str = "alice_is_1_feet_tall";
 
 pub trait TrackerInfo {
-    fn tracker_url(&self) -> anyhow::Result<Url>;
+    fn announce_url(&self) -> &str;
+
+    /// Trackers grouped in tiers (BEP 12), the announce URL alone when there is no list
+    fn announce_tiers(&self) -> Vec<Vec<String>> {
+        vec![vec![self.announce_url().to_string()]]
+    }
+
+    /// What to tell trackers when announcing
+    fn announce_request(&self) -> anyhow::Result<AnnounceRequest>;
+
+    /// Info hash to announce with when trackers do not know the torrent by the one of
+    /// [`Self::announce_request`]: the truncated v2 hash of hybrid torrents
+    fn alternative_info_hash(&self) -> anyhow::Result<Option<[u8; 20]>> {
+        Ok(None)
+    }
+
+    /// HTTP announce URL for the given tracker
+    fn tracker_url_for(&self, announce_url: &str) -> anyhow::Result<Url> {
+        tracker_client::http_announce_url(announce_url, &self.announce_request()?)
+    }
+
+    fn tracker_url(&self) -> anyhow::Result<Url> {
+        self.tracker_url_for(self.announce_url())
+    }
 }
 
 impl TrackerInfo for Torrent {
-    fn tracker_url(&self) -> anyhow::Result<Url> {
-        tracker_url(&self.announce, &self.info_hash()?, self.total_len())
+    fn announce_url(&self) -> &str {
+        &self.announce
+    }
+
+    fn announce_tiers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(tiers) if !tiers.is_empty() => tiers.clone(),
+            _ => vec![vec![self.announce.clone()]],
+        }
+    }
+
+    fn announce_request(&self) -> anyhow::Result<AnnounceRequest> {
+        announce_request(self.info_hash()?, self.total_len())
+    }
+
+    fn alternative_info_hash(&self) -> anyhow::Result<Option<[u8; 20]>> {
+        self.truncated_info_hash_v2()
     }
 }
 
 impl TrackerInfo for MagnetLink {
-    fn tracker_url(&self) -> anyhow::Result<Url> {
-        tracker_url(&self.announce.to_string(), &self.info_hash, 999)
+    fn announce_url(&self) -> &str {
+        self.announce.as_ref().map_or("", |i| i.as_str())
+    }
+
+    /// The tracker alone, no tier for trackerless links
+    fn announce_tiers(&self) -> Vec<Vec<String>> {
+        self.announce.iter().map(|i| vec![i.to_string()]).collect()
+    }
+
+    /// `left` is the link's exact length, when it has one
+    fn announce_request(&self) -> anyhow::Result<AnnounceRequest> {
+        announce_request(self.info_hash, self.exact_length.unwrap_or(999))
     }
 }
 
-fn tracker_url(announce_url: &str, info_hash: &[u8; 20], left: usize) -> anyhow::Result<Url> {
-    let info_hash = hex::encode(info_hash)
-        .chars()
-        .collect::<Vec<_>>()
-        .chunks(2)
-        .map(|i| format!("%{}{}", i[0], i[1]))
-        .collect::<Vec<_>>()
-        .concat();
-
-    Url::parse_with_params(
-        format!("{}?info_hash={}", announce_url, info_hash).as_str(),
-        &[
-            ("peer_id", PEER_ID),
-            ("port", "6881"),
-            ("uploaded", "0"),
-            ("downloaded", "0"),
-            ("left", format!("{}", left.to_string()).as_str()),
-            ("compact", "1"),
-        ],
-    )
-    .context("creating tracker url")
+fn announce_request(info_hash: [u8; 20], left: u64) -> anyhow::Result<AnnounceRequest> {
+    Ok(AnnounceRequest {
+        info_hash,
+        peer_id: PEER_ID.as_bytes().try_into().context("invalid peer id")?,
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left,
+        event: AnnounceEvent::None,
+    })
 }
@@ -0,0 +1,351 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use reqwest::Url;
+
+use crate::tracker_client::{AnnounceEvent, AnnounceResponse};
+
+/// What is known about a tracker
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackerState {
+    pub url: Url,
+    pub last_announce: Option<Instant>,
+    pub interval: Option<Duration>,
+    pub min_interval: Option<Duration>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// Whether the tracker was told we started, and so should be told when we stop
+    pub started: bool,
+}
+
+impl TrackerState {
+    fn new(url: Url) -> Self {
+        Self {
+            url,
+            last_announce: None,
+            interval: None,
+            min_interval: None,
+            consecutive_failures: 0,
+            last_error: None,
+            started: false,
+        }
+    }
+
+    fn answered(&mut self, now: Instant, res: &AnnounceResponse) {
+        self.last_announce = Some(now);
+        self.interval = res.interval.map(Duration::from_secs);
+        self.min_interval = res.min_interval.map(Duration::from_secs);
+        self.consecutive_failures = 0;
+        self.last_error = None;
+    }
+
+    fn failed(&mut self, err: &anyhow::Error) {
+        self.consecutive_failures += 1;
+        self.last_error = Some(format!("{err:#}"));
+    }
+
+    /// Time to wait between two announces: the tracker's interval, but never less than its
+    /// minimum interval
+    pub fn reannounce_interval(&self) -> Option<Duration> {
+        Some(self.interval?.max(self.min_interval.unwrap_or_default()))
+    }
+
+    /// Whether the tracker's interval since the last successful announce has elapsed
+    pub fn is_due(&self, now: Instant) -> bool {
+        match (self.last_announce, self.reannounce_interval()) {
+            (Some(last), Some(interval)) => now >= last + interval,
+            _ => true,
+        }
+    }
+}
+
+/// Owns every tracker of a torrent, grouped in tiers as in BEP 12. Each announce goes to one
+/// tracker per tier: trackers of a tier are tried in order and the first one answering is moved
+/// to the front of its tier. Peers from all tiers are merged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackerManager {
+    tiers: Vec<Vec<TrackerState>>,
+}
+
+impl TrackerManager {
+    /// Trackers from `tiers`, dropping empty tiers and duplicated or invalid URLs
+    pub fn new<S: AsRef<str>>(tiers: &[Vec<S>]) -> Self {
+        let mut known = Vec::new();
+        let tiers = tiers
+            .iter()
+            .map(|tier| {
+                tier.iter()
+                    .filter_map(|url| Url::parse(url.as_ref()).ok())
+                    .filter(|url| {
+                        let new = !known.contains(url);
+                        known.push(url.clone());
+                        new
+                    })
+                    .map(TrackerState::new)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        Self { tiers }
+    }
+
+    /// Shuffles the trackers within each tier, as BEP 12 asks to do once when they are loaded,
+    /// drawing positions from `random`
+    pub fn shuffle(&mut self, mut random: impl FnMut() -> u32) {
+        for tier in &mut self.tiers {
+            for i in (1..tier.len()).rev() {
+                tier.swap(i, random() as usize % (i + 1));
+            }
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<TrackerState>] {
+        &self.tiers
+    }
+
+    /// When the next announce is due, `None` when no tracker ever answered
+    pub fn next_announce(&self) -> Option<Instant> {
+        self.tiers
+            .iter()
+            .filter_map(|tier| tier.first())
+            .filter_map(|i| Some(i.last_announce? + i.reannounce_interval()?))
+            .min()
+    }
+
+    /// Announces to every tier that is due with `announce`, returning the merged peers without
+    /// duplicates. Trackers are told we started the first time they are announced to. Fails
+    /// only when no tracker answered and one failed.
+    pub fn announce(
+        &mut self,
+        now: Instant,
+        mut announce: impl FnMut(&Url, AnnounceEvent) -> anyhow::Result<AnnounceResponse>,
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        let mut peers = Vec::new();
+        let mut first_error = None;
+        for tier in &mut self.tiers {
+            if !tier.first().is_some_and(|i| i.is_due(now)) {
+                continue;
+            }
+            let Some(position) = tier.iter_mut().position(|tracker| {
+                let event = if tracker.started {
+                    AnnounceEvent::None
+                } else {
+                    AnnounceEvent::Started
+                };
+                match announce(&tracker.url, event) {
+                    Ok(res) => {
+                        tracker.answered(now, &res);
+                        tracker.started = true;
+                        for peer in res.peers {
+                            if !peers.contains(&peer) {
+                                peers.push(peer);
+                            }
+                        }
+                        true
+                    }
+                    Err(err) => {
+                        tracker.failed(&err);
+                        first_error.get_or_insert(err);
+                        false
+                    }
+                }
+            }) else {
+                continue;
+            };
+            let tracker = tier.remove(position);
+            tier.insert(0, tracker);
+        }
+        match first_error {
+            Some(err) if peers.is_empty() => Err(err),
+            None if peers.is_empty() && self.tiers.is_empty() => Err(anyhow!("no tracker")),
+            _ => Ok(peers),
+        }
+    }
+
+    /// Tells the trackers that were told we started about `event`, such as the download being
+    /// completed or stopped, whether they are due or not. Trackers told we stopped are told we
+    /// started again on the next announce. Fails with the first error.
+    pub fn notify(
+        &mut self,
+        now: Instant,
+        event: AnnounceEvent,
+        mut announce: impl FnMut(&Url, AnnounceEvent) -> anyhow::Result<AnnounceResponse>,
+    ) -> anyhow::Result<()> {
+        let mut first_error = None;
+        for tracker in self.tiers.iter_mut().filter_map(|tier| tier.first_mut()) {
+            if !tracker.started {
+                continue;
+            }
+            match announce(&tracker.url, event) {
+                Ok(res) => tracker.answered(now, &res),
+                Err(err) => {
+                    tracker.failed(&err);
+                    first_error.get_or_insert(err);
+                }
+            }
+            if event == AnnounceEvent::Stopped {
+                tracker.started = false;
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::anyhow;
+
+    use crate::tracker_client::{AnnounceEvent, AnnounceResponse};
+
+    use super::TrackerManager;
+
+    fn response(peers: &[&str]) -> AnnounceResponse {
+        AnnounceResponse {
+            interval: Some(60),
+            min_interval: None,
+            peers: peers
+                .iter()
+                .map(|i| SocketAddr::from_str(i).expect("valid address"))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn tier_promotion_and_merged_peers() -> anyhow::Result<()> {
+        let mut manager = TrackerManager::new(&[
+            vec!["http://a/announce", "http://b/announce"],
+            vec!["http://c/announce", "http://a/announce"],
+            vec![],
+        ]);
+        assert_eq!(2, manager.tiers().len());
+        assert_eq!(1, manager.tiers()[1].len());
+
+        let now = Instant::now();
+        let mut asked = Vec::new();
+        let peers = manager.announce(now, |url, _| {
+            asked.push(url.host_str().unwrap_or_default().to_string());
+            match url.host_str() {
+                Some("a") => Err(anyhow!("down")),
+                Some("b") => Ok(response(&["1.1.1.1:1", "2.2.2.2:2"])),
+                _ => Ok(response(&["2.2.2.2:2", "3.3.3.3:3"])),
+            }
+        })?;
+
+        assert_eq!(vec!["a", "b", "c"], asked);
+        assert_eq!(3, peers.len());
+        let first_tier = &manager.tiers()[0];
+        assert_eq!(Some("b"), first_tier[0].url.host_str());
+        assert_eq!(1, first_tier[1].consecutive_failures);
+        assert_eq!(Some(now + Duration::from_secs(60)), manager.next_announce());
+
+        // Nothing is due before the interval elapsed
+        let peers = manager.announce(now + Duration::from_secs(1), |_, _| {
+            unreachable!("no tracker is due")
+        })?;
+        assert!(peers.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn event_lifecycle() -> anyhow::Result<()> {
+        let mut manager = TrackerManager::new(&[
+            vec!["http://a/announce"],
+            vec!["http://b/announce", "http://c/announce"],
+        ]);
+        let now = Instant::now();
+        let mut events = Vec::new();
+        let mut record = |url: &reqwest::Url, event| {
+            events.push((url.host_str().unwrap_or_default().to_string(), event));
+            match url.host_str() {
+                Some("b") => Err(anyhow!("down")),
+                _ => Ok(response(&["1.1.1.1:1"])),
+            }
+        };
+
+        manager.announce(now, &mut record)?;
+        manager.announce(now + Duration::from_secs(60), &mut record)?;
+        manager.notify(now, AnnounceEvent::Completed, &mut record)?;
+        manager.notify(now, AnnounceEvent::Stopped, &mut record)?;
+        manager.notify(now, AnnounceEvent::Stopped, &mut record)?;
+
+        let expected = [
+            ("a", AnnounceEvent::Started),
+            ("b", AnnounceEvent::Started),
+            ("c", AnnounceEvent::Started),
+            ("a", AnnounceEvent::None),
+            ("c", AnnounceEvent::None),
+            ("a", AnnounceEvent::Completed),
+            ("c", AnnounceEvent::Completed),
+            ("a", AnnounceEvent::Stopped),
+            ("c", AnnounceEvent::Stopped),
+        ];
+        assert_eq!(
+            expected
+                .iter()
+                .map(|(host, event)| (host.to_string(), *event))
+                .collect::<Vec<_>>(),
+            events
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn min_interval_is_respected() -> anyhow::Result<()> {
+        let mut manager = TrackerManager::new(&[vec!["http://a/announce"]]);
+        let now = Instant::now();
+        manager.announce(now, |_, _| {
+            Ok(AnnounceResponse {
+                interval: Some(10),
+                min_interval: Some(60),
+                ..response(&[])
+            })
+        })?;
+
+        assert_eq!(Some(now + Duration::from_secs(60)), manager.next_announce());
+        assert!(!manager.tiers()[0][0].is_due(now + Duration::from_secs(10)));
+        Ok(())
+    }
+
+    #[test]
+    fn shuffle_within_tiers() {
+        let mut manager = TrackerManager::new(&[
+            vec![
+                "http://a/announce",
+                "http://b/announce",
+                "http://c/announce",
+            ],
+            vec!["http://d/announce"],
+        ]);
+        manager.shuffle(|| 0);
+
+        let hosts = manager
+            .tiers()
+            .iter()
+            .map(|tier| {
+                tier.iter()
+                    .map(|i| i.url.host_str().unwrap_or_default())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![vec!["b", "c", "a"], vec!["d"]], hosts);
+    }
+
+    #[test]
+    fn error_when_no_tracker_answers() {
+        let mut manager = TrackerManager::new(&[vec!["http://a/announce"]]);
+
+        let res = manager.announce(Instant::now(), |_, _| Err(anyhow!("down")));
+
+        assert_eq!("down", res.unwrap_err().to_string());
+        assert_eq!(Some("down".to_string()), manager.tiers()[0][0].last_error);
+    }
+}
@@ -0,0 +1,383 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::Preallocation,
+    file_paths::{self, PathRules},
+    output,
+    sha1::PieceHasher,
+    torrent::Keys,
+    torrent_info::TorrentInfo,
+};
+
+/// A file of the torrent as found on disk
+#[derive(Clone)]
+struct DiskFile {
+    /// Index of the file in the torrent
+    index: usize,
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+}
+
+/// Length and last modification of a file on disk, which tell whether it changed since
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    length: u64,
+    /// Since the Unix epoch, as seconds and nanoseconds
+    modified: (u64, u32),
+}
+
+/// The torrent's content on disk: `path` is the file itself for single-file torrents and the
+/// directory named after the torrent for multi-file ones. Multi-file paths are sanitized for the
+/// platform, as they are when written. Files that are not selected are left out.
+pub struct DiskContent {
+    files: Vec<DiskFile>,
+}
+
+impl DiskContent {
+    pub fn new<TI: TorrentInfo>(torrent_info: &TI, path: &Path) -> Self {
+        let info = torrent_info.info();
+        let (files, _) = file_paths::sanitize_files(torrent_info.files_info(), PathRules::native());
+        let selected = torrent_info.selected_files();
+        let files = files
+            .into_iter()
+            .filter(|i| selected.as_ref().is_none_or(|s| s.contains(&i.index)))
+            .map(|file| DiskFile {
+                index: file.index,
+                path: match info.keys {
+                    Keys::SingleFile { .. } => path.to_path_buf(),
+                    Keys::MultiFile { .. } => path.join(
+                        file.path
+                            .strip_prefix(info.display_name())
+                            .unwrap_or(&file.path),
+                    ),
+                },
+                offset: file.offset,
+                length: file.length,
+            })
+            .collect();
+        Self { files }
+    }
+
+    /// Creates (or truncates) every file, and the directories holding them, reserving their
+    /// space according to `preallocation`
+    pub fn create(&self, preallocation: Preallocation) -> anyhow::Result<()> {
+        for file in &self.files {
+            if let Some(parent) = file.path.parent().filter(|i| !i.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("creating {}", parent.display()))?;
+            }
+            output::open_preallocated(&file.path, file.length, preallocation)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the files that do not exist yet like [`Self::create`], leaving the others as they
+    /// are
+    pub fn create_missing(&self, preallocation: Preallocation) -> anyhow::Result<()> {
+        let missing = Self {
+            files: self
+                .files
+                .iter()
+                .filter(|i| !i.path.exists())
+                .cloned()
+                .collect(),
+        };
+        missing.create(preallocation)
+    }
+
+    /// Fills `buf` with the content starting at `offset`, across file boundaries
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        for (file, start, range) in self.spans(offset, buf.len()) {
+            let mut handle = File::open(&file.path)
+                .with_context(|| format!("opening {}", file.path.display()))?;
+            handle.seek(SeekFrom::Start(start))?;
+            handle
+                .read_exact(&mut buf[range])
+                .with_context(|| format!("reading {}", file.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at `offset` in the content, across file boundaries, the files having been
+    /// created beforehand
+    pub fn write_at(&self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        for (file, start, range) in self.spans(offset, data.len()) {
+            let mut handle = OpenOptions::new()
+                .write(true)
+                .open(&file.path)
+                .with_context(|| format!("opening {}", file.path.display()))?;
+            handle.seek(SeekFrom::Start(start))?;
+            handle
+                .write_all(&data[range])
+                .with_context(|| format!("writing {}", file.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Index in the torrent of each file of the content, with the pieces of `piece_length` it
+    /// overlaps, none for empty files
+    pub fn file_pieces(&self, piece_length: u64) -> Vec<(usize, Range<usize>)> {
+        self.files
+            .iter()
+            .map(|file| {
+                let pieces = if file.length == 0 {
+                    0..0
+                } else {
+                    (file.offset / piece_length) as usize
+                        ..(file.offset + file.length).div_ceil(piece_length) as usize
+                };
+                (file.index, pieces)
+            })
+            .collect()
+    }
+
+    /// Stamp of the file of index `index` in the torrent, `None` when it is not part of the
+    /// content or is missing
+    pub fn stamp(&self, index: usize) -> Option<FileStamp> {
+        let file = self.files.iter().find(|i| i.index == index)?;
+        let metadata = fs::metadata(&file.path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(FileStamp {
+            length: metadata.len(),
+            modified: (modified.as_secs(), modified.subsec_nanos()),
+        })
+    }
+
+    /// The files holding the `len` bytes at `offset`, with where these bytes start in the file
+    /// and their range in the buffer
+    fn spans(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> impl Iterator<Item = (&DiskFile, u64, Range<usize>)> {
+        let end = offset + len as u64;
+        self.files.iter().filter_map(move |file| {
+            let file_end = file.offset + file.length;
+            if file.length == 0 || file_end <= offset || file.offset >= end {
+                return None;
+            }
+            let start = offset.max(file.offset);
+            let stop = end.min(file_end);
+            Some((
+                file,
+                start - file.offset,
+                (start - offset) as usize..(stop - offset) as usize,
+            ))
+        })
+    }
+}
+
+/// Hashes every piece of the content on disk against the torrent, spreading pieces over
+/// `threads` workers. Each worker holds a single piece in memory, which bounds the read-ahead.
+/// Pieces that cannot be read (missing or short files) are reported as failed.
+pub fn verify_pieces<TI: TorrentInfo>(
+    torrent_info: &TI,
+    path: &Path,
+    hasher: &dyn PieceHasher,
+    threads: usize,
+) -> anyhow::Result<Vec<bool>> {
+    torrent_info.validate_layout()?;
+    let content = DiskContent::new(torrent_info, path);
+    let all = vec![true; torrent_info.pieces_count()];
+    Ok(verify_content(
+        torrent_info,
+        &content,
+        &all,
+        hasher,
+        threads,
+    ))
+}
+
+/// Hashes the pieces of `content` set in `only` like [`verify_pieces`], the others being
+/// reported as failed without being read
+pub fn verify_content<TI: TorrentInfo>(
+    torrent_info: &TI,
+    content: &DiskContent,
+    only: &[bool],
+    hasher: &dyn PieceHasher,
+    threads: usize,
+) -> Vec<bool> {
+    let pieces = torrent_info.pieces_info();
+    let hashes = &torrent_info.info().pieces.0;
+    let results = Mutex::new(vec![false; pieces.len()]);
+    let next = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|| {
+                let mut buf = Vec::new();
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(piece) = pieces.get(index) else {
+                        break;
+                    };
+                    if only.get(index) != Some(&true) {
+                        continue;
+                    }
+                    let ok = match usize::try_from(piece.length) {
+                        Ok(length) => {
+                            buf.resize(length, 0);
+                            content.read_at(piece.offset, &mut buf).is_ok()
+                                && hasher.hash(&buf) == hashes[index]
+                        }
+                        Err(_) => false,
+                    };
+                    results.lock().expect("results lock poisoned")[index] = ok;
+                }
+            });
+        }
+    });
+
+    results.into_inner().expect("results lock poisoned")
+}
+
+/// Share of the torrent's bytes, in percent, held by the pieces set in `have`
+pub fn completion<TI: TorrentInfo>(torrent_info: &TI, have: &[bool]) -> f64 {
+    let total_len = torrent_info.total_len();
+    if total_len == 0 {
+        return 100.0;
+    }
+    let have_len = torrent_info
+        .pieces_info()
+        .iter()
+        .filter(|i| have.get(i.index) == Some(&true))
+        .map(|i| i.length)
+        .sum::<u64>();
+    have_len as f64 * 100.0 / total_len as f64
+}
+
+/// Number of workers to use by default, one per core
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |i| i.get())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{
+        cli::Preallocation,
+        sha1::{self, RustCryptoSha1},
+        torrent::Torrent,
+    };
+
+    use super::{completion, verify_pieces, DiskContent};
+
+    fn torrent_for(
+        name: &str,
+        content: &[u8],
+        piece_length: usize,
+        files: &[(&str, usize)],
+    ) -> Torrent {
+        let hashes = content
+            .chunks(piece_length)
+            .flat_map(sha1::hash)
+            .collect::<Vec<_>>();
+        let keys = match files {
+            [] => format!("6:lengthi{}e", content.len()),
+            files => format!(
+                "5:filesl{}e",
+                files
+                    .iter()
+                    .map(|(path, length)| format!(
+                        "d6:lengthi{length}e4:pathl{}:{path}ee",
+                        path.len()
+                    ))
+                    .collect::<String>()
+            ),
+        };
+        let mut torrent_content = Vec::from(format!(
+            "d8:announce31:http://127.0.0.1:44381/announce4:infod{keys}4:name{}:{name}12:piece lengthi{piece_length}e6:pieces{}:",
+            name.len(),
+            hashes.len()
+        ));
+        torrent_content.extend_from_slice(&hashes);
+        torrent_content.extend_from_slice(b"ee");
+        Torrent::from_bytes(&torrent_content).expect("valid torrent")
+    }
+
+    #[test]
+    fn verify_single_file() -> anyhow::Result<()> {
+        let content = (0..250u8).collect::<Vec<_>>();
+        let torrent = torrent_for("data.bin", &content, 64, &[]);
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("data.bin");
+        let mut corrupted = content.clone();
+        corrupted[130] ^= 0xff;
+        fs::write(&path, &corrupted)?;
+
+        assert_eq!(
+            vec![true, true, false, true],
+            verify_pieces(&torrent, &path, &RustCryptoSha1, 3)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verify_multi_file_with_missing_file() -> anyhow::Result<()> {
+        let content = (0..200u8).collect::<Vec<_>>();
+        let torrent = torrent_for("top", &content, 64, &[("a", 100), ("b", 0), ("c", 100)]);
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("a"), &content[..100])?;
+        fs::write(dir.path().join("b"), [])?;
+
+        assert_eq!(
+            vec![true, false, false, false],
+            verify_pieces(&torrent, dir.path(), &RustCryptoSha1, 2)?
+        );
+
+        fs::write(dir.path().join("c"), &content[100..])?;
+        assert_eq!(
+            vec![true; 4],
+            verify_pieces(&torrent, dir.path(), &RustCryptoSha1, 2)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn write_pieces_across_files() -> anyhow::Result<()> {
+        let content = (0..200u8).collect::<Vec<_>>();
+        let torrent = torrent_for("top", &content, 64, &[("a", 100), ("b", 0), ("c", 100)]);
+        let dir = tempfile::tempdir()?;
+        let disk = DiskContent::new(&torrent, dir.path());
+
+        disk.create(Preallocation::None)?;
+        for piece in (0..content.len()).step_by(64).rev() {
+            disk.write_at(
+                piece as u64,
+                &content[piece..(piece + 64).min(content.len())],
+            )?;
+        }
+
+        assert_eq!(content[..100], fs::read(dir.path().join("a"))?);
+        assert_eq!(Vec::<u8>::new(), fs::read(dir.path().join("b"))?);
+        assert_eq!(content[100..], fs::read(dir.path().join("c"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn completion_by_bytes() {
+        let content = (0..200u8).collect::<Vec<_>>();
+        let torrent = torrent_for("data.bin", &content, 64, &[]);
+
+        assert_eq!(0.0, completion(&torrent, &[false; 4]));
+        assert_eq!(100.0, completion(&torrent, &[true; 4]));
+        // the last piece only holds 8 bytes
+        assert_eq!(96.0, completion(&torrent, &[true, true, true, false]));
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    fmt::Display,
+    net::{Ipv4Addr, SocketAddr},
+    ops::Range,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use reqwest::Url;
+
+use crate::{bt_client::HttpClient, torrent::FileInfo, tracker_client};
+
+/// Longest a busy HTTP seed is waited for before asking it again
+const MAX_BUSY_WAIT: Duration = Duration::from_secs(60);
+
+/// A server the torrent's content can be downloaded from over HTTP
+#[derive(Debug, Clone, PartialEq)]
+pub enum WebSeed {
+    /// Serves the torrent's files, which pieces are read from with range requests (BEP 19)
+    UrlList(Url),
+    /// Serves pieces through a script taking the info hash, the piece and ranges within it
+    /// (BEP 17)
+    HttpSeed(Url),
+}
+
+impl WebSeed {
+    pub fn url(&self) -> &Url {
+        match self {
+            WebSeed::UrlList(url) | WebSeed::HttpSeed(url) => url,
+        }
+    }
+}
+
+impl Display for WebSeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.url())
+    }
+}
+
+/// What an HTTP seed answered
+#[derive(Debug, PartialEq)]
+pub enum Fetched {
+    Data(Vec<u8>),
+    /// The seed is busy and asks to come back after a while
+    Busy(Duration),
+}
+
+/// Address standing for the web seed at `index` in the scheduler and the download stats. Peers
+/// never have an unspecified address, so a web seed cannot be mistaken for one.
+pub fn address(index: usize) -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::UNSPECIFIED, (index + 1) as u16))
+}
+
+pub fn is_web_seed(address: SocketAddr) -> bool {
+    address.ip().is_unspecified()
+}
+
+/// URL of `file` on the web seed at `base` (BEP 19). The files of multi-file torrents are below
+/// `base`, under the torrent's name. A single file is `base` itself, unless it ends with a slash
+/// and the file name is to be appended.
+pub fn file_url(base: &Url, file: &FileInfo, multi_file: bool) -> anyhow::Result<Url> {
+    if !multi_file && !base.path().ends_with('/') {
+        return Ok(base.clone());
+    }
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid web seed URL: {base}"))?
+        .pop_if_empty()
+        .extend(file.path.iter().map(|i| i.to_string_lossy()));
+    Ok(url)
+}
+
+/// Fetches `range` of the torrent's byte stream from the files on the web seed at `base`
+pub fn fetch_from_files<C: HttpClient>(
+    client: &C,
+    base: &Url,
+    files: &[FileInfo],
+    multi_file: bool,
+    range: Range<u64>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for (index, range) in file_ranges(files, range) {
+        let expected = range.end - range.start;
+        let bytes = client
+            .get_range(file_url(base, &files[index], multi_file)?, range)
+            .with_context(|| format!("fetching from web seed {base}"))?;
+        if bytes.len() as u64 != expected {
+            bail!(
+                "web seed {base} sent {} bytes instead of {expected}",
+                bytes.len()
+            );
+        }
+        data.extend(bytes);
+    }
+    Ok(data)
+}
+
+/// URL asking the HTTP seed at `base` for `range` of `piece`. BEP 17 ranges are inclusive.
+pub fn http_seed_url(base: &Url, info_hash: &[u8; 20], piece: u32, range: &Range<u64>) -> Url {
+    let mut url = base.clone();
+    let query = format!(
+        "info_hash={}&piece={piece}&ranges={}-{}",
+        tracker_client::url_encode(info_hash),
+        range.start,
+        range.end.saturating_sub(1)
+    );
+    let query = match base.query() {
+        Some(existing) => format!("{existing}&{query}"),
+        None => query,
+    };
+    url.set_query(Some(&query));
+    url
+}
+
+/// Fetches `range` of `piece` from the HTTP seed at `base`. A busy seed answers with a 503 and
+/// the number of seconds to wait as body: the status not being known here, a body shorter than
+/// asked for that is a number is taken for such an answer.
+pub fn fetch_from_http_seed<C: HttpClient>(
+    client: &C,
+    base: &Url,
+    info_hash: &[u8; 20],
+    piece: u32,
+    range: Range<u64>,
+) -> anyhow::Result<Fetched> {
+    let expected = range.end - range.start;
+    let bytes = client
+        .get(http_seed_url(base, info_hash, piece, &range))
+        .with_context(|| format!("fetching from HTTP seed {base}"))?;
+    if bytes.len() as u64 == expected {
+        return Ok(Fetched::Data(bytes));
+    }
+    match std::str::from_utf8(&bytes)
+        .ok()
+        .and_then(|i| i.trim().parse::<u64>().ok())
+    {
+        Some(seconds) if (bytes.len() as u64) < expected => Ok(Fetched::Busy(
+            Duration::from_secs(seconds).min(MAX_BUSY_WAIT),
+        )),
+        _ => bail!(
+            "HTTP seed {base} sent {} bytes instead of {expected}",
+            bytes.len()
+        ),
+    }
+}
+
+/// Parts of `files` holding `range` of the torrent's byte stream, as file indexes along with
+/// the range within each file
+pub fn file_ranges(files: &[FileInfo], range: Range<u64>) -> Vec<(usize, Range<u64>)> {
+    files
+        .iter()
+        .filter(|i| i.length > 0 && i.offset < range.end && range.start < i.offset + i.length)
+        .map(|i| {
+            let start = range.start.max(i.offset) - i.offset;
+            let end = range.end.min(i.offset + i.length) - i.offset;
+            (i.index, start..end)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use reqwest::Url;
+
+    use crate::torrent::FileInfo;
+
+    use super::{file_ranges, file_url, http_seed_url};
+
+    fn file(index: usize, path: &str, offset: u64, length: u64) -> FileInfo {
+        FileInfo {
+            index,
+            path: PathBuf::from(path),
+            offset,
+            length,
+        }
+    }
+
+    #[test]
+    fn file_urls() -> anyhow::Result<()> {
+        let single = file(0, "debian.iso", 0, 10);
+        let nested = file(0, "album/disc 1/track.flac", 0, 10);
+
+        assert_eq!(
+            "http://example.org/get/file.iso",
+            file_url(
+                &Url::parse("http://example.org/get/file.iso")?,
+                &single,
+                false
+            )?
+            .as_str()
+        );
+        assert_eq!(
+            "http://example.org/get/debian.iso",
+            file_url(&Url::parse("http://example.org/get/")?, &single, false)?.as_str()
+        );
+        assert_eq!(
+            "http://example.org/get/album/disc%201/track.flac",
+            file_url(&Url::parse("http://example.org/get")?, &nested, true)?.as_str()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn http_seed_urls() -> anyhow::Result<()> {
+        let info_hash = [[0xab; 19].as_slice(), &[1]].concat().try_into().unwrap();
+
+        assert_eq!(
+            format!(
+                "http://example.org/seed.php?key=1&info_hash={}%01&piece=3&ranges=16384-32767",
+                "%ab".repeat(19)
+            ),
+            http_seed_url(
+                &Url::parse("http://example.org/seed.php?key=1")?,
+                &info_hash,
+                3,
+                &(16384..32768)
+            )
+            .as_str()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ranges_across_files() {
+        let files = [
+            file(0, "a", 0, 10),
+            file(1, "b", 10, 0),
+            file(2, "c", 10, 5),
+            file(3, "d", 15, 20),
+        ];
+
+        assert_eq!(vec![(0, 2..8)], file_ranges(&files, 2..8));
+        assert_eq!(
+            vec![(0, 8..10), (2, 0..5), (3, 0..3)],
+            file_ranges(&files, 8..18)
+        );
+        assert!(file_ranges(&files, 35..40).is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+use std::time::Instant;
+
+use serde_json::{json, Map};
+
+use crate::{
+    cli::LogFormat,
+    logging,
+    peer_messages::{ExtensionMessage, Handshake, Message},
+};
+
+/// Logs peer wire traffic to stderr, one line per message, when enabled
+#[derive(Debug, Clone)]
+pub struct WireTrace {
+    enabled: bool,
+    start: Instant,
+}
+
+impl Default for WireTrace {
+    fn default() -> Self {
+        WireTrace::new(false)
+    }
+}
+
+impl WireTrace {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn sent(&self, message: &Message, size: usize) {
+        self.log("->", &Self::describe(message), size);
+    }
+
+    pub fn received(&self, message: &Message, size: usize) {
+        self.log("<-", &Self::describe(message), size);
+    }
+
+    pub fn sent_handshake(&self, handshake: &Handshake) {
+        self.log("->", &Self::describe_handshake(handshake), 68);
+    }
+
+    pub fn received_handshake(&self, handshake: &Handshake) {
+        self.log("<-", &Self::describe_handshake(handshake), 68);
+    }
+
+    fn log(&self, direction: &str, description: &str, size: usize) {
+        if !self.enabled {
+            return;
+        }
+        let elapsed_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+        match logging::format() {
+            LogFormat::Text => {
+                eprintln!("[{elapsed_ms:>10.3}ms] {direction} {description} ({size}B)")
+            }
+            LogFormat::Json => {
+                let mut fields = Map::new();
+                fields.insert(
+                    "direction".to_string(),
+                    json!(if direction == "->" {
+                        "sent"
+                    } else {
+                        "received"
+                    }),
+                );
+                fields.insert("size".to_string(), json!(size));
+                fields.insert("elapsed_ms".to_string(), json!(elapsed_ms));
+                eprintln!(
+                    "{}",
+                    logging::json_event("trace", "wire", description, fields)
+                );
+            }
+        }
+    }
+
+    fn describe_handshake(handshake: &Handshake) -> String {
+        format!(
+            "Handshake info_hash={} peer_id={}",
+            hex::encode(handshake.info_hash),
+            hex::encode(handshake.peer_id)
+        )
+    }
+
+    /// Compact, single line representation of a message and its relevant fields
+    pub fn describe(message: &Message) -> String {
+        match message {
+            Message::BitField { bitfield } => {
+                format!("{message} bytes={}", bitfield.as_bytes().len())
+            }
+            Message::Have { index }
+            | Message::SuggestPiece { index }
+            | Message::AllowedFast { index } => format!("{message} index={index}"),
+            Message::Port { port } => format!("{message} port={port}"),
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::RejectRequest {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            } => format!("{message} index={index} begin={begin} length={length}"),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => format!(
+                "{message} index={index} begin={begin} length={}",
+                block.len()
+            ),
+            Message::Extension {
+                message: ExtensionMessage::Info { info },
+            } => format!(
+                "{message} handshake ut_metadata={:?} ut_pex={:?}",
+                info.metdata.ut_metadata, info.metdata.ut_pex
+            ),
+            Message::Extension {
+                message: ExtensionMessage::Data { data, info },
+            } => format!(
+                "{message} data msg_type={} piece={} total_size={} info={}",
+                data.msg_type,
+                data.piece,
+                data.total_size,
+                info.is_some()
+            ),
+            Message::Extension {
+                message: ExtensionMessage::Custom { id, payload },
+            } => format!("{message} id={id} bytes={}", payload.len()),
+            _ => format!("{message}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::peer_messages::Message;
+
+    use super::WireTrace;
+
+    #[test]
+    fn describe_request() {
+        assert_eq!(
+            "Request index=1 begin=16384 length=42",
+            WireTrace::describe(&Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 42
+            })
+        );
+    }
+
+    #[test]
+    fn describe_piece() {
+        assert_eq!(
+            "Piece index=3 begin=0 length=2",
+            WireTrace::describe(&Message::Piece {
+                index: 3,
+                begin: 0,
+                block: vec![1, 2]
+            })
+        );
+    }
+}